@@ -11,10 +11,12 @@ use core::sync::atomic::{AtomicU32, Ordering};
 
 use frame::frame_alloc;
 use polyhal::addr::PhysPage;
+use polyhal::backtrace::print_backtrace;
 use polyhal::common::{get_fdt, get_mem_areas, PageAlloc};
 use polyhal::debug_console::DebugConsole;
 use polyhal::define_entry;
 use polyhal::instruction::{ebreak, shutdown};
+use polyhal::trap::ThreadToken;
 use polyhal::trap::TrapType::{self, *};
 use polyhal::trapframe::{TrapFrame, TrapFrameArgs};
 
@@ -32,10 +34,10 @@ impl PageAlloc for PageAllocImpl {
 
 /// kernel interrupt
 #[polyhal::arch_interrupt]
-fn kernel_interrupt(ctx: &mut TrapFrame, trap_type: TrapType) {
+fn kernel_interrupt(ctx: &mut TrapFrame, trap_type: TrapType, _token: ThreadToken) {
     // println!("trap_type @ {:x?} {:#x?}", trap_type, ctx);
     match trap_type {
-        Breakpoint => {
+        Breakpoint(_) => {
             log::info!("BreakPoint @ {:#x}", ctx[TrapFrameArgs::SEPC]);
         }
         SysCall => {
@@ -43,8 +45,8 @@ fn kernel_interrupt(ctx: &mut TrapFrame, trap_type: TrapType) {
             ctx.syscall_ok();
             log::info!("Handle a syscall");
         }
-        StorePageFault(paddr) | LoadPageFault(paddr) | InstructionPageFault(paddr) => {
-            log::info!("page fault: {:#x}", paddr);
+        StorePageFault(info) | LoadPageFault(info) | InstructionPageFault(info) => {
+            log::info!("page fault: {:#x}", info.addr);
         }
         IllegalInstruction(_) => {
             log::info!("illegal instruction");
@@ -138,5 +140,6 @@ fn panic(info: &PanicInfo) -> ! {
     } else {
         log::error!("[kernel] Panicked: {}", info.message().unwrap());
     }
+    print_backtrace();
     shutdown()
 }
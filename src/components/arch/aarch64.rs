@@ -41,4 +41,5 @@ pub(crate) fn arch_init() {
         });
         MEM_AREA.init_by(mem_area);
     }
+    crate::components::irq::init_its();
 }
@@ -9,6 +9,7 @@ pub(crate) fn arch_init() {
     MEM_AREA.init_by(vec![
         (VIRT_ADDR_START | 0x9000_0000, 0x2000_0000)
     ]);
+    crate::components::irq::init();
 }
 
 #[inline]
@@ -48,4 +48,7 @@ pub fn arch_init() {
         mem_area.push((0x8000_0000 | VIRT_ADDR_START, 0x1000_0000));
     }
     MEM_AREA.init_by(mem_area);
+
+    // Initialize the interrupt controller
+    crate::components::irq::init();
 }
@@ -15,6 +15,9 @@ pub(crate) mod vectors {
     pub const APIC_TIMER_VECTOR: u8 = 0xf0;
     pub const APIC_SPURIOUS_VECTOR: u8 = 0xf1;
     pub const APIC_ERROR_VECTOR: u8 = 0xf2;
+    /// The vectors of the MSIs, between the PIC irqs and the APIC vectors.
+    pub const MSI_VECTOR_START: u8 = 0x30;
+    pub const MSI_VECTOR_LAST: u8 = APIC_TIMER_VECTOR - 1;
 }
 
 /// The maximum number of IRQs.
@@ -25,10 +28,16 @@ pub const TIMER_IRQ_NUM: usize = APIC_TIMER_VECTOR as usize;
 
 const IO_APIC_BASE: u64 = 0xFEC0_0000;
 
+/// The physical address of the MSI, the destination apic id is at bit 12.
+pub(crate) const MSI_ADDR_BASE: usize = 0xFEE0_0000;
+
 static mut LOCAL_APIC: Option<LocalApic> = None;
 static mut IS_X2APIC: bool = false;
 static IO_APIC: Once<MutexNoIrq<IoApic>> = Once::new();
 
+/// The allocated vectors of the MSIs.
+static MSI_VECS: MutexNoIrq<[u64; 4]> = MutexNoIrq::new([0; 4]);
+
 /// Registers an IRQ handler for the given IRQ.
 ///
 /// It also enables the IRQ if the registration succeeds. It returns `false` if
@@ -57,6 +66,20 @@ pub(crate) fn io_apic<'a>() -> &'a MutexNoIrq<IoApic> {
     IO_APIC.get().expect("Can't get io_apic")
 }
 
+/// Allocate a free vector for the MSI.
+pub(crate) fn msi_alloc_vector() -> Option<u8> {
+    let mut vecs = MSI_VECS.lock();
+    let vector = (MSI_VECTOR_START..=MSI_VECTOR_LAST)
+        .find(|&vector| vecs[vector as usize / 64] & (1 << (vector % 64)) == 0)?;
+    vecs[vector as usize / 64] |= 1 << (vector % 64);
+    Some(vector)
+}
+
+/// Release the vector allocated by [msi_alloc_vector].
+pub(crate) fn msi_free_vector(vector: u8) {
+    MSI_VECS.lock()[vector as usize / 64] &= !(1 << (vector % 64));
+}
+
 pub(crate) fn raw_apic_id(id_u8: u8) -> u32 {
     if unsafe { IS_X2APIC } {
         id_u8 as u32
@@ -1,6 +1,10 @@
+use alloc::alloc::{alloc, handle_alloc_error};
+use core::alloc::Layout;
 use core::fmt;
+use core::ptr::addr_of_mut;
 
 use spin::Once;
+use x86::msr::{rdmsr, IA32_APIC_BASE};
 use x86_64::instructions::tables::{lgdt, load_tss};
 use x86_64::registers::segmentation::{Segment, SegmentSelector, CS};
 use x86_64::structures::gdt::{Descriptor, DescriptorFlags};
@@ -13,6 +17,61 @@ pub(super) static GDT: Once<GdtStruct> = Once::new();
 #[polyhal_macro::def_percpu]
 pub(super) static TSS: Once<TaskStateSegment> = Once::new();
 
+/// The index of the interrupt stack table used by the double fault.
+#[cfg(feature = "trap")]
+pub(super) const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// The index of the interrupt stack table used by the NMI.
+#[cfg(feature = "trap")]
+pub(super) const NMI_IST_INDEX: u16 = 1;
+/// The index of the interrupt stack table used by the machine check.
+#[cfg(feature = "trap")]
+pub(super) const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+/// The number of the used stacks in the interrupt stack table.
+const IST_NUM: usize = 3;
+
+/// The size of the stack in the interrupt stack table.
+const IST_STACK_SIZE: usize = 0x8000;
+
+/// The stacks in the interrupt stack table, the exceptions using them may
+/// be raised when the kernel stack is broken.
+#[repr(C, align(16))]
+struct IstStacks([[u8; IST_STACK_SIZE]; IST_NUM]);
+
+/// The interrupt stacks of the boot core.
+#[link_section = ".bss.stack"]
+static mut BOOT_IST_STACKS: IstStacks = IstStacks([[0; IST_STACK_SIZE]; IST_NUM]);
+
+/// The BSP flag of the `IA32_APIC_BASE`, it's set on the boot core.
+const APIC_BASE_BSP: u64 = 1 << 8;
+
+/// Get the interrupt stacks of the current cpu.
+///
+/// The boot core uses the static stacks, the heap isn't ready when it
+/// initializes the GDT. The others allocate them.
+fn ist_stacks() -> *mut IstStacks {
+    if unsafe { rdmsr(IA32_APIC_BASE) } & APIC_BASE_BSP != 0 {
+        return unsafe { addr_of_mut!(BOOT_IST_STACKS) };
+    }
+    let layout = Layout::new::<IstStacks>();
+    let stacks = unsafe { alloc(layout) } as *mut IstStacks;
+    if stacks.is_null() {
+        handle_alloc_error(layout);
+    }
+    stacks
+}
+
+/// Create the TSS with the interrupt stack table of the current cpu.
+fn new_tss() -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    let stacks = ist_stacks();
+    for i in 0..IST_NUM {
+        let top = unsafe { (*stacks).0[i].as_ptr_range().end };
+        tss.interrupt_stack_table[i] = VirtAddr::from_ptr(top);
+    }
+    tss
+}
+
 /// A wrapper of the Global Descriptor Table (GDT) with maximum 16 entries.
 #[repr(align(16))]
 pub struct GdtStruct {
@@ -99,7 +158,7 @@ pub fn init() {
     unsafe {
         let tss = TSS.current_ref_raw();
         let gdt = GDT.current_ref_mut_raw();
-        tss.call_once(|| TaskStateSegment::new());
+        tss.call_once(new_tss);
         gdt.call_once(|| GdtStruct::new(tss.get_unchecked()));
         let gdt = gdt.get_unchecked();
         gdt.load();
@@ -1,10 +1,29 @@
 use spin::Once;
 use x86_64::structures::idt::{Entry, HandlerFunc, InterruptDescriptorTable};
 
+#[cfg(feature = "trap")]
+use super::gdt::{DOUBLE_FAULT_IST_INDEX, MACHINE_CHECK_IST_INDEX, NMI_IST_INDEX};
+#[cfg(feature = "trap")]
+use x86::irq::{DOUBLE_FAULT_VECTOR, MACHINE_CHECK_VECTOR, NONMASKABLE_INTERRUPT_VECTOR};
+
 const NUM_INT: usize = 256;
 
 pub(super) static IDT: Once<IdtStruct> = Once::new();
 
+/// Get the index of the interrupt stack table used by the `vector`.
+///
+/// These exceptions may be raised when the kernel stack is broken, they
+/// switch to their own stacks.
+#[cfg(feature = "trap")]
+fn ist_index(vector: u8) -> Option<u16> {
+    match vector {
+        DOUBLE_FAULT_VECTOR => Some(DOUBLE_FAULT_IST_INDEX),
+        NONMASKABLE_INTERRUPT_VECTOR => Some(NMI_IST_INDEX),
+        MACHINE_CHECK_VECTOR => Some(MACHINE_CHECK_IST_INDEX),
+        _ => None,
+    }
+}
+
 /// A wrapper of the Interrupt Descriptor Table (IDT).
 #[repr(transparent)]
 pub struct IdtStruct(InterruptDescriptorTable);
@@ -30,7 +49,10 @@ impl IdtStruct {
         };
         #[cfg(feature = "trap")]
         for i in 0..NUM_INT {
-            entries[i].set_handler_fn(unsafe { core::mem::transmute(ENTRIES[i]) });
+            let options = entries[i].set_handler_fn(unsafe { core::mem::transmute(ENTRIES[i]) });
+            if let Some(index) = ist_index(i as u8) {
+                unsafe { options.set_stack_index(index) };
+            }
         }
         idt
     }
@@ -0,0 +1,22 @@
+use core::arch::asm;
+
+use crate::components::trapframe::TrapFrame;
+
+/// `x29` points to the frame record, the previous frame pointer and the
+/// return address.
+pub(super) const RA_OFFSET: isize = 8;
+pub(super) const PREV_FP_OFFSET: isize = 0;
+
+/// Get the frame pointer of the current function.
+#[inline(always)]
+pub(super) fn current_fp() -> usize {
+    let fp;
+    unsafe { asm!("mov {}, x29", out(reg) fp) };
+    fp
+}
+
+/// Get the frame pointer of the trapped function.
+#[inline]
+pub(super) fn trap_fp(tf: &TrapFrame) -> usize {
+    tf.regs[29]
+}
@@ -0,0 +1,243 @@
+//! Stack backtrace module.
+//!
+//! Walk the stack through the frame pointers, the kernel must be built with
+//! `-Cforce-frame-pointers=yes`. Only the frames in the kernel address space
+//! (above `VIRT_ADDR_START`) are walked, the stack is read through
+//! [probe_read](crate::components::extable::probe_read) if the `trap`
+//! feature is enabled.
+//!
+//! ```rust
+//! #[panic_handler]
+//! fn panic(info: &PanicInfo) -> ! {
+//!     log::error!("{}", info);
+//!     print_backtrace();
+//!     shutdown()
+//! }
+//! ```
+//!
+//! The addresses are symbolized through the symbol table embedded by
+//! [include_symbols](crate::include_symbols), the table is the output of
+//! `nm -n -C` of the kernel itself. So the kernel is linked twice, the
+//! first link with an empty table:
+//!
+//! ```shell
+//! touch kernel.sym && cargo build
+//! nm -n -C target/<target>/release/kernel > kernel.sym && cargo build
+//! ```
+//!
+//! TIPS: The `__ksymtab` section must be placed after the `.text`, otherwise
+//! the second link moves the code.
+
+use core::fmt::{self, Debug, Display};
+use core::mem::size_of;
+
+use crate::components::consts::VIRT_ADDR_START;
+use crate::components::debug_console::println;
+use crate::components::trapframe::{TrapFrame, TrapFrameArgs};
+
+super::define_arch_mods!();
+
+/// The maximum depth of the backtrace.
+const MAX_DEPTH: usize = 64;
+
+/// This is a empty seat for symbol table section.
+/// Force the linker to create the symbol table section.
+#[link_section = "__ksymtab"]
+#[used(linker)]
+static _KSYMTAB_SEAT: [u8; 0] = [];
+
+/// Embed the symbol table generated by `nm -n -C`, the `$path` is relative
+/// to the root of the crate.
+///
+/// ```rust
+/// polyhal::include_symbols!("kernel.sym");
+/// ```
+#[macro_export]
+macro_rules! include_symbols {
+    ($path:literal) => {
+        // Rebuild the crate when the symbol table is changed.
+        const _: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/", $path));
+        // The section is marked as retained (`R`), so `--gc-sections` will not drop it.
+        core::arch::global_asm!(concat!(
+            "
+            .pushsection __ksymtab, \"aR\"
+            .incbin \"", env!("CARGO_MANIFEST_DIR"), "/", $path, "\"
+            .popsection
+            "
+        ));
+    };
+}
+
+/// The function symbol in the symbol table.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    /// The name of the function.
+    pub name: &'static str,
+    /// The start address of the function.
+    pub addr: usize,
+}
+
+/// Get the symbol table embedded by [include_symbols](crate::include_symbols).
+fn symbol_table() -> &'static [u8] {
+    extern "Rust" {
+        fn __start___ksymtab();
+        fn __stop___ksymtab();
+    }
+    let start = __start___ksymtab as usize;
+    let len = __stop___ksymtab as usize - start;
+    unsafe { core::slice::from_raw_parts(start as *const u8, len) }
+}
+
+/// Find the function that contains the `addr` in the symbol table.
+///
+/// Return `None` if the symbol table isn't embedded.
+pub fn lookup_symbol(addr: usize) -> Option<Symbol> {
+    let mut found = None;
+    // The lines are `<addr> <type> <name>` sorted by the address.
+    for line in symbol_table().split(|&c| c == b'\n') {
+        let Ok(line) = core::str::from_utf8(line) else {
+            continue;
+        };
+        let mut parts = line.splitn(3, ' ');
+        let (Some(sym_addr), Some(ty), Some(name)) = (parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        if !matches!(ty, "T" | "t" | "W" | "w") {
+            continue;
+        }
+        let Ok(sym_addr) = usize::from_str_radix(sym_addr, 16) else {
+            continue;
+        };
+        if sym_addr > addr {
+            break;
+        }
+        found = Some(Symbol {
+            name: name.trim_end(),
+            addr: sym_addr,
+        });
+    }
+    found
+}
+
+/// The address formatted with the symbol, like `0xffffffc080200010 <main+0x10>`.
+///
+/// The [Debug] output keeps the format of the address, `{:x?}` prints it in hex.
+#[derive(Clone, Copy)]
+pub struct SymbolAddr(pub usize);
+
+impl SymbolAddr {
+    /// Write the ` <name+offset>` if the address is in the symbol table.
+    fn fmt_symbol(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match lookup_symbol(self.0) {
+            Some(sym) => write!(f, " <{}+{:#x}>", sym.name, self.0 - sym.addr),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Debug for SymbolAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&self.0, f)?;
+        self.fmt_symbol(f)
+    }
+}
+
+impl Display for SymbolAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)?;
+        self.fmt_symbol(f)
+    }
+}
+
+/// The iterator of the return addresses on the stack.
+///
+/// It's created by [backtrace] or [TrapFrame::backtrace].
+pub struct Frames {
+    /// The program counter of the trapped function.
+    pc: Option<usize>,
+    fp: usize,
+    depth: usize,
+}
+
+/// Read the word on the stack, return `None` if it faults.
+#[inline]
+fn read_word(addr: usize) -> Option<usize> {
+    #[cfg(feature = "trap")]
+    {
+        use crate::components::extable::probe_read;
+        let mut bytes = [0u8; size_of::<usize>()];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = probe_read((addr + i).into())?;
+        }
+        Some(usize::from_ne_bytes(bytes))
+    }
+    #[cfg(not(feature = "trap"))]
+    Some(unsafe { (addr as *const usize).read_volatile() })
+}
+
+impl Iterator for Frames {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if let Some(pc) = self.pc.take() {
+            return Some(pc);
+        }
+        let fp = self.fp;
+        if self.depth >= MAX_DEPTH || fp < VIRT_ADDR_START || fp % size_of::<usize>() != 0 {
+            return None;
+        }
+        let ra = read_word(fp.wrapping_add_signed(RA_OFFSET))?;
+        let prev = read_word(fp.wrapping_add_signed(PREV_FP_OFFSET))?;
+        self.depth += 1;
+        // The stack grows downward, the chain is broken if it doesn't go up.
+        self.fp = if prev > fp { prev } else { 0 };
+        match ra {
+            0 => None,
+            _ => Some(ra),
+        }
+    }
+}
+
+/// Get the return addresses from the current function.
+#[inline(always)]
+pub fn backtrace() -> Frames {
+    Frames {
+        pc: None,
+        fp: current_fp(),
+        depth: 0,
+    }
+}
+
+/// Print the backtrace of the frames.
+fn print_frames(frames: Frames) {
+    println!("Backtrace:");
+    for (i, addr) in frames.enumerate() {
+        println!("  #{:<2} {}", i, SymbolAddr(addr));
+    }
+}
+
+/// Print the backtrace from the caller.
+#[inline(never)]
+pub fn print_backtrace() {
+    print_frames(backtrace());
+}
+
+impl TrapFrame {
+    /// Get the trapped program counter and the return addresses from it.
+    ///
+    /// Only the program counter is returned if it's trapped from the user mode.
+    #[inline]
+    pub fn backtrace(&self) -> Frames {
+        Frames {
+            pc: Some(self[TrapFrameArgs::SEPC]),
+            fp: trap_fp(self),
+            depth: 0,
+        }
+    }
+
+    /// Print the backtrace from the trapped function.
+    pub fn print_backtrace(&self) {
+        print_frames(self.backtrace());
+    }
+}
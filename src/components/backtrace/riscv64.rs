@@ -0,0 +1,22 @@
+use core::arch::asm;
+
+use crate::components::trapframe::TrapFrame;
+
+/// `s0` points to the top of the frame, the return address and the previous
+/// frame pointer are saved below it.
+pub(super) const RA_OFFSET: isize = -8;
+pub(super) const PREV_FP_OFFSET: isize = -16;
+
+/// Get the frame pointer of the current function.
+#[inline(always)]
+pub(super) fn current_fp() -> usize {
+    let fp;
+    unsafe { asm!("mv {}, s0", out(reg) fp) };
+    fp
+}
+
+/// Get the frame pointer of the trapped function.
+#[inline]
+pub(super) fn trap_fp(tf: &TrapFrame) -> usize {
+    tf.x[8]
+}
@@ -0,0 +1,22 @@
+use core::arch::asm;
+
+use crate::components::trapframe::TrapFrame;
+
+/// `rbp` points to the previous frame pointer, the return address is pushed
+/// above it by the `call`.
+pub(super) const RA_OFFSET: isize = 8;
+pub(super) const PREV_FP_OFFSET: isize = 0;
+
+/// Get the frame pointer of the current function.
+#[inline(always)]
+pub(super) fn current_fp() -> usize {
+    let fp;
+    unsafe { asm!("mov {}, rbp", out(reg) fp) };
+    fp
+}
+
+/// Get the frame pointer of the trapped function.
+#[inline]
+pub(super) fn trap_fp(tf: &TrapFrame) -> usize {
+    tf.rbp
+}
@@ -20,6 +20,11 @@ use super::PageAlignment;
 #[link_section = ".data"]
 static mut BOOT_PT_L1: PageAlignment = PageAlignment([PTE(0); PageTable::PTE_NUM_IN_PAGE]);
 
+/// The page table of `TTBR1`, the kernel half has its own table because
+/// the last entry is replaced by the kernel stacks.
+#[link_section = ".data"]
+static mut BOOT_PT_KERNEL_L1: PageAlignment = PageAlignment([PTE(0); PageTable::PTE_NUM_IN_PAGE]);
+
 unsafe fn switch_to_el1() {
     SPSel.write(SPSel::SP::ELx);
     SP_EL0.set(0);
@@ -43,6 +48,13 @@ unsafe fn switch_to_el1() {
         // Disable EL1 timer traps and the timer offset.
         CNTHCTL_EL2.modify(CNTHCTL_EL2::EL1PCEN::SET + CNTHCTL_EL2::EL1PCTEN::SET);
         CNTVOFF_EL2.set(0);
+        // Allow EL1 to access the GICv3 cpu interface by the system registers.
+        let pfr0: usize;
+        core::arch::asm!("mrs {0}, id_aa64pfr0_el1", out(reg) pfr0);
+        if (pfr0 >> 24) & 0xf != 0 {
+            // ICC_SRE_EL2: SRE | Enable
+            core::arch::asm!("msr S3_4_C12_C9_5, {0}", "isb", in(reg) 0b1001usize);
+        }
         // Set EL1 to 64bit.
         HCR_EL2.write(HCR_EL2::RW::EL1IsAarch64);
         // Set the return address and exception level.
@@ -85,8 +97,9 @@ unsafe fn init_mmu() {
     // Set both TTBR0 and TTBR1
     // let root_paddr = PhysAddr::from(BOOT_PT_L0.as_ptr() as usize).addr() as _;
     let root_paddr = (BOOT_PT_L1.0.as_ptr() as usize & 0xFFFF_FFFF_F000) as _;
+    let kernel_root_paddr = (BOOT_PT_KERNEL_L1.0.as_ptr() as usize & 0xFFFF_FFFF_F000) as _;
     TTBR0_EL1.set(root_paddr);
-    TTBR1_EL1.set(root_paddr);
+    TTBR1_EL1.set(kernel_root_paddr);
 
     // Flush the entire TLB
     TLB::flush_all();
@@ -103,6 +116,7 @@ unsafe fn init_boot_page_table() {
             PhysPage::from_addr(i * 0x4000_0000),
             PTEFlags::VALID | PTEFlags::AF | PTEFlags::ATTR_INDX | PTEFlags::NG,
         );
+        BOOT_PT_KERNEL_L1.0[i] = BOOT_PT_L1.0[i];
     }
 }
 /// The earliest entry point for the primary CPU.
@@ -185,13 +199,14 @@ pub fn rust_tmp_main(hart_id: usize, device_tree: usize) {
     crate::components::debug_console::DebugConsole::log_init();
     #[cfg(feature = "trap")]
     crate::components::trap::init();
+
+    DTB_PTR.init_by(device_tree | VIRT_ADDR_START);
+
     // Init GIC interrupt controller.
     crate::components::irq::init();
 
     init_cpu();
 
-    DTB_PTR.init_by(device_tree | VIRT_ADDR_START);
-
     // Display Polyhal and Platform Information
     display_info!();
     println!(include_str!("../../banner.txt"));
@@ -210,6 +225,9 @@ pub fn rust_tmp_main(hart_id: usize, device_tree: usize) {
 
 /// Rust secondary entry for core except Boot Core.
 fn rust_secondary_main(hart_id: usize) {
+    // Initialize the GIC of the cpu.
+    crate::components::irq::init_percpu();
+
     // Initialize the cpu configuration.
     init_cpu();
 
@@ -224,6 +242,10 @@ fn init_cpu() {
     // Enable Floating Point Feature.
     CPACR_EL1.write(CPACR_EL1::FPEN::TrapNothing);
     aarch64_cpu::asm::barrier::isb(aarch64_cpu::asm::barrier::SY);
+
+    // Enable PAN, the user memory is only accessed through the uaccess functions.
+    #[cfg(feature = "trap")]
+    crate::components::uaccess::init();
 }
 
 pub fn boot_page_table() -> PageTable {
@@ -231,3 +253,10 @@ pub fn boot_page_table() -> PageTable {
         BOOT_PT_L1.0.as_ptr() as usize & !VIRT_ADDR_START
     }))
 }
+
+/// Get the page table of the kernel half, it's installed to `TTBR1`.
+pub(crate) fn kernel_page_table() -> PageTable {
+    PageTable(crate::addr::PhysAddr(unsafe {
+        BOOT_PT_KERNEL_L1.0.as_ptr() as usize & !VIRT_ADDR_START
+    }))
+}
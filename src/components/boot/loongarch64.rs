@@ -138,6 +138,9 @@ fn init_cpu() {
     // Initialzie Timer
     timer::init_timer();
 
+    // Install the page table of the kernel stacks, it's created after the primary core booted.
+    crate::components::kstack::set_kernel_root();
+
     // Initialize the trap and tlb fill function
     #[cfg(feature = "trap")]
     {
@@ -177,6 +177,9 @@ pub(crate) extern "C" fn rust_secondary_main(hartid: usize) {
     // Initialize CPU Configuration.
     init_cpu();
 
+    // Initialize the interrupt file of the hart.
+    crate::components::irq::init_percpu();
+
     log::info!("secondary hart {} started", hartid);
     unsafe { crate::components::boot::_main_for_arch(hartid) };
     instruction::shutdown();
@@ -185,9 +188,8 @@ pub(crate) extern "C" fn rust_secondary_main(hartid: usize) {
 #[inline]
 fn init_cpu() {
     unsafe {
-        // Enable SUM for access user memory directly.
-        // TODO: Call set_sum() for riscv version up than 1.0, Close when below 1.0
-        sstatus::set_sum();
+        // The user memory is only accessed through the uaccess functions.
+        sstatus::clear_sum();
         // Open float point support.
         sstatus::set_fs(sstatus::FS::Dirty);
         sie::set_sext();
@@ -136,6 +136,9 @@ fn rust_tmp_main(magic: usize, mboot_ptr: usize) {
     arch::gdt::init();
     #[cfg(feature = "trap")]
     crate::components::trap::init_syscall();
+    // Enable SMAP, the user memory is only accessed through the uaccess functions.
+    #[cfg(feature = "trap")]
+    crate::components::uaccess::init();
     timer::init_early();
 
     // enable avx extend instruction set and sse if support avx
@@ -143,6 +146,13 @@ fn rust_tmp_main(magic: usize, mboot_ptr: usize) {
     // IF you want to use avx in the qemu, you can use -cpu IvyBridge-v2 to
     // select a cpu with avx support
     CpuId::new().get_feature_info().map(|features| {
+        // Raise the machine check exception instead of the shutdown.
+        #[cfg(feature = "trap")]
+        if features.has_mce() && features.has_mca() {
+            unsafe {
+                Cr4::write(Cr4::read() | Cr4Flags::MACHINE_CHECK_EXCEPTION);
+            }
+        }
         // Add OSXSave flag to cr4 register if supported
         if features.has_xsave() {
             unsafe {
@@ -0,0 +1,164 @@
+use core::arch::asm;
+
+use super::{BreakpointError, WatchKind};
+use crate::components::trapframe::TrapFrame;
+
+/// `MDSCR_EL1.SS`, software step enable.
+const MDSCR_SS: usize = 1 << 0;
+/// `MDSCR_EL1.KDE`, the debug exceptions are enabled in EL1.
+const MDSCR_KDE: usize = 1 << 13;
+/// `MDSCR_EL1.MDE`, the breakpoints and watchpoints are enabled.
+const MDSCR_MDE: usize = 1 << 15;
+
+/// `SPSR_EL1.SS`, step one instruction after the exception return.
+const SPSR_SS: usize = 1 << 21;
+/// `SPSR_EL1.D`, the debug exceptions are masked.
+const SPSR_D: usize = 1 << 9;
+
+/// `DBGBCR.E | DBGBCR.PMC(EL0 and EL1) | DBGBCR.BAS(A64 instruction)`.
+const BCR_EXECUTE: usize = 1 | (0b11 << 1) | (0b1111 << 5);
+/// `DBGWCR.E | DBGWCR.PAC(EL0 and EL1)`.
+const WCR_ENABLE: usize = 1 | (0b11 << 1);
+
+/// Write the debug register `$reg<n>_el1`, there are at most 16 of them.
+macro_rules! write_dbg_reg {
+    ($reg:literal, $n:expr, $val:expr) => {
+        write_dbg_reg!(@arms $reg, $n, $val, 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15)
+    };
+    (@arms $reg:literal, $n:expr, $val:expr, $($i:literal)*) => {
+        match $n {
+            $($i => unsafe {
+                asm!(concat!("msr ", $reg, stringify!($i), "_el1, {}"), in(reg) $val)
+            },)*
+            _ => unreachable!(),
+        }
+    };
+}
+
+/// Read the `ID_AA64DFR0_EL1`.
+#[inline]
+fn id_aa64dfr0() -> usize {
+    let val: usize;
+    unsafe { asm!("mrs {}, id_aa64dfr0_el1", out(reg) val) };
+    val
+}
+
+/// Read the `MDSCR_EL1`.
+#[inline]
+fn mdscr() -> usize {
+    let val: usize;
+    unsafe { asm!("mrs {}, mdscr_el1", out(reg) val) };
+    val
+}
+
+/// Write the `MDSCR_EL1`.
+#[inline]
+fn set_mdscr(val: usize) {
+    unsafe { asm!("msr mdscr_el1, {}", "isb", in(reg) val) };
+}
+
+/// Unlock the OS lock and enable the breakpoints and watchpoints,
+/// the debug exceptions are unmasked in the current kernel context.
+fn enable_debug() {
+    unsafe { asm!("msr oslar_el1, xzr", "isb") };
+    set_mdscr(mdscr() | MDSCR_MDE | MDSCR_KDE);
+    unsafe { asm!("msr daifclr, #8") };
+}
+
+#[inline]
+pub(super) fn hw_breakpoint_num() -> usize {
+    // ID_AA64DFR0_EL1.BRPs is the number of the breakpoints minus 1.
+    ((id_aa64dfr0() >> 12) & 0xf) + 1
+}
+
+#[inline]
+pub(super) fn hw_watchpoint_num() -> usize {
+    // ID_AA64DFR0_EL1.WRPs is the number of the watchpoints minus 1.
+    ((id_aa64dfr0() >> 20) & 0xf) + 1
+}
+
+pub(super) fn set_hw_breakpoint(slot: usize, addr: usize) -> Result<(), BreakpointError> {
+    if addr % 4 != 0 {
+        return Err(BreakpointError::InvalidRange);
+    }
+    enable_debug();
+    write_dbg_reg!("dbgbcr", slot, 0usize);
+    write_dbg_reg!("dbgbvr", slot, addr);
+    write_dbg_reg!("dbgbcr", slot, BCR_EXECUTE);
+    unsafe { asm!("isb") };
+    Ok(())
+}
+
+pub(super) fn clear_hw_breakpoint(slot: usize) {
+    write_dbg_reg!("dbgbcr", slot, 0usize);
+    unsafe { asm!("isb") };
+}
+
+pub(super) fn set_hw_watchpoint(
+    slot: usize,
+    addr: usize,
+    len: usize,
+    kind: WatchKind,
+) -> Result<(), BreakpointError> {
+    // DBGWCR.LSC, the load and store control.
+    let lsc = match kind {
+        WatchKind::Read => 0b01,
+        WatchKind::Write => 0b10,
+        WatchKind::ReadWrite => 0b11,
+    };
+    // DBGWCR.BAS, the watched bytes in the aligned double word.
+    let bas = ((1 << len) - 1) << (addr & 7);
+    enable_debug();
+    write_dbg_reg!("dbgwcr", slot, 0usize);
+    write_dbg_reg!("dbgwvr", slot, addr & !7);
+    write_dbg_reg!("dbgwcr", slot, WCR_ENABLE | (lsc << 3) | (bas << 5));
+    unsafe { asm!("isb") };
+    Ok(())
+}
+
+pub(super) fn clear_hw_watchpoint(slot: usize) {
+    write_dbg_reg!("dbgwcr", slot, 0usize);
+    unsafe { asm!("isb") };
+}
+
+/// Enable the software step before returning to `tf` if it's stepped.
+///
+/// It must be called with the debug exceptions masked, the step exception
+/// is taken immediately if `MDSCR_EL1.SS` is set and `PSTATE.SS` isn't.
+#[inline]
+pub(crate) fn step_enter(tf: &TrapFrame) {
+    if tf.single_step() {
+        set_mdscr(mdscr() | MDSCR_SS | MDSCR_KDE);
+    }
+}
+
+/// Disable the software step after the exception was taken.
+#[inline]
+pub(crate) fn step_exit() {
+    let mdscr = mdscr();
+    if mdscr & MDSCR_SS != 0 {
+        set_mdscr(mdscr & !MDSCR_SS);
+    }
+}
+
+impl TrapFrame {
+    /// Enable or disable the single-step of the task.
+    ///
+    /// The step is one-shot, `SPSR_EL1.SS` is cleared by the cpu when the
+    /// `SingleStep` trap is taken. The debug exceptions are unmasked if the
+    /// trap frame is from the kernel.
+    #[inline]
+    pub fn set_single_step(&mut self, enable: bool) {
+        match enable {
+            true if !self.from_user() => self.spsr = (self.spsr | SPSR_SS) & !SPSR_D,
+            true => self.spsr |= SPSR_SS,
+            false => self.spsr &= !SPSR_SS,
+        }
+    }
+
+    /// Check if the single-step of the task is enabled.
+    #[inline]
+    pub fn single_step(&self) -> bool {
+        self.spsr & SPSR_SS != 0
+    }
+}
@@ -0,0 +1,201 @@
+use core::arch::asm;
+
+use loongArch64::register::{badv, crmd};
+
+use super::{BreakpointError, WatchKind};
+use crate::components::trap::{FaultInfo, TrapType};
+use crate::components::trapframe::TrapFrame;
+
+/// The memory watchpoint config and status.
+const CSR_MWPC: usize = 0x300;
+const CSR_MWPS: usize = 0x301;
+/// The registers of the memory watchpoint 0, the stride is 8.
+const CSR_MWP0: usize = 0x310;
+/// The fetch watchpoint config and status.
+const CSR_FWPC: usize = 0x380;
+const CSR_FWPS: usize = 0x381;
+/// The registers of the fetch watchpoint 0, the stride is 8.
+const CSR_FWP0: usize = 0x390;
+
+/// The offsets of the watchpoint registers.
+const WP_ADDR: usize = 0;
+const WP_MASK: usize = 1;
+const WP_CTRL: usize = 2;
+const WP_ASID: usize = 3;
+
+/// The maximum number of the watchpoints.
+const MAX_WP_NUM: usize = 8;
+
+/// The watchpoint matches in PLV0 and PLV3.
+const CTRL_PLV0: usize = 1 << 0;
+const CTRL_PLV3: usize = 1 << 3;
+
+/// Skip the next match of the fetch watchpoints.
+const FWPS_SKIP: usize = 1 << 16;
+
+/// `PRMD.PWE`, the watchpoints are enabled after the exception return.
+const PRMD_PWE: usize = 1 << 3;
+
+/// Read the CSR `$csr`.
+macro_rules! csr_read {
+    ($csr:expr) => {{
+        let val: usize;
+        unsafe { asm!("csrrd {}, {}", out(reg) val, const $csr) };
+        val
+    }};
+}
+
+/// Write the CSR `$csr`.
+macro_rules! csr_write {
+    ($csr:expr, $val:expr) => {
+        unsafe { asm!("csrwr {}, {}", inout(reg) $val => _, const $csr) }
+    };
+}
+
+/// Write the register `$reg` of the watchpoint `$n` from `$base`.
+macro_rules! write_wp_reg {
+    ($base:expr, $reg:expr, $n:expr, $val:expr) => {
+        write_wp_reg!(@arms $base, $reg, $n, $val, 0 1 2 3 4 5 6 7)
+    };
+    (@arms $base:expr, $reg:expr, $n:expr, $val:expr, $($i:literal)*) => {
+        match $n {
+            $($i => csr_write!($base + 8 * $i + $reg, $val),)*
+            _ => unreachable!(),
+        }
+    };
+}
+
+/// Get the number of the fetch watchpoints.
+#[inline]
+fn fetch_wp_num() -> usize {
+    (csr_read!(CSR_FWPC) & 0x3f).min(MAX_WP_NUM)
+}
+
+/// The last fetch watchpoint is reserved for the single-step.
+#[inline]
+fn step_slot() -> Option<usize> {
+    fetch_wp_num().checked_sub(1)
+}
+
+#[inline]
+pub(super) fn hw_breakpoint_num() -> usize {
+    step_slot().unwrap_or(0)
+}
+
+#[inline]
+pub(super) fn hw_watchpoint_num() -> usize {
+    (csr_read!(CSR_MWPC) & 0x3f).min(MAX_WP_NUM)
+}
+
+/// Program the fetch watchpoint `slot`.
+fn write_fetch_wp(slot: usize, addr: usize, mask: usize, ctrl: usize) {
+    write_wp_reg!(CSR_FWP0, WP_CTRL, slot, 0usize);
+    write_wp_reg!(CSR_FWP0, WP_ADDR, slot, addr);
+    write_wp_reg!(CSR_FWP0, WP_MASK, slot, mask);
+    write_wp_reg!(CSR_FWP0, WP_ASID, slot, 0usize);
+    write_wp_reg!(CSR_FWP0, WP_CTRL, slot, ctrl);
+}
+
+pub(super) fn set_hw_breakpoint(slot: usize, addr: usize) -> Result<(), BreakpointError> {
+    if addr % 4 != 0 {
+        return Err(BreakpointError::InvalidRange);
+    }
+    write_fetch_wp(slot, addr, 0, CTRL_PLV0 | CTRL_PLV3);
+    crmd::set_we(true);
+    Ok(())
+}
+
+pub(super) fn clear_hw_breakpoint(slot: usize) {
+    write_wp_reg!(CSR_FWP0, WP_CTRL, slot, 0usize);
+}
+
+pub(super) fn set_hw_watchpoint(
+    slot: usize,
+    addr: usize,
+    len: usize,
+    kind: WatchKind,
+) -> Result<(), BreakpointError> {
+    let access: usize = match kind {
+        WatchKind::Read => 0b01,
+        WatchKind::Write => 0b10,
+        WatchKind::ReadWrite => 0b11,
+    };
+    let size: usize = match len {
+        1 => 0b11,
+        2 => 0b10,
+        4 => 0b01,
+        _ => 0b00,
+    };
+    let ctrl = (size << 10) | (access << 8) | CTRL_PLV0 | CTRL_PLV3;
+    write_wp_reg!(CSR_MWP0, WP_CTRL, slot, 0usize);
+    write_wp_reg!(CSR_MWP0, WP_ADDR, slot, addr);
+    write_wp_reg!(CSR_MWP0, WP_MASK, slot, 0usize);
+    write_wp_reg!(CSR_MWP0, WP_ASID, slot, 0usize);
+    write_wp_reg!(CSR_MWP0, WP_CTRL, slot, ctrl);
+    crmd::set_we(true);
+    Ok(())
+}
+
+pub(super) fn clear_hw_watchpoint(slot: usize) {
+    write_wp_reg!(CSR_MWP0, WP_CTRL, slot, 0usize);
+}
+
+/// Enable the watchpoints before returning to `tf`, program the
+/// single-step watchpoint if it's stepped.
+pub(crate) fn step_enter(tf: &mut TrapFrame) {
+    tf.prmd |= PRMD_PWE;
+    if !tf.single_step {
+        return;
+    }
+    if let Some(slot) = step_slot() {
+        // The watchpoint matches all the addresses, skip the instruction at `era`.
+        write_fetch_wp(slot, 0, usize::MAX, CTRL_PLV3);
+        csr_write!(CSR_FWPS, FWPS_SKIP);
+    }
+}
+
+/// Disable the single-step watchpoint after the task trapped back.
+pub(crate) fn step_exit(tf: &TrapFrame) {
+    if let Some(slot) = step_slot().filter(|_| tf.single_step) {
+        write_wp_reg!(CSR_FWP0, WP_CTRL, slot, 0usize);
+    }
+}
+
+/// Decode the watchpoint exception, `fetch` is true if it's from the
+/// fetch watchpoints.
+pub(crate) fn watch_trap(tf: &mut TrapFrame, fetch: bool) -> TrapType {
+    let user = tf.from_user();
+    let info = |addr| FaultInfo { addr, user };
+    if !fetch {
+        // The status is cleared by writing 1.
+        let mwps = csr_read!(CSR_MWPS) & 0xffff;
+        csr_write!(CSR_MWPS, mwps);
+        return TrapType::Watchpoint(info(badv::read().vaddr()));
+    }
+    let fwps = csr_read!(CSR_FWPS) & 0xffff;
+    csr_write!(CSR_FWPS, fwps);
+    match step_slot() {
+        Some(slot) if user && tf.single_step && fwps & (1 << slot) != 0 => {
+            tf.single_step = false;
+            TrapType::SingleStep(info(tf.era))
+        }
+        _ => TrapType::Breakpoint(info(tf.era)),
+    }
+}
+
+impl TrapFrame {
+    /// Enable or disable the single-step of the task.
+    ///
+    /// The step is one-shot, it is disabled after the `SingleStep` trap.
+    /// Only the user mode can be stepped.
+    #[inline]
+    pub fn set_single_step(&mut self, enable: bool) {
+        self.single_step = enable;
+    }
+
+    /// Check if the single-step of the task is enabled.
+    #[inline]
+    pub fn single_step(&self) -> bool {
+        self.single_step
+    }
+}
@@ -0,0 +1,105 @@
+//! Hardware breakpoint, watchpoint and single-step module.
+//!
+//! The breakpoints and watchpoints are programmed in the debug registers of
+//! the current cpu, they are matched in both the kernel and the user mode.
+//! The hits are dispatched to the trap handlers as the
+//! [TrapType](crate::components::trap::TrapType):
+//!
+//! - `Breakpoint`: the pc points to the breakpoint, it isn't advanced like
+//!   the breakpoint instruction. x86_64 sets `RFLAGS.RF` to continue, the
+//!   other architectures hit it again until it's cleared.
+//! - `Watchpoint`: the address is the watched data address.
+//!   x86_64 reports it after the access, the other architectures report it
+//!   before the access, so disable the watchpoint to continue.
+//! - `SingleStep`: the pc points to the next instruction.
+//!
+//! | Architecture | Breakpoint/Watchpoint        | Single-step                   |
+//! | ------------ | ---------------------------- | ----------------------------- |
+//! | x86_64       | `DR0` - `DR7`                | `RFLAGS.TF`                   |
+//! | aarch64      | `DBGBVR`/`DBGWVR`            | `MDSCR_EL1.SS`                |
+//! | riscv64      | Sdtrig through the SBI DBTR  | `icount` trigger, user only   |
+//! | loongarch64  | `FWPS`/`MWPS`                | the last `FWPS`, user only    |
+//!
+//! ```rust
+//! set_watchpoint(0, &COUNTER as *const _ as usize, 8, WatchKind::Write)?;
+//! ```
+
+super::define_arch_mods!();
+
+/// The access that triggers the watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// The error of programming the debug registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointError {
+    /// The slot isn't less than [breakpoint_num] or [watchpoint_num].
+    InvalidSlot,
+    /// The length isn't supported or the address isn't aligned to it.
+    InvalidRange,
+    /// The access kind isn't supported, x86_64 can't watch the reads only.
+    Unsupported,
+    /// The SBI refused to install the trigger.
+    Firmware,
+}
+
+/// Get the number of the hardware breakpoints.
+///
+/// The breakpoints and the watchpoints share the same slots on x86_64 and
+/// riscv64, setting one of them replaces the other.
+pub fn breakpoint_num() -> usize {
+    hw_breakpoint_num()
+}
+
+/// Get the number of the hardware watchpoints.
+pub fn watchpoint_num() -> usize {
+    hw_watchpoint_num()
+}
+
+/// Set the hardware breakpoint `slot` at the instruction `addr`.
+pub fn set_breakpoint(slot: usize, addr: usize) -> Result<(), BreakpointError> {
+    if slot >= breakpoint_num() {
+        return Err(BreakpointError::InvalidSlot);
+    }
+    set_hw_breakpoint(slot, addr)
+}
+
+/// Clear the hardware breakpoint `slot`.
+pub fn clear_breakpoint(slot: usize) -> Result<(), BreakpointError> {
+    if slot >= breakpoint_num() {
+        return Err(BreakpointError::InvalidSlot);
+    }
+    clear_hw_breakpoint(slot);
+    Ok(())
+}
+
+/// Set the hardware watchpoint `slot` at the `len` bytes from `addr`.
+///
+/// The `len` must be 1, 2, 4 or 8 and the `addr` must be aligned to it.
+pub fn set_watchpoint(
+    slot: usize,
+    addr: usize,
+    len: usize,
+    kind: WatchKind,
+) -> Result<(), BreakpointError> {
+    if slot >= watchpoint_num() {
+        return Err(BreakpointError::InvalidSlot);
+    }
+    if !matches!(len, 1 | 2 | 4 | 8) || addr % len != 0 {
+        return Err(BreakpointError::InvalidRange);
+    }
+    set_hw_watchpoint(slot, addr, len, kind)
+}
+
+/// Clear the hardware watchpoint `slot`.
+pub fn clear_watchpoint(slot: usize) -> Result<(), BreakpointError> {
+    if slot >= watchpoint_num() {
+        return Err(BreakpointError::InvalidSlot);
+    }
+    clear_hw_watchpoint(slot);
+    Ok(())
+}
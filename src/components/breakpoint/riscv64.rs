@@ -0,0 +1,182 @@
+use core::arch::asm;
+
+use super::{BreakpointError, WatchKind};
+use crate::components::consts::VIRT_ADDR_START;
+use crate::components::trapframe::TrapFrame;
+use crate::utils::MutexNoIrq;
+
+/// The extension id of the SBI debug triggers extension(DBTR).
+const EID_DBTR: usize = 0x4442_5452;
+const FID_NUM_TRIGGERS: usize = 0;
+const FID_SET_SHMEM: usize = 1;
+const FID_INSTALL_TRIGGERS: usize = 3;
+const FID_UNINSTALL_TRIGGERS: usize = 5;
+
+/// The maximum number of the slots, the triggers are shared by the
+/// breakpoints and the watchpoints.
+const MAX_SLOT_NUM: usize = 8;
+
+/// The slot doesn't hold a trigger.
+const NO_TRIGGER: usize = usize::MAX;
+
+/// `tdata1` of the `mcontrol6` trigger, the breakpoint exception is raised
+/// before the access in both the supervisor and the user mode.
+const MCONTROL6: usize = (6 << 60) | MCONTROL6_S | MCONTROL6_U;
+const MCONTROL6_NAPOT: usize = 1 << 7;
+const MCONTROL6_S: usize = 1 << 4;
+const MCONTROL6_U: usize = 1 << 3;
+const MCONTROL6_EXECUTE: usize = 1 << 2;
+const MCONTROL6_STORE: usize = 1 << 1;
+const MCONTROL6_LOAD: usize = 1 << 0;
+
+/// `tdata1` of the `icount` trigger, the breakpoint exception is raised
+/// after one instruction in the user mode.
+const ICOUNT_STEP: usize = (3 << 60) | (1 << 10) | (1 << 6);
+
+/// The trigger indexes of the slots.
+#[polyhal_macro::def_percpu]
+static TRIGGERS: [usize; MAX_SLOT_NUM] = [NO_TRIGGER; MAX_SLOT_NUM];
+
+/// The trigger index of the single-step.
+#[polyhal_macro::def_percpu]
+static STEP_TRIGGER: usize = NO_TRIGGER;
+
+/// The shared memory entry of the DBTR, `tdata1` - `tdata3` are written
+/// before installing, the trigger index is written back to the first word.
+#[repr(C, align(16))]
+struct TriggerEntry([usize; 4]);
+
+/// The shared memory of the DBTR, it's in the kernel image to have the
+/// physical address, the stack may be out of the linear mapping.
+static TRIGGER_ENTRY: MutexNoIrq<TriggerEntry> = MutexNoIrq::new(TriggerEntry([0; 4]));
+
+/// Call the DBTR function `fid`.
+fn sbi_call(fid: usize, arg0: usize, arg1: usize, arg2: usize) -> Result<usize, BreakpointError> {
+    let (error, value): (isize, usize);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("a0") arg0 => error,
+            inlateout("a1") arg1 => value,
+            in("a2") arg2,
+            in("a6") fid,
+            in("a7") EID_DBTR,
+        );
+    }
+    match error {
+        0 => Ok(value),
+        _ => Err(BreakpointError::Firmware),
+    }
+}
+
+/// Install the trigger, return the index of it.
+fn install_trigger(tdata1: usize, tdata2: usize) -> Result<usize, BreakpointError> {
+    let mut entry = TRIGGER_ENTRY.lock();
+    entry.0 = [tdata1, tdata2, 0, 0];
+    let paddr = &mut *entry as *mut TriggerEntry as usize & !VIRT_ADDR_START;
+    sbi_call(FID_SET_SHMEM, paddr, 0, 0)?;
+    let ret = sbi_call(FID_INSTALL_TRIGGERS, 1, 0, 0);
+    // The shared memory is shared by the harts, release it.
+    let _ = sbi_call(FID_SET_SHMEM, usize::MAX, usize::MAX, 0);
+    ret?;
+    Ok(unsafe { (&entry.0[0] as *const usize).read_volatile() })
+}
+
+/// Uninstall the trigger `idx`.
+#[inline]
+fn uninstall_trigger(idx: usize) {
+    let _ = sbi_call(FID_UNINSTALL_TRIGGERS, idx, 1, 0);
+}
+
+/// Replace the trigger of the `slot`.
+fn write_slot(slot: usize, trigger: Option<(usize, usize)>) -> Result<(), BreakpointError> {
+    TRIGGERS.with_current(|triggers| {
+        if triggers[slot] != NO_TRIGGER {
+            uninstall_trigger(triggers[slot]);
+            triggers[slot] = NO_TRIGGER;
+        }
+        if let Some((tdata1, tdata2)) = trigger {
+            triggers[slot] = install_trigger(tdata1, tdata2)?;
+        }
+        Ok(())
+    })
+}
+
+#[inline]
+pub(super) fn hw_breakpoint_num() -> usize {
+    // Passing 0 counts all the triggers, the DBTR may be unsupported.
+    sbi_call(FID_NUM_TRIGGERS, 0, 0, 0).map_or(0, |num| num.min(MAX_SLOT_NUM))
+}
+
+#[inline]
+pub(super) fn hw_watchpoint_num() -> usize {
+    hw_breakpoint_num()
+}
+
+pub(super) fn set_hw_breakpoint(slot: usize, addr: usize) -> Result<(), BreakpointError> {
+    write_slot(slot, Some((MCONTROL6 | MCONTROL6_EXECUTE, addr)))
+}
+
+pub(super) fn clear_hw_breakpoint(slot: usize) {
+    let _ = write_slot(slot, None);
+}
+
+pub(super) fn set_hw_watchpoint(
+    slot: usize,
+    addr: usize,
+    len: usize,
+    kind: WatchKind,
+) -> Result<(), BreakpointError> {
+    let access = match kind {
+        WatchKind::Read => MCONTROL6_LOAD,
+        WatchKind::Write => MCONTROL6_STORE,
+        WatchKind::ReadWrite => MCONTROL6_LOAD | MCONTROL6_STORE,
+    };
+    // The NAPOT range encodes the length in the low bits of the address.
+    let trigger = match len {
+        1 => (MCONTROL6 | access, addr),
+        _ => (MCONTROL6 | MCONTROL6_NAPOT | access, addr | (len / 2 - 1)),
+    };
+    write_slot(slot, Some(trigger))
+}
+
+pub(super) fn clear_hw_watchpoint(slot: usize) {
+    let _ = write_slot(slot, None);
+}
+
+/// Install the `icount` trigger before returning to `tf` if it's stepped.
+pub(crate) fn step_enter(tf: &TrapFrame) {
+    if !tf.single_step {
+        return;
+    }
+    match install_trigger(ICOUNT_STEP, 0) {
+        Ok(idx) => STEP_TRIGGER.write_current(idx),
+        Err(err) => log::warn!("can't install the single-step trigger: {:?}", err),
+    }
+}
+
+/// Uninstall the `icount` trigger after the task trapped back.
+pub(crate) fn step_exit() {
+    let idx = STEP_TRIGGER.read_current();
+    if idx != NO_TRIGGER {
+        uninstall_trigger(idx);
+        STEP_TRIGGER.write_current(NO_TRIGGER);
+    }
+}
+
+impl TrapFrame {
+    /// Enable or disable the single-step of the task.
+    ///
+    /// The step is one-shot, it is disabled after the `SingleStep` trap.
+    /// Only the user mode can be stepped.
+    #[inline]
+    pub fn set_single_step(&mut self, enable: bool) {
+        self.single_step = enable;
+    }
+
+    /// Check if the single-step of the task is enabled.
+    #[inline]
+    pub fn single_step(&self) -> bool {
+        self.single_step
+    }
+}
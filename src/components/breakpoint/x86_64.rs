@@ -0,0 +1,151 @@
+use core::arch::asm;
+
+use x86_64::registers::debug::{
+    BreakpointCondition, BreakpointSize, DebugAddressRegister, DebugAddressRegisterNumber, Dr0,
+    Dr1, Dr2, Dr3, Dr6, Dr6Flags, Dr7, Dr7Flags,
+};
+use x86_64::registers::rflags::RFlags;
+
+use super::{BreakpointError, WatchKind};
+use crate::components::trap::{FaultInfo, TrapType};
+use crate::components::trapframe::TrapFrame;
+
+/// `DR0` - `DR3` are shared by the breakpoints and the watchpoints.
+const SLOT_NUM: usize = 4;
+
+/// The initial value of `DR6`, the status bits are never cleared by the cpu.
+const DR6_INIT: u64 = 0xffff_0ff0;
+
+#[inline]
+pub(super) fn hw_breakpoint_num() -> usize {
+    SLOT_NUM
+}
+
+#[inline]
+pub(super) fn hw_watchpoint_num() -> usize {
+    SLOT_NUM
+}
+
+/// Get the register number of the `slot`.
+#[inline]
+fn dr_num(slot: usize) -> DebugAddressRegisterNumber {
+    DebugAddressRegisterNumber::new(slot as u8).expect("invalid debug register number")
+}
+
+/// Read the address of the debug register `slot`.
+fn read_addr(slot: usize) -> usize {
+    (match slot {
+        0 => Dr0::read(),
+        1 => Dr1::read(),
+        2 => Dr2::read(),
+        _ => Dr3::read(),
+    }) as usize
+}
+
+/// Program the debug register `slot` and enable it.
+fn write_slot(slot: usize, addr: usize, condition: BreakpointCondition, size: BreakpointSize) {
+    match slot {
+        0 => Dr0::write(addr as u64),
+        1 => Dr1::write(addr as u64),
+        2 => Dr2::write(addr as u64),
+        _ => Dr3::write(addr as u64),
+    }
+    let n = dr_num(slot);
+    let mut dr7 = Dr7::read();
+    dr7.set_condition(n, condition);
+    dr7.set_size(n, size);
+    dr7.insert_flags(Dr7Flags::local_breakpoint_enable(n));
+    Dr7::write(dr7);
+}
+
+/// Disable the debug register `slot`.
+fn clear_slot(slot: usize) {
+    let mut dr7 = Dr7::read();
+    dr7.remove_flags(Dr7Flags::local_breakpoint_enable(dr_num(slot)));
+    Dr7::write(dr7);
+}
+
+pub(super) fn set_hw_breakpoint(slot: usize, addr: usize) -> Result<(), BreakpointError> {
+    // The length of the instruction breakpoint must be 1.
+    write_slot(
+        slot,
+        addr,
+        BreakpointCondition::InstructionExecution,
+        BreakpointSize::Length1B,
+    );
+    Ok(())
+}
+
+#[inline]
+pub(super) fn clear_hw_breakpoint(slot: usize) {
+    clear_slot(slot)
+}
+
+pub(super) fn set_hw_watchpoint(
+    slot: usize,
+    addr: usize,
+    len: usize,
+    kind: WatchKind,
+) -> Result<(), BreakpointError> {
+    let condition = match kind {
+        WatchKind::Read => return Err(BreakpointError::Unsupported),
+        WatchKind::Write => BreakpointCondition::DataWrites,
+        WatchKind::ReadWrite => BreakpointCondition::DataReadsWrites,
+    };
+    let size = BreakpointSize::new(len).ok_or(BreakpointError::InvalidRange)?;
+    write_slot(slot, addr, condition, size);
+    Ok(())
+}
+
+#[inline]
+pub(super) fn clear_hw_watchpoint(slot: usize) {
+    clear_slot(slot)
+}
+
+/// Decode the debug exception(#DB) through `DR6`.
+pub(crate) fn debug_trap(tf: &mut TrapFrame) -> TrapType {
+    let user = tf.from_user();
+    let info = |addr| FaultInfo { addr, user };
+    let dr6 = Dr6::read();
+    unsafe { asm!("mov dr6, {}", in(reg) DR6_INIT) };
+    if dr6.contains(Dr6Flags::STEP) {
+        tf.set_single_step(false);
+        return TrapType::SingleStep(info(tf.rip));
+    }
+    let dr7 = Dr7::read();
+    let hit = (0..SLOT_NUM).find(|&slot| {
+        let n = dr_num(slot);
+        dr6.contains(Dr6Flags::trap(n))
+            && dr7.flags().contains(Dr7Flags::local_breakpoint_enable(n))
+    });
+    match hit {
+        Some(slot) => match dr7.condition(dr_num(slot)) {
+            // The instruction breakpoint is a fault, resume without hitting it again.
+            BreakpointCondition::InstructionExecution => {
+                tf.rflags |= RFlags::RESUME_FLAG.bits() as usize;
+                TrapType::Breakpoint(info(tf.rip))
+            }
+            _ => TrapType::Watchpoint(info(read_addr(slot))),
+        },
+        None => TrapType::Unknown,
+    }
+}
+
+impl TrapFrame {
+    /// Enable or disable the single-step of the task.
+    ///
+    /// The step is one-shot, it is disabled after the `SingleStep` trap.
+    #[inline]
+    pub fn set_single_step(&mut self, enable: bool) {
+        match enable {
+            true => self.rflags |= RFlags::TRAP_FLAG.bits() as usize,
+            false => self.rflags &= !(RFlags::TRAP_FLAG.bits() as usize),
+        }
+    }
+
+    /// Check if the single-step of the task is enabled.
+    #[inline]
+    pub fn single_step(&self) -> bool {
+        self.rflags & RFlags::TRAP_FLAG.bits() as usize != 0
+    }
+}
@@ -30,6 +30,13 @@ pub fn init(page_alloc: &'static dyn PageAlloc) {
 
     // Init current architecture
     arch_init();
+
+    // Install the region of the kernel stacks
+    crate::components::kstack::init();
+
+    // Allocate the counters of the traps
+    #[cfg(feature = "trap")]
+    crate::components::trap::init_stats();
 }
 
 /// Store the number of cpu, this will fill up by startup function.
@@ -0,0 +1,32 @@
+/// Read a byte from `addr` to `val`, return 0 if success, otherwise 1.
+#[naked]
+pub(crate) unsafe extern "C" fn __probe_read(addr: usize, val: *mut u8) -> usize {
+    core::arch::asm!(
+        "
+        1:  ldrb    w2, [x0]
+            strb    w2, [x1]
+            mov     x0, #0
+            ret
+        2:  mov     x0, #1
+            ret
+        ",
+        super::ex_table!("1b", "2b"),
+        options(noreturn)
+    )
+}
+
+/// Write a byte `val` to `addr`, return 0 if success, otherwise 1.
+#[naked]
+pub(crate) unsafe extern "C" fn __probe_write(addr: usize, val: u8) -> usize {
+    core::arch::asm!(
+        "
+        1:  strb    w1, [x0]
+            mov     x0, #0
+            ret
+        2:  mov     x0, #1
+            ret
+        ",
+        super::ex_table!("1b", "2b"),
+        options(noreturn)
+    )
+}
@@ -0,0 +1,32 @@
+/// Read a byte from `addr` to `val`, return 0 if success, otherwise 1.
+#[naked]
+pub(crate) unsafe extern "C" fn __probe_read(addr: usize, val: *mut u8) -> usize {
+    core::arch::asm!(
+        "
+        1:  ld.bu   $t0, $a0, 0
+            st.b    $t0, $a1, 0
+            move    $a0, $zero
+            jr      $ra
+        2:  li.w    $a0, 1
+            jr      $ra
+        ",
+        super::ex_table!("1b", "2b"),
+        options(noreturn)
+    )
+}
+
+/// Write a byte `val` to `addr`, return 0 if success, otherwise 1.
+#[naked]
+pub(crate) unsafe extern "C" fn __probe_write(addr: usize, val: u8) -> usize {
+    core::arch::asm!(
+        "
+        1:  st.b    $a1, $a0, 0
+            move    $a0, $zero
+            jr      $ra
+        2:  li.w    $a0, 1
+            jr      $ra
+        ",
+        super::ex_table!("1b", "2b"),
+        options(noreturn)
+    )
+}
@@ -0,0 +1,112 @@
+//! Exception table module.
+//!
+//! Kernel code can mark the instructions that may fault. When a marked
+//! instruction faults in kernel mode, the trap handler jumps to its fixup
+//! address instead of panicking.
+//!
+//! Mark an instruction in the assembly like this:
+//! ```rust
+//! core::arch::asm!(
+//!     "
+//!     1:  lbu     t0, 0(a0)
+//!         li      a0, 0
+//!         ret
+//!     2:  li      a0, 1
+//!         ret
+//!     ",
+//!     // Jump to label 2 if the instruction at label 1 faults.
+//!     crate::components::extable::ex_table!("1b", "2b"),
+//!     options(noreturn)
+//! )
+//! ```
+//!
+//! [probe_read] and [probe_write] are built on it.
+
+use core::mem::size_of;
+
+use crate::VirtAddr;
+
+super::define_arch_mods!();
+
+/// Exception Table Entry.
+///
+/// The entry is generated by the [ex_table] macro.
+#[repr(C)]
+struct ExceptionEntry {
+    /// The address of the instruction that may fault.
+    insn: usize,
+    /// The address to jump to when the instruction faults.
+    fixup: usize,
+}
+
+/// This is a empty seat for exception table section.
+/// Force the linker to create the exception table section.
+#[link_section = "__ex_table"]
+#[used(linker)]
+static _EX_TABLE_SEAT: [ExceptionEntry; 0] = [];
+
+/// Generate an exception table entry in the assembly.
+///
+/// `$insn` is the label of the instruction that may fault,
+/// `$fixup` is the label that will be jumped to when it faults.
+/// The section is marked as retained (`R`), so `--gc-sections` will not drop it.
+pub(crate) macro ex_table($insn: literal, $fixup: literal) {
+    concat!(
+        "
+        .pushsection __ex_table, \"aR\"
+        .balign 8
+        .quad ", $insn, ", ", $fixup, "
+        .popsection
+        "
+    )
+}
+
+/// Search the exception table, return the fixup address of the given instruction.
+pub(crate) fn search_exception_table(pc: usize) -> Option<usize> {
+    extern "Rust" {
+        fn __start___ex_table();
+        fn __stop___ex_table();
+    }
+    let start = __start___ex_table as usize;
+    let len = (__stop___ex_table as usize - start) / size_of::<ExceptionEntry>();
+    unsafe { core::slice::from_raw_parts(start as *const ExceptionEntry, len) }
+        .iter()
+        .find(|entry| entry.insn == pc)
+        .map(|entry| entry.fixup)
+}
+
+/// Redirect the program counter to the fixup address.
+///
+/// Return true if the faulting instruction was marked in the exception table.
+pub(crate) fn fixup_exception(pc: &mut usize) -> bool {
+    match search_exception_table(*pc) {
+        Some(fixup) => {
+            *pc = fixup;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Read a byte from the given address.
+///
+/// Return `None` instead of panicking if the access faults.
+pub fn probe_read(addr: VirtAddr) -> Option<u8> {
+    let mut val = 0;
+    match unsafe { __probe_read(addr.addr(), &mut val) } {
+        0 => Some(val),
+        _ => None,
+    }
+}
+
+/// Write a byte to the given address.
+///
+/// Return false instead of panicking if the access faults.
+///
+/// # Safety
+///
+/// Caller must ensure that writing to the given address doesn't break
+/// the memory in use.
+pub unsafe fn probe_write(addr: VirtAddr, val: u8) -> bool {
+    __probe_write(addr.addr(), val) == 0
+}
@@ -0,0 +1,32 @@
+/// Read a byte from `addr` to `val`, return 0 if success, otherwise 1.
+#[naked]
+pub(crate) unsafe extern "C" fn __probe_read(addr: usize, val: *mut u8) -> usize {
+    core::arch::asm!(
+        "
+        1:  lbu     t0, 0(a0)
+            sb      t0, 0(a1)
+            li      a0, 0
+            ret
+        2:  li      a0, 1
+            ret
+        ",
+        super::ex_table!("1b", "2b"),
+        options(noreturn)
+    )
+}
+
+/// Write a byte `val` to `addr`, return 0 if success, otherwise 1.
+#[naked]
+pub(crate) unsafe extern "C" fn __probe_write(addr: usize, val: u8) -> usize {
+    core::arch::asm!(
+        "
+        1:  sb      a1, 0(a0)
+            li      a0, 0
+            ret
+        2:  li      a0, 1
+            ret
+        ",
+        super::ex_table!("1b", "2b"),
+        options(noreturn)
+    )
+}
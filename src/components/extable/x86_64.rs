@@ -0,0 +1,34 @@
+// TIPS: Labels only consist of 0 and 1 are ambiguous with binary literals in intel syntax.
+
+/// Read a byte from `addr` to `val`, return 0 if success, otherwise 1.
+#[naked]
+pub(crate) unsafe extern "C" fn __probe_read(addr: usize, val: *mut u8) -> usize {
+    core::arch::asm!(
+        "
+        2:  mov     al, byte ptr [rdi]
+            mov     byte ptr [rsi], al
+            xor     eax, eax
+            ret
+        3:  mov     eax, 1
+            ret
+        ",
+        super::ex_table!("2b", "3b"),
+        options(noreturn)
+    )
+}
+
+/// Write a byte `val` to `addr`, return 0 if success, otherwise 1.
+#[naked]
+pub(crate) unsafe extern "C" fn __probe_write(addr: usize, val: u8) -> usize {
+    core::arch::asm!(
+        "
+        2:  mov     byte ptr [rdi], sil
+            xor     eax, eax
+            ret
+        3:  mov     eax, 1
+            ret
+        ",
+        super::ex_table!("2b", "3b"),
+        options(noreturn)
+    )
+}
@@ -0,0 +1,315 @@
+use core::arch::asm;
+
+use aarch64_cpu::{asm::barrier, registers::CPACR_EL1};
+use spin::Once;
+use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
+
+/// The floating point and Advanced SIMD registers.
+#[repr(C, align(16))]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FpRegs {
+    /// `v0` - `v31`.
+    pub v: [u128; 32],
+    /// Floating point status register.
+    pub fpsr: u32,
+    /// Floating point control register.
+    pub fpcr: u32,
+}
+
+impl FpRegs {
+    /// Save the fpu registers.
+    pub(crate) fn save(&mut self) {
+        let fpsr: usize;
+        let fpcr: usize;
+        unsafe {
+            asm!(
+                "
+                .arch_extension fp
+                .arch_extension simd
+                stp     q0, q1, [{0}, 0]
+                stp     q2, q3, [{0}, 32]
+                stp     q4, q5, [{0}, 64]
+                stp     q6, q7, [{0}, 96]
+                stp     q8, q9, [{0}, 128]
+                stp     q10, q11, [{0}, 160]
+                stp     q12, q13, [{0}, 192]
+                stp     q14, q15, [{0}, 224]
+                stp     q16, q17, [{0}, 256]
+                stp     q18, q19, [{0}, 288]
+                stp     q20, q21, [{0}, 320]
+                stp     q22, q23, [{0}, 352]
+                stp     q24, q25, [{0}, 384]
+                stp     q26, q27, [{0}, 416]
+                stp     q28, q29, [{0}, 448]
+                stp     q30, q31, [{0}, 480]
+                mrs     {1}, fpsr
+                mrs     {2}, fpcr
+                ",
+                in(reg) self.v.as_mut_ptr(),
+                out(reg) fpsr,
+                out(reg) fpcr,
+            );
+        }
+        self.fpsr = fpsr as _;
+        self.fpcr = fpcr as _;
+    }
+
+    /// Restore the fpu registers.
+    pub(crate) fn restore(&self) {
+        unsafe { __fp_restore(self) }
+    }
+}
+
+/// The maximum `ZCR_EL1.LEN`, the vector length is `(LEN + 1) * 128` bits.
+const ZCR_LEN_MAX: usize = 0xf;
+
+/// Whether the SVE is supported.
+static HAS_SVE: Once<bool> = Once::new();
+
+/// Check if the SVE is supported through the `ID_AA64PFR0_EL1.SVE`.
+#[inline]
+fn has_sve() -> bool {
+    *HAS_SVE.call_once(|| {
+        let pfr0: usize;
+        unsafe { asm!("mrs {}, ID_AA64PFR0_EL1", out(reg) pfr0) };
+        (pfr0 >> 32) & 0xf != 0
+    })
+}
+
+/// Get the size of the [ExtState](super::ExtState), `None` if the SVE isn't supported.
+///
+/// The state contains `z0` - `z31`, `p0` - `p15` and `ffr` with the maximum
+/// vector length allowed by the higher exception levels.
+pub fn ext_state_size() -> Option<usize> {
+    if !has_sve() {
+        return None;
+    }
+    set_enabled(true, true);
+    let vl: usize;
+    unsafe {
+        asm!(
+            ".arch_extension sve",
+            "rdvl {}, #1",
+            out(reg) vl,
+        )
+    };
+    Some(32 * vl + 17 * (vl / 8))
+}
+
+/// Save the SVE registers, the SVE must be enabled.
+pub(crate) fn save_ext(data: &mut [u8]) {
+    unsafe { __sve_save(data.as_mut_ptr()) }
+}
+
+/// Restore the SVE registers, the SVE must be enabled.
+pub(crate) fn restore_ext(data: &[u8]) {
+    unsafe { __sve_restore(data.as_ptr()) }
+}
+
+/// Write the `v` registers into the low 128 bits of the saved `z` registers.
+pub(crate) fn merge_regs(data: &mut [u8], regs: &FpRegs) {
+    // The size is `32 * vl + 17 * (vl / 8)`.
+    let vl = data.len() * 8 / 273;
+    data.chunks_exact_mut(vl)
+        .zip(regs.v.iter())
+        .for_each(|(z, v)| z[..16].copy_from_slice(&v.to_le_bytes()));
+}
+
+/// Save `z0` - `z31`, `p0` - `p15` and `ffr` to `data`.
+#[naked]
+unsafe extern "C" fn __sve_save(data: *mut u8) {
+    asm!(
+        "
+            .arch_extension sve
+            str     z0, [x0, #0, mul vl]
+            str     z1, [x0, #1, mul vl]
+            str     z2, [x0, #2, mul vl]
+            str     z3, [x0, #3, mul vl]
+            str     z4, [x0, #4, mul vl]
+            str     z5, [x0, #5, mul vl]
+            str     z6, [x0, #6, mul vl]
+            str     z7, [x0, #7, mul vl]
+            str     z8, [x0, #8, mul vl]
+            str     z9, [x0, #9, mul vl]
+            str     z10, [x0, #10, mul vl]
+            str     z11, [x0, #11, mul vl]
+            str     z12, [x0, #12, mul vl]
+            str     z13, [x0, #13, mul vl]
+            str     z14, [x0, #14, mul vl]
+            str     z15, [x0, #15, mul vl]
+            str     z16, [x0, #16, mul vl]
+            str     z17, [x0, #17, mul vl]
+            str     z18, [x0, #18, mul vl]
+            str     z19, [x0, #19, mul vl]
+            str     z20, [x0, #20, mul vl]
+            str     z21, [x0, #21, mul vl]
+            str     z22, [x0, #22, mul vl]
+            str     z23, [x0, #23, mul vl]
+            str     z24, [x0, #24, mul vl]
+            str     z25, [x0, #25, mul vl]
+            str     z26, [x0, #26, mul vl]
+            str     z27, [x0, #27, mul vl]
+            str     z28, [x0, #28, mul vl]
+            str     z29, [x0, #29, mul vl]
+            str     z30, [x0, #30, mul vl]
+            str     z31, [x0, #31, mul vl]
+            rdvl    x1, #1
+            add     x1, x0, x1, lsl #5
+            str     p0, [x1, #0, mul vl]
+            str     p1, [x1, #1, mul vl]
+            str     p2, [x1, #2, mul vl]
+            str     p3, [x1, #3, mul vl]
+            str     p4, [x1, #4, mul vl]
+            str     p5, [x1, #5, mul vl]
+            str     p6, [x1, #6, mul vl]
+            str     p7, [x1, #7, mul vl]
+            str     p8, [x1, #8, mul vl]
+            str     p9, [x1, #9, mul vl]
+            str     p10, [x1, #10, mul vl]
+            str     p11, [x1, #11, mul vl]
+            str     p12, [x1, #12, mul vl]
+            str     p13, [x1, #13, mul vl]
+            str     p14, [x1, #14, mul vl]
+            str     p15, [x1, #15, mul vl]
+            rdffr   p0.b
+            str     p0, [x1, #16, mul vl]
+            ldr     p0, [x1]
+            ret
+        ",
+        options(noreturn)
+    )
+}
+
+/// Load `z0` - `z31`, `p0` - `p15` and `ffr` from `data`.
+#[naked]
+unsafe extern "C" fn __sve_restore(data: *const u8) {
+    asm!(
+        "
+            .arch_extension sve
+            rdvl    x1, #1
+            add     x1, x0, x1, lsl #5
+            ldr     p0, [x1, #16, mul vl]
+            wrffr   p0.b
+            ldr     p0, [x1, #0, mul vl]
+            ldr     p1, [x1, #1, mul vl]
+            ldr     p2, [x1, #2, mul vl]
+            ldr     p3, [x1, #3, mul vl]
+            ldr     p4, [x1, #4, mul vl]
+            ldr     p5, [x1, #5, mul vl]
+            ldr     p6, [x1, #6, mul vl]
+            ldr     p7, [x1, #7, mul vl]
+            ldr     p8, [x1, #8, mul vl]
+            ldr     p9, [x1, #9, mul vl]
+            ldr     p10, [x1, #10, mul vl]
+            ldr     p11, [x1, #11, mul vl]
+            ldr     p12, [x1, #12, mul vl]
+            ldr     p13, [x1, #13, mul vl]
+            ldr     p14, [x1, #14, mul vl]
+            ldr     p15, [x1, #15, mul vl]
+            ldr     z0, [x0, #0, mul vl]
+            ldr     z1, [x0, #1, mul vl]
+            ldr     z2, [x0, #2, mul vl]
+            ldr     z3, [x0, #3, mul vl]
+            ldr     z4, [x0, #4, mul vl]
+            ldr     z5, [x0, #5, mul vl]
+            ldr     z6, [x0, #6, mul vl]
+            ldr     z7, [x0, #7, mul vl]
+            ldr     z8, [x0, #8, mul vl]
+            ldr     z9, [x0, #9, mul vl]
+            ldr     z10, [x0, #10, mul vl]
+            ldr     z11, [x0, #11, mul vl]
+            ldr     z12, [x0, #12, mul vl]
+            ldr     z13, [x0, #13, mul vl]
+            ldr     z14, [x0, #14, mul vl]
+            ldr     z15, [x0, #15, mul vl]
+            ldr     z16, [x0, #16, mul vl]
+            ldr     z17, [x0, #17, mul vl]
+            ldr     z18, [x0, #18, mul vl]
+            ldr     z19, [x0, #19, mul vl]
+            ldr     z20, [x0, #20, mul vl]
+            ldr     z21, [x0, #21, mul vl]
+            ldr     z22, [x0, #22, mul vl]
+            ldr     z23, [x0, #23, mul vl]
+            ldr     z24, [x0, #24, mul vl]
+            ldr     z25, [x0, #25, mul vl]
+            ldr     z26, [x0, #26, mul vl]
+            ldr     z27, [x0, #27, mul vl]
+            ldr     z28, [x0, #28, mul vl]
+            ldr     z29, [x0, #29, mul vl]
+            ldr     z30, [x0, #30, mul vl]
+            ldr     z31, [x0, #31, mul vl]
+            ret
+        ",
+        options(noreturn)
+    )
+}
+
+/// Enable or disable the floating point and Advanced SIMD instructions at EL0 and EL1.
+///
+/// The SVE instructions at EL0 are enabled only if `ext` is true.
+#[inline]
+pub(crate) fn set_enabled(enabled: bool, ext: bool) {
+    match enabled {
+        true => CPACR_EL1.modify(CPACR_EL1::FPEN::TrapNothing),
+        false => CPACR_EL1.modify(CPACR_EL1::FPEN::TrapEl0El1),
+    }
+    if has_sve() {
+        match enabled && ext {
+            true => {
+                CPACR_EL1.modify(CPACR_EL1::ZEN::TrapNothing);
+                // ZCR_EL1, the effective length is limited by the higher exception levels.
+                unsafe { asm!("msr S3_0_C1_C2_0, {}", in(reg) ZCR_LEN_MAX) };
+            }
+            false => CPACR_EL1.modify(CPACR_EL1::ZEN::TrapEl0),
+        }
+    }
+    barrier::isb(barrier::SY);
+}
+
+/// Load the fpu registers from `regs`.
+///
+/// It is naked to keep the compiler from restoring the callee saved `v` registers.
+#[naked]
+unsafe extern "C" fn __fp_restore(regs: *const FpRegs) {
+    asm!(
+        "
+            .arch_extension fp
+            .arch_extension simd
+            ldp     q0, q1, [x0, 0]
+            ldp     q2, q3, [x0, 32]
+            ldp     q4, q5, [x0, 64]
+            ldp     q6, q7, [x0, 96]
+            ldp     q8, q9, [x0, 128]
+            ldp     q10, q11, [x0, 160]
+            ldp     q12, q13, [x0, 192]
+            ldp     q14, q15, [x0, 224]
+            ldp     q16, q17, [x0, 256]
+            ldp     q18, q19, [x0, 288]
+            ldp     q20, q21, [x0, 320]
+            ldp     q22, q23, [x0, 352]
+            ldp     q24, q25, [x0, 384]
+            ldp     q26, q27, [x0, 416]
+            ldp     q28, q29, [x0, 448]
+            ldp     q30, q31, [x0, 480]
+            add     x0, x0, 32*16
+            ldp     w1, w2, [x0]
+            msr     fpsr, x1
+            msr     fpcr, x2
+            ret
+        ",
+        options(noreturn)
+    )
+}
+
+/// Enable the floating point, Advanced SIMD and SVE in the kernel, return the previous `CPACR_EL1`.
+pub(crate) fn kernel_fpu_begin() -> usize {
+    let prev = CPACR_EL1.get();
+    set_enabled(true, true);
+    prev as _
+}
+
+/// Restore the `CPACR_EL1` returned by [kernel_fpu_begin].
+pub(crate) fn kernel_fpu_end(prev: usize) {
+    CPACR_EL1.set(prev as _);
+    barrier::isb(barrier::SY);
+}
@@ -0,0 +1,397 @@
+use core::arch::asm;
+
+use loongArch64::cpu::CPUCFG;
+use loongArch64::register::euen;
+
+/// The base floating point registers.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FpRegs {
+    /// `f0` - `f31`.
+    pub f: [u64; 32],
+    /// Condition flag registers `fcc0` - `fcc7`, one byte for each.
+    pub fcc: u64,
+    /// Floating point control and status register `fcsr0`.
+    pub fcsr: u32,
+    _reserved: u32,
+}
+
+impl FpRegs {
+    /// Save the fpu registers.
+    pub(crate) fn save(&mut self) {
+        unsafe {
+            asm!(
+                "
+                fst.d   $f0, {0}, 0
+                fst.d   $f1, {0}, 8
+                fst.d   $f2, {0}, 16
+                fst.d   $f3, {0}, 24
+                fst.d   $f4, {0}, 32
+                fst.d   $f5, {0}, 40
+                fst.d   $f6, {0}, 48
+                fst.d   $f7, {0}, 56
+                fst.d   $f8, {0}, 64
+                fst.d   $f9, {0}, 72
+                fst.d   $f10, {0}, 80
+                fst.d   $f11, {0}, 88
+                fst.d   $f12, {0}, 96
+                fst.d   $f13, {0}, 104
+                fst.d   $f14, {0}, 112
+                fst.d   $f15, {0}, 120
+                fst.d   $f16, {0}, 128
+                fst.d   $f17, {0}, 136
+                fst.d   $f18, {0}, 144
+                fst.d   $f19, {0}, 152
+                fst.d   $f20, {0}, 160
+                fst.d   $f21, {0}, 168
+                fst.d   $f22, {0}, 176
+                fst.d   $f23, {0}, 184
+                fst.d   $f24, {0}, 192
+                fst.d   $f25, {0}, 200
+                fst.d   $f26, {0}, 208
+                fst.d   $f27, {0}, 216
+                fst.d   $f28, {0}, 224
+                fst.d   $f29, {0}, 232
+                fst.d   $f30, {0}, 240
+                fst.d   $f31, {0}, 248
+                movfcsr2gr  {1}, $fcsr0
+                ",
+                in(reg) self.f.as_mut_ptr(),
+                out(reg) self.fcsr,
+            );
+            asm!(
+                "
+                movcf2gr    {1}, $fcc0
+                bstrins.d   {0}, {1}, 7, 0
+                movcf2gr    {1}, $fcc1
+                bstrins.d   {0}, {1}, 15, 8
+                movcf2gr    {1}, $fcc2
+                bstrins.d   {0}, {1}, 23, 16
+                movcf2gr    {1}, $fcc3
+                bstrins.d   {0}, {1}, 31, 24
+                movcf2gr    {1}, $fcc4
+                bstrins.d   {0}, {1}, 39, 32
+                movcf2gr    {1}, $fcc5
+                bstrins.d   {0}, {1}, 47, 40
+                movcf2gr    {1}, $fcc6
+                bstrins.d   {0}, {1}, 55, 48
+                movcf2gr    {1}, $fcc7
+                bstrins.d   {0}, {1}, 63, 56
+                ",
+                inout(reg) 0u64 => self.fcc,
+                out(reg) _,
+            );
+        }
+    }
+
+    /// Restore the fpu registers.
+    pub(crate) fn restore(&self) {
+        unsafe { __fp_restore(self) }
+    }
+}
+
+/// Check if the LSX is supported through the `CPUCFG.2.LSX`.
+#[inline]
+fn has_lsx() -> bool {
+    CPUCFG::read(2).get_bit(6)
+}
+
+/// Check if the LASX is supported through the `CPUCFG.2.LASX`.
+#[inline]
+fn has_lasx() -> bool {
+    CPUCFG::read(2).get_bit(7)
+}
+
+/// Get the size of the [ExtState](super::ExtState), `None` if the LSX isn't supported.
+///
+/// The state contains `xr0` - `xr31` if the LASX is supported, otherwise `vr0` - `vr31`.
+pub fn ext_state_size() -> Option<usize> {
+    match (has_lasx(), has_lsx()) {
+        (true, _) => Some(32 * 32),
+        (false, true) => Some(32 * 16),
+        _ => None,
+    }
+}
+
+/// Save the vector registers, the LSX and LASX must be enabled.
+pub(crate) fn save_ext(data: &mut [u8]) {
+    match has_lasx() {
+        true => unsafe { __lasx_save(data.as_mut_ptr()) },
+        false => unsafe { __lsx_save(data.as_mut_ptr()) },
+    }
+}
+
+/// Restore the vector registers, the LSX and LASX must be enabled.
+pub(crate) fn restore_ext(data: &[u8]) {
+    match has_lasx() {
+        true => unsafe { __lasx_restore(data.as_ptr()) },
+        false => unsafe { __lsx_restore(data.as_ptr()) },
+    }
+}
+
+/// Write the `f` registers into the low 64 bits of the saved vector registers.
+pub(crate) fn merge_regs(data: &mut [u8], regs: &FpRegs) {
+    data.chunks_exact_mut(data.len() / 32)
+        .zip(regs.f.iter())
+        .for_each(|(vr, f)| vr[..8].copy_from_slice(&f.to_le_bytes()));
+}
+
+/// Save `vr0` - `vr31` to `data`.
+#[naked]
+unsafe extern "C" fn __lsx_save(data: *mut u8) {
+    asm!(
+        "
+            vst     $vr0, $a0, 0
+            vst     $vr1, $a0, 16
+            vst     $vr2, $a0, 32
+            vst     $vr3, $a0, 48
+            vst     $vr4, $a0, 64
+            vst     $vr5, $a0, 80
+            vst     $vr6, $a0, 96
+            vst     $vr7, $a0, 112
+            vst     $vr8, $a0, 128
+            vst     $vr9, $a0, 144
+            vst     $vr10, $a0, 160
+            vst     $vr11, $a0, 176
+            vst     $vr12, $a0, 192
+            vst     $vr13, $a0, 208
+            vst     $vr14, $a0, 224
+            vst     $vr15, $a0, 240
+            vst     $vr16, $a0, 256
+            vst     $vr17, $a0, 272
+            vst     $vr18, $a0, 288
+            vst     $vr19, $a0, 304
+            vst     $vr20, $a0, 320
+            vst     $vr21, $a0, 336
+            vst     $vr22, $a0, 352
+            vst     $vr23, $a0, 368
+            vst     $vr24, $a0, 384
+            vst     $vr25, $a0, 400
+            vst     $vr26, $a0, 416
+            vst     $vr27, $a0, 432
+            vst     $vr28, $a0, 448
+            vst     $vr29, $a0, 464
+            vst     $vr30, $a0, 480
+            vst     $vr31, $a0, 496
+            ret
+        ",
+        options(noreturn)
+    )
+}
+
+/// Load `vr0` - `vr31` from `data`.
+#[naked]
+unsafe extern "C" fn __lsx_restore(data: *const u8) {
+    asm!(
+        "
+            vld     $vr0, $a0, 0
+            vld     $vr1, $a0, 16
+            vld     $vr2, $a0, 32
+            vld     $vr3, $a0, 48
+            vld     $vr4, $a0, 64
+            vld     $vr5, $a0, 80
+            vld     $vr6, $a0, 96
+            vld     $vr7, $a0, 112
+            vld     $vr8, $a0, 128
+            vld     $vr9, $a0, 144
+            vld     $vr10, $a0, 160
+            vld     $vr11, $a0, 176
+            vld     $vr12, $a0, 192
+            vld     $vr13, $a0, 208
+            vld     $vr14, $a0, 224
+            vld     $vr15, $a0, 240
+            vld     $vr16, $a0, 256
+            vld     $vr17, $a0, 272
+            vld     $vr18, $a0, 288
+            vld     $vr19, $a0, 304
+            vld     $vr20, $a0, 320
+            vld     $vr21, $a0, 336
+            vld     $vr22, $a0, 352
+            vld     $vr23, $a0, 368
+            vld     $vr24, $a0, 384
+            vld     $vr25, $a0, 400
+            vld     $vr26, $a0, 416
+            vld     $vr27, $a0, 432
+            vld     $vr28, $a0, 448
+            vld     $vr29, $a0, 464
+            vld     $vr30, $a0, 480
+            vld     $vr31, $a0, 496
+            ret
+        ",
+        options(noreturn)
+    )
+}
+
+/// Save `xr0` - `xr31` to `data`.
+#[naked]
+unsafe extern "C" fn __lasx_save(data: *mut u8) {
+    asm!(
+        "
+            xvst    $xr0, $a0, 0
+            xvst    $xr1, $a0, 32
+            xvst    $xr2, $a0, 64
+            xvst    $xr3, $a0, 96
+            xvst    $xr4, $a0, 128
+            xvst    $xr5, $a0, 160
+            xvst    $xr6, $a0, 192
+            xvst    $xr7, $a0, 224
+            xvst    $xr8, $a0, 256
+            xvst    $xr9, $a0, 288
+            xvst    $xr10, $a0, 320
+            xvst    $xr11, $a0, 352
+            xvst    $xr12, $a0, 384
+            xvst    $xr13, $a0, 416
+            xvst    $xr14, $a0, 448
+            xvst    $xr15, $a0, 480
+            xvst    $xr16, $a0, 512
+            xvst    $xr17, $a0, 544
+            xvst    $xr18, $a0, 576
+            xvst    $xr19, $a0, 608
+            xvst    $xr20, $a0, 640
+            xvst    $xr21, $a0, 672
+            xvst    $xr22, $a0, 704
+            xvst    $xr23, $a0, 736
+            xvst    $xr24, $a0, 768
+            xvst    $xr25, $a0, 800
+            xvst    $xr26, $a0, 832
+            xvst    $xr27, $a0, 864
+            xvst    $xr28, $a0, 896
+            xvst    $xr29, $a0, 928
+            xvst    $xr30, $a0, 960
+            xvst    $xr31, $a0, 992
+            ret
+        ",
+        options(noreturn)
+    )
+}
+
+/// Load `xr0` - `xr31` from `data`.
+#[naked]
+unsafe extern "C" fn __lasx_restore(data: *const u8) {
+    asm!(
+        "
+            xvld    $xr0, $a0, 0
+            xvld    $xr1, $a0, 32
+            xvld    $xr2, $a0, 64
+            xvld    $xr3, $a0, 96
+            xvld    $xr4, $a0, 128
+            xvld    $xr5, $a0, 160
+            xvld    $xr6, $a0, 192
+            xvld    $xr7, $a0, 224
+            xvld    $xr8, $a0, 256
+            xvld    $xr9, $a0, 288
+            xvld    $xr10, $a0, 320
+            xvld    $xr11, $a0, 352
+            xvld    $xr12, $a0, 384
+            xvld    $xr13, $a0, 416
+            xvld    $xr14, $a0, 448
+            xvld    $xr15, $a0, 480
+            xvld    $xr16, $a0, 512
+            xvld    $xr17, $a0, 544
+            xvld    $xr18, $a0, 576
+            xvld    $xr19, $a0, 608
+            xvld    $xr20, $a0, 640
+            xvld    $xr21, $a0, 672
+            xvld    $xr22, $a0, 704
+            xvld    $xr23, $a0, 736
+            xvld    $xr24, $a0, 768
+            xvld    $xr25, $a0, 800
+            xvld    $xr26, $a0, 832
+            xvld    $xr27, $a0, 864
+            xvld    $xr28, $a0, 896
+            xvld    $xr29, $a0, 928
+            xvld    $xr30, $a0, 960
+            xvld    $xr31, $a0, 992
+            ret
+        ",
+        options(noreturn)
+    )
+}
+
+/// Enable or disable the base floating point instructions.
+///
+/// The LSX and LASX instructions are enabled only if `ext` is true.
+#[inline]
+pub(crate) fn set_enabled(enabled: bool, ext: bool) {
+    euen::set_fpe(enabled);
+    euen::set_sxe(enabled && ext && has_lsx());
+    euen::set_asxe(enabled && ext && has_lasx());
+}
+
+/// Load the fpu registers from `regs`.
+///
+/// It is naked to keep the compiler from restoring the callee saved `fs` registers.
+#[naked]
+unsafe extern "C" fn __fp_restore(regs: *const FpRegs) {
+    asm!(
+        "
+            fld.d   $f0, $a0, 0
+            fld.d   $f1, $a0, 8
+            fld.d   $f2, $a0, 16
+            fld.d   $f3, $a0, 24
+            fld.d   $f4, $a0, 32
+            fld.d   $f5, $a0, 40
+            fld.d   $f6, $a0, 48
+            fld.d   $f7, $a0, 56
+            fld.d   $f8, $a0, 64
+            fld.d   $f9, $a0, 72
+            fld.d   $f10, $a0, 80
+            fld.d   $f11, $a0, 88
+            fld.d   $f12, $a0, 96
+            fld.d   $f13, $a0, 104
+            fld.d   $f14, $a0, 112
+            fld.d   $f15, $a0, 120
+            fld.d   $f16, $a0, 128
+            fld.d   $f17, $a0, 136
+            fld.d   $f18, $a0, 144
+            fld.d   $f19, $a0, 152
+            fld.d   $f20, $a0, 160
+            fld.d   $f21, $a0, 168
+            fld.d   $f22, $a0, 176
+            fld.d   $f23, $a0, 184
+            fld.d   $f24, $a0, 192
+            fld.d   $f25, $a0, 200
+            fld.d   $f26, $a0, 208
+            fld.d   $f27, $a0, 216
+            fld.d   $f28, $a0, 224
+            fld.d   $f29, $a0, 232
+            fld.d   $f30, $a0, 240
+            fld.d   $f31, $a0, 248
+            ld.bu   $t0, $a0, 32*8+0
+            movgr2cf $fcc0, $t0
+            ld.bu   $t0, $a0, 32*8+1
+            movgr2cf $fcc1, $t0
+            ld.bu   $t0, $a0, 32*8+2
+            movgr2cf $fcc2, $t0
+            ld.bu   $t0, $a0, 32*8+3
+            movgr2cf $fcc3, $t0
+            ld.bu   $t0, $a0, 32*8+4
+            movgr2cf $fcc4, $t0
+            ld.bu   $t0, $a0, 32*8+5
+            movgr2cf $fcc5, $t0
+            ld.bu   $t0, $a0, 32*8+6
+            movgr2cf $fcc6, $t0
+            ld.bu   $t0, $a0, 32*8+7
+            movgr2cf $fcc7, $t0
+            ld.wu   $t0, $a0, 33*8
+            movgr2fcsr  $fcsr0, $t0
+            ret
+        ",
+        options(noreturn)
+    )
+}
+
+/// Enable the base floating point, LSX and LASX in the kernel, return the previous `EUEN`.
+pub(crate) fn kernel_fpu_begin() -> usize {
+    let euen = euen::read();
+    let prev = euen.fpe() as usize | (euen.sxe() as usize) << 1 | (euen.asxe() as usize) << 2;
+    set_enabled(true, true);
+    prev
+}
+
+/// Restore the `EUEN` returned by [kernel_fpu_begin].
+pub(crate) fn kernel_fpu_end(prev: usize) {
+    euen::set_fpe(prev & 0b001 != 0);
+    euen::set_sxe(prev & 0b010 != 0);
+    euen::set_asxe(prev & 0b100 != 0);
+}
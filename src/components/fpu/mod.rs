@@ -0,0 +1,238 @@
+//! Floating point state module.
+//!
+//! The floating point state of the user task is kept in the [FpState] of the
+//! [TrapFrame](crate::components::trapframe::TrapFrame) and switched lazily.
+//!
+//! - The state is saved when the task traps back, only if the fpu was enabled
+//!   for it. riscv64 saves it only if `sstatus.FS` is dirty.
+//! - The fpu is enabled before entering the task only if its state is still
+//!   loaded in the current cpu. Otherwise the first fpu instruction traps
+//!   (`sstatus.FS`, `CPACR_EL1.FPEN`, `CR0.TS` or `EUEN.FPE`), then the state
+//!   is loaded and the instruction is executed again.
+//!
+//! So the tasks that never use the fpu don't pay for it, and the saved state
+//! is always up to date in the kernel.
+//!
+//! The vector extensions (AVX, V, SVE and LSX/LASX) are saved in the
+//! [ExtState] only if the task opted in by [FpState::enable_ext], the vector
+//! instructions of the other tasks trap as the illegal instructions.
+//!
+//! TIPS: The kernel shouldn't use the fpu. If it does, it traps on x86_64,
+//! aarch64 and loongarch64 and `sstatus.FS` becomes dirty on riscv64, then
+//! the state is loaded again before the task uses the fpu. So the state is
+//! never corrupted silently.
+
+super::define_arch_mods!();
+
+use alloc::alloc::{alloc_zeroed, dealloc};
+use core::{alloc::Layout, fmt::Debug, ptr::NonNull, slice};
+
+use crate::components::irq::IRQ;
+
+/// No cpu holds the state.
+const NO_CPU: usize = usize::MAX;
+
+/// The alignment of the [ExtState], `xsave` needs 64 bytes.
+const EXT_STATE_ALIGN: usize = 64;
+
+/// The saved registers of the vector extension.
+///
+/// The size is detected at runtime by [ext_state_size], the layout is
+/// architecture specific.
+pub struct ExtState {
+    ptr: NonNull<u8>,
+    size: usize,
+}
+
+unsafe impl Send for ExtState {}
+unsafe impl Sync for ExtState {}
+
+impl ExtState {
+    /// Allocate the initial state, return `None` if the extension isn't supported.
+    fn new() -> Option<Self> {
+        Self::alloc(ext_state_size()?)
+    }
+
+    /// Allocate the zeroed state with the given size.
+    fn alloc(size: usize) -> Option<Self> {
+        let layout = Layout::from_size_align(size, EXT_STATE_ALIGN).ok()?;
+        let ptr = NonNull::new(unsafe { alloc_zeroed(layout) })?;
+        Some(Self { ptr, size })
+    }
+
+    /// Get the saved data.
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.size) }
+    }
+
+    /// Get the mutable saved data.
+    #[inline]
+    pub(crate) fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.size) }
+    }
+}
+
+impl Clone for ExtState {
+    fn clone(&self) -> Self {
+        let mut state = Self::alloc(self.size).expect("can't allocate the extended state");
+        state.as_bytes_mut().copy_from_slice(self.as_bytes());
+        state
+    }
+}
+
+impl Drop for ExtState {
+    fn drop(&mut self) {
+        let layout = Layout::from_size_align(self.size, EXT_STATE_ALIGN).unwrap();
+        unsafe { dealloc(self.ptr.as_ptr(), layout) }
+    }
+}
+
+impl Debug for ExtState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ExtState").field("size", &self.size).finish()
+    }
+}
+
+/// The floating point state of the user task.
+#[derive(Debug, Clone)]
+pub struct FpState {
+    /// The floating point registers.
+    regs: FpRegs,
+    /// The vector extension registers, `None` if the task doesn't use them.
+    ext: Option<ExtState>,
+    /// The `regs` were changed after saved, they overlap the `ext` on
+    /// aarch64 and loongarch64, so merge them before loading.
+    regs_changed: bool,
+    /// The cpu that loaded the state last time.
+    last_cpu: usize,
+}
+
+impl FpState {
+    /// Create a new floating point state with the initial registers.
+    pub fn new() -> Self {
+        Self {
+            regs: FpRegs::default(),
+            ext: None,
+            regs_changed: false,
+            last_cpu: NO_CPU,
+        }
+    }
+
+    /// Enable the vector extension for the task.
+    ///
+    /// Return false if the extension isn't supported or the memory is exhausted.
+    pub fn enable_ext(&mut self) -> bool {
+        if self.ext.is_none() {
+            self.ext = ExtState::new();
+            // Load the extended state before the task uses it.
+            self.last_cpu = NO_CPU;
+        }
+        self.ext.is_some()
+    }
+
+    /// Get the saved vector extension registers.
+    #[inline]
+    pub fn ext(&self) -> Option<&ExtState> {
+        self.ext.as_ref()
+    }
+
+    /// Get the saved floating point registers.
+    #[inline]
+    pub fn regs(&self) -> &FpRegs {
+        &self.regs
+    }
+
+    /// Get the mutable saved floating point registers.
+    ///
+    /// The registers will be loaded again before the task uses the fpu.
+    #[inline]
+    pub fn regs_mut(&mut self) -> &mut FpRegs {
+        self.last_cpu = NO_CPU;
+        self.regs_changed = true;
+        &mut self.regs
+    }
+
+    /// Save the fpu registers, the fpu and the vector extension must be enabled.
+    #[inline]
+    pub(crate) fn save(&mut self) {
+        self.regs.save();
+        if let Some(ext) = &mut self.ext {
+            save_ext(ext.as_bytes_mut());
+        }
+    }
+}
+
+impl Default for FpState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The address of the [FpState] loaded in the fpu of the current cpu.
+#[polyhal_macro::def_percpu]
+static FPU_OWNER: usize = 0;
+
+/// Forget the state loaded in the fpu of the current cpu.
+///
+/// Called after the kernel changed the fpu registers.
+#[inline]
+pub(crate) fn invalidate() {
+    FPU_OWNER.write_current(0);
+}
+
+/// Run `f` with the fpu and SIMD enabled in the kernel.
+///
+/// The user state loaded in the fpu was saved when the task trapped back,
+/// it is loaded again before the task uses the fpu. The interrupts are
+/// disabled in `f` to keep the interrupt handlers from nesting, and the fpu
+/// is disabled again after `f` as before.
+///
+/// ```rust
+/// let sum = with_kernel_fpu(|| unsafe { simd_checksum(buffer) });
+/// ```
+pub fn with_kernel_fpu<R>(f: impl FnOnce() -> R) -> R {
+    let int_enabled = IRQ::int_enabled();
+    IRQ::int_disable();
+    invalidate();
+    let prev = kernel_fpu_begin();
+    let ret = f();
+    kernel_fpu_end(prev);
+    if int_enabled {
+        IRQ::int_enable();
+    }
+    ret
+}
+
+#[cfg(feature = "trap")]
+mod lazy {
+    use super::{merge_regs, restore_ext, FpState, FPU_OWNER};
+    use crate::components::arch::hart_id;
+
+    /// Check if the state is still loaded in the fpu of the current cpu.
+    ///
+    /// The state may be loaded in the other cpu and changed after it was
+    /// loaded in the current cpu, so check both of them.
+    #[inline]
+    pub(crate) fn is_loaded(fp: &FpState) -> bool {
+        fp.last_cpu == hart_id() && FPU_OWNER.read_current() == fp as *const _ as usize
+    }
+
+    /// Load the state into the fpu of the current cpu,
+    /// the fpu and the vector extension must be enabled.
+    pub(crate) fn load(fp: &mut FpState) {
+        fp.regs.restore();
+        if let Some(ext) = &mut fp.ext {
+            if fp.regs_changed {
+                merge_regs(ext.as_bytes_mut(), &fp.regs);
+            }
+            restore_ext(ext.as_bytes());
+        }
+        fp.regs_changed = false;
+        fp.last_cpu = hart_id();
+        FPU_OWNER.write_current(fp as *const _ as usize);
+    }
+}
+
+#[cfg(feature = "trap")]
+pub(crate) use lazy::*;
@@ -0,0 +1,280 @@
+use core::arch::asm;
+
+use riscv::register::sstatus::{self, FS};
+
+/// The floating point registers of the `D` extension.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FpRegs {
+    /// `f0` - `f31`.
+    pub f: [u64; 32],
+    /// Floating point control and status register.
+    pub fcsr: u32,
+    _reserved: u32,
+}
+
+impl FpRegs {
+    /// Save the fpu registers.
+    pub(crate) fn save(&mut self) {
+        unsafe {
+            asm!(
+                "
+                fsd     f0, 0({0})
+                fsd     f1, 8({0})
+                fsd     f2, 16({0})
+                fsd     f3, 24({0})
+                fsd     f4, 32({0})
+                fsd     f5, 40({0})
+                fsd     f6, 48({0})
+                fsd     f7, 56({0})
+                fsd     f8, 64({0})
+                fsd     f9, 72({0})
+                fsd     f10, 80({0})
+                fsd     f11, 88({0})
+                fsd     f12, 96({0})
+                fsd     f13, 104({0})
+                fsd     f14, 112({0})
+                fsd     f15, 120({0})
+                fsd     f16, 128({0})
+                fsd     f17, 136({0})
+                fsd     f18, 144({0})
+                fsd     f19, 152({0})
+                fsd     f20, 160({0})
+                fsd     f21, 168({0})
+                fsd     f22, 176({0})
+                fsd     f23, 184({0})
+                fsd     f24, 192({0})
+                fsd     f25, 200({0})
+                fsd     f26, 208({0})
+                fsd     f27, 216({0})
+                fsd     f28, 224({0})
+                fsd     f29, 232({0})
+                fsd     f30, 240({0})
+                fsd     f31, 248({0})
+                frcsr   {1}
+                ",
+                in(reg) self.f.as_mut_ptr(),
+                out(reg) self.fcsr,
+            );
+        }
+    }
+
+    /// Restore the fpu registers.
+    ///
+    /// `sstatus.FS` is set to clean, the fpu is the same as the saved state.
+    pub(crate) fn restore(&self) {
+        unsafe {
+            __fp_restore(self);
+            sstatus::set_fs(FS::Clean);
+        }
+    }
+}
+
+/// Load the fpu registers from `regs`.
+///
+/// It is naked to keep the compiler from restoring the callee saved `fs` registers.
+#[naked]
+unsafe extern "C" fn __fp_restore(regs: *const FpRegs) {
+    asm!(
+        "
+            fld     f0, 0(a0)
+            fld     f1, 8(a0)
+            fld     f2, 16(a0)
+            fld     f3, 24(a0)
+            fld     f4, 32(a0)
+            fld     f5, 40(a0)
+            fld     f6, 48(a0)
+            fld     f7, 56(a0)
+            fld     f8, 64(a0)
+            fld     f9, 72(a0)
+            fld     f10, 80(a0)
+            fld     f11, 88(a0)
+            fld     f12, 96(a0)
+            fld     f13, 104(a0)
+            fld     f14, 112(a0)
+            fld     f15, 120(a0)
+            fld     f16, 128(a0)
+            fld     f17, 136(a0)
+            fld     f18, 144(a0)
+            fld     f19, 152(a0)
+            fld     f20, 160(a0)
+            fld     f21, 168(a0)
+            fld     f22, 176(a0)
+            fld     f23, 184(a0)
+            fld     f24, 192(a0)
+            fld     f25, 200(a0)
+            fld     f26, 208(a0)
+            fld     f27, 216(a0)
+            fld     f28, 224(a0)
+            fld     f29, 232(a0)
+            fld     f30, 240(a0)
+            fld     f31, 248(a0)
+            lw      t0, 32*8(a0)
+            fscsr   t0
+            ret
+        ",
+        options(noreturn)
+    )
+}
+
+/// The size of the vector CSRs saved before the vector registers,
+/// `vstart`, `vl`, `vtype` and `vcsr`.
+const VCSR_SIZE: usize = 4 * 8;
+
+/// Get the size of the [ExtState](super::ExtState), `None` if the `V` extension isn't supported.
+///
+/// The state contains the vector CSRs and `v0` - `v31`.
+pub fn ext_state_size() -> Option<usize> {
+    let prev: usize;
+    let vs: usize;
+    let vlenb: usize;
+    unsafe {
+        // `sstatus.VS` is read-only zero if the `V` extension isn't supported.
+        asm!(
+            "
+                csrr    {prev}, sstatus
+                li      {vs}, 1 << 9
+                csrs    sstatus, {vs}
+                csrr    {vs}, sstatus
+                csrw    sstatus, {prev}
+            ",
+            prev = out(reg) prev,
+            vs = out(reg) vs,
+        );
+        if vs & (0b11 << 9) == 0 {
+            return None;
+        }
+        asm!(
+            "
+                csrs    sstatus, {vs}
+                csrr    {vlenb}, vlenb
+                csrw    sstatus, {prev}
+            ",
+            vs = in(reg) 1usize << 9,
+            prev = in(reg) prev,
+            vlenb = out(reg) vlenb,
+        );
+    }
+    Some(VCSR_SIZE + 32 * vlenb)
+}
+
+/// Save the vector registers, `sstatus.VS` must not be off.
+pub(crate) fn save_ext(data: &mut [u8]) {
+    unsafe { __vector_save(data.as_mut_ptr()) }
+}
+
+/// Restore the vector registers.
+///
+/// `sstatus.VS` is set to clean, the vector unit is the same as the saved state.
+pub(crate) fn restore_ext(data: &[u8]) {
+    unsafe {
+        set_vs_clean();
+        __vector_restore(data.as_ptr());
+        set_vs_clean();
+    }
+}
+
+/// Set the `sstatus.VS` to clean.
+#[inline]
+unsafe fn set_vs_clean() {
+    asm!("csrc sstatus, {0}", "csrs sstatus, {1}", in(reg) 0b11 << 9, in(reg) 0b10 << 9);
+}
+
+/// The [FpRegs] don't overlap the vector registers, nothing need to do here.
+#[inline]
+pub(crate) fn merge_regs(_data: &mut [u8], _regs: &FpRegs) {}
+
+/// Save the vector CSRs and the vector registers to `data`.
+#[naked]
+unsafe extern "C" fn __vector_save(data: *mut u8) {
+    asm!(
+        "
+            .option push
+            .option arch, +v
+            csrr    t0, vstart
+            csrr    t1, vl
+            csrr    t2, vtype
+            csrr    t3, vcsr
+            sd      t0, 0*8(a0)
+            sd      t1, 1*8(a0)
+            sd      t2, 2*8(a0)
+            sd      t3, 3*8(a0)
+            // The whole register store starts from the `vstart`.
+            csrw    vstart, x0
+            csrr    t4, vlenb
+            slli    t4, t4, 3
+            addi    a1, a0, {vcsr_size}
+            vs8r.v  v0, (a1)
+            add     a1, a1, t4
+            vs8r.v  v8, (a1)
+            add     a1, a1, t4
+            vs8r.v  v16, (a1)
+            add     a1, a1, t4
+            vs8r.v  v24, (a1)
+            csrw    vstart, t0
+            .option pop
+            ret
+        ",
+        vcsr_size = const VCSR_SIZE,
+        options(noreturn)
+    )
+}
+
+/// Load the vector CSRs and the vector registers from `data`.
+#[naked]
+unsafe extern "C" fn __vector_restore(data: *const u8) {
+    asm!(
+        "
+            .option push
+            .option arch, +v
+            csrw    vstart, x0
+            csrr    t4, vlenb
+            slli    t4, t4, 3
+            addi    a1, a0, {vcsr_size}
+            vl8re8.v    v0, (a1)
+            add     a1, a1, t4
+            vl8re8.v    v8, (a1)
+            add     a1, a1, t4
+            vl8re8.v    v16, (a1)
+            add     a1, a1, t4
+            vl8re8.v    v24, (a1)
+            ld      t0, 0*8(a0)
+            ld      t1, 1*8(a0)
+            ld      t2, 2*8(a0)
+            ld      t3, 3*8(a0)
+            vsetvl  x0, t1, t2
+            csrw    vstart, t0
+            csrw    vcsr, t3
+            .option pop
+            ret
+        ",
+        vcsr_size = const VCSR_SIZE,
+        options(noreturn)
+    )
+}
+
+/// The mask of the `sstatus.FS` and `sstatus.VS`.
+const FS_VS_MASK: usize = (0b11 << 13) | (0b11 << 9);
+
+/// Enable the fpu and the vector unit in the kernel, return the previous state.
+///
+/// `sstatus.VS` is read-only zero if the `V` extension isn't supported.
+pub(crate) fn kernel_fpu_begin() -> usize {
+    let prev: usize;
+    unsafe {
+        asm!(
+            "csrr {0}, sstatus",
+            "csrc sstatus, {1}",
+            "csrs sstatus, {2}",
+            out(reg) prev,
+            in(reg) FS_VS_MASK,
+            in(reg) (FS::Clean as usize) << 13 | (FS::Clean as usize) << 9,
+        );
+    }
+    prev & FS_VS_MASK
+}
+
+/// Restore the state returned by [kernel_fpu_begin].
+pub(crate) fn kernel_fpu_end(prev: usize) {
+    unsafe { asm!("csrc sstatus, {0}", "csrs sstatus, {1}", in(reg) FS_VS_MASK, in(reg) prev) };
+}
@@ -0,0 +1,162 @@
+use core::{arch::asm, fmt::Debug};
+
+use raw_cpuid::CpuId;
+use spin::Once;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+
+/// The floating point registers are saved by the `fxsave`.
+pub type FpRegs = FxsaveArea;
+
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+pub struct FxsaveArea {
+    pub fcw: u16,
+    pub fsw: u16,
+    pub ftw: u16,
+    pub fop: u16,
+    pub fip: u64,
+    pub fdp: u64,
+    pub mxcsr: u32,
+    pub mxcsr_mask: u32,
+    pub st: [u64; 16],
+    pub xmm: [u64; 32],
+    _padding: [u64; 12],
+}
+
+impl FxsaveArea {
+    #[inline]
+    pub(crate) fn save(&mut self) {
+        unsafe { core::arch::x86_64::_fxsave64(self as *mut _ as *mut u8) }
+    }
+
+    #[inline]
+    pub(crate) fn restore(&self) {
+        unsafe { core::arch::x86_64::_fxrstor64(self as *const _ as *const u8) }
+    }
+}
+
+impl Default for FxsaveArea {
+    fn default() -> Self {
+        let mut area: FxsaveArea = unsafe { core::mem::MaybeUninit::zeroed().assume_init() };
+        area.fcw = 0x37f;
+        area.ftw = 0xffff;
+        area.mxcsr = 0x1f80;
+        area
+    }
+}
+
+impl Debug for FxsaveArea {
+    fn fmt(&self, _f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        Ok(())
+    }
+}
+
+/// The `XCR0` enabled at boot, the extended state uses all of them.
+static EXT_XCR0: Once<XCr0Flags> = Once::new();
+
+/// Whether the `xsaveopt` is supported.
+static XSAVEOPT: Once<bool> = Once::new();
+
+/// The `XCR0` of the extended state, `None` if `xsave` isn't enabled.
+fn ext_xcr0() -> Option<XCr0Flags> {
+    if !Cr4::read().contains(Cr4Flags::OSXSAVE) {
+        return None;
+    }
+    // Initialized before `set_enabled` changes the `XCR0`.
+    Some(*EXT_XCR0.call_once(XCr0::read))
+}
+
+/// Get the size of the [ExtState](super::ExtState), `None` if the AVX isn't enabled.
+///
+/// The state is saved by the `xsave`, the x87 and SSE part is unused.
+pub fn ext_state_size() -> Option<usize> {
+    if !ext_xcr0()?.contains(XCr0Flags::AVX) {
+        return None;
+    }
+    let info = CpuId::new().get_extended_state_info()?;
+    Some(info.xsave_area_size_supported_features() as usize)
+}
+
+/// The requested-feature bitmap of the `xsave`, the x87 and SSE are saved by `fxsave`.
+#[inline]
+fn ext_mask() -> u64 {
+    ext_xcr0().map_or(0, |xcr0| xcr0.bits() & !(XCr0Flags::X87 | XCr0Flags::SSE).bits())
+}
+
+/// Save the extended state.
+pub(crate) fn save_ext(data: &mut [u8]) {
+    let mask = ext_mask();
+    let xsaveopt = XSAVEOPT.call_once(|| {
+        CpuId::new()
+            .get_extended_state_info()
+            .is_some_and(|info| info.has_xsaveopt())
+    });
+    unsafe {
+        match xsaveopt {
+            true => asm!(
+                "xsaveopt64 [{0}]",
+                in(reg) data.as_mut_ptr(),
+                in("eax") mask as u32,
+                in("edx") (mask >> 32) as u32,
+            ),
+            false => asm!(
+                "xsave64 [{0}]",
+                in(reg) data.as_mut_ptr(),
+                in("eax") mask as u32,
+                in("edx") (mask >> 32) as u32,
+            ),
+        }
+    }
+}
+
+/// Restore the extended state.
+pub(crate) fn restore_ext(data: &[u8]) {
+    let mask = ext_mask();
+    unsafe {
+        asm!(
+            "xrstor64 [{0}]",
+            in(reg) data.as_ptr(),
+            in("eax") mask as u32,
+            in("edx") (mask >> 32) as u32,
+        );
+    }
+}
+
+/// The [FpRegs] don't overlap the extended state, nothing need to do here.
+#[inline]
+pub(crate) fn merge_regs(_data: &mut [u8], _regs: &FpRegs) {}
+
+/// Enable or disable the x87 and SSE instructions through the `CR0.TS`.
+///
+/// The AVX instructions raise `#UD` if `ext` is false, they are disabled in the `XCR0`.
+#[inline]
+pub(crate) fn set_enabled(enabled: bool, ext: bool) {
+    let mut cr0 = Cr0::read();
+    cr0.set(Cr0Flags::TASK_SWITCHED, !enabled);
+    unsafe { Cr0::write(cr0) };
+    if let Some(ext_xcr0) = ext_xcr0() {
+        let xcr0 = match ext {
+            true => ext_xcr0,
+            false => ext_xcr0 & (XCr0Flags::X87 | XCr0Flags::SSE),
+        };
+        if XCr0::read() != xcr0 {
+            unsafe { XCr0::write(xcr0) };
+        }
+    }
+}
+
+/// Enable the x87, SSE and AVX in the kernel, return the previous `CR0`.
+pub(crate) fn kernel_fpu_begin() -> usize {
+    let prev = Cr0::read();
+    set_enabled(true, true);
+    prev.bits() as _
+}
+
+/// Restore the `CR0.TS` returned by [kernel_fpu_begin].
+///
+/// The `XCR0` is set again before entering the user task.
+pub(crate) fn kernel_fpu_end(prev: usize) {
+    let prev = Cr0Flags::from_bits_truncate(prev as _);
+    set_enabled(!prev.contains(Cr0Flags::TASK_SWITCHED), true);
+}
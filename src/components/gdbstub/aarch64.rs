@@ -0,0 +1,65 @@
+use core::arch::asm;
+
+use crate::components::trapframe::{TrapFrame, TRAPFRAME_SIZE};
+
+/// `x0` - `x30`, `sp`, `pc` and `cpsr`.
+pub(super) const GDB_REG_NUM: usize = 34;
+
+/// The minimum size of the cache line.
+const CACHE_LINE_SIZE: usize = 16;
+
+/// Get the stack pointer before the trap, the kernel stack pointer is just
+/// above the saved trap frame.
+#[inline]
+fn trap_sp(tf: &TrapFrame) -> usize {
+    match tf.from_user() {
+        true => tf.sp,
+        false => tf as *const TrapFrame as usize + TRAPFRAME_SIZE,
+    }
+}
+
+/// Read the register `n` in the gdb numbering, return the value and the size in bytes.
+pub(super) fn read_reg(tf: &TrapFrame, n: usize) -> Option<(usize, usize)> {
+    match n {
+        0..=30 => Some((tf.regs[n], 8)),
+        31 => Some((trap_sp(tf), 8)),
+        32 => Some((tf.elr, 8)),
+        33 => Some((tf.spsr, 4)),
+        _ => None,
+    }
+}
+
+/// Write the register `n` in the gdb numbering, the kernel stack pointer
+/// can't be changed.
+pub(super) fn write_reg(tf: &mut TrapFrame, n: usize, val: usize) -> bool {
+    match n {
+        0..=30 => tf.regs[n] = val,
+        31 if tf.from_user() => tf.sp = val,
+        32 => tf.elr = val,
+        33 => tf.spsr = val,
+        _ => return false,
+    }
+    true
+}
+
+/// Get the breakpoint instruction with the `kind` of the `Z0` packet,
+/// the `kind` is the length of the instruction.
+pub(super) fn break_insn(kind: usize) -> Option<&'static [u8]> {
+    match kind {
+        // brk #0
+        4 => Some(&[0x00, 0x00, 0x20, 0xd4]),
+        _ => None,
+    }
+}
+
+/// Synchronize the instruction cache after the code at `addr` was changed.
+pub(super) fn flush_icache(addr: usize, len: usize) {
+    for line in (addr & !(CACHE_LINE_SIZE - 1)..addr + len).step_by(CACHE_LINE_SIZE) {
+        unsafe { asm!("dc cvau, {}", in(reg) line) };
+    }
+    unsafe { asm!("dsb ish") };
+    for line in (addr & !(CACHE_LINE_SIZE - 1)..addr + len).step_by(CACHE_LINE_SIZE) {
+        unsafe { asm!("ic ivau, {}", in(reg) line) };
+    }
+    unsafe { asm!("dsb ish", "isb") };
+}
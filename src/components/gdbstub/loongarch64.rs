@@ -0,0 +1,46 @@
+use core::arch::asm;
+
+use loongArch64::register::badv;
+
+use crate::components::trapframe::TrapFrame;
+
+/// `r0` - `r31`, `orig_a0`, `pc` and `badv`.
+pub(super) const GDB_REG_NUM: usize = 35;
+
+/// Read the register `n` in the gdb numbering, return the value and the size in bytes.
+pub(super) fn read_reg(tf: &TrapFrame, n: usize) -> Option<(usize, usize)> {
+    match n {
+        0..=31 => Some((tf.gpr(n), 8)),
+        32 => Some((tf.orig_a0, 8)),
+        33 => Some((tf.era, 8)),
+        34 => Some((badv::read().vaddr(), 8)),
+        _ => None,
+    }
+}
+
+/// Write the register `n` in the gdb numbering, `badv` is read only.
+pub(super) fn write_reg(tf: &mut TrapFrame, n: usize, val: usize) -> bool {
+    match n {
+        0..=31 => tf.set_gpr(n, val),
+        32 => tf.orig_a0 = val,
+        33 => tf.era = val,
+        _ => return false,
+    }
+    true
+}
+
+/// Get the breakpoint instruction with the `kind` of the `Z0` packet,
+/// the `kind` is the length of the instruction.
+pub(super) fn break_insn(kind: usize) -> Option<&'static [u8]> {
+    match kind {
+        // break 0
+        4 => Some(&[0x00, 0x00, 0x2a, 0x00]),
+        _ => None,
+    }
+}
+
+/// Synchronize the instruction cache after the code was changed.
+#[inline]
+pub(super) fn flush_icache(_addr: usize, _len: usize) {
+    unsafe { asm!("ibar 0") };
+}
@@ -0,0 +1,656 @@
+//! GDB remote stub module.
+//!
+//! Speak the GDB Remote Serial Protocol over the [DebugConsole] or another
+//! serial port implementing [GdbSerial]. The stub is driven by the
+//! `Breakpoint`, `Watchpoint` and `SingleStep` traps from the kernel, the
+//! traps from the user mode are passed to the fallback handler.
+//!
+//! ```rust
+//! gdbstub::init(&gdbstub::ConsoleSerial);
+//! // Wait for the gdb to attach.
+//! gdbstub::breakpoint();
+//! ```
+//!
+//! Then connect the gdb to the serial port, for example in qemu with
+//! `-serial tcp::1234,server`:
+//!
+//! ```shell
+//! gdb kernel.elf -ex "target remote :1234"
+//! ```
+//!
+//! Supported packets: `?`, `g`, `G`, `p`, `P`, `m`, `M`, `c`, `s`, `D`, `k`,
+//! `Z0` - `Z4` and `z0` - `z4`. Only the cpu that trapped is stopped, the
+//! other cpus keep running.
+//!
+//! TIPS: The memory above `VIRT_ADDR_START` is probed by the access marked in
+//! the exception table, the other addresses are translated through the
+//! current page table. The unmapped memory is replied with `E14`. riscv64 and
+//! loongarch64 can't step in the kernel, gdb steps them through breakpoints.
+
+use core::fmt::{self, Write};
+
+use spin::Mutex;
+
+use crate::components::breakpoint::{self, WatchKind};
+use crate::components::consts::VIRT_ADDR_START;
+use crate::components::debug_console::DebugConsole;
+use crate::components::instruction;
+use crate::components::trap::{
+    dispatch_fallback, register_trap_handler, FaultInfo, ThreadToken, TrapClass, TrapType,
+};
+use crate::components::trapframe::{TrapFrame, TrapFrameArgs};
+use crate::components::uaccess::__copy_user;
+use crate::{PageTable, VirtAddr};
+
+super::define_arch_mods!();
+
+/// The maximum size of the packet.
+const PACKET_SIZE: usize = 4096;
+
+/// The hex digits.
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+/// The maximum number of the software breakpoints.
+const SW_BREAK_NUM: usize = 64;
+
+/// The maximum number of the hardware breakpoints and watchpoints.
+const HW_SLOT_NUM: usize = 16;
+
+/// The serial port used to talk with the gdb.
+pub trait GdbSerial: Sync {
+    /// Get a char, return None if there is nothing to read.
+    fn getchar(&self) -> Option<u8>;
+    /// Put a char.
+    fn putchar(&self, c: u8);
+}
+
+/// The [GdbSerial] over the [DebugConsole].
+pub struct ConsoleSerial;
+
+impl GdbSerial for ConsoleSerial {
+    #[inline]
+    fn getchar(&self) -> Option<u8> {
+        DebugConsole::getchar()
+    }
+
+    #[inline]
+    fn putchar(&self, c: u8) {
+        DebugConsole::putchar(c)
+    }
+}
+
+/// The software breakpoint, the original code is saved.
+#[derive(Clone, Copy)]
+struct SwBreak {
+    addr: usize,
+    len: usize,
+    orig: [u8; 4],
+}
+
+/// The hardware breakpoint or watchpoint in the slot.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum HwSlot {
+    Breakpoint(usize),
+    /// The address, the length and the access.
+    Watchpoint(usize, usize, WatchKind),
+}
+
+/// The breakpoints inserted by the gdb.
+struct Breakpoints {
+    sw: [Option<SwBreak>; SW_BREAK_NUM],
+    /// The slots are shared by the breakpoints and the watchpoints.
+    hw: [Option<HwSlot>; HW_SLOT_NUM],
+}
+
+/// The packet buffer.
+struct Buffer {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+/// What to do after the packet was handled.
+enum Action {
+    Reply,
+    Resume,
+    /// Send the reply then resume.
+    Detach,
+}
+
+struct GdbStub {
+    serial: Option<&'static dyn GdbSerial>,
+    rx: Buffer,
+    tx: Buffer,
+    breaks: Breakpoints,
+}
+
+static STUB: Mutex<GdbStub> = Mutex::new(GdbStub {
+    serial: None,
+    rx: Buffer::new(),
+    tx: Buffer::new(),
+    breaks: Breakpoints {
+        sw: [None; SW_BREAK_NUM],
+        hw: [None; HW_SLOT_NUM],
+    },
+});
+
+/// Initialize the gdb stub on the `serial`, register the handler of
+/// [TrapClass::Breakpoint].
+pub fn init(serial: &'static dyn GdbSerial) {
+    STUB.lock().serial = Some(serial);
+    register_trap_handler(TrapClass::Breakpoint, handle_trap);
+}
+
+/// Stop the current cpu and wait for the gdb.
+#[inline]
+pub fn breakpoint() {
+    instruction::ebreak();
+}
+
+/// The trap handler of [TrapClass::Breakpoint].
+fn handle_trap(tf: &mut TrapFrame, trap_type: TrapType, token: ThreadToken) {
+    match trap_type.fault_info() {
+        Some(info) if !info.user => STUB.lock().stop(tf, trap_type, info),
+        _ => dispatch_fallback(tf, trap_type, token),
+    }
+}
+
+impl Buffer {
+    const fn new() -> Self {
+        Self {
+            data: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    #[inline]
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    #[inline]
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    /// Push the byte, it's dropped if the buffer is full.
+    #[inline]
+    fn push(&mut self, c: u8) {
+        if self.len < PACKET_SIZE {
+            self.data[self.len] = c;
+            self.len += 1;
+        }
+    }
+
+    /// Push the `bytes` as the hex string.
+    fn push_hex(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.push(HEX[(b >> 4) as usize]);
+            self.push(HEX[(b & 0xf) as usize]);
+        }
+    }
+
+    /// Push the register value in the target byte order.
+    #[inline]
+    fn push_reg(&mut self, (val, size): (usize, usize)) {
+        self.push_hex(&val.to_le_bytes()[..size]);
+    }
+}
+
+impl Write for Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        s.bytes().for_each(|c| self.push(c));
+        Ok(())
+    }
+}
+
+/// Get the value of the hex digit.
+#[inline]
+fn hex_digit(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|d| d as u8)
+}
+
+/// Parse the hex number.
+fn parse_hex(s: &[u8]) -> Option<usize> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter()
+        .try_fold(0, |acc, &c| Some((acc << 4) | hex_digit(c)? as usize))
+}
+
+/// Parse the hex string to the bytes.
+fn parse_bytes(s: &[u8]) -> impl Iterator<Item = Option<u8>> + '_ {
+    s.chunks(2).map(|pair| match *pair {
+        [hi, lo] => Some((hex_digit(hi)? << 4) | hex_digit(lo)?),
+        _ => None,
+    })
+}
+
+/// Parse the register value in the target byte order.
+fn parse_reg(s: &[u8]) -> Option<usize> {
+    if s.len() > 16 {
+        return None;
+    }
+    let mut bytes = [0u8; 8];
+    for (b, v) in bytes.iter_mut().zip(parse_bytes(s)) {
+        *b = v?;
+    }
+    Some(usize::from_le_bytes(bytes))
+}
+
+/// Split the `s` at the first `sep`.
+#[inline]
+fn split(s: &[u8], sep: u8) -> Option<(&[u8], &[u8])> {
+    let pos = s.iter().position(|&c| c == sep)?;
+    Some((&s[..pos], &s[pos + 1..]))
+}
+
+/// Parse the `addr,len` pair.
+fn parse_range(s: &[u8]) -> Option<(usize, usize)> {
+    let (addr, len) = split(s, b',')?;
+    Some((parse_hex(addr)?, parse_hex(len)?))
+}
+
+/// Get the pointer of the `vaddr`, the user address is translated to the
+/// linear mapping.
+fn mem_ptr(vaddr: usize) -> Option<*mut u8> {
+    if vaddr >= VIRT_ADDR_START {
+        return Some(vaddr as *mut u8);
+    }
+    PageTable::current()
+        .translate(VirtAddr::from(vaddr))
+        .map(|(paddr, _)| paddr.get_mut_ptr())
+}
+
+/// Read the byte at `vaddr`, the fault is fixed up by the exception table.
+#[inline]
+fn read_mem(vaddr: usize) -> Option<u8> {
+    let src = mem_ptr(vaddr)?;
+    let mut val = 0u8;
+    (unsafe { __copy_user(&mut val, src, 1) } == 0).then_some(val)
+}
+
+/// Write the byte at `vaddr`, the fault is fixed up by the exception table.
+#[inline]
+fn write_mem(vaddr: usize, val: u8) -> Option<()> {
+    let dst = mem_ptr(vaddr)?;
+    (unsafe { __copy_user(dst, &val, 1) } == 0).then_some(())
+}
+
+impl GdbStub {
+    /// Report the stop to the gdb and handle the packets until resumed.
+    fn stop(&mut self, tf: &mut TrapFrame, trap_type: TrapType, info: FaultInfo) {
+        let GdbStub {
+            serial,
+            rx,
+            tx,
+            breaks,
+        } = self;
+        let serial = match serial {
+            Some(serial) => *serial,
+            None => return,
+        };
+        tx.clear();
+        breaks.stop_reply(tf, trap_type, info, tx);
+        send_packet(serial, tx);
+        loop {
+            recv_packet(serial, rx);
+            tx.clear();
+            let action = breaks.handle_packet(tf, rx.as_bytes(), tx);
+            if let Action::Reply | Action::Detach = action {
+                send_packet(serial, tx);
+            }
+            if let Action::Resume | Action::Detach = action {
+                return;
+            }
+        }
+    }
+}
+
+/// Receive the packet `$<data>#<checksum>` and acknowledge it.
+fn recv_packet(serial: &dyn GdbSerial, rx: &mut Buffer) {
+    let getchar = || loop {
+        if let Some(c) = serial.getchar() {
+            break c;
+        }
+    };
+    loop {
+        // Skip the acks and the interrupts until the start of the packet.
+        while getchar() != b'$' {}
+        rx.clear();
+        let mut sum = 0u8;
+        loop {
+            match getchar() {
+                b'#' => break,
+                c => {
+                    sum = sum.wrapping_add(c);
+                    rx.push(c);
+                }
+            }
+        }
+        let checksum = parse_hex(&[getchar(), getchar()]);
+        if checksum == Some(sum as usize) {
+            serial.putchar(b'+');
+            return;
+        }
+        serial.putchar(b'-');
+    }
+}
+
+/// Send the packet and retransmit it until it's acknowledged.
+fn send_packet(serial: &dyn GdbSerial, tx: &Buffer) {
+    let sum = tx
+        .as_bytes()
+        .iter()
+        .fold(0u8, |sum, &c| sum.wrapping_add(c));
+    let checksum = [HEX[(sum >> 4) as usize], HEX[(sum & 0xf) as usize]];
+    loop {
+        serial.putchar(b'$');
+        tx.as_bytes().iter().for_each(|&c| serial.putchar(c));
+        serial.putchar(b'#');
+        checksum.iter().for_each(|&c| serial.putchar(c));
+        loop {
+            match serial.getchar() {
+                Some(b'+') => return,
+                Some(b'-') => break,
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Breakpoints {
+    /// Build the stop reply, the pc is moved back to the software breakpoint
+    /// inserted by the gdb.
+    fn stop_reply(
+        &self,
+        tf: &mut TrapFrame,
+        trap_type: TrapType,
+        info: FaultInfo,
+        tx: &mut Buffer,
+    ) {
+        let _ = match trap_type {
+            TrapType::Breakpoint(_) if self.find_sw(info.addr).is_some() => {
+                tf[TrapFrameArgs::SEPC] = info.addr;
+                write!(tx, "T05swbreak:;")
+            }
+            TrapType::Breakpoint(_) if self.hw.contains(&Some(HwSlot::Breakpoint(info.addr))) => {
+                write!(tx, "T05hwbreak:;")
+            }
+            TrapType::Watchpoint(_) => {
+                let watch = self.hw.iter().flatten().find_map(|slot| match *slot {
+                    HwSlot::Watchpoint(addr, len, kind)
+                        if info.addr.checked_sub(addr).is_some_and(|off| off < len) =>
+                    {
+                        Some((addr, kind))
+                    }
+                    _ => None,
+                });
+                let (addr, kind) = watch.unwrap_or((info.addr, WatchKind::Write));
+                let reason = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::ReadWrite => "awatch",
+                };
+                write!(tx, "T05{}:{:x};", reason, addr)
+            }
+            _ => write!(tx, "S05"),
+        };
+    }
+
+    /// Handle the packet, the reply is written to `tx`.
+    fn handle_packet(&mut self, tf: &mut TrapFrame, packet: &[u8], tx: &mut Buffer) -> Action {
+        let (&cmd, args) = match packet.split_first() {
+            Some(pair) => pair,
+            None => return Action::Reply,
+        };
+        let ok = match cmd {
+            b'?' => {
+                let _ = write!(tx, "S05");
+                return Action::Reply;
+            }
+            b'g' => {
+                (0..GDB_REG_NUM)
+                    .filter_map(|n| read_reg(tf, n))
+                    .for_each(|reg| tx.push_reg(reg));
+                return Action::Reply;
+            }
+            b'G' => {
+                let mut rest = args;
+                for n in 0..GDB_REG_NUM {
+                    let Some((_, size)) = read_reg(tf, n) else {
+                        continue;
+                    };
+                    let Some(val) = rest.get(..size * 2).and_then(parse_reg) else {
+                        break;
+                    };
+                    write_reg(tf, n, val);
+                    rest = &rest[size * 2..];
+                }
+                true
+            }
+            b'p' => match parse_hex(args).and_then(|n| read_reg(tf, n)) {
+                Some(reg) => {
+                    tx.push_reg(reg);
+                    return Action::Reply;
+                }
+                None => false,
+            },
+            b'P' => split(args, b'=')
+                .and_then(|(n, val)| Some((parse_hex(n)?, parse_reg(val)?)))
+                .map_or(false, |(n, val)| write_reg(tf, n, val)),
+            b'm' => {
+                let Some((addr, len)) = parse_range(args) else {
+                    return error(tx, 1);
+                };
+                let len = len.min(PACKET_SIZE / 2);
+                let Some(end) = addr.checked_add(len) else {
+                    return error(tx, 1);
+                };
+                (addr..end)
+                    .map_while(read_mem)
+                    .for_each(|b| tx.push_hex(&[b]));
+                if tx.len == 0 && len != 0 {
+                    return error(tx, 0x14);
+                }
+                return Action::Reply;
+            }
+            b'M' => {
+                let Some((range, data)) = split(args, b':') else {
+                    return error(tx, 1);
+                };
+                let Some((addr, len)) = parse_range(range) else {
+                    return error(tx, 1);
+                };
+                let Some(end) = addr.checked_add(len) else {
+                    return error(tx, 1);
+                };
+                let written = (addr..end)
+                    .zip(parse_bytes(data))
+                    .all(|(vaddr, val)| val.and_then(|val| write_mem(vaddr, val)).is_some());
+                if !written {
+                    return error(tx, 0x14);
+                }
+                flush_icache(addr, len);
+                true
+            }
+            b'c' | b's' => {
+                if let Some(addr) = parse_hex(args) {
+                    tf[TrapFrameArgs::SEPC] = addr;
+                }
+                tf.set_single_step(cmd == b's');
+                return Action::Resume;
+            }
+            b'D' => {
+                self.clear();
+                tf.set_single_step(false);
+                let _ = write!(tx, "OK");
+                return Action::Detach;
+            }
+            b'k' => {
+                self.clear();
+                tf.set_single_step(false);
+                return Action::Resume;
+            }
+            b'H' | b'T' => true,
+            b'q' => {
+                let _ = match args {
+                    b"Attached" => write!(tx, "1"),
+                    b"C" => write!(tx, "QC1"),
+                    b"fThreadInfo" => write!(tx, "m1"),
+                    b"sThreadInfo" => write!(tx, "l"),
+                    _ if args.starts_with(b"Supported") => {
+                        write!(tx, "PacketSize={:x};swbreak+;hwbreak+", PACKET_SIZE)
+                    }
+                    _ => Ok(()),
+                };
+                return Action::Reply;
+            }
+            b'Z' | b'z' => match self.handle_break(cmd == b'Z', args) {
+                Some(ok) => ok,
+                // The type isn't supported.
+                None => return Action::Reply,
+            },
+            _ => return Action::Reply,
+        };
+        match ok {
+            true => {
+                let _ = write!(tx, "OK");
+                Action::Reply
+            }
+            false => error(tx, 1),
+        }
+    }
+
+    /// Insert or remove the breakpoint of the `Z`/`z` packet `type,addr,kind`.
+    ///
+    /// Return None if the type isn't supported.
+    fn handle_break(&mut self, insert: bool, args: &[u8]) -> Option<bool> {
+        let (ty, range) = split(args, b',')?;
+        let Some((addr, kind)) = parse_range(range) else {
+            return Some(false);
+        };
+        // The kind is the length of the code or the watched memory.
+        if addr.checked_add(kind).is_none() {
+            return Some(false);
+        }
+        // The kind of the watchpoint is the length.
+        let slot = match ty {
+            b"0" => {
+                return Some(match insert {
+                    true => self.insert_sw(addr, kind),
+                    false => self.remove_sw(addr),
+                })
+            }
+            b"1" => HwSlot::Breakpoint(addr),
+            b"2" => HwSlot::Watchpoint(addr, kind, WatchKind::Write),
+            b"3" => HwSlot::Watchpoint(addr, kind, WatchKind::Read),
+            b"4" => HwSlot::Watchpoint(addr, kind, WatchKind::ReadWrite),
+            _ => return None,
+        };
+        Some(match insert {
+            true => self.insert_hw(slot),
+            false => self.remove_hw(slot),
+        })
+    }
+
+    /// Find the software breakpoint at `addr`.
+    #[inline]
+    fn find_sw(&self, addr: usize) -> Option<usize> {
+        self.sw
+            .iter()
+            .position(|brk| matches!(brk, Some(brk) if brk.addr == addr))
+    }
+
+    /// Replace the code at `addr` with the breakpoint instruction.
+    fn insert_sw(&mut self, addr: usize, kind: usize) -> bool {
+        if self.find_sw(addr).is_some() {
+            return true;
+        }
+        let Some(insn) = break_insn(kind) else {
+            return false;
+        };
+        let Some(entry) = self.sw.iter_mut().find(|brk| brk.is_none()) else {
+            return false;
+        };
+        let mut orig = [0u8; 4];
+        for (i, b) in orig[..insn.len()].iter_mut().enumerate() {
+            match read_mem(addr + i) {
+                Some(val) => *b = val,
+                None => return false,
+            }
+        }
+        for (i, &c) in insn.iter().enumerate() {
+            write_mem(addr + i, c);
+        }
+        flush_icache(addr, insn.len());
+        *entry = Some(SwBreak {
+            addr,
+            len: insn.len(),
+            orig,
+        });
+        true
+    }
+
+    /// Restore the code of the software breakpoint at `addr`.
+    fn remove_sw(&mut self, addr: usize) -> bool {
+        let Some(brk) = self.find_sw(addr).and_then(|i| self.sw[i].take()) else {
+            return false;
+        };
+        for (i, &c) in brk.orig[..brk.len].iter().enumerate() {
+            write_mem(brk.addr + i, c);
+        }
+        flush_icache(brk.addr, brk.len);
+        true
+    }
+
+    /// Program the hardware breakpoint or watchpoint in a free slot.
+    fn insert_hw(&mut self, slot: HwSlot) -> bool {
+        let Some(i) = self.hw.iter().position(Option::is_none) else {
+            return false;
+        };
+        let ret = match slot {
+            HwSlot::Breakpoint(addr) => breakpoint::set_breakpoint(i, addr),
+            HwSlot::Watchpoint(addr, len, kind) => breakpoint::set_watchpoint(i, addr, len, kind),
+        };
+        if ret.is_ok() {
+            self.hw[i] = Some(slot);
+        }
+        ret.is_ok()
+    }
+
+    /// Clear the slot of the hardware breakpoint or watchpoint.
+    fn remove_hw(&mut self, slot: HwSlot) -> bool {
+        let Some(i) = self.hw.iter().position(|s| *s == Some(slot)) else {
+            return false;
+        };
+        let _ = match slot {
+            HwSlot::Breakpoint(_) => breakpoint::clear_breakpoint(i),
+            HwSlot::Watchpoint(..) => breakpoint::clear_watchpoint(i),
+        };
+        self.hw[i] = None;
+        true
+    }
+
+    /// Remove all the breakpoints when the gdb is detached.
+    fn clear(&mut self) {
+        for i in 0..SW_BREAK_NUM {
+            if let Some(brk) = self.sw[i] {
+                self.remove_sw(brk.addr);
+            }
+        }
+        for i in 0..HW_SLOT_NUM {
+            if let Some(slot) = self.hw[i] {
+                self.remove_hw(slot);
+            }
+        }
+    }
+}
+
+/// Send the error reply `Exx`.
+#[inline]
+fn error(tx: &mut Buffer, errno: u8) -> Action {
+    tx.clear();
+    let _ = write!(tx, "E{:02x}", errno);
+    Action::Reply
+}
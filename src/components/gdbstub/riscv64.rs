@@ -0,0 +1,43 @@
+use core::arch::asm;
+
+use crate::components::trapframe::TrapFrame;
+
+/// `x0` - `x31` and `pc`.
+pub(super) const GDB_REG_NUM: usize = 33;
+
+/// Read the register `n` in the gdb numbering, return the value and the size in bytes.
+pub(super) fn read_reg(tf: &TrapFrame, n: usize) -> Option<(usize, usize)> {
+    match n {
+        0..=31 => Some((tf.gpr(n), 8)),
+        32 => Some((tf.sepc, 8)),
+        _ => None,
+    }
+}
+
+/// Write the register `n` in the gdb numbering.
+pub(super) fn write_reg(tf: &mut TrapFrame, n: usize, val: usize) -> bool {
+    match n {
+        0..=31 => tf.set_gpr(n, val),
+        32 => tf.sepc = val,
+        _ => return false,
+    }
+    true
+}
+
+/// Get the breakpoint instruction with the `kind` of the `Z0` packet,
+/// the `kind` is the length of the instruction.
+pub(super) fn break_insn(kind: usize) -> Option<&'static [u8]> {
+    match kind {
+        // c.ebreak
+        2 => Some(&[0x02, 0x90]),
+        // ebreak
+        4 => Some(&[0x73, 0x00, 0x10, 0x00]),
+        _ => None,
+    }
+}
+
+/// Synchronize the instruction cache after the code was changed.
+#[inline]
+pub(super) fn flush_icache(_addr: usize, _len: usize) {
+    unsafe { asm!("fence.i") };
+}
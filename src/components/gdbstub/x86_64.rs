@@ -0,0 +1,63 @@
+use crate::components::trapframe::TrapFrame;
+
+/// `rax`, `rbx`, `rcx`, `rdx`, `rsi`, `rdi`, `rbp`, `rsp`, `r8` - `r15`,
+/// `rip`, `eflags`, `cs`, `ss`, `ds`, `es`, `fs` and `gs`.
+pub(super) const GDB_REG_NUM: usize = 24;
+
+/// Read the register `n` in the gdb numbering, return the value and the size in bytes.
+///
+/// `ds`, `es`, `fs` and `gs` aren't saved in the [TrapFrame], they are 0.
+pub(super) fn read_reg(tf: &TrapFrame, n: usize) -> Option<(usize, usize)> {
+    let val = match n {
+        0 => tf.rax,
+        1 => tf.rbx,
+        2 => tf.rcx,
+        3 => tf.rdx,
+        4 => tf.rsi,
+        5 => tf.rdi,
+        6 => tf.rbp,
+        7 => tf.rsp,
+        8..=15 => tf.gpr(n),
+        16 => tf.rip,
+        17 => return Some((tf.rflags, 4)),
+        18 => return Some((tf.cs, 4)),
+        19 => return Some((tf.ss, 4)),
+        20..=23 => return Some((0, 4)),
+        _ => return None,
+    };
+    Some((val, 8))
+}
+
+/// Write the register `n` in the gdb numbering, the segment registers
+/// can't be changed.
+pub(super) fn write_reg(tf: &mut TrapFrame, n: usize, val: usize) -> bool {
+    match n {
+        0 => tf.rax = val,
+        1 => tf.rbx = val,
+        2 => tf.rcx = val,
+        3 => tf.rdx = val,
+        4 => tf.rsi = val,
+        5 => tf.rdi = val,
+        6 => tf.rbp = val,
+        7 => tf.rsp = val,
+        8..=15 => tf.set_gpr(n, val),
+        16 => tf.rip = val,
+        17 => tf.rflags = val,
+        _ => return false,
+    }
+    true
+}
+
+/// Get the breakpoint instruction with the `kind` of the `Z0` packet,
+/// the `kind` is the length of the instruction.
+pub(super) fn break_insn(kind: usize) -> Option<&'static [u8]> {
+    match kind {
+        // int3
+        1 => Some(&[0xcc]),
+        _ => None,
+    }
+}
+
+/// The instruction cache is coherent on x86_64.
+#[inline]
+pub(super) fn flush_icache(_addr: usize, _len: usize) {}
@@ -1,10 +1,13 @@
+mod gicv2;
+mod gicv3;
+mod its;
+
 use aarch64_cpu::registers::{Readable, DAIF};
-use arm_gicv2::{GicCpuInterface, GicDistributor};
 use arm_gicv2::{translate_irq, InterruptType};
+use fdt::Fdt;
 
-use crate::addr::PhysAddr;
-use crate::components::irq::{IRQVector, IRQ};
-use crate::utils::MutexNoIrq;
+use crate::components::common::{DTB_BIN, DTB_PTR};
+use crate::components::irq::{IRQVector, MsiMessage, IRQ};
 
 /// The maximum number of IRQs.
 #[allow(dead_code)]
@@ -17,55 +20,116 @@ pub const TIMER_IRQ_NUM: usize = translate_irq(14, InterruptType::PPI).unwrap();
 #[allow(dead_code)]
 pub const UART_IRQ_NUM: usize = translate_irq(1, InterruptType::SPI).unwrap();
 
-const GICD_BASE: PhysAddr = PhysAddr::new(0x0800_0000);
-const GICC_BASE: PhysAddr = PhysAddr::new(0x0801_0000);
+/// The interrupt id read from the `IAR` if there is no pending interrupt.
+pub(crate) const GIC_SPURIOUS_IRQ_NUM: usize = 1023;
 
-static GICD: MutexNoIrq<GicDistributor> =
-    MutexNoIrq::new(GicDistributor::new(GICD_BASE.get_mut_ptr()));
+/// The compatible strings of the GICv2 node.
+const GICV2_COMPATIBLE: &[&str] = &["arm,gic-400", "arm,cortex-a15-gic"];
 
-// per-CPU, no lock
-static GICC: GicCpuInterface = GicCpuInterface::new(GICC_BASE.get_mut_ptr());
+/// The compatible string of the GICv3 node.
+const GICV3_COMPATIBLE: &str = "arm,gic-v3";
 
-/// Initializes GICD, GICC on the primary CPU.
+/// Initializes the GIC found in the device tree on the primary CPU.
+///
+/// The GICv3 is preferred, the GICv2 of QEMU virt is used if no GIC is found.
 pub(crate) fn init() {
-    log::info!("Initialize GICv2...");
-    GICD.lock().init();
-    GICC.init();
+    let fdt = DTB_PTR
+        .try_get()
+        .and_then(|ptr| unsafe { Fdt::from_ptr(*ptr as *const u8).ok() });
+    let gicv3 = fdt
+        .as_ref()
+        .and_then(|fdt| fdt.find_compatible(&[GICV3_COMPATIBLE]));
+    if gicv3.is_some_and(gicv3::init) {
+        gicv3::init_percpu();
+    } else {
+        gicv2::init(
+            fdt.as_ref()
+                .and_then(|fdt| fdt.find_compatible(GICV2_COMPATIBLE)),
+        );
+    }
+}
+
+/// Initializes the GIC on the secondary CPU.
+pub(crate) fn init_percpu() {
+    match gicv3::is_present() {
+        true => gicv3::init_percpu(),
+        false => gicv2::init_percpu(),
+    }
+}
+
+/// Initializes the ITS of the GICv3, the LPIs need the page allocator.
+pub(crate) fn init_its() {
+    if let Ok(fdt) = Fdt::new(&DTB_BIN) {
+        its::init(&fdt);
+    }
 }
 
 /// Implmente the irq vector methods
 impl IRQVector {
     /// Get the irq number in this vector
+    ///
+    /// It's the interrupt id, the LPIs of the GICv3 start from 8192.
     #[inline]
-    pub const fn irq_num(&self) -> usize {
-        self.0 & 0x3ff
+    pub fn irq_num(&self) -> usize {
+        match gicv3::is_present() {
+            true => self.0 & 0xff_ffff,
+            false => self.0 & 0x3ff,
+        }
     }
 
     /// Acknowledge the irq
     pub fn ack(&self) {
-        GICC.eoi(self.0 as u32);
+        match gicv3::is_present() {
+            true => gicv3::eoi(self.0),
+            false => gicv2::eoi(self.0),
+        }
     }
 }
 
 /// Get the irq Vector that was
 #[inline]
 pub fn get_irq() -> IRQVector {
-    IRQVector(GICC.iar() as _)
+    match gicv3::is_present() {
+        true => IRQVector(gicv3::iar()),
+        false => IRQVector(gicv2::iar()),
+    }
 }
 
 /// Implement IRQ operations for the IRQ interface.
 impl IRQ {
     /// Enable irq for the given IRQ number.
+    ///
+    /// The SPI is delivered to the current CPU with the GICv3.
     #[inline]
     pub fn irq_enable(irq_num: usize) {
-        GICD.lock().set_enable(irq_num, true);
-        // GICD.lock().configure_interrupt(irq_num, arm_gic::TriggerMode::Level);
+        match gicv3::is_present() {
+            true if irq_num >= gicv3::LPI_START => its::set_enable(irq_num, true),
+            true => gicv3::enable(irq_num),
+            false => gicv2::set_enable(irq_num, true),
+        }
     }
 
     /// Disable irq for the given IRQ number.
     #[inline]
     pub fn irq_disable(irq_num: usize) {
-        GICD.lock().set_enable(irq_num, false);
+        match gicv3::is_present() {
+            true if irq_num >= gicv3::LPI_START => its::set_enable(irq_num, false),
+            true => gicv3::disable(irq_num),
+            false => gicv2::set_enable(irq_num, false),
+        }
+    }
+
+    /// Allocate an MSI delivered to the current CPU and enable it.
+    ///
+    /// The `device_id` is the device id of the ITS, the requester id of the PCI device.
+    /// Return `None` if there is no ITS or the LPIs are used up.
+    pub fn msi_alloc(device_id: u32) -> Option<(IRQVector, MsiMessage)> {
+        its::alloc(device_id).map(|(lpi, msg)| (IRQVector(lpi), msg))
+    }
+
+    /// Disable the MSI and release it.
+    pub fn msi_free(irq: IRQVector) {
+        its::free(irq.0);
     }
 
     /// Enable interrupt.
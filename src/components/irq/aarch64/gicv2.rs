@@ -0,0 +1,74 @@
+//! The GICv2 driver.
+//!
+//! The distributor and the cpu interface are accessed by the memory mapped
+//! registers, the addresses of QEMU virt are used if the GIC node isn't
+//! found in the FDT.
+
+use arm_gicv2::{GicCpuInterface, GicDistributor};
+use fdt::node::FdtNode;
+
+use crate::addr::PhysAddr;
+use crate::utils::{LazyInit, MutexNoIrq};
+
+/// The default addresses of the distributor and the cpu interface on QEMU virt.
+const DEFAULT_GICD_BASE: PhysAddr = PhysAddr::new(0x0800_0000);
+const DEFAULT_GICC_BASE: PhysAddr = PhysAddr::new(0x0801_0000);
+
+static GICD: LazyInit<MutexNoIrq<GicDistributor>> = LazyInit::new();
+
+// per-CPU, no lock
+static GICC: LazyInit<GicCpuInterface> = LazyInit::new();
+
+/// Initialize the distributor and the cpu interface of the current cpu.
+pub(super) fn init(node: Option<FdtNode>) {
+    let mut regs = node.and_then(|node| node.reg());
+    let mut next_reg = |default: PhysAddr| {
+        regs.as_mut()
+            .and_then(|regs| regs.next())
+            .map_or(default, |region| {
+                PhysAddr::new(region.starting_address as usize)
+            })
+    };
+    let gicd_base = next_reg(DEFAULT_GICD_BASE);
+    let gicc_base = next_reg(DEFAULT_GICC_BASE);
+    log::info!(
+        "Initialize GICv2 @ {:#x}, cpu interface @ {:#x}...",
+        gicd_base.addr(),
+        gicc_base.addr()
+    );
+    GICD.init_by(MutexNoIrq::new(GicDistributor::new(
+        gicd_base.get_mut_ptr(),
+    )));
+    GICC.init_by(GicCpuInterface::new(gicc_base.get_mut_ptr()));
+    GICD.lock().init();
+    GICC.init();
+}
+
+/// Initialize the cpu interface of the current cpu, it's banked.
+pub(super) fn init_percpu() {
+    if let Some(gicc) = GICC.try_get() {
+        gicc.init();
+    }
+}
+
+/// Enables or disables the irq, the SGI and the PPI are banked.
+pub(super) fn set_enable(irq_num: usize, enable: bool) {
+    if let Some(gicd) = GICD.try_get() {
+        gicd.lock().set_enable(irq_num, enable);
+    }
+}
+
+/// Acknowledge the highest priority pending interrupt, read `GICC_IAR`.
+#[inline]
+pub(super) fn iar() -> usize {
+    GICC.try_get()
+        .map_or(super::GIC_SPURIOUS_IRQ_NUM, |gicc| gicc.iar() as _)
+}
+
+/// Complete the interrupt, write `GICC_EOIR`.
+#[inline]
+pub(super) fn eoi(iar: usize) {
+    if let Some(gicc) = GICC.try_get() {
+        gicc.eoi(iar as u32);
+    }
+}
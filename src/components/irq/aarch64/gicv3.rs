@@ -0,0 +1,319 @@
+//! The GICv3 driver.
+//!
+//! The distributor routes the SPIs by the affinity, every cpu has its own
+//! redistributor for the SGIs, the PPIs and the LPIs. The cpu interface is
+//! accessed by the system registers. The SPIs are configured as level
+//! triggered and routed to the cpu enabled them.
+
+use aarch64_cpu::registers::{Readable, MPIDR_EL1};
+use core::arch::asm;
+use fdt::node::FdtNode;
+
+use crate::components::consts::VIRT_ADDR_START;
+use crate::utils::LazyInit;
+
+/// The offsets of the distributor registers.
+const GICD_CTLR: usize = 0x0000;
+const GICD_TYPER: usize = 0x0004;
+const GICD_IGROUPR: usize = 0x0080;
+const GICD_ISENABLER: usize = 0x0100;
+const GICD_ICENABLER: usize = 0x0180;
+const GICD_ICPENDR: usize = 0x0280;
+const GICD_IPRIORITYR: usize = 0x0400;
+const GICD_ICFGR: usize = 0x0c00;
+const GICD_IROUTER: usize = 0x6000;
+
+/// The bits of the `GICD_CTLR`.
+const GICD_CTLR_RWP: u32 = 1 << 31;
+const GICD_CTLR_ARE_NS: u32 = 1 << 4;
+const GICD_CTLR_ENABLE_G1A: u32 = 1 << 1;
+const GICD_CTLR_ENABLE_G1: u32 = 1 << 0;
+
+/// The offsets of the redistributor registers in the `RD_base` frame.
+pub(super) const GICR_CTLR: usize = 0x0000;
+pub(super) const GICR_TYPER: usize = 0x0008;
+const GICR_WAKER: usize = 0x0014;
+pub(super) const GICR_PROPBASER: usize = 0x0070;
+pub(super) const GICR_PENDBASER: usize = 0x0078;
+
+/// The offsets of the redistributor registers in the `SGI_base` frame.
+const GICR_SGI_BASE: usize = 0x1_0000;
+const GICR_IGROUPR0: usize = GICR_SGI_BASE + 0x0080;
+const GICR_ISENABLER0: usize = GICR_SGI_BASE + 0x0100;
+const GICR_ICENABLER0: usize = GICR_SGI_BASE + 0x0180;
+const GICR_ICPENDR0: usize = GICR_SGI_BASE + 0x0280;
+const GICR_IPRIORITYR: usize = GICR_SGI_BASE + 0x0400;
+const GICR_ICFGR1: usize = GICR_SGI_BASE + 0x0c04;
+
+/// The bits of the redistributor registers.
+const GICR_CTLR_RWP: u32 = 1 << 3;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+const GICR_TYPER_VLPIS: u64 = 1 << 1;
+const GICR_TYPER_LAST: u64 = 1 << 4;
+
+/// The size of the frames of a redistributor, `RD_base` and `SGI_base`.
+const GICR_FRAME_SIZE: usize = 0x2_0000;
+/// The size of the frames with the virtual LPI frames.
+const GICR_VLPI_FRAME_SIZE: usize = 0x4_0000;
+
+/// The default priority of the interrupts.
+const DEFAULT_PRIORITY: u8 = 0xa0;
+
+/// The first SPI and the maximum number of the SPIs.
+const SPI_START: usize = 32;
+const SPI_END: usize = 1020;
+
+/// The first LPI.
+pub(super) const LPI_START: usize = 8192;
+
+/// The `ICC_SRE_EL1.SRE`, the system register interface is enabled.
+const ICC_SRE_SRE: usize = 1 << 0;
+
+struct GicV3 {
+    /// The virtual address of the distributor.
+    gicd: usize,
+    /// The virtual address of the first redistributor.
+    gicr: usize,
+    /// The distance between the redistributors, 0 means detected by `VLPIS`.
+    stride: usize,
+    /// The number of the irqs before the SPIs end.
+    num_irqs: usize,
+}
+
+static GIC_V3: LazyInit<GicV3> = LazyInit::new();
+
+/// The virtual address of the redistributor of the cpu.
+#[polyhal_macro::def_percpu]
+static GICR_BASE: usize = 0;
+
+#[inline]
+pub(super) fn read(addr: usize) -> u32 {
+    unsafe { (addr as *const u32).read_volatile() }
+}
+
+#[inline]
+pub(super) fn write(addr: usize, value: u32) {
+    unsafe { (addr as *mut u32).write_volatile(value) }
+}
+
+#[inline]
+pub(super) fn read64(addr: usize) -> u64 {
+    unsafe { (addr as *const u64).read_volatile() }
+}
+
+#[inline]
+pub(super) fn write64(addr: usize, value: u64) {
+    unsafe { (addr as *mut u64).write_volatile(value) }
+}
+
+/// The affinity of the current cpu in the format of the `GICR_TYPER`.
+#[inline]
+fn cpu_affinity() -> u64 {
+    let mpidr = MPIDR_EL1.get();
+    (mpidr & 0xff_ffff) | (((mpidr >> 32) & 0xff) << 24)
+}
+
+/// The affinity of the current cpu in the format of the `GICD_IROUTER`.
+#[inline]
+fn cpu_route() -> u64 {
+    MPIDR_EL1.get() & 0xff_00ff_ffff
+}
+
+/// Iterate the `RD_base` of all the redistributors.
+pub(super) fn redistributors() -> impl Iterator<Item = usize> {
+    let mut next = GIC_V3.try_get().map(|gic| (gic.gicr, gic.stride));
+    core::iter::from_fn(move || {
+        let (rd, stride) = next?;
+        let typer = read64(rd + GICR_TYPER);
+        let frame_size = match (stride, typer & GICR_TYPER_VLPIS != 0) {
+            (0, true) => GICR_VLPI_FRAME_SIZE,
+            (0, false) => GICR_FRAME_SIZE,
+            (stride, _) => stride,
+        };
+        next = (typer & GICR_TYPER_LAST == 0).then_some((rd + frame_size, stride));
+        Some(rd)
+    })
+}
+
+/// Wait for the register write of the distributor taking effect.
+#[inline]
+fn wait_gicd_rwp(gic: &GicV3) {
+    while read(gic.gicd + GICD_CTLR) & GICD_CTLR_RWP != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Wait for the register write of the redistributor taking effect.
+#[inline]
+fn wait_gicr_rwp(rd: usize) {
+    while read(rd + GICR_CTLR) & GICR_CTLR_RWP != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Initialize the distributor from the FDT node, return false if its
+/// registers are not found.
+///
+/// All the SPIs are disabled and belong to the non-secure group 1.
+pub(super) fn init(node: FdtNode) -> bool {
+    let mut regs = match node.reg() {
+        Some(regs) => regs,
+        None => return false,
+    };
+    let (Some(gicd), Some(gicr)) = (regs.next(), regs.next()) else {
+        return false;
+    };
+    let stride = node
+        .property("redistributor-stride")
+        .and_then(|prop| prop.as_usize())
+        .unwrap_or(0);
+    let mut gic = GicV3 {
+        gicd: gicd.starting_address as usize | VIRT_ADDR_START,
+        gicr: gicr.starting_address as usize | VIRT_ADDR_START,
+        stride,
+        num_irqs: 0,
+    };
+    gic.num_irqs = (((read(gic.gicd + GICD_TYPER) & 0x1f) as usize + 1) * 32).min(SPI_END);
+
+    write(gic.gicd + GICD_CTLR, 0);
+    wait_gicd_rwp(&gic);
+    for irq in (SPI_START..gic.num_irqs).step_by(32) {
+        write(gic.gicd + GICD_IGROUPR + irq / 8, u32::MAX);
+        write(gic.gicd + GICD_ICENABLER + irq / 8, u32::MAX);
+        write(gic.gicd + GICD_ICPENDR + irq / 8, u32::MAX);
+    }
+    for irq in (SPI_START..gic.num_irqs).step_by(16) {
+        write(gic.gicd + GICD_ICFGR + irq / 4, 0);
+    }
+    for irq in SPI_START..gic.num_irqs {
+        unsafe { ((gic.gicd + GICD_IPRIORITYR + irq) as *mut u8).write_volatile(DEFAULT_PRIORITY) };
+    }
+    wait_gicd_rwp(&gic);
+    write(
+        gic.gicd + GICD_CTLR,
+        GICD_CTLR_ARE_NS | GICD_CTLR_ENABLE_G1A | GICD_CTLR_ENABLE_G1,
+    );
+    log::info!(
+        "GICv3 @ {:#x}, redistributors @ {:#x}: {} irqs",
+        gicd.starting_address as usize,
+        gicr.starting_address as usize,
+        gic.num_irqs
+    );
+    GIC_V3.init_by(gic);
+    true
+}
+
+/// Initialize the redistributor and the cpu interface of the current cpu.
+///
+/// The SGIs and the PPIs are disabled and belong to the non-secure group 1.
+pub(super) fn init_percpu() {
+    let affinity = cpu_affinity();
+    let Some(rd) = redistributors().find(|rd| read64(rd + GICR_TYPER) >> 32 == affinity) else {
+        log::warn!("the redistributor of the cpu {:#x} is not found", affinity);
+        return;
+    };
+    GICR_BASE.write_current(rd);
+
+    // Wake up the redistributor.
+    write(
+        rd + GICR_WAKER,
+        read(rd + GICR_WAKER) & !GICR_WAKER_PROCESSOR_SLEEP,
+    );
+    while read(rd + GICR_WAKER) & GICR_WAKER_CHILDREN_ASLEEP != 0 {
+        core::hint::spin_loop();
+    }
+    write(rd + GICR_IGROUPR0, u32::MAX);
+    write(rd + GICR_ICENABLER0, u32::MAX);
+    write(rd + GICR_ICPENDR0, u32::MAX);
+    write(rd + GICR_ICFGR1, 0);
+    for irq in 0..SPI_START {
+        unsafe { ((rd + GICR_IPRIORITYR + irq) as *mut u8).write_volatile(DEFAULT_PRIORITY) };
+    }
+    wait_gicr_rwp(rd);
+
+    unsafe {
+        // ICC_SRE_EL1
+        let sre: usize;
+        asm!("mrs {0}, S3_0_C12_C12_5", out(reg) sre);
+        asm!("msr S3_0_C12_C12_5, {0}", "isb", in(reg) sre | ICC_SRE_SRE);
+        // ICC_PMR_EL1, unmask all the priorities.
+        asm!("msr S3_0_C4_C6_0, {0}", in(reg) 0xffusize);
+        // ICC_BPR1_EL1, all the priority bits are used for the preemption.
+        asm!("msr S3_0_C12_C12_3, {0}", in(reg) 0usize);
+        // ICC_CTLR_EL1, EOI drops the priority and deactivates the interrupt.
+        asm!("msr S3_0_C12_C12_4, {0}", in(reg) 0usize);
+        // ICC_IGRPEN1_EL1, enable the group 1 interrupts.
+        asm!("msr S3_0_C12_C12_7, {0}", "isb", in(reg) 1usize);
+    }
+}
+
+/// Get the `RD_base` of the redistributor of the current cpu.
+#[inline]
+pub(super) fn current_redistributor() -> Option<usize> {
+    match GICR_BASE.read_current() {
+        0 => None,
+        rd => Some(rd),
+    }
+}
+
+/// Enable the SGI, the PPI or the SPI.
+///
+/// The SGI and the PPI are enabled on the current cpu, the SPI is routed to it.
+pub(super) fn enable(irq_num: usize) {
+    let Some(gic) = GIC_V3.try_get() else {
+        return;
+    };
+    if irq_num < SPI_START {
+        if let Some(rd) = current_redistributor() {
+            write(rd + GICR_ISENABLER0, 1 << irq_num);
+        }
+    } else if irq_num < gic.num_irqs {
+        write64(gic.gicd + GICD_IROUTER + irq_num * 8, cpu_route());
+        write(
+            gic.gicd + GICD_ISENABLER + irq_num / 32 * 4,
+            1 << (irq_num % 32),
+        );
+    }
+}
+
+/// Disable the SGI, the PPI or the SPI.
+///
+/// The SGI and the PPI are only disabled on the current cpu.
+pub(super) fn disable(irq_num: usize) {
+    let Some(gic) = GIC_V3.try_get() else {
+        return;
+    };
+    if irq_num < SPI_START {
+        if let Some(rd) = current_redistributor() {
+            write(rd + GICR_ICENABLER0, 1 << irq_num);
+            wait_gicr_rwp(rd);
+        }
+    } else if irq_num < gic.num_irqs {
+        write(
+            gic.gicd + GICD_ICENABLER + irq_num / 32 * 4,
+            1 << (irq_num % 32),
+        );
+        wait_gicd_rwp(gic);
+    }
+}
+
+/// Acknowledge the highest priority pending interrupt, read `ICC_IAR1_EL1`.
+#[inline]
+pub(super) fn iar() -> usize {
+    let iar: usize;
+    unsafe { asm!("mrs {0}, S3_0_C12_C12_0", out(reg) iar) };
+    iar
+}
+
+/// Complete the interrupt, write `ICC_EOIR1_EL1`.
+#[inline]
+pub(super) fn eoi(iar: usize) {
+    unsafe { asm!("msr S3_0_C12_C12_1, {0}", in(reg) iar) };
+}
+
+/// Check if the GICv3 was initialized.
+#[inline]
+pub(super) fn is_present() -> bool {
+    GIC_V3.is_init()
+}
@@ -0,0 +1,391 @@
+//! The Interrupt Translation Service (ITS) driver of the GICv3.
+//!
+//! The device raises the MSI by writing the event id to the `GITS_TRANSLATER`,
+//! the ITS translates the device id of the writer and the event id to the
+//! LPI, then the LPI is delivered to the redistributor of the collection.
+//! Every redistributor has a collection, the MSI is delivered to the cpu
+//! allocated it.
+//!
+//! The ITS is configured by the commands in the command queue, the tables of
+//! the ITS and the LPIs are in the memory. The LPI configuration table is
+//! shared by all the redistributors and every redistributor has its own
+//! pending table.
+
+use alloc::collections::BTreeMap;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{fence, Ordering};
+
+use fdt::Fdt;
+
+use crate::addr::PhysAddr;
+use crate::components::common::frame_alloc;
+use crate::components::consts::VIRT_ADDR_START;
+use crate::components::irq::MsiMessage;
+use crate::pagetable::PAGE_SIZE;
+use crate::utils::{LazyInit, MutexNoIrq};
+use crate::PhysPage;
+
+use super::gicv3::{
+    self, read, read64, write, write64, GICR_CTLR, GICR_PENDBASER, GICR_PROPBASER, GICR_TYPER,
+    LPI_START,
+};
+
+/// The compatible string of the ITS node.
+const ITS_COMPATIBLE: &str = "arm,gic-v3-its";
+
+/// The offsets of the ITS registers.
+const GITS_CTLR: usize = 0x0000;
+const GITS_TYPER: usize = 0x0008;
+const GITS_CBASER: usize = 0x0080;
+const GITS_CWRITER: usize = 0x0088;
+const GITS_CREADR: usize = 0x0090;
+const GITS_BASER: usize = 0x0100;
+const GITS_TRANSLATER: usize = 0x1_0040;
+
+/// The number of the `GITS_BASER<n>`.
+const GITS_BASER_NUM: usize = 8;
+
+/// The bits of the ITS registers.
+const GITS_CTLR_ENABLED: u32 = 1 << 0;
+const GITS_CTLR_QUIESCENT: u32 = 1 << 31;
+const GITS_TYPER_PTA: u64 = 1 << 19;
+const GITS_VALID: u64 = 1 << 63;
+const GITS_BASER_TYPE_DEVICE: u64 = 1;
+const GITS_BASER_TYPE_COLLECTION: u64 = 4;
+
+/// The `GICR_CTLR.EnableLPIs`.
+const GICR_CTLR_ENABLE_LPIS: u32 = 1 << 0;
+
+/// The inner shareable and write-back cacheable attributes of the tables.
+const ITS_TABLE_ATTRS: u64 = (7 << 59) | (1 << 10);
+const GICR_TABLE_ATTRS: u64 = (7 << 7) | (1 << 10);
+
+/// The command numbers.
+const CMD_SYNC: u64 = 0x05;
+const CMD_MAPD: u64 = 0x08;
+const CMD_MAPC: u64 = 0x09;
+const CMD_MAPTI: u64 = 0x0a;
+const CMD_INV: u64 = 0x0c;
+const CMD_DISCARD: u64 = 0x0f;
+
+/// The size of a command.
+const CMD_SIZE: usize = 32;
+
+/// The number of the interrupt identifier bits, the LPIs are `8192..16384`.
+const LPI_ID_BITS: usize = 14;
+
+/// The number of the LPIs.
+const LPI_NUM: usize = (1 << LPI_ID_BITS) - LPI_START;
+
+/// The LPI configuration, the priority and the enable bit.
+const LPI_PRIORITY: u8 = 0xa0;
+const LPI_ENABLE: u8 = 1 << 0;
+
+/// The number of the event id bits of a device.
+const EVENT_ID_BITS: usize = 5;
+
+/// The maximum number of the redistributors with the LPIs, the pending
+/// table is 64K aligned, the cpus after them can't allocate the MSIs.
+const MAX_LPI_CPUS: usize = 8;
+
+#[repr(C, align(4096))]
+struct PropTable([u8; LPI_NUM]);
+
+#[repr(C, align(0x10000))]
+struct PendTable([u8; (1 << LPI_ID_BITS) / 8]);
+
+const EMPTY_PEND_TABLE: PendTable = PendTable([0; (1 << LPI_ID_BITS) / 8]);
+
+/// The LPI configuration table, the entry `n` is the LPI `8192 + n`.
+static mut PROP_TABLE: PropTable = PropTable([0; LPI_NUM]);
+
+/// The LPI pending tables of the redistributors.
+static mut PEND_TABLES: [PendTable; MAX_LPI_CPUS] = [EMPTY_PEND_TABLE; MAX_LPI_CPUS];
+
+struct Its {
+    /// The virtual address of the registers.
+    base: usize,
+    /// The virtual address of the command queue.
+    cmd_queue: usize,
+    /// The number of the device ids covered by the device table.
+    num_devices: usize,
+    /// The target address of the redistributors in the commands, indexed
+    /// by the collection id.
+    rdbases: [Option<u64>; MAX_LPI_CPUS],
+}
+
+/// The mapped device.
+struct Device {
+    /// The allocated event ids.
+    events: u64,
+}
+
+/// The mapped LPI.
+#[derive(Clone, Copy)]
+struct Mapping {
+    device_id: u32,
+    event_id: u32,
+    icid: usize,
+}
+
+struct ItsState {
+    /// The offset of the next command in the command queue.
+    cwriter: usize,
+    /// The allocated LPIs, the bit `n` is the LPI `8192 + n`.
+    lpis: [u64; LPI_NUM / 64],
+    devices: BTreeMap<u32, Device>,
+    mappings: BTreeMap<usize, Mapping>,
+}
+
+static ITS: LazyInit<Its> = LazyInit::new();
+
+static ITS_STATE: MutexNoIrq<ItsState> = MutexNoIrq::new(ItsState {
+    cwriter: 0,
+    lpis: [0; LPI_NUM / 64],
+    devices: BTreeMap::new(),
+    mappings: BTreeMap::new(),
+});
+
+/// Get the physical address of the kernel image or the direct mapped memory.
+#[inline]
+fn virt_to_phys(addr: usize) -> u64 {
+    (addr & !VIRT_ADDR_START) as u64
+}
+
+/// Allocate a zeroed page for the tables.
+#[inline]
+fn alloc_table() -> PhysPage {
+    let page = frame_alloc();
+    page.drop_clear();
+    page
+}
+
+/// Get the pointer of the configuration of the LPI.
+#[inline]
+fn lpi_config(lpi: usize) -> *mut u8 {
+    unsafe { (addr_of_mut!(PROP_TABLE) as *mut u8).add(lpi - LPI_START) }
+}
+
+impl Its {
+    /// Send the command and wait for it to be consumed.
+    fn send(&self, state: &mut ItsState, cmd: [u64; 4]) {
+        let slot = (self.cmd_queue + state.cwriter) as *mut u64;
+        for (index, dw) in cmd.into_iter().enumerate() {
+            unsafe { slot.add(index).write_volatile(dw) };
+        }
+        fence(Ordering::SeqCst);
+        state.cwriter = (state.cwriter + CMD_SIZE) % PAGE_SIZE;
+        write64(self.base + GITS_CWRITER, state.cwriter as u64);
+        while read64(self.base + GITS_CREADR) != state.cwriter as u64 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Wait for the effects of the commands on the collection.
+    fn sync(&self, state: &mut ItsState, icid: usize) {
+        if let Some(rdbase) = self.rdbases[icid] {
+            self.send(state, [CMD_SYNC, 0, rdbase << 16, 0]);
+        }
+    }
+
+    /// Reload the configuration of the LPI.
+    fn invalidate(&self, state: &mut ItsState, mapping: Mapping) {
+        let dw0 = CMD_INV | (mapping.device_id as u64) << 32;
+        self.send(state, [dw0, mapping.event_id as u64, 0, 0]);
+        self.sync(state, mapping.icid);
+    }
+}
+
+/// Initialize the ITS from the FDT and enable the LPIs of the redistributors.
+///
+/// It must be called after the GICv3 and the page allocator were initialized.
+pub(super) fn init(fdt: &Fdt) {
+    if !gicv3::is_present() {
+        return;
+    }
+    let Some(region) = fdt
+        .find_compatible(&[ITS_COMPATIBLE])
+        .and_then(|node| node.reg()?.next())
+    else {
+        return;
+    };
+    let base = region.starting_address as usize | VIRT_ADDR_START;
+    write(base + GITS_CTLR, 0);
+    while read(base + GITS_CTLR) & GITS_CTLR_QUIESCENT == 0 {
+        core::hint::spin_loop();
+    }
+
+    // Every table has a page, the device ids after the table are not supported.
+    let mut num_devices = 0;
+    for index in 0..GITS_BASER_NUM {
+        let baser = read64(base + GITS_BASER + index * 8);
+        let ty = (baser >> 56) & 0x7;
+        let entry_size = ((baser >> 48) & 0x1f) + 1;
+        if ty != GITS_BASER_TYPE_DEVICE && ty != GITS_BASER_TYPE_COLLECTION {
+            continue;
+        }
+        let table = alloc_table();
+        write64(
+            base + GITS_BASER + index * 8,
+            GITS_VALID
+                | ITS_TABLE_ATTRS
+                | (ty << 56)
+                | ((entry_size - 1) << 48)
+                | table.to_addr() as u64,
+        );
+        if ty == GITS_BASER_TYPE_DEVICE {
+            num_devices = PAGE_SIZE / entry_size as usize;
+        }
+    }
+    let cmd_queue = alloc_table();
+    write64(
+        base + GITS_CBASER,
+        GITS_VALID | ITS_TABLE_ATTRS | cmd_queue.to_addr() as u64,
+    );
+    write64(base + GITS_CWRITER, 0);
+
+    // The LPIs can't be configured after they were enabled.
+    for lpi in LPI_START..LPI_START + LPI_NUM {
+        unsafe { lpi_config(lpi).write_volatile(LPI_PRIORITY) };
+    }
+    let prop_table = virt_to_phys(unsafe { addr_of_mut!(PROP_TABLE) } as usize);
+    let pta = read64(base + GITS_TYPER) & GITS_TYPER_PTA != 0;
+    let mut rdbases = [None; MAX_LPI_CPUS];
+    for (icid, rd) in gicv3::redistributors().take(MAX_LPI_CPUS).enumerate() {
+        if read(rd + GICR_CTLR) & GICR_CTLR_ENABLE_LPIS != 0 {
+            log::warn!("the LPIs of the redistributor {} were enabled", icid);
+            continue;
+        }
+        let pend_table = unsafe { addr_of_mut!(PEND_TABLES[icid]) as usize };
+        write64(
+            rd + GICR_PROPBASER,
+            prop_table | GICR_TABLE_ATTRS | (LPI_ID_BITS - 1) as u64,
+        );
+        write64(
+            rd + GICR_PENDBASER,
+            virt_to_phys(pend_table) | GICR_TABLE_ATTRS,
+        );
+        write(rd + GICR_CTLR, read(rd + GICR_CTLR) | GICR_CTLR_ENABLE_LPIS);
+        rdbases[icid] = Some(match pta {
+            true => virt_to_phys(rd) >> 16,
+            false => (read64(rd + GICR_TYPER) >> 8) & 0xffff,
+        });
+    }
+    write(base + GITS_CTLR, GITS_CTLR_ENABLED);
+
+    let its = Its {
+        base,
+        cmd_queue: cmd_queue.to_addr() | VIRT_ADDR_START,
+        num_devices,
+        rdbases,
+    };
+    let mut state = ITS_STATE.lock();
+    for (icid, rdbase) in its.rdbases.iter().enumerate() {
+        if let Some(rdbase) = rdbase {
+            its.send(
+                &mut state,
+                [CMD_MAPC, 0, GITS_VALID | rdbase << 16 | icid as u64, 0],
+            );
+            its.sync(&mut state, icid);
+        }
+    }
+    drop(state);
+    log::info!(
+        "GICv3 ITS @ {:#x}: {} devices, {} LPIs",
+        region.starting_address as usize,
+        its.num_devices,
+        LPI_NUM
+    );
+    ITS.init_by(its);
+}
+
+/// Get the collection of the current cpu.
+#[inline]
+fn current_collection() -> Option<usize> {
+    let rd = gicv3::current_redistributor()?;
+    gicv3::redistributors()
+        .take(MAX_LPI_CPUS)
+        .position(|other| other == rd)
+}
+
+/// Allocate an LPI for the MSI of the device and deliver it to the current cpu.
+///
+/// The device table has a page, return `None` if the device id is out of it.
+pub(super) fn alloc(device_id: u32) -> Option<(usize, MsiMessage)> {
+    let its = ITS.try_get()?;
+    let icid = current_collection().filter(|icid| its.rdbases[*icid].is_some())?;
+    if device_id as usize >= its.num_devices {
+        return None;
+    }
+    let mut state = ITS_STATE.lock();
+    let index = (0..LPI_NUM).find(|&index| state.lpis[index / 64] & (1 << (index % 64)) == 0)?;
+    if !state.devices.contains_key(&device_id) {
+        // The interrupt translation table of the device.
+        let itt = alloc_table();
+        let dw0 = CMD_MAPD | (device_id as u64) << 32;
+        let dw2 = GITS_VALID | itt.to_addr() as u64;
+        its.send(&mut state, [dw0, (EVENT_ID_BITS - 1) as u64, dw2, 0]);
+    }
+    let device = state
+        .devices
+        .entry(device_id)
+        .or_insert(Device { events: 0 });
+    let event_id = (0..1 << EVENT_ID_BITS).find(|&event| device.events & (1 << event) == 0)?;
+    device.events |= 1 << event_id;
+    state.lpis[index / 64] |= 1 << (index % 64);
+
+    let lpi = LPI_START + index;
+    let mapping = Mapping {
+        device_id,
+        event_id,
+        icid,
+    };
+    unsafe { lpi_config(lpi).write_volatile(LPI_PRIORITY | LPI_ENABLE) };
+    let dw0 = CMD_MAPTI | (device_id as u64) << 32;
+    let dw1 = event_id as u64 | (lpi as u64) << 32;
+    its.send(&mut state, [dw0, dw1, icid as u64, 0]);
+    its.invalidate(&mut state, mapping);
+    state.mappings.insert(lpi, mapping);
+    let msg = MsiMessage {
+        addr: PhysAddr::new(virt_to_phys(its.base + GITS_TRANSLATER) as usize),
+        data: event_id,
+    };
+    Some((lpi, msg))
+}
+
+/// Unmap the LPI allocated by [alloc] and release it.
+pub(super) fn free(lpi: usize) {
+    let Some(its) = ITS.try_get() else {
+        return;
+    };
+    let mut state = ITS_STATE.lock();
+    let Some(mapping) = state.mappings.remove(&lpi) else {
+        return;
+    };
+    unsafe { lpi_config(lpi).write_volatile(LPI_PRIORITY) };
+    let dw0 = CMD_DISCARD | (mapping.device_id as u64) << 32;
+    its.send(&mut state, [dw0, mapping.event_id as u64, 0, 0]);
+    its.sync(&mut state, mapping.icid);
+    if let Some(device) = state.devices.get_mut(&mapping.device_id) {
+        device.events &= !(1 << mapping.event_id);
+    }
+    let index = lpi - LPI_START;
+    state.lpis[index / 64] &= !(1 << (index % 64));
+}
+
+/// Enable or disable the LPI allocated by [alloc].
+pub(super) fn set_enable(lpi: usize, enable: bool) {
+    let Some(its) = ITS.try_get() else {
+        return;
+    };
+    let mut state = ITS_STATE.lock();
+    let Some(mapping) = state.mappings.get(&lpi).copied() else {
+        return;
+    };
+    let config = match enable {
+        true => LPI_PRIORITY | LPI_ENABLE,
+        false => LPI_PRIORITY,
+    };
+    unsafe { lpi_config(lpi).write_volatile(config) };
+    its.invalidate(&mut state, mapping);
+}
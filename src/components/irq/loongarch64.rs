@@ -1,38 +1,119 @@
-use crate::components::irq::IRQ;
+mod eiointc;
+mod pch_msi;
+mod pch_pic;
+
+use fdt::Fdt;
+use loongArch64::register::crmd;
+
+use crate::components::arch::hart_id;
+use crate::components::common::DTB_PTR;
+use crate::components::irq::{IRQVector, MsiMessage, IRQ};
 
 /// Timer IRQ of loongarch64
 pub const TIMER_IRQ: usize = 11;
 
+/// The `HWI0` interrupt of the cores, the EIOINTC raises it.
+#[cfg(feature = "trap")]
+pub(crate) const EXT_IRQ: usize = 2;
+
+/// The uncached direct mapped window, the registers of the devices are
+/// mapped in it.
+const UNCACHED_START: usize = 0x8000_0000_0000_0000;
+
+/// Initialize the interrupt controllers of the LS7A bridge.
+///
+/// The nodes in the device tree are optional, the addresses of QEMU virt
+/// are used if they don't exist.
+pub(crate) fn init() {
+    let fdt = DTB_PTR
+        .try_get()
+        .and_then(|ptr| unsafe { Fdt::from_ptr(*ptr as *const u8).ok() });
+    eiointc::init();
+    pch_pic::init(fdt.as_ref());
+    pch_msi::init(fdt.as_ref());
+}
+
+/// Claim the pending irq of the current core.
+///
+/// Return `None` if there is no pending irq, the `HWI0` interrupt is spurious.
+#[inline]
+pub fn get_irq() -> Option<IRQVector> {
+    eiointc::claim().map(IRQVector)
+}
+
 /// Implement IRQ operations for the IRQ interface.
 impl IRQ {
     /// Enable irq for the given IRQ number.
+    ///
+    /// The irq is the vector of the EIOINTC, it's delivered to the current core.
     #[inline]
-    pub fn irq_enable(_irq_num: usize) {
-        log::warn!("irq not implemented in loongarch64 platform yet");
+    pub fn irq_enable(irq_num: usize) {
+        eiointc::enable(hart_id(), irq_num);
+        pch_pic::enable(irq_num);
     }
 
     /// Disable irq for the given IRQ number.
     #[inline]
-    pub fn irq_disable(_irq_num: usize) {
-        log::warn!("irq not implemented in loongarch64 platform yet");
+    pub fn irq_disable(irq_num: usize) {
+        pch_pic::disable(irq_num);
+        eiointc::disable(irq_num);
+    }
+
+    /// Allocate an MSI delivered to the current core and enable it.
+    ///
+    /// The `device_id` is only used by the ITS on aarch64.
+    /// Return `None` if the MSIs are not supported or all the vectors are used.
+    pub fn msi_alloc(_device_id: u32) -> Option<(IRQVector, MsiMessage)> {
+        let addr = pch_msi::doorbell()?;
+        let vector = pch_msi::alloc_vector()?;
+        eiointc::enable(hart_id(), vector);
+        let msg = MsiMessage {
+            addr,
+            data: vector as u32,
+        };
+        Some((IRQVector(vector), msg))
+    }
+
+    /// Disable the MSI and release it.
+    pub fn msi_free(irq: IRQVector) {
+        eiointc::disable(irq.0);
+        pch_msi::free_vector(irq.0);
     }
 
-    /// Enable interrupt
+    /// Enable interrupts.
     #[inline]
     pub fn int_enable() {
-        log::warn!("int_enable not implemented in loongarch64 platform yet");
+        crmd::set_ie(true);
     }
 
-    /// Disable interrupt
+    /// Disable interrupts.
     #[inline]
     pub fn int_disable() {
-        log::warn!("int_disable not implemented in loongarch64 platform yet");
+        crmd::set_ie(false);
     }
 
-    /// Check if the interrupt was enabled.
+    /// Check if the interrupts was enabled.
     #[inline]
     pub fn int_enabled() -> bool {
-        log::warn!("int_enabled not implemented in loongarch64 platform yet");
-        false
+        crmd::read().ie()
+    }
+}
+
+/// Implmente the irq vector methods
+impl IRQVector {
+    /// Get the irq number in this vector
+    ///
+    /// It's the vector of the EIOINTC, the PCH-PIC input `n` is the vector `n`.
+    #[inline]
+    pub fn irq_num(&self) -> usize {
+        self.0
+    }
+
+    /// Acknowledge the irq
+    ///
+    /// The vector was cleared when it was claimed from the EIOINTC, the
+    /// PCH-PIC input raising it is acknowledged.
+    pub fn ack(&self) {
+        pch_pic::ack(self.0);
     }
 }
@@ -0,0 +1,110 @@
+//! The Extended I/O Interrupt Controller (EIOINTC) driver.
+//!
+//! The EIOINTC has 256 vectors, the PCH-PIC and the PCH-MSI raise the
+//! vectors and the EIOINTC routes them to the cores. It's configured by the
+//! IOCSR, all the vectors are mapped to the `HWI0` of the cores and every
+//! vector is routed to the core enabled it.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use loongArch64::iocsr::{iocsr_read_d, iocsr_read_w, iocsr_write_b, iocsr_write_d, iocsr_write_w};
+
+use crate::utils::MutexNoIrq;
+
+/// The number of the vectors.
+pub(super) const VEC_COUNT: usize = 256;
+
+/// The extended I/O interrupt enable bit of the `MISC_FUNC`.
+const MISC_FUNC: usize = 0x420;
+const MISC_FUNC_EXT_IOI_EN: u64 = 1 << 48;
+
+/// The IOCSR offsets of the EIOINTC registers.
+const NODEMAP: usize = 0x14a0;
+const IPMAP: usize = 0x14c0;
+const ENABLE: usize = 0x1600;
+const BOUNCE: usize = 0x1680;
+const ISR: usize = 0x1800;
+const ROUTE: usize = 0x1c00;
+
+/// Map the vectors to the `HWI0` of the cores.
+const IPMAP_HWI0: u32 = 0x0101_0101;
+
+/// The number of the cores in a node.
+const CORES_PER_NODE: usize = 4;
+
+static PRESENT: AtomicBool = AtomicBool::new(false);
+
+/// Lock the read-modify-write of the enable registers.
+static ENABLE_LOCK: MutexNoIrq<()> = MutexNoIrq::new(());
+
+/// Initialize the EIOINTC, all the vectors are disabled.
+pub(super) fn init() {
+    iocsr_write_d(MISC_FUNC, iocsr_read_d(MISC_FUNC) | MISC_FUNC_EXT_IOI_EN);
+    for index in 0..VEC_COUNT / 32 {
+        iocsr_write_w(
+            NODEMAP + index * 4,
+            ((1 << (index * 2 + 1)) << 16) | (1 << (index * 2)),
+        );
+    }
+    iocsr_write_w(IPMAP, IPMAP_HWI0);
+    iocsr_write_w(IPMAP + 4, IPMAP_HWI0);
+    for vector in (0..VEC_COUNT).step_by(32) {
+        iocsr_write_w(ENABLE + vector / 8, 0);
+        iocsr_write_w(BOUNCE + vector / 8, 0);
+    }
+    PRESENT.store(true, Ordering::Release);
+    log::info!("EIOINTC: {} vectors", VEC_COUNT);
+}
+
+/// Set the enable bit of the vector.
+fn set_enable(vector: usize, enable: bool) {
+    let offset = ENABLE + vector / 32 * 4;
+    let bit = 1 << (vector % 32);
+    let _guard = ENABLE_LOCK.lock();
+    match enable {
+        true => iocsr_write_w(offset, iocsr_read_w(offset) | bit),
+        false => iocsr_write_w(offset, iocsr_read_w(offset) & !bit),
+    }
+}
+
+/// Route the vector to the core and enable it.
+pub(super) fn enable(hart_id: usize, vector: usize) {
+    if !is_present() || vector >= VEC_COUNT {
+        return;
+    }
+    let node = hart_id / CORES_PER_NODE;
+    let core = hart_id % CORES_PER_NODE;
+    iocsr_write_b(ROUTE + vector, ((node << 4) | (1 << core)) as u8);
+    set_enable(vector, true);
+}
+
+/// Disable the vector on all the cores.
+pub(super) fn disable(vector: usize) {
+    if is_present() && vector < VEC_COUNT {
+        set_enable(vector, false);
+    }
+}
+
+/// Claim the lowest pending vector of the current core.
+///
+/// The status bit is cleared, return `None` if there is no pending vector.
+pub(super) fn claim() -> Option<usize> {
+    if !is_present() {
+        return None;
+    }
+    (0..VEC_COUNT / 64).find_map(|index| {
+        let pending = iocsr_read_d(ISR + index * 8);
+        if pending == 0 {
+            return None;
+        }
+        let bit = pending.trailing_zeros() as usize;
+        iocsr_write_d(ISR + index * 8, 1 << bit);
+        Some(index * 64 + bit)
+    })
+}
+
+/// Check if the EIOINTC was initialized.
+#[inline]
+pub(super) fn is_present() -> bool {
+    PRESENT.load(Ordering::Acquire)
+}
@@ -0,0 +1,82 @@
+//! The PCH-MSI driver of the LS7A bridge.
+//!
+//! The device raises the vector of the EIOINTC by writing the vector to the
+//! doorbell of the PCH-MSI, the vectors after the PCH-PIC inputs are used.
+
+use core::ops::Range;
+
+use fdt::Fdt;
+
+use crate::addr::PhysAddr;
+use crate::utils::{LazyInit, MutexNoIrq};
+
+use super::eiointc::VEC_COUNT;
+use super::pch_pic;
+
+/// The compatible string of the PCH-MSI node.
+const PCH_MSI_COMPATIBLE: &str = "loongson,pch-msi-1.0";
+
+/// The physical address of the doorbell on QEMU virt.
+const DEFAULT_DOORBELL: usize = 0x2ff0_0000;
+
+struct PchMsi {
+    /// The physical address of the doorbell.
+    doorbell: usize,
+    /// The vectors of the MSIs.
+    vectors: Range<usize>,
+}
+
+static PCH_MSI: LazyInit<PchMsi> = LazyInit::new();
+
+/// The allocated vectors of the MSIs.
+static MSI_VECS: MutexNoIrq<[u64; VEC_COUNT / 64]> = MutexNoIrq::new([0; VEC_COUNT / 64]);
+
+/// Initialize the PCH-MSI, the node in the FDT is optional.
+///
+/// It must be initialized after the PCH-PIC.
+pub(super) fn init(fdt: Option<&Fdt>) {
+    let node = fdt.and_then(|fdt| fdt.find_compatible(&[PCH_MSI_COMPATIBLE]));
+    let doorbell = node
+        .and_then(|node| node.reg()?.next())
+        .map_or(DEFAULT_DOORBELL, |region| region.starting_address as usize);
+    let start = node
+        .and_then(|node| node.property("loongson,msi-base-vec"))
+        .and_then(|prop| prop.as_usize())
+        .unwrap_or(pch_pic::vectors().end)
+        .min(VEC_COUNT);
+    let num = node
+        .and_then(|node| node.property("loongson,msi-num-vecs"))
+        .and_then(|prop| prop.as_usize())
+        .unwrap_or(VEC_COUNT - start);
+    let msi = PchMsi {
+        doorbell,
+        vectors: start..(start + num).min(VEC_COUNT),
+    };
+    log::info!("PCH-MSI @ {:#x}: vectors {:?}", msi.doorbell, msi.vectors);
+    PCH_MSI.init_by(msi);
+}
+
+/// Get the physical address of the doorbell.
+#[inline]
+pub(super) fn doorbell() -> Option<PhysAddr> {
+    PCH_MSI.try_get().map(|msi| PhysAddr::new(msi.doorbell))
+}
+
+/// Allocate a free vector for the MSI.
+pub(super) fn alloc_vector() -> Option<usize> {
+    let msi = PCH_MSI.try_get()?;
+    let mut vecs = MSI_VECS.lock();
+    let vector = msi
+        .vectors
+        .clone()
+        .find(|&vector| vecs[vector / 64] & (1 << (vector % 64)) == 0)?;
+    vecs[vector / 64] |= 1 << (vector % 64);
+    Some(vector)
+}
+
+/// Release the vector allocated by [alloc_vector].
+pub(super) fn free_vector(vector: usize) {
+    if vector < VEC_COUNT {
+        MSI_VECS.lock()[vector / 64] &= !(1 << (vector % 64));
+    }
+}
@@ -0,0 +1,167 @@
+//! The PCH-PIC driver of the LS7A bridge.
+//!
+//! The PCH-PIC collects the wired interrupts of the devices, the input `n`
+//! raises the vector `base + n` of the EIOINTC. The inputs are configured as
+//! level high, like the devices of QEMU virt.
+
+use fdt::Fdt;
+
+use crate::utils::{LazyInit, MutexNoIrq};
+
+use super::UNCACHED_START;
+
+/// The compatible string of the PCH-PIC node.
+const PCH_PIC_COMPATIBLE: &str = "loongson,pch-pic-1.0";
+
+/// The physical address of the PCH-PIC on QEMU virt.
+const DEFAULT_BASE: usize = 0x1000_0000;
+
+/// The maximum number of the inputs.
+const MAX_INPUTS: usize = 64;
+
+/// The offsets of the PCH-PIC registers.
+const INT_ID_HI: usize = 0x004;
+const INT_MASK: usize = 0x020;
+const HTMSI_EN: usize = 0x040;
+const INT_EDGE: usize = 0x060;
+const INT_CLEAR: usize = 0x080;
+const ROUTE_ENTRY: usize = 0x100;
+const HTMSI_VEC: usize = 0x200;
+const INT_POL: usize = 0x3e0;
+
+struct PchPic {
+    /// The virtual address of the registers.
+    base: usize,
+    /// The vector of the input 0.
+    vec_base: usize,
+    /// The number of the inputs.
+    num_inputs: usize,
+}
+
+static PCH_PIC: LazyInit<PchPic> = LazyInit::new();
+
+/// Lock the read-modify-write of the mask registers.
+static MASK_LOCK: MutexNoIrq<()> = MutexNoIrq::new(());
+
+impl PchPic {
+    #[inline]
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    #[inline]
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(value) }
+    }
+
+    #[inline]
+    fn write_byte(&self, offset: usize, value: u8) {
+        unsafe { ((self.base + offset) as *mut u8).write_volatile(value) }
+    }
+
+    /// Get the input of the vector.
+    #[inline]
+    fn input(&self, vector: usize) -> Option<usize> {
+        vector
+            .checked_sub(self.vec_base)
+            .filter(|input| *input < self.num_inputs)
+    }
+
+    /// Set the mask bit of the input.
+    fn set_mask(&self, input: usize, mask: bool) {
+        let offset = INT_MASK + input / 32 * 4;
+        let bit = 1 << (input % 32);
+        let _guard = MASK_LOCK.lock();
+        match mask {
+            true => self.write(offset, self.read(offset) | bit),
+            false => self.write(offset, self.read(offset) & !bit),
+        }
+    }
+}
+
+/// Initialize the PCH-PIC, the node in the FDT is optional.
+///
+/// All the inputs are masked and forwarded to the EIOINTC.
+pub(super) fn init(fdt: Option<&Fdt>) {
+    let node = fdt.and_then(|fdt| fdt.find_compatible(&[PCH_PIC_COMPATIBLE]));
+    let addr = node
+        .and_then(|node| node.reg()?.next())
+        .map_or(DEFAULT_BASE, |region| region.starting_address as usize);
+    let vec_base = node
+        .and_then(|node| node.property("loongson,pic-base-vec"))
+        .and_then(|prop| prop.as_usize())
+        .unwrap_or(0);
+    let mut pic = PchPic {
+        base: addr | UNCACHED_START,
+        vec_base,
+        num_inputs: 0,
+    };
+    // The number of the inputs minus 1 is in the bits 16..24.
+    pic.num_inputs = (((pic.read(INT_ID_HI) >> 16) & 0xff) as usize + 1).min(MAX_INPUTS);
+    for input in 0..pic.num_inputs {
+        pic.write_byte(HTMSI_VEC + input, (pic.vec_base + input) as u8);
+        pic.write_byte(ROUTE_ENTRY + input, 1);
+    }
+    for input in (0..pic.num_inputs).step_by(32) {
+        pic.write(INT_MASK + input / 8, u32::MAX);
+        pic.write(HTMSI_EN + input / 8, u32::MAX);
+        pic.write(INT_EDGE + input / 8, 0);
+        pic.write(INT_POL + input / 8, 0);
+    }
+    log::info!(
+        "PCH-PIC @ {:#x}: {} inputs, vector base {}",
+        addr,
+        pic.num_inputs,
+        pic.vec_base
+    );
+    PCH_PIC.init_by(pic);
+}
+
+/// Unmask the input raising the vector, do nothing if it's not an input.
+pub(super) fn enable(vector: usize) {
+    let Some(pic) = PCH_PIC.try_get() else {
+        return;
+    };
+    if let Some(input) = pic.input(vector) {
+        pic.set_mask(input, false);
+    }
+}
+
+/// Mask the input raising the vector, do nothing if it's not an input.
+pub(super) fn disable(vector: usize) {
+    let Some(pic) = PCH_PIC.try_get() else {
+        return;
+    };
+    if let Some(input) = pic.input(vector) {
+        pic.set_mask(input, true);
+    }
+}
+
+/// Acknowledge the input raising the vector.
+///
+/// The edge input is cleared. The level input is only forwarded when it
+/// changes, it's masked and unmasked to forward it again if it's still
+/// asserted.
+pub(super) fn ack(vector: usize) {
+    let Some(pic) = PCH_PIC.try_get() else {
+        return;
+    };
+    let Some(input) = pic.input(vector) else {
+        return;
+    };
+    let bit = 1 << (input % 32);
+    if pic.read(INT_EDGE + input / 32 * 4) & bit != 0 {
+        pic.write(INT_CLEAR + input / 32 * 4, bit);
+    } else if pic.read(INT_MASK + input / 32 * 4) & bit == 0 {
+        pic.set_mask(input, true);
+        pic.set_mask(input, false);
+    }
+}
+
+/// Get the vectors raised by the inputs, the MSIs use the other vectors.
+#[inline]
+pub(super) fn vectors() -> core::ops::Range<usize> {
+    PCH_PIC
+        .try_get()
+        .map_or(0..0, |pic| pic.vec_base..pic.vec_base + pic.num_inputs)
+}
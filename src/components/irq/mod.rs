@@ -25,11 +25,22 @@
 //! let enabled = IRQ::int_enabled();
 //! ```
 
+use crate::addr::PhysAddr;
+
 super::define_arch_mods!();
 
 pub struct IRQ;
 
 impl IRQ {}
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct IRQVector(pub(crate) usize);
+
+/// The message of the MSI, the device writes `data` to `addr` to raise the irq.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiMessage {
+    /// The physical address of the doorbell.
+    pub addr: PhysAddr,
+    /// The data written to the doorbell.
+    pub data: u32,
+}
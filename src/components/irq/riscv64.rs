@@ -1,19 +1,152 @@
+mod aplic;
+mod imsic;
+mod plic;
+
+use alloc::vec::Vec;
+use fdt::node::FdtNode;
+use fdt::Fdt;
 use riscv::register::sstatus::{self, clear_sie, set_sie};
 
-use crate::components::irq::{IRQVector, IRQ};
+use crate::components::arch::hart_id;
+use crate::components::common::DTB_BIN;
+use crate::components::irq::{IRQVector, MsiMessage, IRQ};
+
+/// The supervisor external interrupt of the harts in `interrupts-extended`.
+const IRQ_S_EXT: usize = 9;
+
+/// Map the hart ids to the entries of the supervisor external interrupt in
+/// the `interrupts-extended` of the node, indexed by the hart id.
+///
+/// Every entry is the phandle of the hart's interrupt controller and the
+/// interrupt of the hart, the value is the index of the entry. It's the
+/// context of the PLIC and the hart index of the IMSIC.
+fn parse_s_ext_harts(fdt: &Fdt, node: FdtNode) -> Vec<Option<usize>> {
+    // The phandles of the interrupt controllers of the harts.
+    let intcs: Vec<(usize, usize)> = fdt
+        .find_node("/cpus")
+        .into_iter()
+        .flat_map(|cpus| cpus.children())
+        .filter_map(|cpu| {
+            let hart_id = cpu.property("reg")?.as_usize()?;
+            let intc = cpu
+                .children()
+                .find(|node| node.name.starts_with("interrupt-controller"))?;
+            Some((intc.property("phandle")?.as_usize()?, hart_id))
+        })
+        .collect();
+    let Some(prop) = node.property("interrupts-extended") else {
+        return Vec::new();
+    };
+    let cells = prop
+        .value
+        .chunks_exact(4)
+        .map(|cell| u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]) as usize)
+        .collect::<Vec<_>>();
+    let mut harts = Vec::new();
+    for (index, entry) in cells.chunks_exact(2).enumerate() {
+        let (phandle, irq) = (entry[0], entry[1]);
+        let hart_id = intcs.iter().find(|(p, _)| *p == phandle).map(|(_, id)| *id);
+        if let (IRQ_S_EXT, Some(hart_id)) = (irq, hart_id) {
+            if harts.len() <= hart_id {
+                harts.resize(hart_id + 1, None);
+            }
+            harts[hart_id] = Some(index);
+        }
+    }
+    harts
+}
+
+/// Initialize the interrupt controller found in the device tree.
+///
+/// The AIA (APLIC + IMSIC) is preferred, then the PLIC.
+pub(crate) fn init() {
+    let Ok(fdt) = Fdt::new(&DTB_BIN) else {
+        log::warn!("no device tree, the external interrupts are not supported");
+        return;
+    };
+    if let Some(phandle) = imsic::init(&fdt) {
+        if !aplic::init(&fdt, phandle) {
+            log::warn!("APLIC is not found, only the MSIs are supported");
+        }
+        imsic::init_percpu();
+    } else if !plic::init(&fdt) {
+        log::warn!("no interrupt controller is found in the device tree");
+    }
+}
+
+/// Initialize the interrupt controller on the secondary hart.
+pub(crate) fn init_percpu() {
+    imsic::init_percpu();
+}
+
+/// Claim the pending irq of the current hart.
+///
+/// Return `None` if there is no pending irq, the `SupervisorExternal`
+/// interrupt is spurious if the interrupt controller exists.
+#[inline]
+pub fn get_irq() -> Option<IRQVector> {
+    match imsic::is_present() {
+        true => imsic::claim().map(IRQVector),
+        false => plic::claim(hart_id()).map(IRQVector),
+    }
+}
+
+/// Check if the irqs are delivered by the PLIC or the IMSIC.
+#[cfg(feature = "trap")]
+#[inline]
+pub(crate) fn has_irq_controller() -> bool {
+    imsic::is_present() || plic::is_present()
+}
 
 /// Implement IRQ operations for the IRQ interface.
 impl IRQ {
     /// Enable irq for the given IRQ number.
+    ///
+    /// The irq is delivered to the current hart.
     #[inline]
-    pub fn irq_enable(_irq_num: usize) {
-        log::warn!("irq not implemented in riscv platform yet");
+    pub fn irq_enable(irq_num: usize) {
+        if !imsic::is_present() {
+            return plic::enable(hart_id(), irq_num);
+        }
+        imsic::enable(irq_num);
+        if let Some(hart_index) = imsic::hart_index(hart_id()) {
+            aplic::enable(irq_num, hart_index);
+        }
     }
 
     /// Disable irq for the given IRQ number.
+    ///
+    /// The MSI is only disabled on the current hart with the AIA.
     #[inline]
-    pub fn irq_disable(_irq_num: usize) {
-        log::warn!("irq not implemented in riscv platform yet");
+    pub fn irq_disable(irq_num: usize) {
+        if !imsic::is_present() {
+            return plic::disable(irq_num);
+        }
+        match irq_num <= aplic::num_sources() {
+            true => aplic::disable(irq_num),
+            false => imsic::disable(irq_num),
+        }
+    }
+
+    /// Allocate an MSI delivered to the current hart and enable it.
+    ///
+    /// The `device_id` is only used by the ITS on aarch64.
+    /// Return `None` if the MSIs are not supported or all the identities are used.
+    pub fn msi_alloc(_device_id: u32) -> Option<(IRQVector, MsiMessage)> {
+        let addr = imsic::file_addr(hart_id())?;
+        let id = imsic::alloc_id(aplic::num_sources() + 1)?;
+        imsic::enable(id);
+        let msg = MsiMessage {
+            addr,
+            data: id as u32,
+        };
+        Some((IRQVector(id), msg))
+    }
+
+    /// Disable the MSI on the current hart and release it.
+    pub fn msi_free(irq: IRQVector) {
+        imsic::disable(irq.0);
+        imsic::free_id(irq.0);
     }
 
     /// Enable interrupts.
@@ -38,14 +171,21 @@ impl IRQ {
 /// Implmente the irq vector methods
 impl IRQVector {
     /// Get the irq number in this vector
+    ///
+    /// It's the interrupt identity with the AIA, the wired source `n` is the identity `n`.
     #[inline]
     pub fn irq_num(&self) -> usize {
-        log::warn!("ack not implemented in riscv platform yet");
         self.0
     }
 
     /// Acknowledge the irq
+    ///
+    /// Complete the irq on the current hart, it must be the hart claimed it.
+    /// The identity was cleared when it was claimed from the IMSIC.
     pub fn ack(&self) {
-        log::warn!("ack not implemented in riscv platform yet");
+        match imsic::is_present() {
+            true => aplic::resample(self.0),
+            false => plic::complete(hart_id(), self.0),
+        }
     }
 }
@@ -0,0 +1,142 @@
+//! The Advanced Platform-Level Interrupt Controller (APLIC) driver of the AIA.
+//!
+//! The supervisor domain of the APLIC forwards the wired interrupts to the
+//! IMSIC as the MSIs, the source `n` is forwarded as the identity `n` of the
+//! target hart. The sources are configured as level high, like the devices
+//! of QEMU virt.
+
+use fdt::Fdt;
+
+use crate::components::consts::VIRT_ADDR_START;
+use crate::utils::LazyInit;
+
+/// The compatible string of the APLIC node.
+const APLIC_COMPATIBLE: &str = "riscv,aplic";
+
+/// The maximum number of the sources, source 0 is reserved.
+const MAX_SOURCES: usize = 1024;
+
+/// The offsets of the APLIC registers.
+const DOMAINCFG: usize = 0x0000;
+const SOURCECFG_BASE: usize = 0x0004;
+const CLRIP_BASE: usize = 0x1d00;
+const SETIENUM: usize = 0x1edc;
+const CLRIENUM: usize = 0x1fdc;
+const SETIPNUM_LE: usize = 0x2000;
+const TARGET_BASE: usize = 0x3004;
+
+/// The bits of the `domaincfg`.
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM_MSI: u32 = 1 << 2;
+
+/// The source modes of the `sourcecfg`.
+const SOURCECFG_INACTIVE: u32 = 0;
+const SOURCECFG_LEVEL_HIGH: u32 = 6;
+
+/// The shift of the hart index in the `target`.
+const TARGET_HART_SHIFT: usize = 18;
+
+struct Aplic {
+    /// The virtual address of the registers.
+    base: usize,
+    /// The number of the sources.
+    num_sources: usize,
+}
+
+static APLIC: LazyInit<Aplic> = LazyInit::new();
+
+impl Aplic {
+    #[inline]
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    #[inline]
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(value) }
+    }
+}
+
+/// Initialize the supervisor APLIC which forwards the MSIs to the IMSIC of
+/// `msi_parent`, return false if it doesn't exist.
+///
+/// The APLIC delivering the interrupts directly to the harts isn't supported.
+pub(super) fn init(fdt: &Fdt, msi_parent: usize) -> bool {
+    let node = fdt.all_nodes().find(|node| {
+        node.compatible()
+            .is_some_and(|compat| compat.all().any(|c| c == APLIC_COMPATIBLE))
+            && node
+                .property("msi-parent")
+                .and_then(|prop| prop.as_usize())
+                .is_some_and(|phandle| phandle == msi_parent)
+    });
+    let Some(region) = node.and_then(|node| node.reg()?.next()) else {
+        return false;
+    };
+    let num_sources = node
+        .and_then(|node| node.property("riscv,num-sources"))
+        .and_then(|prop| prop.as_usize())
+        .map_or(MAX_SOURCES - 1, |num| num.min(MAX_SOURCES - 1));
+    let aplic = Aplic {
+        base: region.starting_address as usize | VIRT_ADDR_START,
+        num_sources,
+    };
+    aplic.write(DOMAINCFG, 0);
+    for source in 1..=aplic.num_sources {
+        aplic.write(SOURCECFG_BASE + (source - 1) * 4, SOURCECFG_INACTIVE);
+    }
+    aplic.write(DOMAINCFG, DOMAINCFG_IE | DOMAINCFG_DM_MSI);
+    log::info!(
+        "APLIC @ {:#x}: {} sources",
+        region.starting_address as usize,
+        aplic.num_sources
+    );
+    APLIC.init_by(aplic);
+    true
+}
+
+/// Get the number of the sources, the identities above it are for the MSIs.
+#[inline]
+pub(super) fn num_sources() -> usize {
+    APLIC.try_get().map_or(0, |aplic| aplic.num_sources)
+}
+
+/// Check if the irq is a source of the APLIC.
+#[inline]
+fn is_source(source: usize) -> bool {
+    source != 0 && source <= num_sources()
+}
+
+/// Enable the source and forward it to the hart index.
+pub(super) fn enable(source: usize, hart_index: usize) {
+    let Some(aplic) = APLIC.try_get().filter(|_| is_source(source)) else {
+        return;
+    };
+    aplic.write(SOURCECFG_BASE + (source - 1) * 4, SOURCECFG_LEVEL_HIGH);
+    aplic.write(
+        TARGET_BASE + (source - 1) * 4,
+        ((hart_index << TARGET_HART_SHIFT) | source) as u32,
+    );
+    aplic.write(SETIENUM, source as u32);
+}
+
+/// Disable the source on all the harts.
+pub(super) fn disable(source: usize) {
+    if let Some(aplic) = APLIC.try_get().filter(|_| is_source(source)) {
+        aplic.write(CLRIENUM, source as u32);
+    }
+}
+
+/// Forward the level source again if it's still asserted.
+///
+/// The pending bit is cleared when the MSI was sent, the source isn't
+/// sampled again in the MSI delivery mode.
+pub(super) fn resample(source: usize) {
+    let Some(aplic) = APLIC.try_get().filter(|_| is_source(source)) else {
+        return;
+    };
+    // Reading `in_clrip` returns the rectified input values.
+    if aplic.read(CLRIP_BASE + source / 32 * 4) & (1 << (source % 32)) != 0 {
+        aplic.write(SETIPNUM_LE, source as u32);
+    }
+}
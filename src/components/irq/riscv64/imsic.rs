@@ -0,0 +1,204 @@
+//! The Incoming MSI Controller (IMSIC) driver of the AIA.
+//!
+//! Every hart has a supervisor interrupt file, the device raises the
+//! interrupt identity by writing it to the file of the target hart. The
+//! files are configured by the indirect csrs of the hart, so every hart
+//! initializes its own file and the identities are enabled on the current
+//! hart.
+
+use alloc::vec::Vec;
+use core::arch::asm;
+
+use fdt::Fdt;
+use riscv::register::sstatus::{self, clear_sie, set_sie};
+
+use crate::addr::PhysAddr;
+use crate::utils::{LazyInit, MutexNoIrq};
+
+use super::parse_s_ext_harts;
+
+/// The compatible strings of the IMSIC node.
+const IMSIC_COMPATIBLE: &[&str] = &["riscv,imsics", "qemu,imsics"];
+
+/// The maximum number of the identities, identity 0 is reserved.
+const MAX_IDS: usize = 2048;
+
+/// The size of the interrupt file.
+const FILE_SIZE: usize = 0x1000;
+
+/// The indirect registers selected by `siselect`.
+const EIDELIVERY: usize = 0x70;
+const EITHRESHOLD: usize = 0x72;
+const EIE0: usize = 0xc0;
+
+struct Imsic {
+    /// The physical address of the interrupt file of hart index 0.
+    base: usize,
+    /// The distance between the interrupt files of the harts.
+    stride: usize,
+    /// The number of the identities.
+    num_ids: usize,
+    /// The hart index of the harts, indexed by the hart id.
+    harts: Vec<Option<usize>>,
+}
+
+static IMSIC: LazyInit<Imsic> = LazyInit::new();
+
+/// The allocated identities of the MSIs.
+static MSI_IDS: MutexNoIrq<[u64; MAX_IDS / 64]> = MutexNoIrq::new([0; MAX_IDS / 64]);
+
+/// Run `f` with the interrupts disabled, `siselect` is shared with the handlers.
+#[inline]
+fn without_irq<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = sstatus::read().sie();
+    unsafe { clear_sie() };
+    let ret = f();
+    if enabled {
+        unsafe { set_sie() };
+    }
+    ret
+}
+
+/// Write the indirect register of the supervisor interrupt file.
+#[inline]
+fn write_indirect(reg: usize, value: usize) {
+    without_irq(|| unsafe {
+        asm!("csrw 0x150, {0}", "csrw 0x151, {1}", in(reg) reg, in(reg) value);
+    })
+}
+
+/// Set or clear the bits of the indirect register.
+#[inline]
+fn update_indirect(reg: usize, bits: usize, set: bool) {
+    without_irq(|| unsafe {
+        asm!("csrw 0x150, {0}", in(reg) reg);
+        match set {
+            true => asm!("csrs 0x151, {0}", in(reg) bits),
+            false => asm!("csrc 0x151, {0}", in(reg) bits),
+        }
+    })
+}
+
+/// Initialize the supervisor IMSIC from the FDT, return its phandle or 0
+/// if it has no phandle, `None` if it doesn't exist.
+///
+/// The machine level IMSIC has the same compatible strings, it's skipped
+/// by the interrupt of the harts.
+pub(super) fn init(fdt: &Fdt) -> Option<usize> {
+    let node = fdt.all_nodes().find(|node| {
+        node.compatible()
+            .is_some_and(|compat| compat.all().any(|c| IMSIC_COMPATIBLE.contains(&c)))
+            && parse_s_ext_harts(fdt, *node).iter().any(Option::is_some)
+    })?;
+    let region = node.reg()?.next()?;
+    let guest_bits = node
+        .property("riscv,guest-index-bits")
+        .and_then(|prop| prop.as_usize())
+        .unwrap_or(0);
+    let num_ids = node
+        .property("riscv,num-ids")
+        .and_then(|prop| prop.as_usize())
+        .map_or(MAX_IDS - 1, |num| num.min(MAX_IDS - 1));
+    let imsic = Imsic {
+        base: region.starting_address as usize,
+        stride: FILE_SIZE << guest_bits,
+        num_ids,
+        harts: parse_s_ext_harts(fdt, node),
+    };
+    log::info!(
+        "IMSIC @ {:#x}: {} identities, {} harts",
+        imsic.base,
+        imsic.num_ids,
+        imsic.harts.iter().flatten().count()
+    );
+    IMSIC.init_by(imsic);
+    Some(
+        node.property("phandle")
+            .and_then(|prop| prop.as_usize())
+            .unwrap_or(0),
+    )
+}
+
+/// Initialize the interrupt file of the current hart.
+///
+/// The interrupts are delivered from the file, all the identities are
+/// disabled and the threshold is cleared.
+pub(super) fn init_percpu() {
+    let Some(imsic) = IMSIC.try_get() else {
+        return;
+    };
+    for id in (0..=imsic.num_ids).step_by(64) {
+        write_indirect(EIE0 + id / 64 * 2, 0);
+    }
+    write_indirect(EITHRESHOLD, 0);
+    write_indirect(EIDELIVERY, 1);
+}
+
+/// Check if the identity is valid.
+#[inline]
+fn is_valid(id: usize) -> bool {
+    IMSIC
+        .try_get()
+        .is_some_and(|imsic| id != 0 && id <= imsic.num_ids)
+}
+
+/// Enable the identity on the current hart.
+pub(super) fn enable(id: usize) {
+    if is_valid(id) {
+        update_indirect(EIE0 + id / 64 * 2, 1 << (id % 64), true);
+    }
+}
+
+/// Disable the identity on the current hart.
+pub(super) fn disable(id: usize) {
+    if is_valid(id) {
+        update_indirect(EIE0 + id / 64 * 2, 1 << (id % 64), false);
+    }
+}
+
+/// Claim the highest priority pending identity of the current hart.
+///
+/// Return `None` if there is no pending identity.
+pub(super) fn claim() -> Option<usize> {
+    IMSIC.try_get()?;
+    let topei: usize;
+    unsafe { asm!("csrrw {0}, 0x15c, zero", out(reg) topei) };
+    match (topei >> 16) & (MAX_IDS - 1) {
+        0 => None,
+        id => Some(id),
+    }
+}
+
+/// Get the hart index of the hart, it's the target of the APLIC.
+pub(super) fn hart_index(hart_id: usize) -> Option<usize> {
+    IMSIC.try_get()?.harts.get(hart_id).copied().flatten()
+}
+
+/// Get the physical address of the interrupt file of the hart.
+pub(super) fn file_addr(hart_id: usize) -> Option<PhysAddr> {
+    let imsic = IMSIC.try_get()?;
+    let index = hart_index(hart_id)?;
+    Some(PhysAddr::new(imsic.base + index * imsic.stride))
+}
+
+/// Allocate a free identity in `start..=num_ids` for the MSI.
+pub(super) fn alloc_id(start: usize) -> Option<usize> {
+    let imsic = IMSIC.try_get()?;
+    let mut ids = MSI_IDS.lock();
+    let id = (start.max(1)..=imsic.num_ids).find(|&id| ids[id / 64] & (1 << (id % 64)) == 0)?;
+    ids[id / 64] |= 1 << (id % 64);
+    Some(id)
+}
+
+/// Release the identity allocated by [alloc_id].
+pub(super) fn free_id(id: usize) {
+    if id < MAX_IDS {
+        MSI_IDS.lock()[id / 64] &= !(1 << (id % 64));
+    }
+}
+
+/// Check if the IMSIC was initialized.
+#[inline]
+pub(super) fn is_present() -> bool {
+    IMSIC.is_init()
+}
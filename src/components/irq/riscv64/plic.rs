@@ -0,0 +1,177 @@
+//! The Platform-Level Interrupt Controller (PLIC) driver.
+//!
+//! Every hart has a supervisor context in the PLIC, the contexts are found
+//! by the `interrupts-extended` property of the PLIC node in the FDT. The
+//! threshold of the contexts are cleared, so the irq with any nonzero
+//! priority is delivered to the enabled contexts.
+
+use alloc::vec::Vec;
+use fdt::Fdt;
+
+use crate::components::consts::VIRT_ADDR_START;
+use crate::utils::{LazyInit, MutexNoIrq};
+
+use super::parse_s_ext_harts;
+
+/// The compatible strings of the PLIC node.
+const PLIC_COMPATIBLE: &[&str] = &["riscv,plic0", "sifive,plic-1.0.0"];
+
+/// The maximum number of the interrupt sources, source 0 is reserved.
+const MAX_SOURCES: usize = 1024;
+
+/// The offsets of the PLIC registers.
+const PRIORITY_BASE: usize = 0x0;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const CONTEXT_THRESHOLD: usize = 0x0;
+const CONTEXT_CLAIM: usize = 0x4;
+
+/// The default priority of the enabled irqs.
+const DEFAULT_PRIORITY: u32 = 1;
+
+struct Plic {
+    /// The virtual address of the registers.
+    base: usize,
+    /// The number of the interrupt sources.
+    ndev: usize,
+    /// The supervisor context of the harts, indexed by the hart id.
+    contexts: Vec<Option<usize>>,
+}
+
+static PLIC: LazyInit<Plic> = LazyInit::new();
+
+/// Lock the read-modify-write of the enable registers.
+static ENABLE_LOCK: MutexNoIrq<()> = MutexNoIrq::new(());
+
+impl Plic {
+    #[inline]
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    #[inline]
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(value) }
+    }
+
+    /// Get the supervisor context of the hart.
+    #[inline]
+    fn context(&self, hart_id: usize) -> Option<usize> {
+        self.contexts.get(hart_id).copied().flatten()
+    }
+
+    /// Set the enable bit of the irq in the context.
+    fn set_enable(&self, context: usize, irq_num: usize, enable: bool) {
+        let offset = ENABLE_BASE + context * ENABLE_STRIDE + irq_num / 32 * 4;
+        let bit = 1 << (irq_num % 32);
+        let _guard = ENABLE_LOCK.lock();
+        match enable {
+            true => self.write(offset, self.read(offset) | bit),
+            false => self.write(offset, self.read(offset) & !bit),
+        }
+    }
+}
+
+/// Initialize the PLIC from the FDT, return false if it doesn't exist.
+///
+/// The priorities are cleared and all the irqs are disabled.
+pub(super) fn init(fdt: &Fdt) -> bool {
+    let Some(node) = fdt.find_compatible(PLIC_COMPATIBLE) else {
+        return false;
+    };
+    let Some(region) = node.reg().and_then(|mut reg| reg.next()) else {
+        return false;
+    };
+    let ndev = node
+        .property("riscv,ndev")
+        .and_then(|prop| prop.as_usize())
+        .map_or(MAX_SOURCES - 1, |ndev| ndev.min(MAX_SOURCES - 1));
+    let contexts = parse_s_ext_harts(fdt, node);
+    let plic = Plic {
+        base: region.starting_address as usize | VIRT_ADDR_START,
+        ndev,
+        contexts,
+    };
+    for irq_num in 1..=plic.ndev {
+        plic.write(PRIORITY_BASE + irq_num * 4, 0);
+    }
+    for context in plic.contexts.iter().flatten() {
+        for irq_num in (0..=plic.ndev).step_by(32) {
+            plic.write(ENABLE_BASE + context * ENABLE_STRIDE + irq_num / 32 * 4, 0);
+        }
+        plic.write(
+            CONTEXT_BASE + context * CONTEXT_STRIDE + CONTEXT_THRESHOLD,
+            0,
+        );
+    }
+    log::info!(
+        "PLIC @ {:#x}: {} sources, {} harts",
+        region.starting_address as usize,
+        plic.ndev,
+        plic.contexts.iter().flatten().count()
+    );
+    PLIC.init_by(plic);
+    true
+}
+
+/// Enable the irq on the context of the current hart.
+pub(super) fn enable(hart_id: usize, irq_num: usize) {
+    let Some(plic) = PLIC.try_get() else {
+        return;
+    };
+    let Some(context) = plic.context(hart_id) else {
+        return;
+    };
+    if irq_num == 0 || irq_num > plic.ndev {
+        return;
+    }
+    plic.write(PRIORITY_BASE + irq_num * 4, DEFAULT_PRIORITY);
+    plic.set_enable(context, irq_num, true);
+}
+
+/// Disable the irq on the contexts of all the harts.
+pub(super) fn disable(irq_num: usize) {
+    let Some(plic) = PLIC.try_get() else {
+        return;
+    };
+    if irq_num == 0 || irq_num > plic.ndev {
+        return;
+    }
+    for context in plic.contexts.iter().flatten() {
+        plic.set_enable(*context, irq_num, false);
+    }
+}
+
+/// Claim the highest priority pending irq of the current hart.
+///
+/// Return `None` if there is no PLIC, or the irq was claimed by the other harts.
+pub(super) fn claim(hart_id: usize) -> Option<usize> {
+    let plic = PLIC.try_get()?;
+    let context = plic.context(hart_id)?;
+    match plic.read(CONTEXT_BASE + context * CONTEXT_STRIDE + CONTEXT_CLAIM) {
+        0 => None,
+        irq_num => Some(irq_num as usize),
+    }
+}
+
+/// Complete the irq claimed by the current hart.
+pub(super) fn complete(hart_id: usize, irq_num: usize) {
+    let Some(plic) = PLIC.try_get() else {
+        return;
+    };
+    if let Some(context) = plic.context(hart_id) {
+        plic.write(
+            CONTEXT_BASE + context * CONTEXT_STRIDE + CONTEXT_CLAIM,
+            irq_num as u32,
+        );
+    }
+}
+
+/// Check if the PLIC was initialized.
+#[cfg(feature = "trap")]
+#[inline]
+pub(super) fn is_present() -> bool {
+    PLIC.is_init()
+}
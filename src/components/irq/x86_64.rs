@@ -1,11 +1,26 @@
-use crate::components::irq::{IRQ, IRQVector};
-use crate::components::arch::apic::{io_apic, local_apic};
+use crate::components::irq::{IRQ, IRQVector, MsiMessage};
+use crate::components::arch::apic::vectors::MSI_VECTOR_START;
+use crate::components::arch::apic::{
+    io_apic, local_apic, msi_alloc_vector, msi_free_vector, MSI_ADDR_BASE,
+};
+use crate::components::arch::hart_id;
+use crate::components::consts::PIC_VECTOR_OFFSET;
+use crate::addr::PhysAddr;
+
+/// The first irq number of the MSIs, the irq number is the vector minus
+/// the [PIC_VECTOR_OFFSET].
+const MSI_IRQ_START: usize = (MSI_VECTOR_START - PIC_VECTOR_OFFSET) as usize;
 
 /// Implement IRQ operations for the IRQ interface.
 impl IRQ {
     /// Enable irq for the given IRQ number.
+    ///
+    /// The MSI is enabled when it's allocated, it's not routed by the IO APIC.
     #[inline]
     pub fn irq_enable(irq_num: usize) {
+        if irq_num >= MSI_IRQ_START {
+            return;
+        }
         unsafe {
             io_apic().lock().enable_irq(irq_num as _);
         }
@@ -14,11 +29,39 @@ impl IRQ {
     /// Disable irq for the given IRQ number.
     #[inline]
     pub fn irq_disable(irq_num: usize) {
+        if irq_num >= MSI_IRQ_START {
+            return;
+        }
         unsafe {
             io_apic().lock().disable_irq(irq_num as _);
         }
     }
 
+    /// Allocate an MSI delivered to the local APIC of the current cpu.
+    ///
+    /// The `device_id` is only used by the ITS on aarch64.
+    /// Return `None` if all the vectors are used or the apic id can't be the
+    /// destination of the xAPIC message.
+    pub fn msi_alloc(_device_id: u32) -> Option<(IRQVector, MsiMessage)> {
+        let apic_id = hart_id();
+        if apic_id > 0xff {
+            return None;
+        }
+        let vector = msi_alloc_vector()?;
+        let msg = MsiMessage {
+            addr: PhysAddr::new(MSI_ADDR_BASE | apic_id << 12),
+            data: vector as u32,
+        };
+        Some((IRQVector((vector - PIC_VECTOR_OFFSET) as usize), msg))
+    }
+
+    /// Release the MSI, the device must stop raising it.
+    pub fn msi_free(irq: IRQVector) {
+        if irq.0 >= MSI_IRQ_START {
+            msi_free_vector((irq.0 + PIC_VECTOR_OFFSET as usize) as u8);
+        }
+    }
+
     /// Enable interrupts.
     #[inline]
     pub fn int_enable() {
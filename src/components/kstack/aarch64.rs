@@ -0,0 +1,9 @@
+use crate::components::boot::kernel_page_table;
+use crate::components::pagetable::PageTable;
+
+/// Get the page table that maps the kernel stacks, it's the page table of
+/// `TTBR1` which is shared by all the address spaces.
+#[inline]
+pub(super) fn kernel_root() -> PageTable {
+    kernel_page_table()
+}
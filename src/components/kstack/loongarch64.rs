@@ -0,0 +1,29 @@
+use loongArch64::register::pgdh;
+use spin::Once;
+
+use crate::components::common::frame_alloc;
+use crate::components::pagetable::PageTable;
+
+/// The page table of the kernel stacks, it's installed to `PGDH` and
+/// created when it's used first.
+static KERNEL_ROOT: Once<PageTable> = Once::new();
+
+/// Get the page table that maps the kernel stacks.
+///
+/// The kernel is mapped by the direct mapping windows, so the page table
+/// for the higher half only holds the kernel stacks.
+pub(super) fn kernel_root() -> PageTable {
+    *KERNEL_ROOT.call_once(|| {
+        let frame = frame_alloc();
+        frame.drop_clear();
+        pgdh::set_base(frame.to_addr());
+        PageTable(frame.into())
+    })
+}
+
+/// Install the page table of the kernel stacks on the current core.
+pub(crate) fn set_kernel_root() {
+    if let Some(root) = KERNEL_ROOT.get() {
+        pgdh::set_base(root.0.addr());
+    }
+}
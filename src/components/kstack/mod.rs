@@ -0,0 +1,223 @@
+//! Kernel stack module.
+//!
+//! The kernel stacks are mapped in the top 1 GiB of the kernel address space.
+//! Every stack is placed in the upper half of a slot which is twice the
+//! [KERNEL_STACK_SIZE], the lower half of the slot is never mapped and works
+//! as the guard area of the stack.
+//!
+//! ```text
+//! |  guard  |  stack  |  guard  |  stack  | ...
+//! ^ slot 0            ^ slot 1
+//! ```
+//!
+//! If the `trap` feature is enabled, the trap entry checks the stack pointer
+//! before saving the trap frame. The frame is saved on the emergency stack if
+//! it would be saved in the guard area, then the overflow is reported by a
+//! panic. The kernel page fault in the guard area is reported in the same way.
+//!
+//! ```rust
+//! let stack = KernelStack::new().expect("can't allocate the kernel stack");
+//! kcontext[KContextArgs::KSP] = stack.top();
+//! ```
+//!
+//! TIPS: The region is installed by [init](crate::common::init), the page
+//! tables created before it don't map the kernel stacks. The stack passed to
+//! `boot_core` must be in the linear mapping on aarch64 and loongarch64,
+//! they use it before the paging is enabled.
+//!
+//! TIPS: The emergency stack is shared by all the cores, it's only used to
+//! report the overflow. x86_64 doesn't switch the stack in the trap entry,
+//! the cpu raises the double fault if it can't push the trap frame, and the
+//! double fault reports the overflow on its own stack.
+
+use core::fmt::{self, Debug};
+
+use crate::components::common::frame_alloc;
+use crate::components::pagetable::{PageTable, PAGE_SIZE, PTE, TLB};
+use crate::utils::MutexNoIrq;
+use crate::{MappingFlags, MappingSize, VirtAddr, VirtPage};
+
+super::define_arch_mods!();
+
+/// The size of the kernel stack.
+pub const KERNEL_STACK_SIZE: usize = 0x4_0000;
+
+/// The size of the slot, the lower half is the guard area.
+const SLOT_SIZE: usize = KERNEL_STACK_SIZE * 2;
+
+/// The start of the kernel stack region, the region is the top 1 GiB.
+pub(crate) const REGION_START: usize = 0xffff_ffff_c000_0000;
+
+/// The number of the slots, the last slot is dropped, the top of it is
+/// out of the address space.
+const SLOT_NUM: usize = (0usize.wrapping_sub(REGION_START)) / SLOT_SIZE - 1;
+
+/// The bitmap of the allocated slots.
+static SLOTS: MutexNoIrq<[u64; SLOT_NUM.div_ceil(64)]> =
+    MutexNoIrq::new([0; SLOT_NUM.div_ceil(64)]);
+
+/// Install the page table of the kernel stack region.
+///
+/// The boot page tables map the whole region by the huge pages, replace the
+/// entry with a page table. The entry belongs to the table of the kernel
+/// half, it isn't shared with the identity mapping of the boot.
+pub(crate) fn init() {
+    let vpn = VirtPage::from_addr(REGION_START);
+    let mut pte_list = PageTable::get_pte_list(kernel_root().0);
+    if PageTable::PAGE_LEVEL == 4 {
+        pte_list = PageTable::get_pte_list(pte_list[vpn.pn_index(3)].address());
+    }
+    let pte = &mut pte_list[vpn.pn_index(2)];
+    if !pte.is_table() {
+        let frame = frame_alloc();
+        frame.drop_clear();
+        *pte = PTE::new_table(frame);
+    }
+    TLB::flush_all();
+}
+
+/// The kernel stack with the guard area.
+///
+/// The slot is released when it's dropped, but the stack stays mapped and
+/// is reused by the next [KernelStack::new]. Unmapping it would need the
+/// TLB shootdown on the other cores, they may have cached the mapping.
+pub struct KernelStack {
+    slot: usize,
+}
+
+impl KernelStack {
+    /// Allocate a kernel stack of [KERNEL_STACK_SIZE].
+    ///
+    /// Return `None` if all the slots are used.
+    pub fn new() -> Option<Self> {
+        let slot = {
+            let mut slots = SLOTS.lock();
+            let slot = (0..SLOT_NUM).find(|&i| slots[i / 64] & (1 << (i % 64)) == 0)?;
+            slots[slot / 64] |= 1 << (slot % 64);
+            slot
+        };
+        let stack = Self { slot };
+        let pt = kernel_root();
+        // The slot keeps its mapping after the stack is dropped.
+        if pt.translate(VirtAddr::new(stack.bottom())).is_some() {
+            return Some(stack);
+        }
+        let flags = MappingFlags::R
+            | MappingFlags::W
+            | MappingFlags::A
+            | MappingFlags::D
+            | MappingFlags::G;
+        for addr in (stack.bottom()..stack.top()).step_by(PAGE_SIZE) {
+            pt.map_kernel(
+                VirtPage::from_addr(addr),
+                frame_alloc(),
+                flags,
+                MappingSize::Page4KB,
+            );
+        }
+        Some(stack)
+    }
+
+    /// Get the bottom of the stack, the lowest mapped address.
+    #[inline]
+    pub fn bottom(&self) -> usize {
+        REGION_START + self.slot * SLOT_SIZE + KERNEL_STACK_SIZE
+    }
+
+    /// Get the top of the stack, the initial stack pointer.
+    #[inline]
+    pub fn top(&self) -> usize {
+        self.bottom() + KERNEL_STACK_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        SLOTS.lock()[self.slot / 64] &= !(1 << (self.slot % 64));
+    }
+}
+
+impl Debug for KernelStack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KernelStack")
+            .field("bottom", &format_args!("{:#x}", self.bottom()))
+            .field("top", &format_args!("{:#x}", self.top()))
+            .finish()
+    }
+}
+
+/// Get the range of the stack whose slot contains the `addr`.
+fn slot_range(addr: usize) -> Option<(usize, usize)> {
+    let slot = addr.checked_sub(REGION_START)? / SLOT_SIZE;
+    let bottom = REGION_START + slot * SLOT_SIZE + KERNEL_STACK_SIZE;
+    (slot < SLOT_NUM).then_some((bottom, bottom + KERNEL_STACK_SIZE))
+}
+
+/// Check if the `addr` is in the guard area of the kernel stacks.
+#[inline]
+pub fn is_stack_guard(addr: usize) -> bool {
+    slot_range(addr).is_some() && addr & KERNEL_STACK_SIZE == 0
+}
+
+#[cfg(feature = "trap")]
+pub(crate) use overflow::*;
+
+#[cfg(feature = "trap")]
+mod overflow {
+    use super::{is_stack_guard, slot_range};
+    use crate::components::backtrace::SymbolAddr;
+    use crate::components::debug_console::println;
+    use crate::components::trap::{TrapClass, TrapType};
+    #[cfg(not(target_arch = "x86_64"))]
+    use crate::components::trapframe::kernel_frame;
+    use crate::components::trapframe::{TrapFrame, TrapFrameArgs};
+
+    /// The size of the emergency stack.
+    #[cfg(not(target_arch = "x86_64"))]
+    pub(crate) const EMERGENCY_STACK_SIZE: usize = 0x1_0000;
+
+    /// The stack used to report the kernel stack overflow.
+    #[cfg(not(target_arch = "x86_64"))]
+    #[link_section = ".bss.stack"]
+    pub(crate) static mut EMERGENCY_STACK: [u8; EMERGENCY_STACK_SIZE] =
+        [0; EMERGENCY_STACK_SIZE];
+
+    /// The shift of the bit that splits the guard area and the stack in the
+    /// slot, it's used by the trap entry.
+    #[cfg(not(target_arch = "x86_64"))]
+    pub(crate) const KSTACK_SHIFT: usize = super::KERNEL_STACK_SIZE.trailing_zeros() as usize;
+
+    /// Print the trap frame and the backtrace, then panic.
+    pub(crate) fn report_overflow(tf: &TrapFrame, addr: usize) -> ! {
+        println!("{:#x?}", tf);
+        tf.print_backtrace();
+        let (bottom, top) = slot_range(addr).unwrap_or_default();
+        panic!(
+            "kernel stack overflow at {}: address {:#x}, the stack is {:#x} - {:#x}",
+            SymbolAddr(tf[TrapFrameArgs::SEPC]),
+            addr,
+            bottom,
+            top
+        )
+    }
+
+    /// Report the overflow, called by the trap entry on the emergency stack.
+    ///
+    /// The stack pointer of the trap frame is the overflowed stack pointer.
+    #[cfg(not(target_arch = "x86_64"))]
+    pub(crate) extern "C" fn kernel_stack_overflow(tf: *mut TrapFrame) -> ! {
+        let tf = unsafe { kernel_frame(tf) };
+        report_overflow(tf, tf[TrapFrameArgs::SP])
+    }
+
+    /// Report the overflow if the kernel page fault is in the guard area.
+    pub(crate) fn check_stack_overflow(tf: &TrapFrame, trap_type: TrapType) {
+        if trap_type.class() != TrapClass::PageFault {
+            return;
+        }
+        match trap_type.fault_info() {
+            Some(info) if !info.user && is_stack_guard(info.addr) => report_overflow(tf, info.addr),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,9 @@
+use crate::components::boot::boot_page_table;
+use crate::components::pagetable::PageTable;
+
+/// Get the page table that maps the kernel stacks, the kernel half of it
+/// is shared by all the page tables.
+#[inline]
+pub(super) fn kernel_root() -> PageTable {
+    boot_page_table()
+}
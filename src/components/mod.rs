@@ -3,21 +3,36 @@
 //!
 
 pub(crate) mod arch;
+pub mod backtrace;
 pub mod boot;
+#[cfg(feature = "trap")]
+pub mod breakpoint;
 pub mod common;
 pub mod consts;
 pub mod debug_console;
+#[cfg(feature = "trap")]
+pub mod extable;
+// The fpu state is only saved and restored by the trap.
+#[cfg_attr(not(feature = "trap"), allow(dead_code))]
+pub mod fpu;
+#[cfg(feature = "gdbstub")]
+pub mod gdbstub;
 pub mod instruction;
 pub mod irq;
 pub mod kcontext;
+pub mod kstack;
 pub mod macros;
 pub mod mem;
 pub mod multicore;
 pub mod pagetable;
 pub mod percpu;
+#[cfg(feature = "trap")]
+pub mod signal;
 pub mod timer;
 #[cfg(feature = "trap")]
 pub mod trap;
 pub mod trapframe;
+#[cfg(feature = "trap")]
+pub mod uaccess;
 
 use polyhal_macro::define_arch_mods;
@@ -0,0 +1,150 @@
+use core::mem::size_of;
+
+use crate::components::trapframe::TrapFrame;
+use crate::components::uaccess::clear_user;
+use crate::VirtAddr;
+
+use super::{read_user, stack_sub, write_user, SigInfo, SigStack, SignalError, BAD_ADDRESS};
+
+/// The size of the `__reserved` area in the `sigcontext`.
+const SIGCONTEXT_RESERVED_SIZE: usize = 4096;
+
+/// The same as the `sigcontext` in Linux without the `__reserved` area.
+///
+/// The `__reserved` area follows it and contains the extended contexts.
+/// Only the [FpsimdContext] is pushed, the rest is zero filled, which is
+/// the end of the extended contexts.
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+struct SigContext {
+    fault_address: u64,
+    regs: [usize; 31],
+    sp: usize,
+    pc: usize,
+    pstate: usize,
+}
+
+/// The same as the `ucontext` in Linux.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UContext {
+    flags: usize,
+    link: usize,
+    stack: SigStack,
+    sigmask: u64,
+    _unused: [u8; 120],
+    mcontext: SigContext,
+}
+
+/// The same as the `rt_sigframe` in Linux, without the `__reserved` area.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SigFrame {
+    info: SigInfo,
+    uc: UContext,
+}
+
+/// The size of the whole `rt_sigframe`.
+const SIGFRAME_SIZE: usize = size_of::<SigFrame>() + SIGCONTEXT_RESERVED_SIZE;
+
+/// The magic of the [FpsimdContext].
+const FPSIMD_MAGIC: u32 = 0x46508001;
+
+/// The extended context of the floating point and Advanced SIMD registers,
+/// the same as the `fpsimd_context` in Linux.
+#[repr(C, align(16))]
+struct FpsimdContext {
+    magic: u32,
+    size: u32,
+    fpsr: u32,
+    fpcr: u32,
+    vregs: [u128; 32],
+}
+
+/// The frame record pointed by the `x29`, the same as the `frame_record` in Linux.
+#[repr(C)]
+struct FrameRecord {
+    fp: usize,
+    lr: usize,
+}
+
+pub(super) fn setup_sigframe(
+    tf: &mut TrapFrame,
+    sp: usize,
+    info: &SigInfo,
+    handler: usize,
+    restorer: usize,
+    mask: u64,
+    uc_stack: SigStack,
+) -> Result<(), SignalError> {
+    let record_addr = stack_sub(sp, size_of::<FrameRecord>())? & !0xf;
+    let frame_addr = stack_sub(record_addr, SIGFRAME_SIZE)? & !0xf;
+    let frame = SigFrame {
+        info: *info,
+        uc: UContext {
+            flags: 0,
+            link: 0,
+            stack: uc_stack,
+            sigmask: mask,
+            _unused: [0; 120],
+            mcontext: SigContext {
+                fault_address: 0,
+                regs: tf.regs,
+                sp: tf.sp,
+                pc: tf.elr,
+                pstate: tf.spsr,
+            },
+        },
+    };
+    write_user(frame_addr, &frame)?;
+    let fpsimd_addr = frame_addr + size_of::<SigFrame>();
+    let fpsimd = FpsimdContext {
+        magic: FPSIMD_MAGIC,
+        size: size_of::<FpsimdContext>() as _,
+        fpsr: tf.fp.regs().fpsr,
+        fpcr: tf.fp.regs().fpcr,
+        vregs: tf.fp.regs().v,
+    };
+    write_user(fpsimd_addr, &fpsimd)?;
+    clear_user(
+        VirtAddr::new(fpsimd_addr + size_of::<FpsimdContext>()),
+        SIGCONTEXT_RESERVED_SIZE - size_of::<FpsimdContext>(),
+    )?;
+    let record = FrameRecord {
+        fp: tf.regs[29],
+        lr: tf.regs[30],
+    };
+    write_user(record_addr, &record)?;
+
+    let frame = frame_addr as *const SigFrame;
+    tf.elr = handler;
+    tf.sp = frame_addr;
+    tf.regs[0] = info.signo as _;
+    tf.regs[1] = unsafe { core::ptr::addr_of!((*frame).info) } as _;
+    tf.regs[2] = unsafe { core::ptr::addr_of!((*frame).uc) } as _;
+    tf.regs[29] = record_addr;
+    tf.regs[30] = restorer;
+    Ok(())
+}
+
+pub(super) fn restore_frame(tf: &mut TrapFrame) -> Result<u64, SignalError> {
+    let frame: SigFrame = read_user(tf.sp)?;
+    let mcontext = frame.uc.mcontext;
+    // Only EL0t with all DAIF bits clear is allowed.
+    if mcontext.pstate & 0x3ff != 0 {
+        return Err(SignalError::InvalidFrame);
+    }
+    let fpsimd_addr = tf.sp.checked_add(size_of::<SigFrame>()).ok_or(BAD_ADDRESS)?;
+    let fpsimd: FpsimdContext = read_user(fpsimd_addr)?;
+    if fpsimd.magic == FPSIMD_MAGIC {
+        let fp = tf.fp.regs_mut();
+        fp.v = fpsimd.vregs;
+        fp.fpsr = fpsimd.fpsr;
+        fp.fpcr = fpsimd.fpcr;
+    }
+    tf.regs = mcontext.regs;
+    tf.sp = mcontext.sp;
+    tf.elr = mcontext.pc;
+    tf.spsr = mcontext.pstate;
+    Ok(frame.uc.sigmask)
+}
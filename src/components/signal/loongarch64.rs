@@ -0,0 +1,113 @@
+use crate::components::trapframe::TrapFrame;
+
+use super::{read_user, stack_sub, write_user, SigInfo, SigStack, SignalError};
+
+/// The same as the `sigcontext` in Linux.
+///
+/// The extended contexts follow it, aligned to 16 bytes.
+#[repr(C, align(16))]
+struct SigContext {
+    pc: usize,
+    regs: [usize; 32],
+    flags: u32,
+}
+
+/// The same as the `ucontext` in Linux.
+#[repr(C)]
+struct UContext {
+    flags: usize,
+    link: usize,
+    stack: SigStack,
+    sigmask: u64,
+    _unused: [u8; 120],
+    mcontext: SigContext,
+}
+
+/// The magic of the [FpuContext].
+const FPU_CTX_MAGIC: u32 = 0x46505501;
+
+/// The extended context of the base floating point registers,
+/// the same as the `sctx_info` followed by the `fpu_context` in Linux.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct FpuContext {
+    magic: u32,
+    size: u32,
+    _padding: u64,
+    regs: [u64; 32],
+    fcc: u64,
+    fcsr: u32,
+}
+
+/// The same as the `rt_sigframe` in Linux, followed by the extended contexts.
+#[repr(C)]
+struct SigFrame {
+    info: SigInfo,
+    uc: UContext,
+    fpu: FpuContext,
+    /// The end of the extended contexts, the same as the `sctx_info` with zero.
+    ext_end: [u64; 2],
+}
+
+pub(super) fn setup_sigframe(
+    tf: &mut TrapFrame,
+    sp: usize,
+    info: &SigInfo,
+    handler: usize,
+    restorer: usize,
+    mask: u64,
+    uc_stack: SigStack,
+) -> Result<(), SignalError> {
+    let frame_addr = stack_sub(sp, core::mem::size_of::<SigFrame>())? & !0xf;
+    let frame = SigFrame {
+        info: *info,
+        uc: UContext {
+            flags: 0,
+            link: 0,
+            stack: uc_stack,
+            sigmask: mask,
+            _unused: [0; 120],
+            mcontext: SigContext {
+                pc: tf.era,
+                regs: tf.regs,
+                flags: 0,
+            },
+        },
+        fpu: FpuContext {
+            magic: FPU_CTX_MAGIC,
+            size: core::mem::size_of::<FpuContext>() as _,
+            _padding: 0,
+            regs: tf.fp.regs().f,
+            fcc: tf.fp.regs().fcc,
+            fcsr: tf.fp.regs().fcsr,
+        },
+        ext_end: [0; 2],
+    };
+    write_user(frame_addr, &frame)?;
+
+    let frame = frame_addr as *const SigFrame;
+    tf.era = handler;
+    tf.regs[1] = restorer;
+    tf.regs[3] = frame_addr;
+    tf.regs[4] = info.signo as _;
+    tf.regs[5] = unsafe { core::ptr::addr_of!((*frame).info) } as _;
+    tf.regs[6] = unsafe { core::ptr::addr_of!((*frame).uc) } as _;
+    Ok(())
+}
+
+pub(super) fn restore_frame(tf: &mut TrapFrame) -> Result<u64, SignalError> {
+    let frame_addr = tf.regs[3];
+    let frame: SigFrame = read_user(frame_addr)?;
+    let mcontext = frame.uc.mcontext;
+    tf.era = mcontext.pc;
+    // r0 is always zero.
+    tf.regs[1..].copy_from_slice(&mcontext.regs[1..]);
+    let fpu = frame.fpu;
+    if fpu.magic == FPU_CTX_MAGIC {
+        let fp = tf.fp.regs_mut();
+        fp.f = fpu.regs;
+        fp.fcc = fpu.fcc;
+        fp.fcsr = fpu.fcsr;
+    }
+    Ok(frame.uc.sigmask)
+}
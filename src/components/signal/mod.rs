@@ -0,0 +1,159 @@
+//! Signal frame module.
+//!
+//! Build the Linux compatible signal frame (`rt_sigframe`) on the user stack
+//! and restore it on `rt_sigreturn`.
+//!
+//! ```rust
+//! // Deliver the signal.
+//! let info = SigInfo::new(SIGSEGV, SEGV_MAPERR);
+//! push_sigframe(ctx, &info, action.handler, action.restorer, mask, None)?;
+//! // Handle the `rt_sigreturn` syscall.
+//! let mask = restore_sigframe(ctx)?;
+//! ```
+//!
+//! TIPS: Only the floating point state saved in the [TrapFrame] is pushed,
+//! the vector extension states are not included.
+
+use core::mem::{size_of, MaybeUninit};
+
+use crate::components::trapframe::{TrapFrame, TrapFrameArgs};
+use crate::components::uaccess::{copy_from_user, copy_to_user, UserAccessError};
+use crate::VirtAddr;
+
+super::define_arch_mods!();
+
+/// The signal stack is in use.
+pub const SS_ONSTACK: i32 = 1;
+/// The signal stack is disabled.
+pub const SS_DISABLE: i32 = 2;
+
+/// Signal information, the same as the `siginfo_t` in Linux.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SigInfo {
+    pub signo: i32,
+    pub errno: i32,
+    pub code: i32,
+    _pad: i32,
+    /// The union fields, such as `si_addr`, `si_pid` and `si_uid`.
+    pub fields: [usize; 14],
+}
+
+impl SigInfo {
+    /// Create a signal information with empty fields.
+    pub const fn new(signo: i32, code: i32) -> Self {
+        Self {
+            signo,
+            errno: 0,
+            code,
+            _pad: 0,
+            fields: [0; 14],
+        }
+    }
+}
+
+/// Signal stack, the same as the `stack_t` in Linux.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SigStack {
+    pub sp: usize,
+    pub flags: i32,
+    pub size: usize,
+}
+
+impl SigStack {
+    /// The disabled signal stack.
+    pub const DISABLED: SigStack = SigStack {
+        sp: 0,
+        flags: SS_DISABLE,
+        size: 0,
+    };
+
+    /// Check if the `sp` is in the signal stack.
+    #[inline]
+    pub fn contains(&self, sp: usize) -> bool {
+        sp > self.sp && sp - self.sp <= self.size
+    }
+}
+
+/// The error of the signal frame operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalError {
+    /// Failed to access the signal frame on the user stack.
+    Access(UserAccessError),
+    /// The registers in the signal frame are not allowed for the user.
+    InvalidFrame,
+}
+
+impl From<UserAccessError> for SignalError {
+    fn from(err: UserAccessError) -> Self {
+        SignalError::Access(err)
+    }
+}
+
+/// Push the signal frame on the user stack and redirect the [TrapFrame] to the `handler`.
+///
+/// The `handler` returns to the `restorer` which should call `rt_sigreturn`.
+/// `mask` is the signal mask saved in the frame. The frame is pushed on
+/// the `alt_stack` if it is given and the task isn't running on it.
+pub fn push_sigframe(
+    tf: &mut TrapFrame,
+    info: &SigInfo,
+    handler: usize,
+    restorer: usize,
+    mask: u64,
+    alt_stack: Option<SigStack>,
+) -> Result<(), SignalError> {
+    let sp = tf[TrapFrameArgs::SP];
+    let (sp, uc_stack) = match alt_stack {
+        Some(stack) if stack.contains(sp) => (
+            sp,
+            SigStack {
+                flags: SS_ONSTACK,
+                ..stack
+            },
+        ),
+        Some(stack) => (
+            stack.sp.checked_add(stack.size).ok_or(BAD_ADDRESS)?,
+            SigStack { flags: 0, ..stack },
+        ),
+        None => (sp, SigStack::DISABLED),
+    };
+    setup_sigframe(tf, sp, info, handler, restorer, mask, uc_stack)
+}
+
+/// Restore the [TrapFrame] from the signal frame pushed by [push_sigframe].
+///
+/// Call it when handling the `rt_sigreturn` syscall, return the saved signal mask.
+pub fn restore_sigframe(tf: &mut TrapFrame) -> Result<u64, SignalError> {
+    restore_frame(tf)
+}
+
+/// The error of the frame address out of the address space, the user stack
+/// pointer may be any value.
+const BAD_ADDRESS: SignalError = SignalError::Access(UserAccessError::BadAddress);
+
+/// Get the address `size` bytes below the user stack pointer `sp`.
+#[inline]
+fn stack_sub(sp: usize, size: usize) -> Result<usize, SignalError> {
+    sp.checked_sub(size).ok_or(BAD_ADDRESS)
+}
+
+/// Write the `val` to the user address `addr`.
+#[inline]
+fn write_user<T>(addr: usize, val: &T) -> Result<(), UserAccessError> {
+    let bytes = unsafe { core::slice::from_raw_parts(val as *const T as *const u8, size_of::<T>()) };
+    copy_to_user(VirtAddr::new(addr), bytes)
+}
+
+/// Read a `T` from the user address `addr`.
+///
+/// `T` must be valid for any bit pattern.
+#[inline]
+fn read_user<T>(addr: usize) -> Result<T, UserAccessError> {
+    let mut val = MaybeUninit::<T>::zeroed();
+    let bytes =
+        unsafe { core::slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    copy_from_user(bytes, VirtAddr::new(addr))?;
+    Ok(unsafe { val.assume_init() })
+}
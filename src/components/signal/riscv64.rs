@@ -0,0 +1,96 @@
+use crate::components::trapframe::TrapFrame;
+
+use super::{read_user, stack_sub, write_user, SigInfo, SigStack, SignalError};
+
+/// Floating point state, the same as the `__riscv_fp_state` in Linux.
+///
+/// It is large enough for the `Q` extension, only the `D` extension is used.
+#[repr(C, align(16))]
+struct FpState {
+    f: [u64; 64],
+    fcsr: u32,
+    _reserved: [u32; 3],
+}
+
+/// The same as the `sigcontext` in Linux.
+#[repr(C)]
+struct SigContext {
+    /// `pc` and `x1` - `x31`.
+    regs: [usize; 32],
+    fpregs: FpState,
+}
+
+/// The same as the `ucontext` in Linux.
+#[repr(C)]
+struct UContext {
+    flags: usize,
+    link: usize,
+    stack: SigStack,
+    sigmask: u64,
+    _unused: [u8; 120],
+    mcontext: SigContext,
+}
+
+/// The same as the `rt_sigframe` in Linux.
+#[repr(C)]
+struct SigFrame {
+    info: SigInfo,
+    uc: UContext,
+}
+
+pub(super) fn setup_sigframe(
+    tf: &mut TrapFrame,
+    sp: usize,
+    info: &SigInfo,
+    handler: usize,
+    restorer: usize,
+    mask: u64,
+    uc_stack: SigStack,
+) -> Result<(), SignalError> {
+    let frame_addr = stack_sub(sp, core::mem::size_of::<SigFrame>())? & !0xf;
+    let mut regs = tf.x;
+    regs[0] = tf.sepc;
+    let mut f = [0; 64];
+    f[..32].copy_from_slice(&tf.fp.regs().f);
+    let frame = SigFrame {
+        info: *info,
+        uc: UContext {
+            flags: 0,
+            link: 0,
+            stack: uc_stack,
+            sigmask: mask,
+            _unused: [0; 120],
+            mcontext: SigContext {
+                regs,
+                fpregs: FpState {
+                    f,
+                    fcsr: tf.fp.regs().fcsr,
+                    _reserved: [0; 3],
+                },
+            },
+        },
+    };
+    write_user(frame_addr, &frame)?;
+
+    let frame = frame_addr as *const SigFrame;
+    tf.sepc = handler;
+    tf.x[1] = restorer;
+    tf.x[2] = frame_addr;
+    tf.x[10] = info.signo as _;
+    tf.x[11] = unsafe { core::ptr::addr_of!((*frame).info) } as _;
+    tf.x[12] = unsafe { core::ptr::addr_of!((*frame).uc) } as _;
+    Ok(())
+}
+
+pub(super) fn restore_frame(tf: &mut TrapFrame) -> Result<u64, SignalError> {
+    let frame_addr = tf.x[2];
+    let frame: SigFrame = read_user(frame_addr)?;
+    let regs = frame.uc.mcontext.regs;
+    tf.sepc = regs[0];
+    tf.x[1..].copy_from_slice(&regs[1..]);
+    let fpregs = frame.uc.mcontext.fpregs;
+    let fp = tf.fp.regs_mut();
+    fp.f.copy_from_slice(&fpregs.f[..32]);
+    fp.fcsr = fpregs.fcsr;
+    Ok(frame.uc.sigmask)
+}
@@ -0,0 +1,168 @@
+use core::mem::size_of;
+
+use crate::components::trapframe::{FxsaveArea, TrapFrame};
+
+use super::{read_user, stack_sub, write_user, SigInfo, SigStack, SignalError};
+
+/// The flags that the user can change through the signal frame.
+/// AC, OF, DF, TF, SF, ZF, AF, PF, CF and RF.
+const FIX_EFLAGS: usize = 0x50dd5;
+
+/// The flags cleared when entering the signal handler, DF, TF and RF.
+const CLEAR_EFLAGS: usize = 0x10500;
+
+/// The size of the red zone below the user stack pointer.
+const RED_ZONE_SIZE: usize = 128;
+
+/// The same as the `sigcontext` in Linux.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SigContext {
+    r8: usize,
+    r9: usize,
+    r10: usize,
+    r11: usize,
+    r12: usize,
+    r13: usize,
+    r14: usize,
+    r15: usize,
+    rdi: usize,
+    rsi: usize,
+    rbp: usize,
+    rbx: usize,
+    rdx: usize,
+    rax: usize,
+    rcx: usize,
+    rsp: usize,
+    rip: usize,
+    eflags: usize,
+    cs: u16,
+    gs: u16,
+    fs: u16,
+    ss: u16,
+    err: usize,
+    trapno: usize,
+    oldmask: usize,
+    cr2: usize,
+    /// The pointer to the `fxsave` area.
+    fpstate: usize,
+    _reserved: [usize; 8],
+}
+
+/// The same as the `ucontext` in Linux.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UContext {
+    flags: usize,
+    link: usize,
+    stack: SigStack,
+    mcontext: SigContext,
+    sigmask: u64,
+}
+
+/// The same as the `rt_sigframe` in Linux.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SigFrame {
+    /// The return address of the handler.
+    pretcode: usize,
+    uc: UContext,
+    info: SigInfo,
+}
+
+pub(super) fn setup_sigframe(
+    tf: &mut TrapFrame,
+    sp: usize,
+    info: &SigInfo,
+    handler: usize,
+    restorer: usize,
+    mask: u64,
+    uc_stack: SigStack,
+) -> Result<(), SignalError> {
+    let fp_addr = stack_sub(sp, RED_ZONE_SIZE + size_of::<FxsaveArea>())? & !0x3f;
+    // The stack is aligned to 16 bytes before the `call`, then pushes the return address.
+    let frame_addr = stack_sub(stack_sub(fp_addr, size_of::<SigFrame>())? & !0xf, 8)?;
+    write_user(fp_addr, tf.fp.regs())?;
+    let frame = SigFrame {
+        pretcode: restorer,
+        uc: UContext {
+            flags: 0,
+            link: 0,
+            stack: uc_stack,
+            mcontext: SigContext {
+                r8: tf.r8,
+                r9: tf.r9,
+                r10: tf.r10,
+                r11: tf.r11,
+                r12: tf.r12,
+                r13: tf.r13,
+                r14: tf.r14,
+                r15: tf.r15,
+                rdi: tf.rdi,
+                rsi: tf.rsi,
+                rbp: tf.rbp,
+                rbx: tf.rbx,
+                rdx: tf.rdx,
+                rax: tf.rax,
+                rcx: tf.rcx,
+                rsp: tf.rsp,
+                rip: tf.rip,
+                eflags: tf.rflags,
+                cs: tf.cs as _,
+                gs: 0,
+                fs: 0,
+                ss: tf.ss as _,
+                err: tf.error_code,
+                trapno: tf.vector,
+                oldmask: mask as _,
+                cr2: 0,
+                fpstate: fp_addr,
+                _reserved: [0; 8],
+            },
+            sigmask: mask,
+        },
+        info: *info,
+    };
+    write_user(frame_addr, &frame)?;
+
+    let frame = frame_addr as *const SigFrame;
+    tf.rip = handler;
+    tf.rsp = frame_addr;
+    tf.rdi = info.signo as _;
+    tf.rsi = unsafe { core::ptr::addr_of!((*frame).info) } as _;
+    tf.rdx = unsafe { core::ptr::addr_of!((*frame).uc) } as _;
+    tf.rax = 0;
+    tf.rflags &= !CLEAR_EFLAGS;
+    Ok(())
+}
+
+pub(super) fn restore_frame(tf: &mut TrapFrame) -> Result<u64, SignalError> {
+    // The `ret` in the handler has popped the `pretcode`.
+    let frame: SigFrame = read_user(stack_sub(tf.rsp, size_of::<usize>())?)?;
+    let mcontext = frame.uc.mcontext;
+    if mcontext.fpstate != 0 {
+        let mut fx_area: FxsaveArea = read_user(mcontext.fpstate)?;
+        // Reserved bits in the MXCSR raise #GP on `fxrstor`.
+        fx_area.mxcsr &= 0xffff;
+        *tf.fp.regs_mut() = fx_area;
+    }
+    tf.r8 = mcontext.r8;
+    tf.r9 = mcontext.r9;
+    tf.r10 = mcontext.r10;
+    tf.r11 = mcontext.r11;
+    tf.r12 = mcontext.r12;
+    tf.r13 = mcontext.r13;
+    tf.r14 = mcontext.r14;
+    tf.r15 = mcontext.r15;
+    tf.rdi = mcontext.rdi;
+    tf.rsi = mcontext.rsi;
+    tf.rbp = mcontext.rbp;
+    tf.rbx = mcontext.rbx;
+    tf.rdx = mcontext.rdx;
+    tf.rax = mcontext.rax;
+    tf.rcx = mcontext.rcx;
+    tf.rsp = mcontext.rsp;
+    tf.rip = mcontext.rip;
+    tf.rflags = (tf.rflags & !FIX_EFLAGS) | (mcontext.eflags & FIX_EFLAGS);
+    Ok(frame.uc.sigmask)
+}
@@ -3,13 +3,27 @@ use core::arch::{asm, global_asm};
 use aarch64_cpu::registers::{Writeable, ESR_EL1, FAR_EL1, VBAR_EL1};
 use tock_registers::interfaces::Readable;
 
-use crate::components::irq::{get_irq, TIMER_IRQ_NUM};
+use crate::components::irq::{get_irq, GIC_SPURIOUS_IRQ_NUM, TIMER_IRQ_NUM};
 use crate::components::timer::set_next_timer;
-use crate::components::trapframe::TrapFrame;
+use crate::components::trapframe::{kernel_frame, TrapFrame, TRAPFRAME_SIZE};
 
-use crate::components::trap::{EscapeReason, TrapType};
+use crate::components::breakpoint;
+use crate::components::fpu;
+use crate::components::trap::{
+    count_spurious, count_trap, dispatch_trap, emulate_insn, fixup_kernel_fault, EscapeReason,
+    FaultInfo, ThreadToken, TrapType,
+};
+use crate::utils::bit;
 
-global_asm!(include_str!("aarch64/trap.S"));
+global_asm!(
+    include_str!("aarch64/trap.S"),
+    kstack_region = const crate::components::kstack::REGION_START,
+    kstack_shift = const crate::components::kstack::KSTACK_SHIFT,
+    emergency_stack = sym crate::components::kstack::EMERGENCY_STACK,
+    emergency_stack_size = const crate::components::kstack::EMERGENCY_STACK_SIZE,
+    trapframe_size = const TRAPFRAME_SIZE,
+    stack_overflow = sym crate::components::kstack::kernel_stack_overflow,
+);
 
 #[repr(u8)]
 #[derive(Debug, PartialEq)]
@@ -31,11 +45,32 @@ enum TrapSource {
     LowerAArch32 = 3,
 }
 
+/// Kernel trap entry, called by `trap.S`.
+///
+/// The single step of the kernel is paused while the trap is handled by
+/// [handle_exception].
 #[no_mangle]
-fn handle_exception(tf: &mut TrapFrame, kind: TrapKind, source: TrapSource) -> TrapType {
+extern "C" fn kernel_trap_entry(tf: *mut TrapFrame, kind: TrapKind, source: TrapSource) {
+    let tf = unsafe { kernel_frame(tf) };
+    breakpoint::step_exit();
+    handle_exception(tf, kind, source, ThreadToken::NONE);
+    breakpoint::step_enter(tf);
+}
+
+fn handle_exception(
+    tf: &mut TrapFrame,
+    kind: TrapKind,
+    source: TrapSource,
+    token: ThreadToken,
+) -> TrapType {
     if kind == TrapKind::Irq {
         let irq = get_irq();
         let trap_type = match irq.irq_num() {
+            // The spurious interrupt is not acknowledged.
+            GIC_SPURIOUS_IRQ_NUM => {
+                count_spurious();
+                return TrapType::Unknown;
+            }
             TIMER_IRQ_NUM => {
                 irq.ack();
                 set_next_timer();
@@ -43,7 +78,8 @@ fn handle_exception(tf: &mut TrapFrame, kind: TrapKind, source: TrapSource) -> T
             }
             _ => TrapType::Irq(irq),
         };
-        unsafe { crate::components::trap::_interrupt_for_arch(tf, trap_type, 0) };
+        count_trap(trap_type);
+        dispatch_trap(tf, trap_type, token);
         return trap_type;
     }
     if kind != TrapKind::Synchronous {
@@ -53,34 +89,51 @@ fn handle_exception(tf: &mut TrapFrame, kind: TrapKind, source: TrapSource) -> T
         );
     }
     let esr = ESR_EL1.extract();
+    let iss = esr.read(ESR_EL1::ISS) as usize;
+    let user = tf.from_user();
+    let info = |addr| FaultInfo { addr, user };
     let trap_type = match esr.read_as_enum(ESR_EL1::EC) {
         Some(ESR_EL1::EC::Value::Brk64) => {
+            let pc = tf.elr;
             tf.elr += 4;
-            TrapType::Breakpoint
+            TrapType::Breakpoint(info(pc))
+        }
+        Some(ESR_EL1::EC::Value::BreakpointLowerEL)
+        | Some(ESR_EL1::EC::Value::BreakpointCurrentEL) => TrapType::Breakpoint(info(tf.elr)),
+        Some(ESR_EL1::EC::Value::WatchpointLowerEL)
+        | Some(ESR_EL1::EC::Value::WatchpointCurrentEL) => {
+            TrapType::Watchpoint(info(FAR_EL1.get() as _))
+        }
+        Some(ESR_EL1::EC::Value::SoftwareStepLowerEL)
+        | Some(ESR_EL1::EC::Value::SoftwareStepCurrentEL) => TrapType::SingleStep(info(tf.elr)),
+        Some(ESR_EL1::EC::Value::SVC64) => {
+            tf.orig_x0 = tf.regs[0];
+            TrapType::SysCall
         }
-        Some(ESR_EL1::EC::Value::SVC64) => TrapType::SysCall,
         Some(ESR_EL1::EC::Value::DataAbortLowerEL)
-        | Some(ESR_EL1::EC::Value::InstrAbortLowerEL) => {
-            let iss = esr.read(ESR_EL1::ISS);
-            log::warn!(
-                "EL0 Page Fault @ {:#x}, FAR={:#x}, ISS={:#x}",
-                tf.elr,
-                FAR_EL1.get(),
-                iss
-            );
-            TrapType::InstructionPageFault(FAR_EL1.get() as _)
+        | Some(ESR_EL1::EC::Value::DataAbortCurrentEL) => {
+            decode_abort(iss, FAR_EL1.get() as _, user, false)
         }
-        Some(ESR_EL1::EC::Value::DataAbortCurrentEL)
+        Some(ESR_EL1::EC::Value::InstrAbortLowerEL)
         | Some(ESR_EL1::EC::Value::InstrAbortCurrentEL) => {
-            let iss = esr.read(ESR_EL1::ISS);
-            log::warn!(
-                "EL1 Page Fault @ {:#x}, FAR={:#x}, ISS={:#x}:\n{:#x?}",
-                tf.elr,
-                FAR_EL1.get(),
-                iss,
-                tf,
-            );
-            TrapType::InstructionPageFault(FAR_EL1.get() as _)
+            decode_abort(iss, FAR_EL1.get() as _, user, true)
+        }
+        Some(ESR_EL1::EC::Value::PCAlignmentFault) => {
+            TrapType::InstructionMisaligned(info(FAR_EL1.get() as _))
+        }
+        Some(ESR_EL1::EC::Value::SPAlignmentFault) => {
+            // The kernel stack pointer is just above the saved trap frame.
+            let sp = match user {
+                true => tf.sp,
+                false => tf as *const TrapFrame as usize + TRAPFRAME_SIZE,
+            };
+            TrapType::AlignmentCheck(info(sp))
+        }
+        Some(ESR_EL1::EC::Value::TrappedFP) | Some(ESR_EL1::EC::Value::TrappedSve) => {
+            TrapType::FpuDisabled(info(tf.elr))
+        }
+        Some(ESR_EL1::EC::Value::Unknown) | Some(ESR_EL1::EC::Value::IllegalExecutionState) => {
+            TrapType::IllegalInstruction(info(tf.elr))
         }
         _ => {
             panic!(
@@ -92,10 +145,55 @@ fn handle_exception(tf: &mut TrapFrame, kind: TrapKind, source: TrapSource) -> T
             );
         }
     };
-    unsafe { crate::components::trap::_interrupt_for_arch(tf, trap_type, 0) };
+    count_trap(trap_type);
+    // The kernel uses the fpu, the state loaded in it will be changed.
+    if !user && matches!(trap_type, TrapType::FpuDisabled(_)) {
+        fpu::invalidate();
+        fpu::set_enabled(true, false);
+        return trap_type;
+    }
+    // The emulated instruction is neither fixed up nor dispatched.
+    if emulate_insn(tf, trap_type) {
+        return TrapType::Unknown;
+    }
+    if !user && trap_type.fault_info().is_some() && !trap_type.is_debug() {
+        // The marked kernel instruction is retried or jumps to the fixup code.
+        if fixup_kernel_fault(tf, trap_type, token) {
+            return trap_type;
+        }
+        log::warn!(
+            "EL1 Exception {:x?} @ {:#x}, ISS={:#x}:\n{:#x?}",
+            trap_type,
+            tf.elr,
+            iss,
+            tf,
+        );
+    }
+    dispatch_trap(tf, trap_type, token);
     trap_type
 }
 
+/// Decode the data abort or instruction abort through the fault status code.
+fn decode_abort(iss: usize, far: usize, user: bool, is_fetch: bool) -> TrapType {
+    let info = FaultInfo { addr: far, user };
+    // WnR is only valid if the abort was not caused by a cache maintenance instruction.
+    let is_write = !is_fetch && iss & bit!(6) != 0 && iss & bit!(8) == 0;
+    match (iss & 0x3f, is_fetch, is_write) {
+        // Translation fault, Access flag fault and Permission fault.
+        (0b000100..=0b001111, true, _) => TrapType::InstructionPageFault(info),
+        (0b000100..=0b001111, _, true) => TrapType::StorePageFault(info),
+        (0b000100..=0b001111, _, false) => TrapType::LoadPageFault(info),
+        // Alignment fault.
+        (0b100001, true, _) => TrapType::InstructionMisaligned(info),
+        (0b100001, _, true) => TrapType::StoreMisaligned(info),
+        (0b100001, _, false) => TrapType::LoadMisaligned(info),
+        // Address size fault, external abort and the others.
+        (_, true, _) => TrapType::InstructionAccessFault(info),
+        (_, _, true) => TrapType::StoreAccessFault(info),
+        (_, _, false) => TrapType::LoadAccessFault(info),
+    }
+}
+
 pub fn init() {
     extern "C" {
         fn exception_vector_base();
@@ -150,6 +248,86 @@ extern "C" fn user_restore(context: *mut TrapFrame) -> TrapKind {
 }
 
 pub fn run_user_task(cx: &mut TrapFrame) -> EscapeReason {
-    let trap_kind = user_restore(cx);
-    handle_exception(cx, trap_kind, TrapSource::LowerAArch64).into()
+    run_user_once(cx, ThreadToken::NONE)
+}
+
+/// Run the user task until it traps back, dispatch the trap with the `token`.
+pub(crate) fn run_user_once(cx: &mut TrapFrame, token: ThreadToken) -> EscapeReason {
+    loop {
+        let enabled = fpu::is_loaded(&cx.fp);
+        let ext = cx.fp.ext().is_some();
+        fpu::set_enabled(enabled, ext);
+        breakpoint::step_enter(cx);
+        let trap_kind = user_restore(cx);
+        breakpoint::step_exit();
+        if enabled {
+            cx.fp.save();
+        }
+        // Load the fpu state if the user task trapped by the disabled fpu,
+        // the SVE is disabled if the task didn't enable the extended state.
+        let fpu_trap = match ESR_EL1.read_as_enum(ESR_EL1::EC) {
+            Some(ESR_EL1::EC::Value::TrappedFP) => true,
+            Some(ESR_EL1::EC::Value::TrappedSve) => ext,
+            _ => false,
+        };
+        if trap_kind == TrapKind::Synchronous && fpu_trap {
+            count_trap(TrapType::FpuDisabled(FaultInfo {
+                addr: cx.elr,
+                user: true,
+            }));
+            fpu::set_enabled(true, ext);
+            fpu::load(&mut cx.fp);
+            continue;
+        }
+        // The trap handled in place, such as the emulated instruction, resumes the task.
+        match handle_exception(cx, trap_kind, TrapSource::LowerAArch64, token) {
+            TrapType::Unknown => continue,
+            trap_type => return trap_type.into(),
+        }
+    }
+}
+
+/// Save the callee saved registers on the kernel stack, then call `entry`
+/// with the trap frame and the [ThreadToken] pointing to the saved registers.
+#[naked]
+pub(crate) unsafe extern "C" fn __into_user(
+    tf: *mut TrapFrame,
+    entry: extern "C" fn(&mut TrapFrame, ThreadToken) -> !,
+) -> usize {
+    asm!(
+        "
+            sub     sp, sp, 12*8
+            stp     x19, x20, [sp, 0*8]
+            stp     x21, x22, [sp, 2*8]
+            stp     x23, x24, [sp, 4*8]
+            stp     x25, x26, [sp, 6*8]
+            stp     x27, x28, [sp, 8*8]
+            stp     x29, x30, [sp, 10*8]
+            mov     x2, x1
+            mov     x1, sp
+            br      x2
+        ",
+        options(noreturn)
+    )
+}
+
+/// Restore the callee saved registers saved by [__into_user],
+/// then return `value` from it.
+#[naked]
+pub(crate) unsafe extern "C" fn __restore_token(token: usize, value: usize) -> ! {
+    asm!(
+        "
+            mov     sp, x0
+            ldp     x19, x20, [sp, 0*8]
+            ldp     x21, x22, [sp, 2*8]
+            ldp     x23, x24, [sp, 4*8]
+            ldp     x25, x26, [sp, 6*8]
+            ldp     x27, x28, [sp, 8*8]
+            ldp     x29, x30, [sp, 10*8]
+            add     sp, sp, 12*8
+            mov     x0, x1
+            ret
+        ",
+        options(noreturn)
+    )
 }
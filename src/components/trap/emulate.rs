@@ -0,0 +1,183 @@
+//! Instruction emulation.
+//!
+//! The instructions that the cpu or the firmware doesn't implement raise
+//! [TrapType::IllegalInstruction]. The instruction at the faulting pc is
+//! passed to the registered emulators before the trap is dispatched, the
+//! trap is not dispatched if one of them emulated it, and [run_user_task]
+//! resumes the user task instead of returning.
+//!
+//! ```rust
+//! // Emulate `rdtime` on riscv64 if reading the `time` csr traps.
+//! fn rdtime(ctx: &mut TrapFrame, insn: &Instruction) -> Option<usize> {
+//!     let rd = (insn.raw() >> 7) as usize & 0x1f;
+//!     if insn.raw() & !(0x1f << 7) != 0xc010_2073 {
+//!         return None;
+//!     }
+//!     if rd != 0 {
+//!         ctx.x[rd] = current_time();
+//!     }
+//!     Some(insn.len())
+//! }
+//!
+//! register_insn_emulator(rdtime);
+//! ```
+//!
+//! [run_user_task]: super::run_user_task
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::components::trapframe::{TrapFrame, TrapFrameArgs};
+use crate::components::uaccess::{__copy_user, copy_from_user};
+use crate::VirtAddr;
+
+use super::TrapType;
+
+/// The maximum number of the emulators that can be registered.
+pub const INSN_EMULATOR_NUM: usize = 16;
+
+/// The maximum length of the instruction.
+#[cfg(target_arch = "x86_64")]
+const MAX_INSN_LEN: usize = 15;
+#[cfg(not(target_arch = "x86_64"))]
+const MAX_INSN_LEN: usize = 4;
+
+/// The instruction emulator.
+///
+/// Return the length of the emulated instruction, the pc is moved past it.
+/// Return `None` if the instruction is not handled by the emulator.
+pub type InsnEmulator = fn(&mut TrapFrame, &Instruction) -> Option<usize>;
+
+/// The instruction at the faulting pc.
+#[derive(Debug, Clone, Copy)]
+pub struct Instruction {
+    /// The address of the instruction.
+    pub pc: usize,
+    /// Whether the instruction was executed in user mode.
+    pub user: bool,
+    bytes: [u8; MAX_INSN_LEN],
+    len: usize,
+}
+
+impl Instruction {
+    /// Get the fetched bytes of the instruction.
+    ///
+    /// The instruction length isn't decoded on x86_64, the bytes are fetched
+    /// until the maximum length or the end of the readable memory.
+    #[inline]
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// Get the length of the fetched bytes.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check if no byte was fetched, it's never true for the emulators.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get the first 4 bytes of the instruction in little endian,
+    /// the compressed instruction on riscv64 is zero extended.
+    #[inline]
+    pub fn raw(&self) -> u32 {
+        let mut raw = [0u8; 4];
+        let len = self.len.min(4);
+        raw[..len].copy_from_slice(&self.bytes[..len]);
+        u32::from_le_bytes(raw)
+    }
+
+    /// Fetch the instruction at `pc`, return `None` if it can't be read.
+    fn fetch(pc: usize, user: bool) -> Option<Self> {
+        let mut insn = Self {
+            pc,
+            user,
+            bytes: [0; MAX_INSN_LEN],
+            len: 0,
+        };
+        // Fetch the bytes one by one, the instruction may cross the page.
+        while insn.len < insn_len(insn.bytes()) {
+            let addr = pc + insn.len;
+            let byte = &mut insn.bytes[insn.len..insn.len + 1];
+            let ok = match user {
+                true => copy_from_user(byte, VirtAddr::new(addr)).is_ok(),
+                false => unsafe { __copy_user(byte.as_mut_ptr(), addr as _, 1) == 0 },
+            };
+            if !ok {
+                break;
+            }
+            insn.len += 1;
+        }
+        match cfg!(target_arch = "x86_64") {
+            true => (insn.len > 0).then_some(insn),
+            false => (insn.len == insn_len(insn.bytes())).then_some(insn),
+        }
+    }
+}
+
+/// Get the length of the instruction from the fetched bytes.
+#[inline]
+fn insn_len(fetched: &[u8]) -> usize {
+    match fetched.first() {
+        // The compressed instruction has 2 bytes.
+        Some(byte) if cfg!(target_arch = "riscv64") && byte & 0b11 != 0b11 => 2,
+        _ => MAX_INSN_LEN,
+    }
+}
+
+/// The empty slot of the emulator table.
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: AtomicUsize = AtomicUsize::new(0);
+
+/// The function pointers of the emulators, 0 means not registered.
+static INSN_EMULATORS: [AtomicUsize; INSN_EMULATOR_NUM] = [EMPTY; INSN_EMULATOR_NUM];
+
+/// Register the instruction emulator, the emulators are tried in the
+/// order of the registration.
+///
+/// Return false if [INSN_EMULATOR_NUM] emulators were registered.
+pub fn register_insn_emulator(emulator: InsnEmulator) -> bool {
+    INSN_EMULATORS.iter().any(|slot| {
+        slot.compare_exchange(0, emulator as usize, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    })
+}
+
+/// Unregister the instruction emulator.
+pub fn unregister_insn_emulator(emulator: InsnEmulator) {
+    for slot in INSN_EMULATORS.iter() {
+        let _ = slot.compare_exchange(emulator as usize, 0, Ordering::AcqRel, Ordering::Acquire);
+    }
+}
+
+/// Emulate the instruction if the trap is [TrapType::IllegalInstruction].
+///
+/// Return true and move the pc past the instruction if emulated.
+pub(crate) fn emulate_insn(ctx: &mut TrapFrame, trap_type: TrapType) -> bool {
+    let TrapType::IllegalInstruction(info) = trap_type else {
+        return false;
+    };
+    if INSN_EMULATORS
+        .iter()
+        .all(|slot| slot.load(Ordering::Acquire) == 0)
+    {
+        return false;
+    }
+    let Some(insn) = Instruction::fetch(info.addr, info.user) else {
+        return false;
+    };
+    for slot in INSN_EMULATORS.iter() {
+        let emulator = match slot.load(Ordering::Acquire) {
+            0 => continue,
+            ptr => unsafe { core::mem::transmute::<usize, InsnEmulator>(ptr) },
+        };
+        if let Some(len) = emulator(ctx, &insn) {
+            ctx[TrapFrameArgs::SEPC] += len;
+            return true;
+        }
+    }
+    false
+}
@@ -0,0 +1,218 @@
+//! Runtime trap handler registration.
+//!
+//! The traps are dispatched in the following order:
+//! 1. [TrapType::Irq] goes to the handler registered by [register_irq_handler].
+//! 2. The handler registered for the [TrapClass] by [register_trap_handler].
+//! 3. The fallback registered by [set_fallback_handler].
+//! 4. The function marked by `#[arch_interrupt]`.
+//!
+//! ```rust
+//! fn uart_irq(_irq: IRQVector) {
+//!     log::info!("uart interrupt");
+//! }
+//!
+//! fn page_fault(ctx: &mut TrapFrame, trap_type: TrapType, _token: ThreadToken) {
+//!     log::info!("page fault: {:x?}", trap_type.fault_info());
+//! }
+//!
+//! register_irq_handler(10, uart_irq);
+//! register_trap_handler(TrapClass::PageFault, page_fault);
+//! ```
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::components::extable::{fixup_exception, search_exception_table};
+use crate::components::irq::IRQVector;
+use crate::components::kstack::check_stack_overflow;
+use crate::components::pagetable::{MappingFlags, PageTable, USER_VADDR_END};
+use crate::components::trapframe::{TrapFrame, TrapFrameArgs};
+use crate::VirtAddr;
+
+use super::{ThreadToken, TrapType};
+
+/// The maximum number of the IRQs that can be registered.
+///
+/// It covers the LPIs of the GICv3 on aarch64, the MSIs are the LPIs from
+/// 8192 to 16383.
+#[cfg(target_arch = "aarch64")]
+pub const IRQ_HANDLER_NUM: usize = 16384;
+
+/// The maximum number of the IRQs that can be registered.
+#[cfg(not(target_arch = "aarch64"))]
+pub const IRQ_HANDLER_NUM: usize = 1024;
+
+/// The handler of the irq.
+pub type IrqHandler = fn(IRQVector);
+
+/// The handler of the trap.
+///
+/// The [ThreadToken] is valid only if the task was entered by [TrapFrame::into_user].
+pub type TrapHandler = fn(&mut TrapFrame, TrapType, ThreadToken);
+
+/// The class of the [TrapType], used to register the trap handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum TrapClass {
+    /// `Breakpoint`, `Watchpoint` and `SingleStep`.
+    Breakpoint,
+    SysCall,
+    Timer,
+    /// `StorePageFault`, `LoadPageFault` and `InstructionPageFault`.
+    PageFault,
+    /// `StoreAccessFault`, `LoadAccessFault`, `InstructionAccessFault` and
+    /// `GeneralProtection`.
+    AccessFault,
+    /// `StoreMisaligned`, `LoadMisaligned`, `InstructionMisaligned` and `AlignmentCheck`.
+    Misaligned,
+    IllegalInstruction,
+    FpuDisabled,
+    /// `Irq` without a registered irq handler and `SupervisorExternal`.
+    Irq,
+    Unknown,
+}
+
+impl TrapClass {
+    /// The number of the trap classes.
+    pub(super) const NUM: usize = TrapClass::Unknown as usize + 1;
+}
+
+impl TrapType {
+    /// Get the class of the trap.
+    pub fn class(&self) -> TrapClass {
+        match self {
+            TrapType::Breakpoint(_) | TrapType::Watchpoint(_) | TrapType::SingleStep(_) => {
+                TrapClass::Breakpoint
+            }
+            TrapType::SysCall => TrapClass::SysCall,
+            TrapType::Timer => TrapClass::Timer,
+            TrapType::StorePageFault(_)
+            | TrapType::LoadPageFault(_)
+            | TrapType::InstructionPageFault(_) => TrapClass::PageFault,
+            TrapType::StoreAccessFault(_)
+            | TrapType::LoadAccessFault(_)
+            | TrapType::InstructionAccessFault(_)
+            | TrapType::GeneralProtection(_) => TrapClass::AccessFault,
+            TrapType::StoreMisaligned(_)
+            | TrapType::LoadMisaligned(_)
+            | TrapType::InstructionMisaligned(_)
+            | TrapType::AlignmentCheck(_) => TrapClass::Misaligned,
+            TrapType::IllegalInstruction(_) => TrapClass::IllegalInstruction,
+            TrapType::FpuDisabled(_) => TrapClass::FpuDisabled,
+            TrapType::Irq(_) | TrapType::SupervisorExternal => TrapClass::Irq,
+            TrapType::Unknown => TrapClass::Unknown,
+        }
+    }
+}
+
+/// The empty slot of the handler tables.
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: AtomicUsize = AtomicUsize::new(0);
+
+/// The function pointers of the handlers, 0 means not registered.
+static IRQ_HANDLERS: [AtomicUsize; IRQ_HANDLER_NUM] = [EMPTY; IRQ_HANDLER_NUM];
+static TRAP_HANDLERS: [AtomicUsize; TrapClass::NUM] = [EMPTY; TrapClass::NUM];
+static FALLBACK_HANDLER: AtomicUsize = EMPTY;
+
+/// Register the handler for the irq `irq_num`, replace the previous one.
+///
+/// Return false if the `irq_num` is out of [IRQ_HANDLER_NUM].
+pub fn register_irq_handler(irq_num: usize, handler: IrqHandler) -> bool {
+    match IRQ_HANDLERS.get(irq_num) {
+        Some(slot) => {
+            slot.store(handler as usize, Ordering::Release);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Unregister the handler for the irq `irq_num`.
+pub fn unregister_irq_handler(irq_num: usize) {
+    if let Some(slot) = IRQ_HANDLERS.get(irq_num) {
+        slot.store(0, Ordering::Release);
+    }
+}
+
+/// Register the handler for the trap class, replace the previous one.
+pub fn register_trap_handler(class: TrapClass, handler: TrapHandler) {
+    TRAP_HANDLERS[class as usize].store(handler as usize, Ordering::Release);
+}
+
+/// Unregister the handler for the trap class.
+pub fn unregister_trap_handler(class: TrapClass) {
+    TRAP_HANDLERS[class as usize].store(0, Ordering::Release);
+}
+
+/// Set the fallback handler, `None` restores the `#[arch_interrupt]` function.
+pub fn set_fallback_handler(handler: Option<TrapHandler>) {
+    FALLBACK_HANDLER.store(handler.map_or(0, |h| h as usize), Ordering::Release);
+}
+
+/// Load the handler from the slot.
+#[inline]
+fn load_handler<T: Copy>(slot: &AtomicUsize) -> Option<T> {
+    match slot.load(Ordering::Acquire) {
+        0 => None,
+        ptr => Some(unsafe { core::mem::transmute_copy(&ptr) }),
+    }
+}
+
+/// Dispatch the trap to the registered handlers.
+pub(crate) fn dispatch_trap(ctx: &mut TrapFrame, trap_type: TrapType, token: ThreadToken) {
+    // The kernel stack overflow can't be handled, report it before the handlers.
+    check_stack_overflow(ctx, trap_type);
+    if let TrapType::Irq(irq) = trap_type {
+        let handler = IRQ_HANDLERS
+            .get(irq.irq_num())
+            .and_then(load_handler::<IrqHandler>);
+        if let Some(handler) = handler {
+            return handler(irq);
+        }
+    }
+    if let Some(handler) = load_handler::<TrapHandler>(&TRAP_HANDLERS[trap_type.class() as usize]) {
+        return handler(ctx, trap_type, token);
+    }
+    dispatch_fallback(ctx, trap_type, token);
+}
+
+/// Dispatch the trap to the fallback handler, the handler of the class is skipped.
+pub(crate) fn dispatch_fallback(ctx: &mut TrapFrame, trap_type: TrapType, token: ThreadToken) {
+    match load_handler::<TrapHandler>(&FALLBACK_HANDLER) {
+        Some(handler) => handler(ctx, trap_type, token),
+        None => unsafe { super::_interrupt_for_arch(ctx, trap_type, token) },
+    }
+}
+
+/// Handle the fault of the kernel instruction marked in the exception table.
+///
+/// The page fault on the user address is dispatched first, the handler may
+/// populate the page. The instruction is retried if the page is accessible
+/// after it, otherwise it jumps to the fixup code.
+/// Return false if the trap isn't raised by a marked kernel instruction.
+pub(crate) fn fixup_kernel_fault(
+    ctx: &mut TrapFrame,
+    trap_type: TrapType,
+    token: ThreadToken,
+) -> bool {
+    let info = match trap_type.fault_info() {
+        Some(info) if !info.user && !trap_type.is_debug() => info,
+        _ => return false,
+    };
+    if search_exception_table(ctx[TrapFrameArgs::SEPC]).is_none() {
+        return false;
+    }
+    if trap_type.class() == TrapClass::PageFault && info.addr <= USER_VADDR_END {
+        dispatch_trap(ctx, trap_type, token);
+        let flags = match trap_type {
+            TrapType::StorePageFault(_) => MappingFlags::U | MappingFlags::W,
+            _ => MappingFlags::U,
+        };
+        let mapped = PageTable::current()
+            .translate(VirtAddr::new(info.addr))
+            .is_some_and(|(_, mflags)| mflags.contains(flags));
+        if mapped {
+            return true;
+        }
+    }
+    fixup_exception(&mut ctx[TrapFrameArgs::SEPC])
+}
@@ -8,10 +8,15 @@ use loongArch64::register::{
 };
 use unaligned::emulate_load_store_insn;
 
-use crate::components::trapframe::TrapFrame;
+use crate::components::trapframe::{kernel_frame, TrapFrame};
 
-use crate::components::trap::{EscapeReason, TrapType};
-use crate::irq::TIMER_IRQ;
+use crate::components::breakpoint;
+use crate::components::fpu;
+use crate::components::trap::{
+    count_spurious, count_trap, dispatch_trap, emulate_insn, fixup_kernel_fault, EscapeReason,
+    FaultInfo, ThreadToken, TrapType,
+};
+use crate::irq::{get_irq, EXT_IRQ, TIMER_IRQ};
 
 global_asm!(
     r"
@@ -181,8 +186,99 @@ pub fn disable_irq() {
 }
 
 pub fn run_user_task(cx: &mut TrapFrame) -> EscapeReason {
-    user_restore(cx);
-    loongarch64_trap_handler(cx).into()
+    run_user_once(cx, ThreadToken::NONE)
+}
+
+/// Run the user task until it traps back, dispatch the trap with the `token`.
+pub(crate) fn run_user_once(cx: &mut TrapFrame, token: ThreadToken) -> EscapeReason {
+    loop {
+        let enabled = fpu::is_loaded(&cx.fp);
+        let ext = cx.fp.ext().is_some();
+        fpu::set_enabled(enabled, ext);
+        breakpoint::step_enter(cx);
+        user_restore(cx);
+        breakpoint::step_exit(cx);
+        if enabled {
+            cx.fp.save();
+        }
+        // Load the fpu state if the user task trapped by the disabled fpu,
+        // the LSX and LASX are disabled if the task didn't enable the extended state.
+        let estat = estat::read();
+        let fpu_trap = match estat.cause() {
+            Trap::Exception(Exception::FloatingPointUnavailable) => true,
+            Trap::Unknown => ext && matches!(estat.ecode(), ECODE_SXD | ECODE_ASXD),
+            _ => false,
+        };
+        if fpu_trap {
+            count_trap(TrapType::FpuDisabled(FaultInfo {
+                addr: cx.era,
+                user: true,
+            }));
+            fpu::set_enabled(true, ext);
+            fpu::load(&mut cx.fp);
+            continue;
+        }
+        // The trap handled in place, such as the emulated instruction, resumes the task.
+        match loongarch64_trap_handler(cx, token) {
+            TrapType::Unknown => continue,
+            trap_type => return trap_type.into(),
+        }
+    }
+}
+
+/// Save the callee saved registers on the kernel stack, then call `entry`
+/// with the trap frame and the [ThreadToken] pointing to the saved registers.
+#[naked]
+pub(crate) unsafe extern "C" fn __into_user(
+    tf: *mut TrapFrame,
+    entry: extern "C" fn(&mut TrapFrame, ThreadToken) -> !,
+) -> usize {
+    asm!(
+        "
+            addi.d  $sp, $sp, -12*8
+            st.d    $ra, $sp, 0*8
+            st.d    $fp, $sp, 1*8
+            st.d    $s0, $sp, 2*8
+            st.d    $s1, $sp, 3*8
+            st.d    $s2, $sp, 4*8
+            st.d    $s3, $sp, 5*8
+            st.d    $s4, $sp, 6*8
+            st.d    $s5, $sp, 7*8
+            st.d    $s6, $sp, 8*8
+            st.d    $s7, $sp, 9*8
+            st.d    $s8, $sp, 10*8
+            move    $t0, $a1
+            move    $a1, $sp
+            jr      $t0
+        ",
+        options(noreturn)
+    )
+}
+
+/// Restore the callee saved registers saved by [__into_user],
+/// then return `value` from it.
+#[naked]
+pub(crate) unsafe extern "C" fn __restore_token(token: usize, value: usize) -> ! {
+    asm!(
+        "
+            move    $sp, $a0
+            ld.d    $ra, $sp, 0*8
+            ld.d    $fp, $sp, 1*8
+            ld.d    $s0, $sp, 2*8
+            ld.d    $s1, $sp, 3*8
+            ld.d    $s2, $sp, 4*8
+            ld.d    $s3, $sp, 5*8
+            ld.d    $s4, $sp, 6*8
+            ld.d    $s5, $sp, 7*8
+            ld.d    $s6, $sp, 8*8
+            ld.d    $s7, $sp, 9*8
+            ld.d    $s8, $sp, 10*8
+            addi.d  $sp, $sp, 12*8
+            move    $a0, $a1
+            jr      $ra
+        ",
+        options(noreturn)
+    )
 }
 
 #[naked]
@@ -195,7 +291,20 @@ pub unsafe extern "C" fn trap_vector_base() {
                 csrrd   $sp, 0x1
                 andi    $sp, $sp, 0x3
                 bnez    $sp, {user_vec} 
-            
+
+                // Check if the trap frame would be saved in the guard area of
+                // the kernel stacks, only sp is usable, KSAVE_USP holds it.
+                csrrd   $sp, KSAVE_USP
+                addi.d  $sp, $sp, -{trapframe_size}
+                srai.d  $sp, $sp, 30
+                addi.d  $sp, $sp, 1
+                bnez    $sp, 1f
+                csrrd   $sp, KSAVE_USP
+                addi.d  $sp, $sp, -{trapframe_size}
+                srli.d  $sp, $sp, {kstack_shift}
+                andi    $sp, $sp, 1
+                beqz    $sp, 2f
+            1:
                 csrrd   $sp, KSAVE_USP
                 addi.d  $sp, $sp, -{trapframe_size} // allocate space
             
@@ -209,10 +318,25 @@ pub unsafe extern "C" fn trap_vector_base() {
                 // Load registers from sp, include new sp
                 LOAD_REGS
                 ertn
+
+            2:
+                // Report the overflow on the emergency stack.
+                la.pcrel    $sp, {emergency_stack}
+                addu16i.d   $sp, $sp, {emergency_stack_size} >> 16
+                addi.d      $sp, $sp, -{trapframe_size}
+
+                SAVE_REGS
+
+                move    $a0, $sp
+                bl      {stack_overflow}
         ",
         trapframe_size = const crate::components::trapframe::TRAPFRAME_SIZE,
+        kstack_shift = const crate::components::kstack::KSTACK_SHIFT,
+        emergency_stack = sym crate::components::kstack::EMERGENCY_STACK,
+        emergency_stack_size = const crate::components::kstack::EMERGENCY_STACK_SIZE,
+        stack_overflow = sym crate::components::kstack::kernel_stack_overflow,
         user_vec = sym user_vec,
-        trap_handler = sym loongarch64_trap_handler,
+        trap_handler = sym kernel_trap_entry,
         options(noreturn)
     );
 }
@@ -258,6 +382,13 @@ pub const _PS_1G: usize = 0x1e;
 
 pub const PAGE_SIZE_SHIFT: usize = 12;
 
+/// 128-bit vector (LSX) instructions disabled exception.
+const ECODE_SXD: usize = 0x10;
+/// 256-bit vector (LASX) instructions disabled exception.
+const ECODE_ASXD: usize = 0x11;
+/// Watchpoint exception.
+const ECODE_WPE: usize = 0x13;
+
 pub fn tlb_init(tlbrentry: usize) {
     // // setup PWCTL
     // unsafe {
@@ -295,17 +426,31 @@ pub fn set_trap_vector_base() {
     eentry::set_eentry(trap_vector_base as usize);
 }
 
-fn loongarch64_trap_handler(tf: &mut TrapFrame) -> TrapType {
+/// Kernel trap entry, called by [trap_vector_base].
+///
+/// The assembly only passes the trap frame in `$a0`, the [TrapType] of
+/// [loongarch64_trap_handler] is dropped, the kernel trap is resumed.
+extern "C" fn kernel_trap_entry(tf: *mut TrapFrame) {
+    loongarch64_trap_handler(unsafe { kernel_frame(tf) }, ThreadToken::NONE);
+}
+
+fn loongarch64_trap_handler(tf: &mut TrapFrame, token: ThreadToken) -> TrapType {
     let estat = estat::read();
+    let user = tf.from_user();
+    let info = |addr| FaultInfo { addr, user };
     let trap_type = match estat.cause() {
         Trap::Exception(Exception::Breakpoint) => {
+            let pc = tf.era;
             tf.era += 4;
-            TrapType::Breakpoint
+            TrapType::Breakpoint(info(pc))
         }
+        // The misaligned access is emulated, it's neither fixed up nor dispatched.
         Trap::Exception(Exception::AddressNotAligned) => {
             // error!("address not aligned: {:#x?}", tf);
+            // ALE doesn't tell whether it was a load or a store, only the class is counted.
+            count_trap(TrapType::LoadMisaligned(info(badv::read().vaddr())));
             unsafe { emulate_load_store_insn(tf) }
-            TrapType::Unknown
+            return TrapType::Unknown;
         }
         Trap::Interrupt(_) => {
             let irq_num: usize = estat.is().trailing_zeros() as usize;
@@ -315,22 +460,59 @@ fn loongarch64_trap_handler(tf: &mut TrapFrame) -> TrapType {
                     ticlr::clear_timer_interrupt();
                     TrapType::Timer
                 }
+                EXT_IRQ => match get_irq() {
+                    Some(irq) => TrapType::Irq(irq),
+                    None => {
+                        count_spurious();
+                        return TrapType::Unknown;
+                    }
+                },
                 _ => panic!("unknown interrupt: {}", irq_num),
             }
         }
-        Trap::Exception(Exception::Syscall) => TrapType::SysCall,
+        Trap::Exception(Exception::Syscall) => {
+            tf.orig_a0 = tf.regs[4];
+            TrapType::SysCall
+        }
         Trap::Exception(Exception::StorePageFault)
         | Trap::Exception(Exception::PageModifyFault) => {
-            TrapType::StorePageFault(badv::read().vaddr())
+            TrapType::StorePageFault(info(badv::read().vaddr()))
         }
         Trap::Exception(Exception::PageNonExecutableFault)
         | Trap::Exception(Exception::FetchPageFault) => {
-            TrapType::InstructionPageFault(badv::read().vaddr())
+            TrapType::InstructionPageFault(info(badv::read().vaddr()))
         }
         // Load Fault
         Trap::Exception(Exception::LoadPageFault)
         | Trap::Exception(Exception::PageNonReadableFault) => {
-            TrapType::LoadPageFault(badv::read().vaddr())
+            TrapType::LoadPageFault(info(badv::read().vaddr()))
+        }
+        // PPI and ADEM don't tell whether it was a load or a store.
+        Trap::Exception(Exception::PagePrivilegeIllegal) if badv::read().vaddr() == tf.era => {
+            TrapType::InstructionAccessFault(info(tf.era))
+        }
+        Trap::Exception(Exception::PagePrivilegeIllegal)
+        | Trap::Exception(Exception::MemoryAccessAddressError)
+        | Trap::Exception(Exception::BoundsCheckFault) => {
+            TrapType::LoadAccessFault(info(badv::read().vaddr()))
+        }
+        Trap::Exception(Exception::FetchInstructionAddressError) => {
+            TrapType::InstructionAccessFault(info(badv::read().vaddr()))
+        }
+        Trap::Exception(Exception::InstructionNotExist)
+        | Trap::Exception(Exception::InstructionPrivilegeIllegal) => {
+            TrapType::IllegalInstruction(info(tf.era))
+        }
+        Trap::Exception(Exception::FloatingPointUnavailable) => {
+            TrapType::FpuDisabled(info(tf.era))
+        }
+        // The loongArch64 crate does not decode WPE, SXD and ASXD.
+        Trap::Unknown if estat.ecode() == ECODE_WPE => {
+            // The sub code of the fetch watchpoint is 0.
+            breakpoint::watch_trap(tf, estat.esubcode() == 0)
+        }
+        Trap::Unknown if matches!(estat.ecode(), ECODE_SXD | ECODE_ASXD) => {
+            TrapType::FpuDisabled(info(tf.era))
         }
         Trap::MachineError(_) => todo!(),
         Trap::Unknown => todo!(),
@@ -344,7 +526,22 @@ fn loongarch64_trap_handler(tf: &mut TrapFrame) -> TrapType {
             );
         }
     };
+    count_trap(trap_type);
+    // The kernel uses the fpu, the state loaded in it will be changed.
+    if !user && matches!(trap_type, TrapType::FpuDisabled(_)) {
+        fpu::invalidate();
+        fpu::set_enabled(true, false);
+        return trap_type;
+    }
+    // The emulated instruction is neither fixed up nor dispatched.
+    if emulate_insn(tf, trap_type) {
+        return TrapType::Unknown;
+    }
+    // The marked kernel instruction is retried or jumps to the fixup code.
+    if fixup_kernel_fault(tf, trap_type, token) {
+        return trap_type;
+    }
     // info!("return to addr: {:#x}", tf.era);
-    unsafe { crate::components::trap::_interrupt_for_arch(tf, trap_type, 0) };
+    dispatch_trap(tf, trap_type, token);
     trap_type
 }
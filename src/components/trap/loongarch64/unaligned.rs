@@ -1,6 +1,4 @@
 #![allow(dead_code)]
-use core::arch::global_asm;
-
 use loongArch64::register::badv;
 
 use crate::components::trapframe::TrapFrame;
@@ -38,24 +36,6 @@ pub const FSTXD_OP: u32 = 0x7078;
 pub const FLDXS_OP: u32 = 0x7060;
 pub const FLDXD_OP: u32 = 0x7068;
 
-global_asm!(
-    r#"
-    // 2, 4, 1
-    .macro fixup_ex from, to, fix
-    .if \fix
-        .section .fixup, "ax"
-    \to: 
-        li.w	$a0, -1
-        jr	$ra
-        .previous
-    .endif
-        .section __ex_table, "a"
-        .word	\from\()b, \to\()b
-        .previous
-    .endm
-"#
-);
-
 #[naked]
 unsafe extern "C" fn unaligned_read(addr: u64, value: &mut u64, n: u64, symbol: u32) -> i32 {
     core::arch::asm!(
@@ -88,10 +68,12 @@ unsafe extern "C" fn unaligned_read(addr: u64, value: &mut u64, n: u64, symbol:
         5:	li.w    $a0, -1
             jr	    $ra
 
-            fixup_ex 1, 6, 1
-            fixup_ex 2, 6, 0
-            fixup_ex 4, 6, 0
+        6:	li.w    $a0, -1
+            jr	    $ra
         ",
+        crate::components::extable::ex_table!("1b", "6b"),
+        crate::components::extable::ex_table!("2b", "6b"),
+        crate::components::extable::ex_table!("4b", "6b"),
         options(noreturn)
     )
 }
@@ -115,9 +97,11 @@ unsafe extern "C" fn unaligned_write(_addr: u64, _value: u64, _n: u64) -> i32 {
     
     3:	li.w    $a0, -1
         jr	    $ra
-    
-        fixup_ex 2, 4, 1
+
+    4:	li.w    $a0, -1
+        jr	    $ra
         ",
+        crate::components::extable::ex_table!("2b", "4b"),
         options(noreturn)
     )
 }
@@ -1,44 +1,244 @@
 //! Define and initialize the trap handler.
 //!
-//!
+//! The handlers can be registered at runtime, see [handler] for details.
+
+mod emulate;
+mod handler;
+mod stats;
 
 use super::irq::IRQVector;
 use super::trapframe::TrapFrame;
 
+pub use emulate::*;
+pub use handler::*;
+pub use stats::*;
+
 super::define_arch_mods!();
 
+/// Details of the access that raised a fault-like trap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultInfo {
+    /// The faulting virtual address.
+    ///
+    /// For traps that have no data address, such as breakpoints and
+    /// illegal instructions, this is the address of the trapping instruction.
+    pub addr: usize,
+    /// Whether the trap was raised while running in user mode.
+    pub user: bool,
+}
+
+/// Trap Type
+///
+/// Every variant has the same meaning on all architectures.
+/// - `*PageFault`: the page table has no valid mapping or denies the access.
+/// - `*AccessFault`: the address itself can't be accessed, regardless of the page table.
+/// - `*Misaligned`: the address is not aligned to the access size.
 #[derive(Debug, Clone, Copy)]
 pub enum TrapType {
-    Breakpoint,
+    Breakpoint(FaultInfo),
     SysCall,
     Timer,
     Unknown,
     SupervisorExternal,
-    StorePageFault(usize),
-    LoadPageFault(usize),
-    InstructionPageFault(usize),
-    IllegalInstruction(usize),
+    StorePageFault(FaultInfo),
+    LoadPageFault(FaultInfo),
+    InstructionPageFault(FaultInfo),
+    StoreAccessFault(FaultInfo),
+    LoadAccessFault(FaultInfo),
+    InstructionAccessFault(FaultInfo),
+    StoreMisaligned(FaultInfo),
+    LoadMisaligned(FaultInfo),
+    InstructionMisaligned(FaultInfo),
+    /// The stack pointer or an access was misaligned while alignment checking is enabled.
+    AlignmentCheck(FaultInfo),
+    IllegalInstruction(FaultInfo),
+    /// The general protection fault of x86_64, raised by the privileged
+    /// instructions and the non-canonical addresses, the address is the
+    /// faulting instruction.
+    GeneralProtection(FaultInfo),
+    /// A floating point or SIMD instruction was executed while FP/SIMD is disabled.
+    FpuDisabled(FaultInfo),
+    /// The hardware watchpoint was hit, the address is the watched data address.
+    Watchpoint(FaultInfo),
+    /// The single-step was completed, the address is the next instruction.
+    SingleStep(FaultInfo),
     Irq(IRQVector),
 }
 
+impl TrapType {
+    /// Get the fault details if this trap carries them.
+    pub fn fault_info(&self) -> Option<FaultInfo> {
+        match *self {
+            TrapType::Breakpoint(info)
+            | TrapType::StorePageFault(info)
+            | TrapType::LoadPageFault(info)
+            | TrapType::InstructionPageFault(info)
+            | TrapType::StoreAccessFault(info)
+            | TrapType::LoadAccessFault(info)
+            | TrapType::InstructionAccessFault(info)
+            | TrapType::StoreMisaligned(info)
+            | TrapType::LoadMisaligned(info)
+            | TrapType::InstructionMisaligned(info)
+            | TrapType::AlignmentCheck(info)
+            | TrapType::IllegalInstruction(info)
+            | TrapType::GeneralProtection(info)
+            | TrapType::FpuDisabled(info)
+            | TrapType::Watchpoint(info)
+            | TrapType::SingleStep(info) => Some(info),
+            _ => None,
+        }
+    }
+
+    /// Check if the trap is raised by the debug facilities, the faulting
+    /// kernel instruction of it shouldn't be fixed up by the exception table.
+    #[inline]
+    pub(crate) fn is_debug(&self) -> bool {
+        matches!(
+            self,
+            TrapType::Breakpoint(_) | TrapType::Watchpoint(_) | TrapType::SingleStep(_)
+        )
+    }
+}
+
+/// The kind of the memory access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Load,
+    Store,
+    Execute,
+}
+
+/// The reason why [run_user_task] returned.
+///
+/// Every [TrapType] has a corresponding reason, so the scheduler can handle
+/// the trap after the task escaped instead of inside the interrupt hook.
+/// The addresses are the same as [FaultInfo::addr].
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EscapeReason {
     NoReason,
-    IRQ,
+    Irq(IRQVector),
+    /// `SupervisorExternal` interrupt which has no irq vector.
+    External,
     Timer,
     SysCall,
+    Breakpoint(usize),
+    PageFault { addr: usize, kind: AccessKind },
+    AccessFault { addr: usize, kind: AccessKind },
+    Misaligned { addr: usize, kind: AccessKind },
+    AlignmentCheck(usize),
+    IllegalInstruction(usize),
+    GeneralProtection(usize),
+    FpuDisabled(usize),
+    Watchpoint(usize),
+    SingleStep(usize),
 }
 
-// TODO: Implement Into EscapeReason
-impl Into<EscapeReason> for TrapType {
-    fn into(self) -> EscapeReason {
-        match self {
+impl From<TrapType> for EscapeReason {
+    fn from(trap_type: TrapType) -> Self {
+        use AccessKind::*;
+        match trap_type {
+            TrapType::Breakpoint(info) => EscapeReason::Breakpoint(info.addr),
             TrapType::SysCall => EscapeReason::SysCall,
-            _ => EscapeReason::NoReason,
+            TrapType::Timer => EscapeReason::Timer,
+            TrapType::Unknown => EscapeReason::NoReason,
+            TrapType::SupervisorExternal => EscapeReason::External,
+            TrapType::StorePageFault(info) => EscapeReason::PageFault {
+                addr: info.addr,
+                kind: Store,
+            },
+            TrapType::LoadPageFault(info) => EscapeReason::PageFault {
+                addr: info.addr,
+                kind: Load,
+            },
+            TrapType::InstructionPageFault(info) => EscapeReason::PageFault {
+                addr: info.addr,
+                kind: Execute,
+            },
+            TrapType::StoreAccessFault(info) => EscapeReason::AccessFault {
+                addr: info.addr,
+                kind: Store,
+            },
+            TrapType::LoadAccessFault(info) => EscapeReason::AccessFault {
+                addr: info.addr,
+                kind: Load,
+            },
+            TrapType::InstructionAccessFault(info) => EscapeReason::AccessFault {
+                addr: info.addr,
+                kind: Execute,
+            },
+            TrapType::StoreMisaligned(info) => EscapeReason::Misaligned {
+                addr: info.addr,
+                kind: Store,
+            },
+            TrapType::LoadMisaligned(info) => EscapeReason::Misaligned {
+                addr: info.addr,
+                kind: Load,
+            },
+            TrapType::InstructionMisaligned(info) => EscapeReason::Misaligned {
+                addr: info.addr,
+                kind: Execute,
+            },
+            TrapType::AlignmentCheck(info) => EscapeReason::AlignmentCheck(info.addr),
+            TrapType::IllegalInstruction(info) => EscapeReason::IllegalInstruction(info.addr),
+            TrapType::GeneralProtection(info) => EscapeReason::GeneralProtection(info.addr),
+            TrapType::FpuDisabled(info) => EscapeReason::FpuDisabled(info.addr),
+            TrapType::Watchpoint(info) => EscapeReason::Watchpoint(info.addr),
+            TrapType::SingleStep(info) => EscapeReason::SingleStep(info.addr),
+            TrapType::Irq(irq) => EscapeReason::Irq(irq),
         }
     }
 }
 
+/// A token pointing to the kernel stack position saved by [TrapFrame::into_user].
+///
+/// The trap handlers receive it when the task traps back, call
+/// [ThreadToken::restore] to return to that position.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadToken(usize);
+
+impl ThreadToken {
+    /// The token of the traps which are not from [TrapFrame::into_user].
+    pub(crate) const NONE: ThreadToken = ThreadToken(0);
+
+    /// Check if the token points to a saved kernel stack position.
+    #[inline]
+    pub const fn is_valid(&self) -> bool {
+        self.0 != 0
+    }
+
+    /// Return to the position saved by [TrapFrame::into_user],
+    /// the `into_user` call returns `value`.
+    ///
+    /// # Safety
+    ///
+    /// The stack frames above the saved position are discarded without
+    /// running their destructors. The token must be valid and the
+    /// `into_user` call must not have returned.
+    pub unsafe fn restore(self, value: usize) -> ! {
+        assert!(self.is_valid(), "restore an invalid thread token");
+        __restore_token(self.0, value)
+    }
+}
+
+impl TrapFrame {
+    /// Enter the user mode with this trap frame.
+    ///
+    /// The traps from the user are dispatched to the trap handlers with a
+    /// [ThreadToken], then the task enters the user mode again. This returns
+    /// the value passed to [ThreadToken::restore].
+    pub fn into_user(&mut self) -> usize {
+        unsafe { __into_user(self, into_user_loop) }
+    }
+}
+
+/// Run the user task until a handler restores the token.
+extern "C" fn into_user_loop(tf: &mut TrapFrame, token: ThreadToken) -> ! {
+    loop {
+        run_user_once(tf, token);
+    }
+}
+
 extern "Rust" {
-    pub(crate) fn _interrupt_for_arch(ctx: &mut TrapFrame, trap_type: TrapType, token: usize);
+    pub(crate) fn _interrupt_for_arch(ctx: &mut TrapFrame, trap_type: TrapType, token: ThreadToken);
 }
@@ -1,12 +1,23 @@
-use crate::components::{consts::VIRT_ADDR_START, timer, trapframe::TrapFrame};
+mod misaligned;
+
+use crate::components::trapframe::{kernel_frame, TrapFrame};
+use crate::components::{consts::VIRT_ADDR_START, timer};
 use core::arch::{asm, global_asm};
 use riscv::register::{
     scause::{self, Exception, Interrupt, Trap},
+    sstatus::{self, FS},
     stval, stvec,
 };
 
-use crate::components::trap::TrapType;
-use crate::components::trap::EscapeReason;
+use crate::components::breakpoint;
+use crate::components::fpu;
+use crate::components::irq::{get_irq, has_irq_controller};
+use crate::components::uaccess::copy_from_user;
+use crate::VirtAddr;
+use crate::components::trap::{
+    count_spurious, count_trap, dispatch_trap, emulate_insn, fixup_kernel_fault, EscapeReason,
+    FaultInfo, ThreadToken, TrapType,
+};
 
 global_asm!(
     r"
@@ -90,34 +101,161 @@ pub(crate) fn init() {
     crate::components::timer::init();
 }
 
-// 内核中断回调
+/// Read the instruction at the given address.
+///
+/// Read it as two halfwords, the instruction may be only 2-byte aligned.
+/// The user instruction is copied from the user, `None` if the copy faulted.
+fn read_insn(pc: usize, user: bool) -> Option<u32> {
+    let read_half = |addr: usize| match user {
+        true => {
+            let mut buf = [0u8; 2];
+            copy_from_user(&mut buf, VirtAddr::new(addr)).ok()?;
+            Some(u16::from_le_bytes(buf) as u32)
+        }
+        false => Some(unsafe { (addr as *const u16).read_volatile() } as u32),
+    };
+    let low = read_half(pc)?;
+    if low & 0b11 != 0b11 {
+        return Some(low);
+    }
+    Some(low | (read_half(pc + 2)? << 16))
+}
+
+/// The `ebreak` instruction.
+const EBREAK: u32 = 0x0010_0073;
+/// The `c.ebreak` instruction.
+const C_EBREAK: u32 = 0x9002;
+
+/// Get the length of the given instruction.
+#[inline]
+fn insn_len(insn: u32) -> usize {
+    match insn & 0b11 == 0b11 {
+        true => 4,
+        false => 2,
+    }
+}
+
+/// Check if the instruction is a floating point instruction.
+fn is_fp_insn(insn: u32) -> bool {
+    if insn_len(insn) == 2 {
+        // c.fld, c.fsd, c.fldsp, c.fsdsp
+        let funct3 = (insn >> 13) & 0b111;
+        return matches!(insn & 0b11, 0b00 | 0b10) && matches!(funct3, 0b001 | 0b101);
+    }
+    match insn & 0x7f {
+        // LOAD-FP and STORE-FP with the scalar widths
+        0x07 | 0x27 => matches!((insn >> 12) & 0b111, 0b001..=0b100),
+        // FMADD, FMSUB, FNMSUB, FNMADD, OP-FP
+        0x43 | 0x47 | 0x4b | 0x4f | 0x53 => true,
+        // csr instructions access fflags, frm or fcsr
+        0x73 => (insn >> 12) & 0b11 != 0 && matches!(insn >> 20, 0x1..=0x3),
+        _ => false,
+    }
+}
+
+/// Check if the instruction is a vector instruction.
+fn is_vector_insn(insn: u32) -> bool {
+    if insn_len(insn) == 2 {
+        return false;
+    }
+    match insn & 0x7f {
+        // OP-V
+        0x57 => true,
+        // LOAD-FP and STORE-FP with the vector widths
+        0x07 | 0x27 => matches!((insn >> 12) & 0b111, 0b000 | 0b101..=0b111),
+        // csr instructions access vstart, vxsat, vxrm, vcsr, vl, vtype or vlenb
+        0x73 => {
+            (insn >> 12) & 0b11 != 0 && matches!(insn >> 20, 0x008..=0x00a | 0x00f | 0xc20..=0xc22)
+        }
+        _ => false,
+    }
+}
+
+/// Kernel trap entry, called by [kernelvec].
+///
+/// [kernel_callback] returns the [TrapType] through a pointer passed in `a0`,
+/// [kernelvec] only passes the trap frame in `a0`.
 #[no_mangle]
-fn kernel_callback(context: &mut TrapFrame) -> TrapType {
+extern "C" fn kernel_trap_entry(context: *mut TrapFrame) {
+    kernel_callback(unsafe { kernel_frame(context) }, ThreadToken::NONE);
+}
+
+// 内核中断回调
+fn kernel_callback(context: &mut TrapFrame, token: ThreadToken) -> TrapType {
     let scause = scause::read();
     let stval = stval::read();
+    let user = context.from_user();
+    let info = |addr| FaultInfo { addr, user };
     let trap_type = match scause.cause() {
         // 中断异常
         Trap::Exception(Exception::Breakpoint) => {
-            context.sepc += 2;
-            TrapType::Breakpoint
-        }
-        Trap::Exception(Exception::LoadFault) => {
-            if stval > VIRT_ADDR_START {
-                panic!("kernel error: {:#x}", stval);
+            let pc = context.sepc;
+            if user && context.single_step {
+                // The icount trigger fires after the instruction was executed.
+                context.single_step = false;
+                TrapType::SingleStep(info(pc))
+            } else {
+                match read_insn(pc, user) {
+                    // The user page was unmapped after the trap was raised.
+                    None => TrapType::InstructionPageFault(info(pc)),
+                    Some(insn) if insn == EBREAK || insn == C_EBREAK => {
+                        context.sepc += insn_len(insn);
+                        TrapType::Breakpoint(info(pc))
+                    }
+                    // The execute trigger fires before the instruction.
+                    Some(_) if stval == pc => TrapType::Breakpoint(info(pc)),
+                    Some(_) => TrapType::Watchpoint(info(stval)),
+                }
             }
-            TrapType::Unknown
         }
-        Trap::Exception(Exception::UserEnvCall) => TrapType::SysCall,
+        Trap::Exception(Exception::LoadFault) => TrapType::LoadAccessFault(info(stval)),
+        Trap::Exception(Exception::UserEnvCall) => {
+            context.orig_a0 = context.x[10];
+            TrapType::SysCall
+        }
         // 时钟中断
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
             timer::set_next_timeout();
             TrapType::Timer
         }
-        Trap::Exception(Exception::StorePageFault) => TrapType::StorePageFault(stval),
-        Trap::Exception(Exception::StoreFault) => TrapType::StorePageFault(stval),
-        Trap::Exception(Exception::InstructionPageFault) => TrapType::InstructionPageFault(stval),
-        Trap::Exception(Exception::IllegalInstruction) => TrapType::IllegalInstruction(stval),
-        Trap::Exception(Exception::LoadPageFault) => TrapType::LoadPageFault(stval),
+        Trap::Exception(Exception::StorePageFault) => TrapType::StorePageFault(info(stval)),
+        Trap::Exception(Exception::StoreFault) => TrapType::StoreAccessFault(info(stval)),
+        Trap::Exception(Exception::InstructionPageFault) => {
+            TrapType::InstructionPageFault(info(stval))
+        }
+        Trap::Exception(Exception::InstructionFault) => {
+            TrapType::InstructionAccessFault(info(stval))
+        }
+        Trap::Exception(Exception::LoadPageFault) => TrapType::LoadPageFault(info(stval)),
+        Trap::Exception(Exception::LoadMisaligned) => TrapType::LoadMisaligned(info(stval)),
+        Trap::Exception(Exception::StoreMisaligned) => TrapType::StoreMisaligned(info(stval)),
+        Trap::Exception(Exception::InstructionMisaligned) => {
+            TrapType::InstructionMisaligned(info(stval))
+        }
+        Trap::Exception(Exception::IllegalInstruction) => {
+            // stval may hold the faulting instruction, it is zero if not supported.
+            let insn = match stval {
+                0 => read_insn(context.sepc, user),
+                _ => Some(stval as u32),
+            };
+            match insn {
+                None => TrapType::InstructionPageFault(info(context.sepc)),
+                Some(insn) if context.sstatus.fs() == FS::Off && is_fp_insn(insn) => {
+                    TrapType::FpuDisabled(info(context.sepc))
+                }
+                Some(_) => TrapType::IllegalInstruction(info(context.sepc)),
+            }
+        }
+        // The irq number is claimed from the PLIC, the handler acknowledges it.
+        Trap::Interrupt(Interrupt::SupervisorExternal) if has_irq_controller() => {
+            match get_irq() {
+                Some(irq) => TrapType::Irq(irq),
+                None => {
+                    count_spurious();
+                    return TrapType::Unknown;
+                }
+            }
+        }
         Trap::Interrupt(Interrupt::SupervisorExternal) => TrapType::SupervisorExternal,
         _ => {
             log::error!(
@@ -130,7 +268,29 @@ fn kernel_callback(context: &mut TrapFrame) -> TrapType {
             panic!("未知中断: {:#x?}", context);
         }
     };
-    unsafe { crate::components::trap::_interrupt_for_arch(context, trap_type, 0) };
+    count_trap(trap_type);
+    // The user access was emulated in `run_user_once` if possible.
+    let misaligned = matches!(
+        trap_type,
+        TrapType::LoadMisaligned(_) | TrapType::StoreMisaligned(_)
+    );
+    if !user && misaligned && misaligned::emulate(context, stval) {
+        return TrapType::Unknown;
+    }
+    // The emulated instruction is neither fixed up nor dispatched.
+    if emulate_insn(context, trap_type) {
+        return TrapType::Unknown;
+    }
+    // The marked kernel instruction is retried or jumps to the fixup code.
+    if fixup_kernel_fault(context, trap_type, token) {
+        return trap_type;
+    }
+    if let TrapType::LoadAccessFault(info) = trap_type {
+        if !user && info.addr > VIRT_ADDR_START {
+            panic!("kernel error: {:#x}", info.addr);
+        }
+    }
+    dispatch_trap(context, trap_type, token);
     trap_type
 }
 
@@ -146,6 +306,19 @@ pub unsafe extern "C" fn kernelvec() {
             bnez    sp, uservec
             csrr    sp, sscratch
 
+            // Check if the trap frame would be saved in the guard area of
+            // the kernel stacks, only sp is usable, sscratch holds it.
+            addi    sp, sp, -{cx_size}
+            srai    sp, sp, 30
+            addi    sp, sp, 1
+            bnez    sp, 1f
+            csrr    sp, sscratch
+            addi    sp, sp, -{cx_size}
+            srli    sp, sp, {kstack_shift}
+            andi    sp, sp, 1
+            beqz    sp, 2f
+        1:
+            csrr    sp, sscratch
             addi    sp, sp, -{cx_size}
             
             SAVE_GENERAL_REGS
@@ -153,12 +326,27 @@ pub unsafe extern "C" fn kernelvec() {
 
             mv      a0, sp
 
-            call kernel_callback
+            call kernel_trap_entry
 
             LOAD_GENERAL_REGS
             sret
+
+        2:
+            // Report the overflow on the emergency stack.
+            la      sp, {emergency_stack} + {emergency_stack_size}
+            addi    sp, sp, -{cx_size}
+
+            SAVE_GENERAL_REGS
+            csrw    sscratch, x0
+
+            mv      a0, sp
+            call    {stack_overflow}
         ",
         cx_size = const crate::components::trapframe::TRAPFRAME_SIZE,
+        kstack_shift = const crate::components::kstack::KSTACK_SHIFT,
+        emergency_stack = sym crate::components::kstack::EMERGENCY_STACK,
+        emergency_stack_size = const crate::components::kstack::EMERGENCY_STACK_SIZE,
+        stack_overflow = sym crate::components::kstack::kernel_stack_overflow,
         options(noreturn)
     )
 }
@@ -200,9 +388,6 @@ extern "C" fn user_restore(context: *mut TrapFrame) {
             "   sd      sp, 8*0(a0)
                 csrw    sscratch, a0
                 mv      sp, a0
-            
-                .short  0x2452      # fld  fs0, 272(sp)
-                .short  0x24f2      # fld  fs1, 280(sp)
 
                 LOAD_GENERAL_REGS
                 sret
@@ -225,9 +410,6 @@ pub unsafe extern "C" fn uservec() {
         SAVE_GENERAL_REGS
         csrw    sscratch, x0
 
-        .word   0x10813827          # fsd fs0, 272(sp)
-        .word   0x10913c27          # fsd fs1, 280(sp)
-
         mv      a0, sp
         ld      sp, 0*8(a0)
         sd      x0, 0*8(a0)
@@ -262,14 +444,155 @@ pub unsafe extern "C" fn uservec() {
 
 /// Return EscapeReson related to interrupt type.
 pub fn run_user_task(context: &mut TrapFrame) -> EscapeReason {
-    user_restore(context);
-    kernel_callback(context).into()
+    run_user_once(context, ThreadToken::NONE)
+}
+
+/// Run the user task until it traps back, dispatch the trap with the `token`.
+pub(crate) fn run_user_once(context: &mut TrapFrame, token: ThreadToken) -> EscapeReason {
+    loop {
+        // The kernel changed the fpu registers after the state was saved.
+        if sstatus::read().fs() == FS::Dirty {
+            fpu::invalidate();
+        }
+        let loaded = fpu::is_loaded(&context.fp);
+        match loaded {
+            true => context.set_fs(FS::Clean),
+            false => context.set_fs(FS::Off),
+        }
+        match loaded && context.fp.ext().is_some() {
+            true => context.set_vs(FS::Clean),
+            false => context.set_vs(FS::Off),
+        }
+        breakpoint::step_enter(context);
+        user_restore(context);
+        breakpoint::step_exit();
+        if context.sstatus.fs() == FS::Dirty || context.vs() == FS::Dirty {
+            context.fp.save();
+        }
+        unsafe { sstatus::set_fs(FS::Clean) };
+        // Resume the task if the fpu state was loaded or the access was emulated.
+        if load_fpu_state(context) || emulate_misaligned(context) {
+            continue;
+        }
+        // The trap handled in place, such as the emulated instruction, resumes the task.
+        match kernel_callback(context, token) {
+            TrapType::Unknown => continue,
+            trap_type => return trap_type.into(),
+        }
+    }
+}
+
+/// Load the fpu state if the user task trapped by the disabled fpu or vector unit.
+///
+/// The vector instructions are illegal if the task didn't enable the extended state.
+fn load_fpu_state(context: &mut TrapFrame) -> bool {
+    if scause::read().cause() != Trap::Exception(Exception::IllegalInstruction) {
+        return false;
+    }
+    // The fault of the fetch is reported by the `kernel_callback`.
+    let insn = match stval::read() {
+        0 => match read_insn(context.sepc, true) {
+            Some(insn) => insn,
+            None => return false,
+        },
+        stval => stval as u32,
+    };
+    let fp = context.sstatus.fs() == FS::Off && is_fp_insn(insn);
+    let vector =
+        context.fp.ext().is_some() && context.vs() == FS::Off && is_vector_insn(insn);
+    if !fp && !vector {
+        return false;
+    }
+    count_trap(TrapType::FpuDisabled(FaultInfo {
+        addr: context.sepc,
+        user: true,
+    }));
+    fpu::load(&mut context.fp);
+    true
+}
+
+/// Emulate the misaligned load or store if the user task trapped by it.
+fn emulate_misaligned(context: &mut TrapFrame) -> bool {
+    let stval = stval::read();
+    let info = FaultInfo {
+        addr: stval,
+        user: true,
+    };
+    let trap_type = match scause::read().cause() {
+        Trap::Exception(Exception::LoadMisaligned) => TrapType::LoadMisaligned(info),
+        Trap::Exception(Exception::StoreMisaligned) => TrapType::StoreMisaligned(info),
+        _ => return false,
+    };
+    // The access is counted by the `kernel_callback` if it can't be emulated.
+    if !misaligned::emulate(context, stval) {
+        return false;
+    }
+    count_trap(trap_type);
+    true
 }
 
 /// Run user task until interrupt is received.
 pub fn run_user_task_forever(context: &mut TrapFrame) -> ! {
     loop {
-        user_restore(context);
-        kernel_callback(context);
+        run_user_once(context, ThreadToken::NONE);
     }
 }
+
+/// Save the callee saved registers on the kernel stack, then call `entry`
+/// with the trap frame and the [ThreadToken] pointing to the saved registers.
+#[naked]
+pub(crate) unsafe extern "C" fn __into_user(
+    tf: *mut TrapFrame,
+    entry: extern "C" fn(&mut TrapFrame, ThreadToken) -> !,
+) -> usize {
+    asm!(
+        "
+            addi    sp, sp, -14*8
+            sd      ra, 0*8(sp)
+            sd      s0, 1*8(sp)
+            sd      s1, 2*8(sp)
+            sd      s2, 3*8(sp)
+            sd      s3, 4*8(sp)
+            sd      s4, 5*8(sp)
+            sd      s5, 6*8(sp)
+            sd      s6, 7*8(sp)
+            sd      s7, 8*8(sp)
+            sd      s8, 9*8(sp)
+            sd      s9, 10*8(sp)
+            sd      s10, 11*8(sp)
+            sd      s11, 12*8(sp)
+            mv      t0, a1
+            mv      a1, sp
+            jr      t0
+        ",
+        options(noreturn)
+    )
+}
+
+/// Restore the callee saved registers saved by [__into_user],
+/// then return `value` from it.
+#[naked]
+pub(crate) unsafe extern "C" fn __restore_token(token: usize, value: usize) -> ! {
+    asm!(
+        "
+            mv      sp, a0
+            ld      ra, 0*8(sp)
+            ld      s0, 1*8(sp)
+            ld      s1, 2*8(sp)
+            ld      s2, 3*8(sp)
+            ld      s3, 4*8(sp)
+            ld      s4, 5*8(sp)
+            ld      s5, 6*8(sp)
+            ld      s6, 7*8(sp)
+            ld      s7, 8*8(sp)
+            ld      s8, 9*8(sp)
+            ld      s9, 10*8(sp)
+            ld      s10, 11*8(sp)
+            ld      s11, 12*8(sp)
+            addi    sp, sp, 14*8
+            mv      a0, a1
+            ret
+        ",
+        options(noreturn)
+    )
+}
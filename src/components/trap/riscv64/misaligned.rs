@@ -0,0 +1,220 @@
+//! Emulate the misaligned loads and stores.
+//!
+//! The misaligned accesses trap to the supervisor if the SBI doesn't emulate
+//! them, or if there is no SBI when running with `-bios none`. The access is
+//! split into the byte accesses, the faults during them are fixed up by the
+//! exception table, and the trap is reported as before if it can't be emulated.
+
+use crate::components::fpu::FpRegs;
+use crate::components::trapframe::TrapFrame;
+use crate::components::uaccess::{__copy_user, copy_from_user, copy_to_user};
+use crate::VirtAddr;
+
+/// The register of the load or store.
+#[derive(Debug, Clone, Copy)]
+enum Reg {
+    /// The general purpose register.
+    X(usize),
+    /// The floating point register.
+    F(usize),
+}
+
+/// The decoded load or store instruction.
+#[derive(Debug, Clone, Copy)]
+struct Access {
+    reg: Reg,
+    /// The size of the access in bytes.
+    len: usize,
+    /// The loaded value is sign extended.
+    signed: bool,
+    store: bool,
+}
+
+impl Access {
+    #[inline]
+    const fn load(reg: Reg, len: usize, signed: bool) -> Option<Self> {
+        Some(Self {
+            reg,
+            len,
+            signed,
+            store: false,
+        })
+    }
+
+    #[inline]
+    const fn store(reg: Reg, len: usize) -> Option<Self> {
+        Some(Self {
+            reg,
+            len,
+            signed: false,
+            store: true,
+        })
+    }
+}
+
+/// Decode the compressed load or store.
+fn decode_compressed(insn: u32) -> Option<Access> {
+    use Reg::*;
+    let funct3 = (insn >> 13) & 0b111;
+    // rd' and rs2' of the quadrant 0.
+    let rs = ((insn >> 2) & 0b111) as usize + 8;
+    // rd and rs2 of the quadrant 2.
+    let rd = ((insn >> 7) & 0x1f) as usize;
+    let rs2 = ((insn >> 2) & 0x1f) as usize;
+    match (insn & 0b11, funct3) {
+        // c.fld, c.lw, c.ld
+        (0b00, 0b001) => Access::load(F(rs), 8, false),
+        (0b00, 0b010) => Access::load(X(rs), 4, true),
+        (0b00, 0b011) => Access::load(X(rs), 8, false),
+        // c.lhu, c.lh and c.sh of the Zcb extension.
+        (0b00, 0b100) => match ((insn >> 10) & 0b111, (insn >> 6) & 1) {
+            (0b001, 0) => Access::load(X(rs), 2, false),
+            (0b001, 1) => Access::load(X(rs), 2, true),
+            (0b011, 0) => Access::store(X(rs), 2),
+            _ => None,
+        },
+        // c.fsd, c.sw, c.sd
+        (0b00, 0b101) => Access::store(F(rs), 8),
+        (0b00, 0b110) => Access::store(X(rs), 4),
+        (0b00, 0b111) => Access::store(X(rs), 8),
+        // c.fldsp, c.lwsp, c.ldsp
+        (0b10, 0b001) => Access::load(F(rd), 8, false),
+        (0b10, 0b010) => Access::load(X(rd), 4, true),
+        (0b10, 0b011) => Access::load(X(rd), 8, false),
+        // c.fsdsp, c.swsp, c.sdsp
+        (0b10, 0b101) => Access::store(F(rs2), 8),
+        (0b10, 0b110) => Access::store(X(rs2), 4),
+        (0b10, 0b111) => Access::store(X(rs2), 8),
+        _ => None,
+    }
+}
+
+/// Decode the load or store instruction.
+fn decode(insn: u32) -> Option<Access> {
+    use Reg::*;
+    if insn & 0b11 != 0b11 {
+        return decode_compressed(insn);
+    }
+    let funct3 = (insn >> 12) & 0b111;
+    let rd = ((insn >> 7) & 0x1f) as usize;
+    let rs2 = ((insn >> 20) & 0x1f) as usize;
+    match (insn & 0x7f, funct3) {
+        // lh, lw, ld, lhu, lwu
+        (0x03, 0b001) => Access::load(X(rd), 2, true),
+        (0x03, 0b010) => Access::load(X(rd), 4, true),
+        (0x03, 0b011) => Access::load(X(rd), 8, false),
+        (0x03, 0b101) => Access::load(X(rd), 2, false),
+        (0x03, 0b110) => Access::load(X(rd), 4, false),
+        // sh, sw, sd
+        (0x23, 0b001) => Access::store(X(rs2), 2),
+        (0x23, 0b010) => Access::store(X(rs2), 4),
+        (0x23, 0b011) => Access::store(X(rs2), 8),
+        // flw, fld
+        (0x07, 0b010) => Access::load(F(rd), 4, false),
+        (0x07, 0b011) => Access::load(F(rd), 8, false),
+        // fsw, fsd
+        (0x27, 0b010) => Access::store(F(rs2), 4),
+        (0x27, 0b011) => Access::store(F(rs2), 8),
+        _ => None,
+    }
+}
+
+/// Read the bytes at `addr`, return false if faulted.
+fn read_bytes(addr: usize, buf: &mut [u8], user: bool) -> bool {
+    match user {
+        true => copy_from_user(buf, VirtAddr::new(addr)).is_ok(),
+        false => unsafe { __copy_user(buf.as_mut_ptr(), addr as _, buf.len()) == 0 },
+    }
+}
+
+/// Write the bytes to `addr`, return false if faulted.
+fn write_bytes(addr: usize, buf: &[u8], user: bool) -> bool {
+    match user {
+        true => copy_to_user(VirtAddr::new(addr), buf).is_ok(),
+        false => unsafe { __copy_user(addr as _, buf.as_ptr(), buf.len()) == 0 },
+    }
+}
+
+/// Fetch the instruction at `pc`, it may be only 2-byte aligned.
+fn fetch_insn(pc: usize, user: bool) -> Option<u32> {
+    let mut buf = [0u8; 4];
+    if !read_bytes(pc, &mut buf[..2], user) {
+        return None;
+    }
+    if buf[0] & 0b11 == 0b11 && !read_bytes(pc + 2, &mut buf[2..], user) {
+        return None;
+    }
+    Some(u32::from_le_bytes(buf))
+}
+
+/// Read the floating point register.
+///
+/// The user registers were saved in the trap frame when the task trapped back,
+/// the kernel registers are still in the fpu.
+fn read_fpr(context: &TrapFrame, index: usize) -> u64 {
+    match context.from_user() {
+        true => context.fp.regs().f[index],
+        false => {
+            let mut regs = FpRegs::default();
+            regs.save();
+            regs.f[index]
+        }
+    }
+}
+
+/// Write the floating point register.
+///
+/// The user registers are loaded again before the task uses the fpu.
+fn write_fpr(context: &mut TrapFrame, index: usize, value: u64) {
+    match context.from_user() {
+        true => context.fp.regs_mut().f[index] = value,
+        false => {
+            let mut regs = FpRegs::default();
+            regs.save();
+            regs.f[index] = value;
+            regs.restore();
+        }
+    }
+}
+
+/// Emulate the misaligned load or store at `sepc`, `addr` is the faulting address.
+///
+/// Return true and move `sepc` to the next instruction if emulated.
+pub(super) fn emulate(context: &mut TrapFrame, addr: usize) -> bool {
+    let user = context.from_user();
+    let Some(insn) = fetch_insn(context.sepc, user) else {
+        return false;
+    };
+    let Some(access) = decode(insn) else {
+        return false;
+    };
+    let mut buf = [0u8; 8];
+    if access.store {
+        let value = match access.reg {
+            Reg::X(index) => context.x[index] as u64,
+            Reg::F(index) => read_fpr(context, index),
+        };
+        buf = value.to_le_bytes();
+        if !write_bytes(addr, &buf[..access.len], user) {
+            return false;
+        }
+    } else {
+        if !read_bytes(addr, &mut buf[..access.len], user) {
+            return false;
+        }
+        let shift = 64 - access.len * 8;
+        let value = match access.signed {
+            true => ((u64::from_le_bytes(buf) << shift) as i64 >> shift) as u64,
+            false => u64::from_le_bytes(buf),
+        };
+        match access.reg {
+            Reg::X(0) => {}
+            Reg::X(index) => context.x[index] = value as usize,
+            // The single precision value is NaN-boxed.
+            Reg::F(index) if access.len == 4 => write_fpr(context, index, value | !0 << 32),
+            Reg::F(index) => write_fpr(context, index, value),
+        }
+    }
+    context.sepc += super::insn_len(insn);
+    true
+}
@@ -0,0 +1,226 @@
+//! Per-cpu interrupt and trap statistics.
+//!
+//! Every trap is counted by its [TrapClass] and the irqs are also
+//! counted by the irq number, the spurious interrupts of the interrupt
+//! controller are counted separately. The statistics are like the rows of
+//! `/proc/interrupts` on Linux, one column for every cpu.
+//!
+//! ```rust
+//! for row in trap_stats() {
+//!     print!("{}:", row.kind);
+//!     row.counts().for_each(|count| print!(" {:>10}", count));
+//!     println!();
+//! }
+//! ```
+//!
+//! TIPS: The counters are allocated by [init](crate::common::init), the traps
+//! before it are not counted. The hart ids may be sparse, such as the apic
+//! ids on x86_64, a cpu takes a free slot of the counters when it counts the
+//! first trap.
+
+use alloc::vec::Vec;
+use core::fmt::{self, Display};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::components::arch::hart_id;
+use crate::components::common::get_cpu_num;
+use crate::utils::LazyInit;
+
+use super::{TrapClass, TrapType, IRQ_HANDLER_NUM};
+
+/// All the trap classes in the order of the rows.
+const CLASSES: [TrapClass; TrapClass::NUM] = [
+    TrapClass::Breakpoint,
+    TrapClass::SysCall,
+    TrapClass::Timer,
+    TrapClass::PageFault,
+    TrapClass::AccessFault,
+    TrapClass::Misaligned,
+    TrapClass::IllegalInstruction,
+    TrapClass::FpuDisabled,
+    TrapClass::Irq,
+    TrapClass::Unknown,
+];
+
+/// The slot isn't taken by a cpu.
+const NO_SLOT: usize = usize::MAX;
+
+/// The zero counter.
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+
+/// The counters of a cpu.
+///
+/// The irq counters are allocated on the heap, there are 16384 of them on
+/// aarch64 to cover the LPIs.
+struct CpuStats {
+    /// The hart id of the cpu owning the slot, [NO_SLOT] if it's free.
+    hart_id: AtomicUsize,
+    irqs: Vec<AtomicUsize>,
+    traps: [AtomicUsize; TrapClass::NUM],
+    spurious: AtomicUsize,
+}
+
+impl CpuStats {
+    fn new() -> Self {
+        Self {
+            hart_id: AtomicUsize::new(NO_SLOT),
+            irqs: (0..IRQ_HANDLER_NUM).map(|_| ZERO).collect(),
+            traps: [ZERO; TrapClass::NUM],
+            spurious: ZERO,
+        }
+    }
+
+    /// Get the counter of the row.
+    fn counter(&self, kind: StatKind) -> Option<&AtomicUsize> {
+        match kind {
+            StatKind::Irq(irq_num) => self.irqs.get(irq_num),
+            StatKind::Trap(class) => Some(&self.traps[class as usize]),
+            StatKind::Spurious => Some(&self.spurious),
+        }
+    }
+}
+
+/// The counters of all the cpus, a slot for every cpu.
+static STATS: LazyInit<Vec<CpuStats>> = LazyInit::new();
+
+/// The index of the slot taken by the current cpu.
+#[polyhal_macro::def_percpu]
+static STATS_SLOT: usize = NO_SLOT;
+
+/// Allocate the counters of all the cpus.
+pub(crate) fn init_stats() {
+    STATS.init_by((0..get_cpu_num()).map(|_| CpuStats::new()).collect());
+}
+
+/// Get the counters of the current cpu, take a free slot if it has none.
+fn current_stats(stats: &[CpuStats]) -> Option<&CpuStats> {
+    let slot = STATS_SLOT.read_current();
+    if slot != NO_SLOT {
+        return stats.get(slot);
+    }
+    let hart_id = hart_id();
+    let slot = stats.iter().position(|stats| {
+        stats
+            .hart_id
+            .compare_exchange(NO_SLOT, hart_id, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+    })?;
+    STATS_SLOT.write_current(slot);
+    stats.get(slot)
+}
+
+/// Get the counters of the cpu `hart_id`.
+fn cpu_stats(stats: &[CpuStats], hart_id: usize) -> Option<&CpuStats> {
+    stats
+        .iter()
+        .find(|stats| stats.hart_id.load(Ordering::Acquire) == hart_id)
+}
+
+/// Increase the counter of the row on the current cpu.
+#[inline]
+fn count(kind: StatKind) {
+    let counter = STATS
+        .try_get()
+        .and_then(|stats| current_stats(stats))
+        .and_then(|stats| stats.counter(kind));
+    if let Some(counter) = counter {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Count the trap on the current cpu.
+///
+/// It's called by the trap entry once the trap is decoded, before the trap
+/// may be handled in place, such as the lazy fpu, the emulated instruction
+/// and the fixup of the kernel fault. [dispatch_trap] doesn't count it.
+///
+/// [dispatch_trap]: super::dispatch_trap
+#[inline]
+pub(crate) fn count_trap(trap_type: TrapType) {
+    if let TrapType::Irq(irq) = trap_type {
+        count(StatKind::Irq(irq.irq_num()));
+    }
+    count(StatKind::Trap(trap_type.class()));
+}
+
+/// Count the spurious interrupt of the interrupt controller on the current cpu.
+#[inline]
+pub(crate) fn count_spurious() {
+    count(StatKind::Spurious);
+}
+
+/// The row of the statistics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatKind {
+    /// The irq with the irq number.
+    Irq(usize),
+    /// The traps of the class, including the irqs.
+    Trap(TrapClass),
+    /// The spurious interrupts of the interrupt controller, they are not dispatched.
+    Spurious,
+}
+
+impl Display for StatKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatKind::Irq(irq_num) => write!(f, "{}", irq_num),
+            StatKind::Trap(class) => write!(f, "{:?}", class),
+            StatKind::Spurious => write!(f, "Spurious"),
+        }
+    }
+}
+
+/// The counts of a row on all the cpus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrapStat {
+    /// The row of the statistics.
+    pub kind: StatKind,
+}
+
+impl TrapStat {
+    /// Get the count on the cpu `hart_id`, it's 0 if the cpu never trapped.
+    pub fn count(&self, hart_id: usize) -> usize {
+        STATS
+            .try_get()
+            .and_then(|stats| cpu_stats(stats, hart_id))
+            .and_then(|stats| stats.counter(self.kind))
+            .map_or(0, |counter| counter.load(Ordering::Relaxed))
+    }
+
+    /// Get the counts on all the cpus which trapped, ordered by the hart id.
+    pub fn counts(&self) -> impl Iterator<Item = usize> + '_ {
+        let mut hart_ids: Vec<usize> = STATS.try_get().map_or(Vec::new(), |stats| {
+            stats
+                .iter()
+                .map(|stats| stats.hart_id.load(Ordering::Acquire))
+                .filter(|&hart_id| hart_id != NO_SLOT)
+                .collect()
+        });
+        hart_ids.sort_unstable();
+        hart_ids.into_iter().map(|hart_id| self.count(hart_id))
+    }
+
+    /// Get the sum of the counts on all the cpus.
+    pub fn total(&self) -> usize {
+        self.counts().sum()
+    }
+}
+
+/// Iterate the rows of the statistics.
+///
+/// The irqs which never fired are skipped, then every trap class and the
+/// spurious interrupts follow.
+pub fn trap_stats() -> impl Iterator<Item = TrapStat> {
+    let irqs = (0..IRQ_HANDLER_NUM)
+        .map(|irq_num| TrapStat {
+            kind: StatKind::Irq(irq_num),
+        })
+        .filter(|stat| stat.total() != 0);
+    let traps = CLASSES.into_iter().map(|class| TrapStat {
+        kind: StatKind::Trap(class),
+    });
+    irqs.chain(traps).chain([TrapStat {
+        kind: StatKind::Spurious,
+    }])
+}
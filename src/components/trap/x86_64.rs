@@ -1,20 +1,30 @@
 use core::arch::{asm, global_asm};
-use core::mem::{offset_of, size_of};
+use core::mem::offset_of;
 
 use bitflags::bitflags;
 use x86_64::registers::model_specific::{Efer, EferFlags, KernelGsBase, LStar, SFMask, Star};
 use x86_64::registers::rflags::RFlags;
 use x86_64::VirtAddr;
 
+use x86::msr::{rdmsr, wrmsr, IA32_MC0_ADDR, IA32_MC0_STATUS, IA32_MCG_CAP, IA32_MCG_STATUS};
 use x86::{controlregs::cr2, irq::*};
 
 use crate::components::arch::apic::{local_apic, vectors::*};
 use crate::components::arch::gdt::{set_tss_kernel_sp, GdtStruct};
+use crate::components::backtrace::SymbolAddr;
 use crate::components::consts::{PIC_VECTOR_OFFSET, SYSCALL_VECTOR};
+use crate::components::breakpoint;
 use crate::components::irq;
-use crate::components::trapframe::{FxsaveArea, TrapFrame, TRAPFRAME_SIZE};
+use crate::components::trapframe::{kernel_frame, TrapFrame, TRAPFRAME_SIZE};
 use crate::components::percpu::PerCPUReserved;
-use crate::components::trap::{EscapeReason, TrapType};
+use crate::components::extable::search_exception_table;
+use crate::components::fpu;
+use crate::components::kstack::{is_stack_guard, report_overflow};
+use crate::components::uaccess::user_access_end;
+use crate::components::trap::{
+    count_spurious, count_trap, dispatch_trap, emulate_insn, fixup_kernel_fault, EscapeReason,
+    FaultInfo, ThreadToken, TrapType,
+};
 
 global_asm!(
     r"
@@ -58,21 +68,92 @@ bitflags! {
     }
 }
 
-// 内核中断回调
+/// The bits of the `IA32_MCG_STATUS`.
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+const MCG_STATUS_MCIP: u64 = 1 << 2;
+
+/// The bits of the `IA32_MCi_STATUS`.
+const MCI_STATUS_VAL: u64 = 1 << 63;
+const MCI_STATUS_UC: u64 = 1 << 61;
+const MCI_STATUS_ADDRV: u64 = 1 << 58;
+const MCI_STATUS_PCC: u64 = 1 << 57;
+
+/// The system control port B, the NMI reason is in the high bits.
+const NMI_REASON_PORT: u16 = 0x61;
+
+/// Decode and report the machine check(#MC) through the error banks.
+///
+/// Panic if the error is uncorrected or the interrupted program can't be resumed.
+fn machine_check(context: &TrapFrame) {
+    let mcg_status = unsafe { rdmsr(IA32_MCG_STATUS) };
+    let banks = unsafe { rdmsr(IA32_MCG_CAP) } as u32 & 0xff;
+    let mut fatal = mcg_status & MCG_STATUS_RIPV == 0;
+    for i in 0..banks {
+        let status = unsafe { rdmsr(IA32_MC0_STATUS + 4 * i) };
+        if status & MCI_STATUS_VAL == 0 {
+            continue;
+        }
+        let addr = match status & MCI_STATUS_ADDRV {
+            0 => 0,
+            _ => unsafe { rdmsr(IA32_MC0_ADDR + 4 * i) },
+        };
+        log::error!("machine check bank {}: status={:#x} addr={:#x}", i, status, addr);
+        fatal |= status & (MCI_STATUS_UC | MCI_STATUS_PCC) != 0;
+        unsafe { wrmsr(IA32_MC0_STATUS + 4 * i, 0) };
+    }
+    if fatal {
+        panic!(
+            "#MC @ {}, mcg_status={:#x}:\n{:#x?}",
+            SymbolAddr(context.rip),
+            mcg_status,
+            context
+        );
+    }
+    unsafe { wrmsr(IA32_MCG_STATUS, mcg_status & !MCG_STATUS_MCIP) };
+}
+
+/// Kernel trap entry, called by [kernelvec].
 #[no_mangle]
-fn kernel_callback(context: &mut TrapFrame) {
+extern "C" fn kernel_trap_entry(context: *mut TrapFrame) {
+    kernel_callback(unsafe { kernel_frame(context) }, ThreadToken::NONE);
+}
+
+// 内核中断回调
+fn kernel_callback(context: &mut TrapFrame, token: ThreadToken) -> TrapType {
+    let user = context.from_user();
+    let info = |addr| FaultInfo { addr, user };
     let trap_type = match context.vector as u8 {
         PAGE_FAULT_VECTOR => {
-            let pflags = PageFaultFlags::from_bits_truncate(context.rflags as _);
+            let pflags = PageFaultFlags::from_bits_truncate(context.error_code as _);
+            let fault_info = info(unsafe { cr2() });
+            // The reserved bits of the page table entry were set, it's broken.
+            if pflags.contains(PageFaultFlags::R) {
+                panic!(
+                    "#PF with the reserved bits @ {:#x}, fault_vaddr={:#x} error_code={:#x}:\n{:#x?}",
+                    context.rip, fault_info.addr, context.error_code, context
+                );
+            }
             if pflags.contains(PageFaultFlags::I) {
-                TrapType::InstructionPageFault(unsafe { cr2() })
+                TrapType::InstructionPageFault(fault_info)
             } else if pflags.contains(PageFaultFlags::W) {
-                TrapType::StorePageFault(unsafe { cr2() })
+                TrapType::StorePageFault(fault_info)
             } else {
-                TrapType::LoadPageFault(unsafe { cr2() })
+                TrapType::LoadPageFault(fault_info)
             }
         }
-        BREAKPOINT_VECTOR => TrapType::Breakpoint,
+        // int3 is a trap, rip points to the next instruction.
+        BREAKPOINT_VECTOR => TrapType::Breakpoint(info(context.rip - 1)),
+        DEBUG_VECTOR => breakpoint::debug_trap(context),
+        INVALID_OPCODE_VECTOR => TrapType::IllegalInstruction(info(context.rip)),
+        DEVICE_NOT_AVAILABLE_VECTOR => TrapType::FpuDisabled(info(context.rip)),
+        ALIGNMENT_CHECK_VECTOR => TrapType::AlignmentCheck(info(context.rip)),
+        // Privileged instructions and non-canonical accesses raise #GP, the
+        // marked kernel instruction accessed a non-canonical user address.
+        GENERAL_PROTECTION_FAULT_VECTOR
+            if user || search_exception_table(context.rip).is_some() =>
+        {
+            TrapType::GeneralProtection(info(context.rip))
+        }
         GENERAL_PROTECTION_FAULT_VECTOR => {
             panic!(
                 "#GP @ {:#x}, fault_vaddr={:#x} error_code={:#x}:\n{:#x?}",
@@ -82,12 +163,41 @@ fn kernel_callback(context: &mut TrapFrame) {
                 context
             );
         }
+        // The double fault is an abort, it's raised on its own stack.
+        DOUBLE_FAULT_VECTOR => {
+            let addr = unsafe { cr2() };
+            // The cpu can't push the trap frame on the overflowed kernel stack.
+            if is_stack_guard(addr) {
+                report_overflow(context, addr);
+            }
+            panic!(
+                "#DF @ {}, fault_vaddr={:#x}:\n{:#x?}",
+                SymbolAddr(context.rip),
+                addr,
+                context
+            );
+        }
+        // The NMI and the corrected machine check are reported, then resumed.
+        NONMASKABLE_INTERRUPT_VECTOR => {
+            let reason = unsafe { x86::io::inb(NMI_REASON_PORT) };
+            log::warn!("NMI @ {}, reason={:#x}", SymbolAddr(context.rip), reason);
+            return TrapType::Unknown;
+        }
+        MACHINE_CHECK_VECTOR => {
+            machine_check(context);
+            return TrapType::Unknown;
+        }
+        // The spurious interrupt doesn't need the EOI.
+        APIC_SPURIOUS_VECTOR => {
+            count_spurious();
+            return TrapType::Unknown;
+        }
         APIC_TIMER_VECTOR => {
             unsafe { local_apic().end_of_interrupt() };
             TrapType::Timer
         }
-        // PIC IRQS
-        0x20..=0x2f => TrapType::Irq(irq::IRQVector(
+        // PIC IRQS and MSIs
+        0x20..=0x2f | MSI_VECTOR_START..=MSI_VECTOR_LAST => TrapType::Irq(irq::IRQVector(
             context.vector as usize - PIC_VECTOR_OFFSET as usize,
         )),
         _ => {
@@ -97,7 +207,23 @@ fn kernel_callback(context: &mut TrapFrame) {
             );
         }
     };
-    unsafe { crate::components::trap::_interrupt_for_arch(context, trap_type, 0) };
+    count_trap(trap_type);
+    // The kernel uses the fpu, the state loaded in it will be changed.
+    if !user && matches!(trap_type, TrapType::FpuDisabled(_)) {
+        fpu::invalidate();
+        fpu::set_enabled(true, false);
+        return trap_type;
+    }
+    // The emulated instruction is neither fixed up nor dispatched.
+    if emulate_insn(context, trap_type) {
+        return TrapType::Unknown;
+    }
+    // The marked kernel instruction is retried or jumps to the fixup code.
+    if fixup_kernel_fault(context, trap_type, token) {
+        return trap_type;
+    }
+    dispatch_trap(context, trap_type, token);
+    trap_type
 }
 
 #[naked]
@@ -123,9 +249,26 @@ pub unsafe extern "C" fn kernelvec() {
             push    rcx
             push    rax
 
+            // The cpu pushed the frame on the interrupted stack, there is no
+            // room for the rest of the trap frame above it. Copy the saved
+            // registers to a whole trap frame and copy them back after the trap.
+            mov     rsi, rsp
+            sub     rsp, {trapframe_size}
+            mov     rdi, rsp
+            mov     rcx, {frame_size} / 8
+            cld
+            rep     movsq
+
             mov     rdi, rsp
             call    {trap_handler}
 
+            mov     rsi, rsp
+            lea     rdi, [rsp + {trapframe_size}]
+            mov     rcx, {frame_size} / 8
+            cld
+            rep     movsq
+            add     rsp, {trapframe_size}
+
             pop     rax
             pop     rcx
             pop     rdx
@@ -145,7 +288,9 @@ pub unsafe extern "C" fn kernelvec() {
             add     rsp, 32                     # pop fs_base, gs_base, vector, error_code
             iretq
         ",
-        trap_handler = sym kernel_callback,
+        trap_handler = sym kernel_trap_entry,
+        trapframe_size = const TRAPFRAME_SIZE,
+        frame_size = const offset_of!(TrapFrame, fp),
         options(noreturn)
     )
 }
@@ -175,6 +320,17 @@ pub unsafe extern "C" fn uservec() {
 
             swapgs
 
+            // The frame is saved on the interrupt stack if the exception
+            // uses the IST, copy it to the user context.
+            mov     rdi, gs:{PERCPU_USER_CONTEXT_OFFSET}
+            sub     rdi, {frame_size}
+            cmp     rdi, rsp
+            je      2f
+            mov     rsi, rsp
+            mov     rcx, {frame_size} / 8
+            cld
+            rep     movsq
+        2:
             mov     rdi, rsp
             mov    rsp, gs:{PERCPU_KERNEL_RSP_OFFSET}  // kernel rsp
 
@@ -195,6 +351,8 @@ pub unsafe extern "C" fn uservec() {
         ",
         // PERCPU_KERNEL_RSP_OFFSET = const PERCPU_KERNEL_RSP_OFFSET,
         PERCPU_KERNEL_RSP_OFFSET = const offset_of!(PerCPUReserved, kernel_rsp),
+        PERCPU_USER_CONTEXT_OFFSET = const offset_of!(PerCPUReserved, user_context),
+        frame_size = const offset_of!(TrapFrame, fp),
         options(noreturn)
     );
 }
@@ -375,11 +533,16 @@ unsafe extern "C" fn syscall_entry() {
     )
 }
 
-/// Return Some(()) if it was interrupt by syscall, otherwise None.
+/// Return EscapeReson related to interrupt type.
 pub fn run_user_task(context: &mut TrapFrame) -> EscapeReason {
+    run_user_once(context, ThreadToken::NONE)
+}
+
+/// Run the user task until it traps back, dispatch the trap with the `token`.
+pub(crate) fn run_user_once(context: &mut TrapFrame, token: ThreadToken) -> EscapeReason {
     // TODO: set tss kernel sp just once, before task run.
     let cx_general_top =
-        context as *mut TrapFrame as usize + TRAPFRAME_SIZE - size_of::<FxsaveArea>();
+        context as *mut TrapFrame as usize + offset_of!(TrapFrame, fp);
     set_tss_kernel_sp(cx_general_top);
     // USER_CONTEXT.write_current(cx_general_top);
     unsafe {
@@ -390,18 +553,79 @@ pub fn run_user_task(context: &mut TrapFrame) -> EscapeReason {
             USER_CONTEXT = const offset_of!(PerCPUReserved, user_context)
         );
     }
-    context.fx_area.restore();
-    user_restore(context);
-    context.fx_area.save();
-
-    match context.vector {
-        SYSCALL_VECTOR => {
-            unsafe { crate::components::trap::_interrupt_for_arch(context, TrapType::SysCall, 0) };
-            EscapeReason::SysCall
+    loop {
+        let enabled = fpu::is_loaded(&context.fp);
+        let ext = context.fp.ext().is_some();
+        fpu::set_enabled(enabled, ext);
+        user_restore(context);
+        // The user task may set `RFLAGS.AC`, it disables SMAP in the kernel.
+        user_access_end(false);
+        if enabled {
+            context.fp.save();
         }
-        _ => {
-            kernel_callback(context);
-            EscapeReason::NoReason
+        // Load the fpu state if the user task trapped by the disabled fpu.
+        if context.vector == DEVICE_NOT_AVAILABLE_VECTOR as usize {
+            count_trap(TrapType::FpuDisabled(FaultInfo {
+                addr: context.rip,
+                user: true,
+            }));
+            fpu::set_enabled(true, ext);
+            fpu::load(&mut context.fp);
+            continue;
+        }
+        if context.vector == SYSCALL_VECTOR {
+            context.orig_rax = context.rax;
+            count_trap(TrapType::SysCall);
+            dispatch_trap(context, TrapType::SysCall, token);
+            return EscapeReason::SysCall;
+        }
+        // The trap handled in place, such as the emulated instruction, resumes the task.
+        match kernel_callback(context, token) {
+            TrapType::Unknown => continue,
+            trap_type => return trap_type.into(),
         }
     }
 }
+
+/// Save the callee saved registers on the kernel stack, then call `entry`
+/// with the trap frame and the [ThreadToken] pointing to the saved registers.
+#[naked]
+pub(crate) unsafe extern "C" fn __into_user(
+    tf: *mut TrapFrame,
+    entry: extern "C" fn(&mut TrapFrame, ThreadToken) -> !,
+) -> usize {
+    asm!(
+        "
+            push    rbp
+            push    rbx
+            push    r12
+            push    r13
+            push    r14
+            push    r15
+            mov     rax, rsi
+            mov     rsi, rsp
+            jmp     rax
+        ",
+        options(noreturn)
+    )
+}
+
+/// Restore the callee saved registers saved by [__into_user],
+/// then return `value` from it.
+#[naked]
+pub(crate) unsafe extern "C" fn __restore_token(token: usize, value: usize) -> ! {
+    asm!(
+        "
+            mov     rsp, rdi
+            pop     r15
+            pop     r14
+            pop     r13
+            pop     r12
+            pop     rbx
+            pop     rbp
+            mov     rax, rsi
+            ret
+        ",
+        options(noreturn)
+    )
+}
@@ -1,16 +1,46 @@
-use core::ops::{Index, IndexMut};
+use core::{
+    fmt::Debug,
+    ops::{Index, IndexMut},
+};
 
-use crate::components::trapframe::TrapFrameArgs;
+use crate::components::{backtrace::SymbolAddr, fpu::FpState, trapframe::TrapFrameArgs};
 
-/// Saved registers when a trap (interrupt or exception) occurs.#[allow(missing_docs)]
+/// The number of general purpose registers, `x0` - `x30` and `sp`.
+///
+/// The numbers of the registers are the same as the DWARF register numbers.
+pub const GPR_NUM: usize = 32;
+
+/// The DWARF register number of the program counter.
+pub const DWARF_PC: Option<usize> = Some(32);
+
+/// Saved registers when a trap (interrupt or exception) occurs.
+#[allow(missing_docs)]
 #[repr(C)]
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Default, Clone)]
 pub struct TrapFrame {
     pub regs: [usize; 31],
     pub sp: usize,
     pub elr: usize,
     pub spsr: usize,
     pub tpidr: usize,
+    /// The floating point state, it isn't saved in the kernel trap.
+    pub fp: FpState,
+    /// The `x0` at the syscall entry, it is overwritten by the return value.
+    pub orig_x0: usize,
+}
+
+impl Debug for TrapFrame {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TrapFrame")
+            .field("regs", &self.regs)
+            .field("sp", &self.sp)
+            .field("elr", &SymbolAddr(self.elr))
+            .field("spsr", &self.spsr)
+            .field("tpidr", &self.tpidr)
+            .field("fp", &self.fp)
+            .field("orig_x0", &self.orig_x0)
+            .finish()
+    }
 }
 
 impl TrapFrame {
@@ -40,8 +70,58 @@ impl TrapFrame {
         (self.spsr >> 2) & 0x3 == 0
     }
 
+    /// The `elr` already points to the next instruction of the `svc`,
+    /// nothing need to do here.
     #[inline]
     pub fn syscall_ok(&mut self) {}
+
+    /// Get the syscall number.
+    #[inline]
+    pub fn syscall_number(&self) -> usize {
+        self.regs[8]
+    }
+
+    /// Get the syscall arguments, `x0` is read from [TrapFrame::orig_x0].
+    #[inline]
+    pub fn syscall_args(&self) -> [usize; 6] {
+        [
+            self.orig_x0,
+            self.regs[1],
+            self.regs[2],
+            self.regs[3],
+            self.regs[4],
+            self.regs[5],
+        ]
+    }
+
+    /// Execute the `svc` again when the task returns to the user.
+    #[inline]
+    pub fn restart_syscall(&mut self) {
+        self.elr -= 4;
+        self.regs[0] = self.orig_x0;
+    }
+
+    /// Read the general purpose register `x{n}`, `31` is the `sp`.
+    ///
+    /// Panic if `n` is not less than [GPR_NUM].
+    #[inline]
+    pub fn gpr(&self, n: usize) -> usize {
+        match n {
+            31 => self.sp,
+            _ => self.regs[n],
+        }
+    }
+
+    /// Write the general purpose register `x{n}`, `31` is the `sp`.
+    ///
+    /// Panic if `n` is not less than [GPR_NUM].
+    #[inline]
+    pub fn set_gpr(&mut self, n: usize, val: usize) {
+        match n {
+            31 => self.sp = val,
+            _ => self.regs[n] = val,
+        }
+    }
 }
 
 impl Index<TrapFrameArgs> for TrapFrame {
@@ -1,11 +1,22 @@
-use core::ops::{Index, IndexMut};
+use core::{
+    fmt::Debug,
+    ops::{Index, IndexMut},
+};
 
-use crate::components::trapframe::TrapFrameArgs;
+use crate::components::{backtrace::SymbolAddr, fpu::FpState, trapframe::TrapFrameArgs};
+
+/// The number of general purpose registers, `r0` - `r31`.
+///
+/// The numbers of the registers are the same as the DWARF register numbers.
+pub const GPR_NUM: usize = 32;
+
+/// The DWARF register number of the program counter, loongarch64 doesn't have one.
+pub const DWARF_PC: Option<usize> = None;
 
 /// Saved registers when a trap (interrupt or exception) occurs.
 #[allow(missing_docs)]
 #[repr(C)]
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Default, Clone)]
 pub struct TrapFrame {
     /// General Registers
     pub regs: [usize; 32],
@@ -13,6 +24,25 @@ pub struct TrapFrame {
     pub prmd: usize,
     /// Exception Return Address
     pub era: usize,
+    /// The floating point state, it isn't saved in the kernel trap.
+    pub fp: FpState,
+    /// The `a0` at the syscall entry, it is overwritten by the return value.
+    pub orig_a0: usize,
+    /// The single-step is enabled, see [TrapFrame::set_single_step].
+    pub(crate) single_step: bool,
+}
+
+impl Debug for TrapFrame {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TrapFrame")
+            .field("regs", &self.regs)
+            .field("prmd", &self.prmd)
+            .field("era", &SymbolAddr(self.era))
+            .field("fp", &self.fp)
+            .field("orig_a0", &self.orig_a0)
+            .field("single_step", &self.single_step)
+            .finish()
+    }
 }
 
 impl TrapFrame {
@@ -30,10 +60,63 @@ impl TrapFrame {
 }
 
 impl TrapFrame {
+    /// Check if the trapframe was from user.
+    #[inline]
+    pub fn from_user(&self) -> bool {
+        // PPLV 3 is the user privilege level.
+        self.prmd & 0b11 == 0b11
+    }
+
     pub fn syscall_ok(&mut self) {
         self.era += 4;
     }
 
+    /// Get the syscall number.
+    #[inline]
+    pub fn syscall_number(&self) -> usize {
+        self.regs[11]
+    }
+
+    /// Get the syscall arguments, `a0` is read from [TrapFrame::orig_a0].
+    #[inline]
+    pub fn syscall_args(&self) -> [usize; 6] {
+        [
+            self.orig_a0,
+            self.regs[5],
+            self.regs[6],
+            self.regs[7],
+            self.regs[8],
+            self.regs[9],
+        ]
+    }
+
+    /// Execute the `syscall` again when the task returns to the user.
+    ///
+    /// It must be called after [TrapFrame::syscall_ok].
+    #[inline]
+    pub fn restart_syscall(&mut self) {
+        self.era -= 4;
+        self.regs[4] = self.orig_a0;
+    }
+
+    /// Read the general purpose register `r{n}`.
+    ///
+    /// Panic if `n` is not less than [GPR_NUM].
+    #[inline]
+    pub fn gpr(&self, n: usize) -> usize {
+        self.regs[n]
+    }
+
+    /// Write the general purpose register `r{n}`, writing `r0` is ignored.
+    ///
+    /// Panic if `n` is not less than [GPR_NUM].
+    #[inline]
+    pub fn set_gpr(&mut self, n: usize, val: usize) {
+        if n != 0 {
+            self.regs[n] = val;
+        }
+    }
+
     #[inline]
     pub fn args(&self) -> [usize; 6] {
         [
@@ -22,5 +22,105 @@ pub enum TrapFrameArgs {
     SYSCALL,
 }
 
+/// The error number of the syscall, returned to the user as `-errno`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub i32);
+
+impl Errno {
+    /// Interrupted system call.
+    pub const EINTR: Self = Self(4);
+    /// Restart the syscall if the signal handler has `SA_RESTART`,
+    /// otherwise return [Errno::EINTR].
+    pub const ERESTARTSYS: Self = Self(512);
+    /// Always restart the syscall.
+    pub const ERESTARTNOINTR: Self = Self(513);
+    /// Restart the syscall if no signal handler is called.
+    pub const ERESTARTNOHAND: Self = Self(514);
+}
+
 /// The size of the [TrapFrame]
 pub const TRAPFRAME_SIZE: usize = size_of::<TrapFrame>();
+
+/// Initialize the fields after the registers, the kernel trap entry
+/// allocates the whole trap frame but doesn't save them.
+///
+/// # Safety
+///
+/// The `tf` must point to the trap frame saved by the kernel trap entry.
+#[cfg(feature = "trap")]
+pub(crate) unsafe fn kernel_frame<'a>(tf: *mut TrapFrame) -> &'a mut TrapFrame {
+    let start = core::mem::offset_of!(TrapFrame, fp);
+    (tf as *mut u8).add(start).write_bytes(0, TRAPFRAME_SIZE - start);
+    core::ptr::addr_of_mut!((*tf).fp).write(crate::components::fpu::FpState::new());
+    &mut *tf
+}
+
+/// A portable snapshot of the user visible registers in the [TrapFrame].
+///
+/// `gpr` has the same numbering as [TrapFrame::gpr].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    /// General purpose registers.
+    pub gpr: [usize; GPR_NUM],
+    /// Program counter.
+    pub pc: usize,
+    /// Thread local storage pointer.
+    pub tls: usize,
+}
+
+impl TrapFrame {
+    /// Take a snapshot of the registers.
+    pub fn registers(&self) -> Registers {
+        Registers {
+            gpr: core::array::from_fn(|i| self.gpr(i)),
+            pc: self[TrapFrameArgs::SEPC],
+            tls: self[TrapFrameArgs::TLS],
+        }
+    }
+
+    /// Write the registers back to the trap frame.
+    ///
+    /// `tls` is written after `gpr`, it wins if they are the same register.
+    pub fn set_registers(&mut self, regs: &Registers) {
+        regs.gpr
+            .iter()
+            .enumerate()
+            .for_each(|(i, val)| self.set_gpr(i, *val));
+        self[TrapFrameArgs::SEPC] = regs.pc;
+        self[TrapFrameArgs::TLS] = regs.tls;
+    }
+
+    /// Set the return value of the syscall.
+    ///
+    /// All the supported ABIs return `-errno` in the return register on error.
+    #[inline]
+    pub fn set_syscall_return(&mut self, ret: Result<usize, Errno>) {
+        self[TrapFrameArgs::RET] = match ret {
+            Ok(val) => val,
+            Err(errno) => -(errno.0 as isize) as usize,
+        };
+    }
+
+    /// Read the register through the DWARF register number.
+    ///
+    /// Return `None` if the register isn't saved in the [TrapFrame].
+    pub fn dwarf_reg(&self, dwarf: usize) -> Option<usize> {
+        match dwarf {
+            n if n < GPR_NUM => Some(self.gpr(n)),
+            n if Some(n) == DWARF_PC => Some(self[TrapFrameArgs::SEPC]),
+            _ => None,
+        }
+    }
+
+    /// Write the register through the DWARF register number.
+    ///
+    /// Return false if the register isn't saved in the [TrapFrame].
+    pub fn set_dwarf_reg(&mut self, dwarf: usize, val: usize) -> bool {
+        match dwarf {
+            n if n < GPR_NUM => self.set_gpr(n, val),
+            n if Some(n) == DWARF_PC => self[TrapFrameArgs::SEPC] = val,
+            _ => return false,
+        }
+        true
+    }
+}
@@ -3,9 +3,19 @@ use core::{
     ops::{Index, IndexMut},
 };
 
+#[cfg(feature = "trap")]
+use riscv::register::sstatus::FS;
 use riscv::register::sstatus::{self, Sstatus, SPP};
 
-use crate::components::trapframe::TrapFrameArgs;
+use crate::components::{backtrace::SymbolAddr, fpu::FpState, trapframe::TrapFrameArgs};
+
+/// The number of general purpose registers, `x0` - `x31`.
+///
+/// The numbers of the registers are the same as the DWARF register numbers.
+pub const GPR_NUM: usize = 32;
+
+/// The DWARF register number of the program counter, riscv64 doesn't have one.
+pub const DWARF_PC: Option<usize> = None;
 
 #[repr(C)]
 #[derive(Clone)]
@@ -14,13 +24,18 @@ pub struct TrapFrame {
     pub x: [usize; 32], // 32 个通用寄存器
     pub sstatus: Sstatus,
     pub sepc: usize,
-    pub fsx: [usize; 2],
+    /// The floating point state, it isn't saved in the kernel trap.
+    pub fp: FpState,
+    /// The `a0` at the syscall entry, it is overwritten by the return value.
+    pub orig_a0: usize,
+    /// The single-step is enabled, see [TrapFrame::set_single_step].
+    pub(crate) single_step: bool,
 }
 
 impl Debug for TrapFrame {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("Context")
-            .field("ra", &self.x[1])
+            .field("ra", &SymbolAddr(self.x[1]))
             .field("sp", &self.x[2])
             .field("gp", &self.x[3])
             .field("tp", &self.x[4])
@@ -52,8 +67,8 @@ impl Debug for TrapFrame {
             .field("t5", &self.x[30])
             .field("t6", &self.x[31])
             .field("sstatus", &self.sstatus)
-            .field("sepc", &self.sepc)
-            .field("fsx", &self.fsx)
+            .field("sepc", &SymbolAddr(self.sepc))
+            .field("fp", &self.fp)
             .finish()
     }
 }
@@ -66,7 +81,9 @@ impl TrapFrame {
             x: [0usize; 32],
             sstatus: sstatus::read(),
             sepc: 0,
-            fsx: [0; 2],
+            fp: FpState::new(),
+            orig_a0: 0,
+            single_step: false,
         }
     }
 
@@ -85,6 +102,89 @@ impl TrapFrame {
     pub fn syscall_ok(&mut self) {
         self.sepc += 4;
     }
+
+    /// Get the syscall number.
+    #[inline]
+    pub fn syscall_number(&self) -> usize {
+        self.x[17]
+    }
+
+    /// Get the syscall arguments, `a0` is read from [TrapFrame::orig_a0].
+    #[inline]
+    pub fn syscall_args(&self) -> [usize; 6] {
+        [
+            self.orig_a0,
+            self.x[11],
+            self.x[12],
+            self.x[13],
+            self.x[14],
+            self.x[15],
+        ]
+    }
+
+    /// Execute the `ecall` again when the task returns to the user.
+    ///
+    /// It must be called after [TrapFrame::syscall_ok].
+    #[inline]
+    pub fn restart_syscall(&mut self) {
+        self.sepc -= 4;
+        self.x[10] = self.orig_a0;
+    }
+
+    /// Set the `sstatus.FS` that the task runs with.
+    #[cfg(feature = "trap")]
+    #[inline]
+    pub(crate) fn set_fs(&mut self, fs: FS) {
+        self.set_sstatus_field(13, fs as usize);
+    }
+
+    /// Get the `sstatus.VS` that the task runs with.
+    #[cfg(feature = "trap")]
+    #[inline]
+    pub(crate) fn vs(&self) -> FS {
+        let bits: usize = unsafe { core::mem::transmute(self.sstatus) };
+        match (bits >> 9) & 0b11 {
+            0 => FS::Off,
+            1 => FS::Initial,
+            2 => FS::Clean,
+            _ => FS::Dirty,
+        }
+    }
+
+    /// Set the `sstatus.VS` that the task runs with.
+    #[cfg(feature = "trap")]
+    #[inline]
+    pub(crate) fn set_vs(&mut self, vs: FS) {
+        self.set_sstatus_field(9, vs as usize);
+    }
+
+    /// Set the 2-bit field of the `sstatus` at `shift`.
+    #[cfg(feature = "trap")]
+    #[inline]
+    fn set_sstatus_field(&mut self, shift: usize, val: usize) {
+        // Sstatus doesn't provide the setters, it only contains the bits.
+        let bits: usize = unsafe { core::mem::transmute(self.sstatus) };
+        let bits = (bits & !(0b11 << shift)) | (val << shift);
+        self.sstatus = unsafe { core::mem::transmute::<usize, Sstatus>(bits) };
+    }
+
+    /// Read the general purpose register `x{n}`.
+    ///
+    /// Panic if `n` is not less than [GPR_NUM].
+    #[inline]
+    pub fn gpr(&self, n: usize) -> usize {
+        self.x[n]
+    }
+
+    /// Write the general purpose register `x{n}`, writing `x0` is ignored.
+    ///
+    /// Panic if `n` is not less than [GPR_NUM].
+    #[inline]
+    pub fn set_gpr(&mut self, n: usize, val: usize) {
+        if n != 0 {
+            self.x[n] = val;
+        }
+    }
 }
 
 impl Index<TrapFrameArgs> for TrapFrame {
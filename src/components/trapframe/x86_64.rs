@@ -3,59 +3,36 @@ use core::{
     ops::{Index, IndexMut},
 };
 
+#[cfg(feature = "trap")]
+use core::mem::size_of;
+
 use x86_64::registers::rflags::RFlags;
 
-use crate::components::{arch::gdt::GdtStruct, trapframe::TrapFrameArgs};
+use crate::components::{
+    arch::gdt::GdtStruct, backtrace::SymbolAddr, fpu::FpState, trapframe::TrapFrameArgs,
+};
 
-#[repr(C, align(16))]
-#[derive(Clone)]
-pub struct FxsaveArea {
-    pub fcw: u16,
-    pub fsw: u16,
-    pub ftw: u16,
-    pub fop: u16,
-    pub fip: u64,
-    pub fdp: u64,
-    pub mxcsr: u32,
-    pub mxcsr_mask: u32,
-    pub st: [u64; 16],
-    pub xmm: [u64; 32],
-    _padding: [u64; 12],
-}
+#[cfg(feature = "trap")]
+use crate::components::uaccess::{copy_from_user, copy_to_user, UserAccessError};
+#[cfg(feature = "trap")]
+use crate::VirtAddr;
 
-impl FxsaveArea {
-    #[inline]
-    pub(crate) fn save(&mut self) {
-        unsafe { core::arch::x86_64::_fxsave64(self as *mut _ as *mut u8) }
-    }
+pub use crate::components::fpu::FxsaveArea;
 
-    #[inline]
-    pub(crate) fn restore(&self) {
-        unsafe { core::arch::x86_64::_fxrstor64(self as *const _ as *const u8) }
-    }
-}
+/// The number of general purpose registers.
+///
+/// The numbers of the registers are the same as the DWARF register numbers:
+/// `rax`, `rdx`, `rcx`, `rbx`, `rsi`, `rdi`, `rbp`, `rsp`, `r8` - `r15`.
+pub const GPR_NUM: usize = 16;
 
-impl Default for FxsaveArea {
-    fn default() -> Self {
-        let mut area: FxsaveArea = unsafe { core::mem::MaybeUninit::zeroed().assume_init() };
-        area.fcw = 0x37f;
-        area.ftw = 0xffff;
-        area.mxcsr = 0x1f80;
-        area
-    }
-}
-
-impl Debug for FxsaveArea {
-    fn fmt(&self, _f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        Ok(())
-    }
-}
+/// The DWARF register number of the program counter(return address column).
+pub const DWARF_PC: Option<usize> = Some(16);
 
 /// Saved registers when a trap (interrupt or exception) occurs.
 /// This is need be align 16, because tss trap ptr should be align 16? I think it is.
 #[allow(missing_docs)]
 #[repr(C, align(16))]
-#[derive(Debug, Default, Clone)]
+#[derive(Default, Clone)]
 pub struct TrapFrame {
     pub rax: usize,
     pub rcx: usize,
@@ -87,8 +64,43 @@ pub struct TrapFrame {
     pub rsp: usize,
     pub ss: usize,
 
-    // save fx area
-    pub fx_area: FxsaveArea,
+    /// The floating point state, it isn't saved in the kernel trap.
+    pub fp: FpState,
+    /// The `rax` at the syscall entry, it is overwritten by the return value.
+    pub orig_rax: usize,
+}
+
+impl Debug for TrapFrame {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("TrapFrame")
+            .field("rax", &self.rax)
+            .field("rcx", &self.rcx)
+            .field("rdx", &self.rdx)
+            .field("rbx", &self.rbx)
+            .field("rbp", &self.rbp)
+            .field("rsi", &self.rsi)
+            .field("rdi", &self.rdi)
+            .field("r8", &self.r8)
+            .field("r9", &self.r9)
+            .field("r10", &self.r10)
+            .field("r11", &self.r11)
+            .field("r12", &self.r12)
+            .field("r13", &self.r13)
+            .field("r14", &self.r14)
+            .field("r15", &self.r15)
+            .field("fs_base", &self.fs_base)
+            .field("gs_base", &self.gs_base)
+            .field("vector", &self.vector)
+            .field("error_code", &self.error_code)
+            .field("rip", &SymbolAddr(self.rip))
+            .field("cs", &self.cs)
+            .field("rflags", &self.rflags)
+            .field("rsp", &self.rsp)
+            .field("ss", &self.ss)
+            .field("fp", &self.fp)
+            .field("orig_rax", &self.orig_rax)
+            .finish()
+    }
 }
 
 impl TrapFrame {
@@ -110,9 +122,82 @@ impl TrapFrame {
         [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
     }
 
+    /// The `rip` already points to the next instruction of the `syscall`,
+    /// nothing need to do here.
+    #[inline]
+    pub fn syscall_ok(&mut self) {}
+
+    /// Get the syscall number, it is read from [TrapFrame::orig_rax].
+    #[inline]
+    pub fn syscall_number(&self) -> usize {
+        self.orig_rax
+    }
+
+    /// Get the syscall arguments.
+    #[inline]
+    pub fn syscall_args(&self) -> [usize; 6] {
+        self.args()
+    }
+
+    /// Execute the `syscall` again when the task returns to the user.
     #[inline]
-    pub fn syscall_ok(&mut self) {
-        // self.sepc += 4;
+    pub fn restart_syscall(&mut self) {
+        // The `syscall` instruction is 2 bytes.
+        self.rip -= 2;
+        self.rax = self.orig_rax;
+    }
+
+    /// Read the general purpose register `n`, see [GPR_NUM] for the numbering.
+    ///
+    /// Panic if `n` is not less than [GPR_NUM].
+    #[inline]
+    pub fn gpr(&self, n: usize) -> usize {
+        match n {
+            0 => self.rax,
+            1 => self.rdx,
+            2 => self.rcx,
+            3 => self.rbx,
+            4 => self.rsi,
+            5 => self.rdi,
+            6 => self.rbp,
+            7 => self.rsp,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            15 => self.r15,
+            _ => panic!("invalid general purpose register number: {}", n),
+        }
+    }
+
+    /// Write the general purpose register `n`, see [GPR_NUM] for the numbering.
+    ///
+    /// Panic if `n` is not less than [GPR_NUM].
+    #[inline]
+    pub fn set_gpr(&mut self, n: usize, val: usize) {
+        let reg = match n {
+            0 => &mut self.rax,
+            1 => &mut self.rdx,
+            2 => &mut self.rcx,
+            3 => &mut self.rbx,
+            4 => &mut self.rsi,
+            5 => &mut self.rdi,
+            6 => &mut self.rbp,
+            7 => &mut self.rsp,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            15 => &mut self.r15,
+            _ => panic!("invalid general purpose register number: {}", n),
+        };
+        *reg = val;
     }
 
     /// Check if the trapframe was from user.
@@ -122,13 +207,38 @@ impl TrapFrame {
     }
 }
 
+/// The return address is on the user stack, it's accessed through the uaccess.
+#[cfg(feature = "trap")]
+impl TrapFrame {
+    /// Read the return address at the top of the user stack.
+    ///
+    /// It's the return address only at the entry of the function.
+    pub fn read_ra(&self) -> Result<usize, UserAccessError> {
+        let mut bytes = [0u8; size_of::<usize>()];
+        copy_from_user(&mut bytes, VirtAddr::new(self.rsp))?;
+        Ok(usize::from_ne_bytes(bytes))
+    }
+
+    /// Push the return address to the user stack like the `call` instruction.
+    ///
+    /// The stack pointer is not changed if the push faults.
+    pub fn push_ra(&mut self, ra: usize) -> Result<(), UserAccessError> {
+        let sp = self.rsp.wrapping_sub(size_of::<usize>());
+        copy_to_user(VirtAddr::new(sp), &ra.to_ne_bytes())?;
+        self.rsp = sp;
+        Ok(())
+    }
+}
+
 impl Index<TrapFrameArgs> for TrapFrame {
     type Output = usize;
 
     fn index(&self, index: TrapFrameArgs) -> &Self::Output {
         match index {
             TrapFrameArgs::SEPC => &self.rip,
-            TrapFrameArgs::RA => unimplemented!("Can't get return address in x86_64"),
+            TrapFrameArgs::RA => {
+                unimplemented!("Can't get return address in x86_64, use TrapFrame::read_ra")
+            }
             TrapFrameArgs::ARG0 => &self.rdi,
             TrapFrameArgs::ARG1 => &self.rsi,
             TrapFrameArgs::ARG2 => &self.rdx,
@@ -145,10 +255,7 @@ impl IndexMut<TrapFrameArgs> for TrapFrame {
         match index {
             TrapFrameArgs::SEPC => &mut self.rip,
             TrapFrameArgs::RA => {
-                // set return address, at x86_64 is push return address to rsp, shoule be execute at end.
-                log::warn!("set_ra in x86_64 is push return address to rsp, shoule be execute at end");
-                self.rsp -= 8;
-                unsafe { (self.rsp as *mut usize).as_mut().unwrap() }
+                unimplemented!("Can't set return address in x86_64, use TrapFrame::push_ra")
             }
             TrapFrameArgs::ARG0 => &mut self.rdi,
             TrapFrameArgs::ARG1 => &mut self.rsi,
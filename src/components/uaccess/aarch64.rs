@@ -0,0 +1,159 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use aarch64_cpu::registers::{Readable, Writeable, SCTLR_EL1};
+
+/// The `PSTATE.PAN` bit in the `PAN` register.
+const PAN_BIT: usize = 1 << 22;
+
+/// The `SCTLR_EL1.SPAN` bit, PAN is set on the exception to EL1 if it's clear.
+const SCTLR_SPAN: u64 = 1 << 23;
+
+/// Whether PAN is enabled, the `PAN` register only exists with FEAT_PAN.
+static PAN_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enable PAN on the current cpu if it's supported.
+///
+/// The kernel can only access the user memory between [user_access_begin]
+/// and [user_access_end] after it.
+pub(crate) fn init() {
+    let mmfr1: usize;
+    unsafe { asm!("mrs {}, id_aa64mmfr1_el1", out(reg) mmfr1) };
+    if (mmfr1 >> 20) & 0xf == 0 {
+        return;
+    }
+    SCTLR_EL1.set(SCTLR_EL1.get() & !SCTLR_SPAN);
+    unsafe { asm!("msr S3_0_C4_C2_3, {}", in(reg) PAN_BIT) };
+    PAN_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Allow the kernel to access the user memory, return the previous state.
+#[inline]
+pub(crate) fn user_access_begin() -> bool {
+    if !PAN_ENABLED.load(Ordering::Relaxed) {
+        return true;
+    }
+    let pan: usize;
+    unsafe {
+        asm!("mrs {}, S3_0_C4_C2_3", out(reg) pan);
+        asm!("msr S3_0_C4_C2_3, xzr");
+    }
+    pan & PAN_BIT == 0
+}
+
+/// Restore the state returned by [user_access_begin].
+#[inline]
+pub(crate) fn user_access_end(prev: bool) {
+    if !prev && PAN_ENABLED.load(Ordering::Relaxed) {
+        unsafe { asm!("msr S3_0_C4_C2_3, {}", in(reg) PAN_BIT) };
+    }
+}
+
+/// Copy `len` bytes from `src` to `dst`, return the number of bytes not copied.
+#[naked]
+pub(crate) unsafe extern "C" fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    core::arch::asm!(
+        "
+            cbz     x2, 3f
+        1:  ldrb    w3, [x1], #1
+        2:  strb    w3, [x0], #1
+            sub     x2, x2, #1
+            cbnz    x2, 1b
+        3:  mov     x0, x2
+            ret
+        ",
+        crate::components::extable::ex_table!("1b", "3b"),
+        crate::components::extable::ex_table!("2b", "3b"),
+        options(noreturn)
+    )
+}
+
+/// Fill `len` bytes at `dst` with zero, return the number of bytes not cleared.
+#[naked]
+pub(crate) unsafe extern "C" fn __clear_user(dst: *mut u8, len: usize) -> usize {
+    core::arch::asm!(
+        "
+            cbz     x1, 2f
+        1:  strb    wzr, [x0], #1
+            sub     x1, x1, #1
+            cbnz    x1, 1b
+        2:  mov     x0, x1
+            ret
+        ",
+        crate::components::extable::ex_table!("1b", "2b"),
+        options(noreturn)
+    )
+}
+
+/// Copy at most `len` bytes of the string at `src` to `dst`.
+/// Return the length of the string without NUL, or -1 if faulted.
+#[naked]
+pub(crate) unsafe extern "C" fn __strncpy_user(dst: *mut u8, src: *const u8, len: usize) -> isize {
+    core::arch::asm!(
+        "
+            mov     x4, x2
+            cbz     x4, 2f
+        1:  ldrb    w3, [x1], #1
+            strb    w3, [x0], #1
+            cbz     w3, 2f
+            sub     x4, x4, #1
+            cbnz    x4, 1b
+        2:  sub     x0, x2, x4
+            ret
+        3:  mov     x0, #-1
+            ret
+        ",
+        crate::components::extable::ex_table!("1b", "3b"),
+        options(noreturn)
+    )
+}
+
+/// Compare `*addr` with `expected` and swap it with `new` if equal.
+/// Write the previous value to `old`, return 0 if success, otherwise 1.
+#[naked]
+pub(crate) unsafe extern "C" fn __atomic_cmpxchg_u32(
+    addr: *mut u32,
+    expected: u32,
+    new: u32,
+    old: *mut u32,
+) -> usize {
+    core::arch::asm!(
+        "
+        1:  ldaxr   w4, [x0]
+            cmp     w4, w1
+            b.ne    2f
+        3:  stlxr   w5, w2, [x0]
+            cbnz    w5, 1b
+        2:  clrex
+            str     w4, [x3]
+            mov     x0, #0
+            ret
+        4:  mov     x0, #1
+            ret
+        ",
+        crate::components::extable::ex_table!("1b", "4b"),
+        crate::components::extable::ex_table!("3b", "4b"),
+        options(noreturn)
+    )
+}
+
+/// Swap `*addr` with `val`.
+/// Write the previous value to `old`, return 0 if success, otherwise 1.
+#[naked]
+pub(crate) unsafe extern "C" fn __atomic_xchg_u32(addr: *mut u32, val: u32, old: *mut u32) -> usize {
+    core::arch::asm!(
+        "
+        1:  ldaxr   w3, [x0]
+        2:  stlxr   w4, w1, [x0]
+            cbnz    w4, 1b
+            str     w3, [x2]
+            mov     x0, #0
+            ret
+        3:  mov     x0, #1
+            ret
+        ",
+        crate::components::extable::ex_table!("1b", "3b"),
+        crate::components::extable::ex_table!("2b", "3b"),
+        options(noreturn)
+    )
+}
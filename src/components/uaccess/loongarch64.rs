@@ -0,0 +1,121 @@
+/// Allow the kernel to access the user memory, return the previous state.
+///
+/// The kernel can always access the user memory on loongarch64.
+#[inline]
+pub(crate) fn user_access_begin() -> bool {
+    true
+}
+
+/// Restore the state returned by [user_access_begin].
+#[inline]
+pub(crate) fn user_access_end(_prev: bool) {}
+
+/// Copy `len` bytes from `src` to `dst`, return the number of bytes not copied.
+#[naked]
+pub(crate) unsafe extern "C" fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    core::arch::asm!(
+        "
+            beqz    $a2, 3f
+        1:  ld.bu   $t0, $a1, 0
+        2:  st.b    $t0, $a0, 0
+            addi.d  $a0, $a0, 1
+            addi.d  $a1, $a1, 1
+            addi.d  $a2, $a2, -1
+            bnez    $a2, 1b
+        3:  move    $a0, $a2
+            jr      $ra
+        ",
+        crate::components::extable::ex_table!("1b", "3b"),
+        crate::components::extable::ex_table!("2b", "3b"),
+        options(noreturn)
+    )
+}
+
+/// Fill `len` bytes at `dst` with zero, return the number of bytes not cleared.
+#[naked]
+pub(crate) unsafe extern "C" fn __clear_user(dst: *mut u8, len: usize) -> usize {
+    core::arch::asm!(
+        "
+            beqz    $a1, 2f
+        1:  st.b    $zero, $a0, 0
+            addi.d  $a0, $a0, 1
+            addi.d  $a1, $a1, -1
+            bnez    $a1, 1b
+        2:  move    $a0, $a1
+            jr      $ra
+        ",
+        crate::components::extable::ex_table!("1b", "2b"),
+        options(noreturn)
+    )
+}
+
+/// Copy at most `len` bytes of the string at `src` to `dst`.
+/// Return the length of the string without NUL, or -1 if faulted.
+#[naked]
+pub(crate) unsafe extern "C" fn __strncpy_user(dst: *mut u8, src: *const u8, len: usize) -> isize {
+    core::arch::asm!(
+        "
+            move    $t1, $a2
+            beqz    $t1, 2f
+        1:  ld.bu   $t0, $a1, 0
+            st.b    $t0, $a0, 0
+            beqz    $t0, 2f
+            addi.d  $a0, $a0, 1
+            addi.d  $a1, $a1, 1
+            addi.d  $t1, $t1, -1
+            bnez    $t1, 1b
+        2:  sub.d   $a0, $a2, $t1
+            jr      $ra
+        3:  li.w    $a0, -1
+            jr      $ra
+        ",
+        crate::components::extable::ex_table!("1b", "3b"),
+        options(noreturn)
+    )
+}
+
+/// Compare `*addr` with `expected` and swap it with `new` if equal.
+/// Write the previous value to `old`, return 0 if success, otherwise 1.
+#[naked]
+pub(crate) unsafe extern "C" fn __atomic_cmpxchg_u32(
+    addr: *mut u32,
+    expected: u32,
+    new: u32,
+    old: *mut u32,
+) -> usize {
+    core::arch::asm!(
+        "
+        1:  ll.w    $t0, $a0, 0
+            bne     $t0, $a1, 2f
+            move    $t1, $a2
+        3:  sc.w    $t1, $a0, 0
+            beqz    $t1, 1b
+        2:  st.w    $t0, $a3, 0
+            move    $a0, $zero
+            jr      $ra
+        4:  li.w    $a0, 1
+            jr      $ra
+        ",
+        crate::components::extable::ex_table!("1b", "4b"),
+        crate::components::extable::ex_table!("3b", "4b"),
+        options(noreturn)
+    )
+}
+
+/// Swap `*addr` with `val`.
+/// Write the previous value to `old`, return 0 if success, otherwise 1.
+#[naked]
+pub(crate) unsafe extern "C" fn __atomic_xchg_u32(addr: *mut u32, val: u32, old: *mut u32) -> usize {
+    core::arch::asm!(
+        "
+        1:  amswap_db.w $t0, $a1, $a0
+            st.w        $t0, $a2, 0
+            move        $a0, $zero
+            jr          $ra
+        2:  li.w        $a0, 1
+            jr          $ra
+        ",
+        crate::components::extable::ex_table!("1b", "2b"),
+        options(noreturn)
+    )
+}
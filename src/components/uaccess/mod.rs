@@ -0,0 +1,104 @@
+//! User memory access module.
+//!
+//! Copy data between the kernel and the user address space safely.
+//! The address range is checked against [USER_VADDR_END] and the faults
+//! during the copy are fixed up by the exception table, so a bad user
+//! pointer returns an error instead of panicking the kernel.
+//!
+//! The kernel can only access the user memory during the copy on the
+//! architectures supporting it (SUM on riscv64, PAN on aarch64 and SMAP on
+//! x86_64).
+//!
+//! TIPS: The page fault on the user address is dispatched to the kernel
+//! interrupt handler before the fixup, the handler can populate the page and
+//! the copy continues. The [FaultInfo::user] of it is `false`.
+//!
+//! [USER_VADDR_END]: crate::pagetable::USER_VADDR_END
+//! [FaultInfo::user]: crate::trap::FaultInfo::user
+
+pub mod user_atomic;
+
+use crate::{pagetable::USER_VADDR_END, VirtAddr};
+
+super::define_arch_mods!();
+
+/// The error of the user memory access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserAccessError {
+    /// The address range is out of the user address space.
+    BadAddress,
+    /// A fault occurred during the access.
+    Fault,
+}
+
+/// Enable the kernel access to the user memory, restore it when dropped.
+struct UserAccessGuard(bool);
+
+impl UserAccessGuard {
+    #[inline]
+    fn new() -> Self {
+        Self(user_access_begin())
+    }
+}
+
+impl Drop for UserAccessGuard {
+    #[inline]
+    fn drop(&mut self) {
+        user_access_end(self.0)
+    }
+}
+
+/// Check the range `[addr, addr + len)` is in the user address space.
+#[inline]
+fn check_user_range(addr: VirtAddr, len: usize) -> Result<(), UserAccessError> {
+    match addr.addr().checked_add(len) {
+        Some(end) if end <= USER_VADDR_END + 1 => Ok(()),
+        _ => Err(UserAccessError::BadAddress),
+    }
+}
+
+/// Copy `dst.len()` bytes from the user address `src` to `dst`.
+pub fn copy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<(), UserAccessError> {
+    check_user_range(src, dst.len())?;
+    let _guard = UserAccessGuard::new();
+    match unsafe { __copy_user(dst.as_mut_ptr(), src.addr() as _, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(UserAccessError::Fault),
+    }
+}
+
+/// Copy `src.len()` bytes from `src` to the user address `dst`.
+pub fn copy_to_user(dst: VirtAddr, src: &[u8]) -> Result<(), UserAccessError> {
+    check_user_range(dst, src.len())?;
+    let _guard = UserAccessGuard::new();
+    match unsafe { __copy_user(dst.addr() as _, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(UserAccessError::Fault),
+    }
+}
+
+/// Fill `len` bytes at the user address `dst` with zero.
+pub fn clear_user(dst: VirtAddr, len: usize) -> Result<(), UserAccessError> {
+    check_user_range(dst, len)?;
+    let _guard = UserAccessGuard::new();
+    match unsafe { __clear_user(dst.addr() as _, len) } {
+        0 => Ok(()),
+        _ => Err(UserAccessError::Fault),
+    }
+}
+
+/// Copy a NUL-terminated string from the user address `src` to `dst`.
+///
+/// Return the length of the string without the trailing NUL. If the
+/// return value equals to `dst.len()`, the string is truncated and `dst`
+/// is not NUL-terminated.
+pub fn strncpy_from_user(dst: &mut [u8], src: VirtAddr) -> Result<usize, UserAccessError> {
+    check_user_range(src, 0)?;
+    // The string may end before the end of the user address space.
+    let len = dst.len().min(USER_VADDR_END + 1 - src.addr());
+    let _guard = UserAccessGuard::new();
+    match unsafe { __strncpy_user(dst.as_mut_ptr(), src.addr() as _, len) } {
+        -1 => Err(UserAccessError::Fault),
+        copied => Ok(copied as usize),
+    }
+}
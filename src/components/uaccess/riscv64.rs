@@ -0,0 +1,126 @@
+use riscv::register::sstatus;
+
+/// Allow the kernel to access the user memory, return the previous state.
+#[inline]
+pub(crate) fn user_access_begin() -> bool {
+    let prev = sstatus::read().sum();
+    unsafe { sstatus::set_sum() };
+    prev
+}
+
+/// Restore the state returned by [user_access_begin].
+#[inline]
+pub(crate) fn user_access_end(prev: bool) {
+    if !prev {
+        unsafe { sstatus::clear_sum() };
+    }
+}
+
+/// Copy `len` bytes from `src` to `dst`, return the number of bytes not copied.
+#[naked]
+pub(crate) unsafe extern "C" fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    core::arch::asm!(
+        "
+            beqz    a2, 3f
+        1:  lbu     t0, 0(a1)
+        2:  sb      t0, 0(a0)
+            addi    a0, a0, 1
+            addi    a1, a1, 1
+            addi    a2, a2, -1
+            bnez    a2, 1b
+        3:  mv      a0, a2
+            ret
+        ",
+        crate::components::extable::ex_table!("1b", "3b"),
+        crate::components::extable::ex_table!("2b", "3b"),
+        options(noreturn)
+    )
+}
+
+/// Fill `len` bytes at `dst` with zero, return the number of bytes not cleared.
+#[naked]
+pub(crate) unsafe extern "C" fn __clear_user(dst: *mut u8, len: usize) -> usize {
+    core::arch::asm!(
+        "
+            beqz    a1, 2f
+        1:  sb      zero, 0(a0)
+            addi    a0, a0, 1
+            addi    a1, a1, -1
+            bnez    a1, 1b
+        2:  mv      a0, a1
+            ret
+        ",
+        crate::components::extable::ex_table!("1b", "2b"),
+        options(noreturn)
+    )
+}
+
+/// Copy at most `len` bytes of the string at `src` to `dst`.
+/// Return the length of the string without NUL, or -1 if faulted.
+#[naked]
+pub(crate) unsafe extern "C" fn __strncpy_user(dst: *mut u8, src: *const u8, len: usize) -> isize {
+    core::arch::asm!(
+        "
+            mv      t1, a2
+            beqz    t1, 2f
+        1:  lbu     t0, 0(a1)
+            sb      t0, 0(a0)
+            beqz    t0, 2f
+            addi    a0, a0, 1
+            addi    a1, a1, 1
+            addi    t1, t1, -1
+            bnez    t1, 1b
+        2:  sub     a0, a2, t1
+            ret
+        3:  li      a0, -1
+            ret
+        ",
+        crate::components::extable::ex_table!("1b", "3b"),
+        options(noreturn)
+    )
+}
+
+/// Compare `*addr` with `expected` and swap it with `new` if equal.
+/// Write the previous value to `old`, return 0 if success, otherwise 1.
+#[naked]
+pub(crate) unsafe extern "C" fn __atomic_cmpxchg_u32(
+    addr: *mut u32,
+    expected: u32,
+    new: u32,
+    old: *mut u32,
+) -> usize {
+    core::arch::asm!(
+        "
+        1:  lr.w.aqrl   t0, (a0)
+            bne         t0, a1, 2f
+        3:  sc.w.aqrl   t1, a2, (a0)
+            bnez        t1, 1b
+        2:  sw          t0, 0(a3)
+            li          a0, 0
+            ret
+        4:  li          a0, 1
+            ret
+        ",
+        crate::components::extable::ex_table!("1b", "4b"),
+        crate::components::extable::ex_table!("3b", "4b"),
+        options(noreturn)
+    )
+}
+
+/// Swap `*addr` with `val`.
+/// Write the previous value to `old`, return 0 if success, otherwise 1.
+#[naked]
+pub(crate) unsafe extern "C" fn __atomic_xchg_u32(addr: *mut u32, val: u32, old: *mut u32) -> usize {
+    core::arch::asm!(
+        "
+        1:  amoswap.w.aqrl  t0, a1, (a0)
+            sw              t0, 0(a2)
+            li              a0, 0
+            ret
+        2:  li              a0, 1
+            ret
+        ",
+        crate::components::extable::ex_table!("1b", "2b"),
+        options(noreturn)
+    )
+}
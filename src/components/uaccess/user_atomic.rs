@@ -0,0 +1,93 @@
+//! Atomic operations on the user memory.
+//!
+//! These are used to implement futex. The faults during the operations
+//! are reported as [UserAccessError::Fault].
+
+use core::mem::size_of;
+
+use crate::VirtAddr;
+
+use super::{
+    __atomic_cmpxchg_u32, __atomic_xchg_u32, check_user_range, copy_from_user, UserAccessError,
+    UserAccessGuard,
+};
+
+/// The operation of the [fetch_op_u32].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtomicOp {
+    /// `*addr = operand`
+    Set,
+    /// `*addr += operand`
+    Add,
+    /// `*addr |= operand`
+    Or,
+    /// `*addr &= !operand`
+    AndNot,
+    /// `*addr ^= operand`
+    Xor,
+}
+
+impl AtomicOp {
+    #[inline]
+    fn apply(self, old: u32, operand: u32) -> u32 {
+        match self {
+            AtomicOp::Set => operand,
+            AtomicOp::Add => old.wrapping_add(operand),
+            AtomicOp::Or => old | operand,
+            AtomicOp::AndNot => old & !operand,
+            AtomicOp::Xor => old ^ operand,
+        }
+    }
+}
+
+/// Check the `u32` at `addr` is in the user address space and aligned.
+#[inline]
+fn check_user_u32(addr: VirtAddr) -> Result<(), UserAccessError> {
+    if addr.addr() % size_of::<u32>() != 0 {
+        return Err(UserAccessError::BadAddress);
+    }
+    check_user_range(addr, size_of::<u32>())
+}
+
+/// Compare the `u32` at `addr` with `expected`, replace it with `new` if equal.
+///
+/// Return the previous value, the exchange succeeded if it equals to `expected`.
+pub fn cmpxchg_u32(addr: VirtAddr, expected: u32, new: u32) -> Result<u32, UserAccessError> {
+    check_user_u32(addr)?;
+    let _guard = UserAccessGuard::new();
+    let mut old = 0;
+    match unsafe { __atomic_cmpxchg_u32(addr.addr() as _, expected, new, &mut old) } {
+        0 => Ok(old),
+        _ => Err(UserAccessError::Fault),
+    }
+}
+
+/// Replace the `u32` at `addr` with `val`, return the previous value.
+pub fn xchg_u32(addr: VirtAddr, val: u32) -> Result<u32, UserAccessError> {
+    check_user_u32(addr)?;
+    let _guard = UserAccessGuard::new();
+    let mut old = 0;
+    match unsafe { __atomic_xchg_u32(addr.addr() as _, val, &mut old) } {
+        0 => Ok(old),
+        _ => Err(UserAccessError::Fault),
+    }
+}
+
+/// Apply `op` with `operand` to the `u32` at `addr` atomically,
+/// return the previous value.
+pub fn fetch_op_u32(addr: VirtAddr, op: AtomicOp, operand: u32) -> Result<u32, UserAccessError> {
+    if op == AtomicOp::Set {
+        return xchg_u32(addr, operand);
+    }
+    check_user_u32(addr)?;
+    // The value read here may be stale, it is validated by the cmpxchg.
+    let mut buf = [0u8; size_of::<u32>()];
+    copy_from_user(&mut buf, addr)?;
+    let mut old = u32::from_ne_bytes(buf);
+    loop {
+        match cmpxchg_u32(addr, old, op.apply(old, operand))? {
+            current if current == old => return Ok(old),
+            current => old = current,
+        }
+    }
+}
@@ -0,0 +1,145 @@
+// TIPS: Labels only consist of 0 and 1 are ambiguous with binary literals in intel syntax.
+
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use raw_cpuid::CpuId;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::rflags::{self, RFlags};
+
+/// Whether SMAP is enabled, `stac` and `clac` are invalid without it.
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enable SMAP on the current cpu if it's supported.
+///
+/// The kernel can only access the user memory between [user_access_begin]
+/// and [user_access_end] after it.
+pub(crate) fn init() {
+    let smap = CpuId::new()
+        .get_extended_feature_info()
+        .is_some_and(|features| features.has_smap());
+    if smap {
+        unsafe { Cr4::write(Cr4::read() | Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION) };
+        SMAP_ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Allow the kernel to access the user memory, return the previous state.
+#[inline]
+pub(crate) fn user_access_begin() -> bool {
+    if !SMAP_ENABLED.load(Ordering::Relaxed) {
+        return true;
+    }
+    let prev = rflags::read().contains(RFlags::ALIGNMENT_CHECK);
+    unsafe { asm!("stac") };
+    prev
+}
+
+/// Restore the state returned by [user_access_begin].
+#[inline]
+pub(crate) fn user_access_end(prev: bool) {
+    if !prev && SMAP_ENABLED.load(Ordering::Relaxed) {
+        unsafe { asm!("clac") };
+    }
+}
+
+/// Copy `len` bytes from `src` to `dst`, return the number of bytes not copied.
+#[naked]
+pub(crate) unsafe extern "C" fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize {
+    core::arch::asm!(
+        "
+            mov     rcx, rdx
+        2:  rep movsb
+        3:  mov     rax, rcx
+            ret
+        ",
+        crate::components::extable::ex_table!("2b", "3b"),
+        options(noreturn)
+    )
+}
+
+/// Fill `len` bytes at `dst` with zero, return the number of bytes not cleared.
+#[naked]
+pub(crate) unsafe extern "C" fn __clear_user(dst: *mut u8, len: usize) -> usize {
+    core::arch::asm!(
+        "
+            mov     rcx, rsi
+            xor     eax, eax
+        2:  rep stosb
+        3:  mov     rax, rcx
+            ret
+        ",
+        crate::components::extable::ex_table!("2b", "3b"),
+        options(noreturn)
+    )
+}
+
+/// Copy at most `len` bytes of the string at `src` to `dst`.
+/// Return the length of the string without NUL, or -1 if faulted.
+#[naked]
+pub(crate) unsafe extern "C" fn __strncpy_user(dst: *mut u8, src: *const u8, len: usize) -> isize {
+    core::arch::asm!(
+        "
+            mov     rcx, rdx
+            test    rcx, rcx
+            jz      3f
+        2:  mov     al, byte ptr [rsi]
+            mov     byte ptr [rdi], al
+            test    al, al
+            jz      3f
+            inc     rsi
+            inc     rdi
+            dec     rcx
+            jnz     2b
+        3:  mov     rax, rdx
+            sub     rax, rcx
+            ret
+        4:  mov     rax, -1
+            ret
+        ",
+        crate::components::extable::ex_table!("2b", "4b"),
+        options(noreturn)
+    )
+}
+
+/// Compare `*addr` with `expected` and swap it with `new` if equal.
+/// Write the previous value to `old`, return 0 if success, otherwise 1.
+#[naked]
+pub(crate) unsafe extern "C" fn __atomic_cmpxchg_u32(
+    addr: *mut u32,
+    expected: u32,
+    new: u32,
+    old: *mut u32,
+) -> usize {
+    core::arch::asm!(
+        "
+            mov     eax, esi
+        2:  lock cmpxchg dword ptr [rdi], edx
+            mov     dword ptr [rcx], eax
+            xor     eax, eax
+            ret
+        3:  mov     eax, 1
+            ret
+        ",
+        crate::components::extable::ex_table!("2b", "3b"),
+        options(noreturn)
+    )
+}
+
+/// Swap `*addr` with `val`.
+/// Write the previous value to `old`, return 0 if success, otherwise 1.
+#[naked]
+pub(crate) unsafe extern "C" fn __atomic_xchg_u32(addr: *mut u32, val: u32, old: *mut u32) -> usize {
+    core::arch::asm!(
+        "
+        2:  xchg    dword ptr [rdi], esi
+            mov     dword ptr [rdx], esi
+            xor     eax, eax
+            ret
+        3:  mov     eax, 1
+            ret
+        ",
+        crate::components::extable::ex_table!("2b", "3b"),
+        options(noreturn)
+    )
+}
@@ -38,17 +38,17 @@
 //!
 //! /// kernel interrupt
 //! #[polyhal::arch_interrupt]
-//! fn kernel_interrupt(ctx: &mut TrapFrame, trap_type: TrapType) {
+//! fn kernel_interrupt(ctx: &mut TrapFrame, trap_type: TrapType, token: ThreadToken) {
 //!     // println!("trap_type @ {:x?} {:#x?}", trap_type, ctx);
 //!     match trap_type {
-//!         Breakpoint => return,
+//!         Breakpoint(_) => return,
 //!         UserEnvCall => {
 //!             // jump to next instruction anyway
 //!             ctx.syscall_ok();
 //!             log::info!("Handle a syscall");
 //!         }
-//!         StorePageFault(_paddr) | LoadPageFault(_paddr) | InstructionPageFault(_paddr) => {
-//!             log::info!("page fault");
+//!         StorePageFault(info) | LoadPageFault(info) | InstructionPageFault(info) => {
+//!             log::info!("page fault @ {:#x}, from user: {}", info.addr, info.user);
 //!         }
 //!         IllegalInstruction(_) => {
 //!             log::info!("illegal instruction");
@@ -88,6 +88,13 @@
 //!
 //! The main(hardid: usize) is the entry point.
 //!
+//! The function marked by `#[arch_interrupt]` is the fallback of the traps.
+//! The handlers for irqs and trap classes can also be registered at runtime,
+//! see [register_irq_handler] and [register_trap_handler].
+//! The `token` is valid if the trap came from the task entered by
+//! [TrapFrame::into_user](trapframe::TrapFrame::into_user), restore it to
+//! return there, see `docs/for-async-runtime.md`.
+//!
 //! You can find details in the example.
 //!
 //! In this crate you can find some interfaces to use.
@@ -172,6 +179,7 @@ pub mod tests {
     use crate::PhysPage;
     use crate::TrapFrame;
     use crate::TrapType;
+    use crate::trap::ThreadToken;
     use buddy_system_allocator::LockedHeap;
     use core::panic::PanicInfo;
 
@@ -226,7 +234,7 @@ pub mod tests {
 
     /// kernel interrupt
     #[crate::arch_interrupt]
-    fn kernel_interrupt(ctx: &mut TrapFrame, trap_type: TrapType) {
+    fn kernel_interrupt(ctx: &mut TrapFrame, trap_type: TrapType, token: ThreadToken) {
         // println!("trap_type @ {:x?} {:#x?}", trap_type, ctx);
     }
 